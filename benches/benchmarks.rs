@@ -185,6 +185,8 @@ fn server_benchmarks(c: &mut Criterion) {
                     key: key.clone(),
                     value: value.clone(),
                     ttl_seconds: None,
+                    content_type: None,
+                    tags: Default::default(),
                 });
                 client.set(request).await.unwrap();
             });
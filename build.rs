@@ -7,6 +7,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build_client(true)
         .file_descriptor_set_path(&descriptor_path)
         .protoc_arg("--experimental_allow_proto3_optional")
+        // Idempotency fingerprints hash the encoded request, so map fields of
+        // mutating requests must encode in a stable order
+        .btree_map(".kvstore.SetRequest.tags")
+        .btree_map(".kvstore.HashSetRequest.fields")
         .compile_protos(&["proto/kvstore.proto"], &["proto"])?;
     Ok(())
 }
@@ -0,0 +1,549 @@
+//! Scheduled namespace backups
+//!
+//! A [`BackupWorker`] exports configured namespaces on a cron schedule to an
+//! [`ObjectStore`] as NDJSON snapshots (the format of
+//! [`KVStore::export_namespace`]), independently of Redis persistence, and
+//! keeps the newest snapshots of each namespace. [`restore_snapshot`] replays
+//! a snapshot into a namespace.
+//!
+//! Snapshots are stored as `{name}/{timestamp}.ndjson`, where `name` labels
+//! the namespace so that tokens never appear in object names, and the
+//! timestamp is when the backup was scheduled, in UTC.
+
+use crate::error::{KVStoreError, Result};
+use crate::store::{read_ndjson, ExportRecord, ImportConflict, ImportSummary, INTERNAL_PREFIX};
+use crate::KVStore;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use cron::Schedule;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio_stream::{Stream, StreamExt};
+
+/// Default backup schedule: every hour, on the hour
+pub const DEFAULT_BACKUP_SCHEDULE: &str = "0 0 * * * *";
+
+/// Default number of snapshots kept per namespace
+pub const DEFAULT_BACKUP_RETENTION: usize = 24;
+
+/// How long a scheduled backup stays claimed by the instance running it
+const CLAIM_TTL_SECONDS: u64 = 24 * 60 * 60;
+
+/// Format of snapshot ids, sorting in time order
+const SNAPSHOT_ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+const SNAPSHOT_SUFFIX: &str = ".ndjson";
+
+/// Size of the chunks objects are read in
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A stream of object contents
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>>> + Send>>;
+
+/// Storage for backup snapshots
+///
+/// Object names are `/`-separated paths. Implementations must not expose a
+/// partially written object under its name.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Store an object, replacing any existing object of the same name
+    async fn put(&self, name: &str, body: ByteStream) -> Result<()>;
+
+    /// Read an object, or fail with [`KVStoreError::KeyNotFound`]
+    async fn get(&self, name: &str) -> Result<ByteStream>;
+
+    /// List the names of the objects starting with `prefix`, sorted
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Delete an object; deleting a missing object succeeds
+    async fn delete(&self, name: &str) -> Result<()>;
+}
+
+/// An [`ObjectStore`] keeping objects as files under a directory
+#[derive(Debug, Clone)]
+pub struct FileObjectStore {
+    root: PathBuf,
+}
+
+impl FileObjectStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FileObjectStore { root: root.into() }
+    }
+
+    /// Map an object name to its file, refusing names that would escape the
+    /// root directory
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        let mut path = self.root.clone();
+        for component in name.split('/') {
+            if component.is_empty()
+                || component == "."
+                || component == ".."
+                || component.contains('\\')
+            {
+                return Err(KVStoreError::InvalidRequest(format!(
+                    "Invalid object name: {}",
+                    name
+                )));
+            }
+            path.push(component);
+        }
+        Ok(path)
+    }
+}
+
+/// Turn a reader into a stream of chunks
+pub fn read_chunks(reader: impl AsyncRead + Send + Unpin + 'static) -> ByteStream {
+    Box::pin(futures::stream::unfold(reader, |mut reader| async move {
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        match reader.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(chunk), reader))
+            }
+            Err(e) => Some((Err(e.into()), reader)),
+        }
+    }))
+}
+
+#[async_trait]
+impl ObjectStore for FileObjectStore {
+    async fn put(&self, name: &str, mut body: ByteStream) -> Result<()> {
+        let path = self.path(name)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Written next to the object and renamed into place once complete
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let written = async {
+            let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&partial).await?);
+            while let Some(chunk) = body.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+            file.get_ref().sync_all().await?;
+            tokio::fs::rename(&partial, &path).await?;
+            Ok(())
+        }
+        .await;
+
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        written
+    }
+
+    async fn get(&self, name: &str) -> Result<ByteStream> {
+        match tokio::fs::File::open(self.path(name)?).await {
+            Ok(file) => Ok(read_chunks(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(KVStoreError::KeyNotFound(name.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut dirs = vec![(self.root.clone(), String::new())];
+        while let Some((dir, dir_name)) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let Ok(file_name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                let name = format!("{}{}", dir_name, file_name);
+                if entry.file_type().await?.is_dir() {
+                    // Only descend into directories that can hold matches
+                    let dir_name = format!("{}/", name);
+                    if dir_name.starts_with(prefix) || prefix.starts_with(&dir_name) {
+                        dirs.push((entry.path(), dir_name));
+                    }
+                } else if name.starts_with(prefix) && !name.ends_with(".partial") {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(name)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A namespace to back up, labelled with a name used for its snapshots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupTarget {
+    pub name: String,
+    pub token: String,
+}
+
+impl BackupTarget {
+    pub fn new(name: &str, token: &str) -> Result<Self> {
+        check_backup_name(name)?;
+        if token.is_empty() {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Backup {} has no token",
+                name
+            )));
+        }
+        Ok(BackupTarget {
+            name: name.to_string(),
+            token: token.to_string(),
+        })
+    }
+}
+
+/// Parses `name=token`
+impl FromStr for BackupTarget {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, token) = s.split_once('=').ok_or_else(|| {
+            KVStoreError::InvalidRequest(format!("Expected name=token, got {}", s))
+        })?;
+        BackupTarget::new(name.trim(), token.trim())
+    }
+}
+
+/// Check that a backup name is usable as a single object name component
+pub fn check_backup_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Invalid backup name '{}': use letters, digits, '-', '_' and '.'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// The id of a snapshot taken at `at`
+pub fn snapshot_id(at: DateTime<Utc>) -> String {
+    at.format(SNAPSHOT_ID_FORMAT).to_string()
+}
+
+/// Parse a snapshot id back into the time it was taken
+pub fn parse_snapshot_id(id: &str) -> Result<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(id, SNAPSHOT_ID_FORMAT)
+        .map(|at| at.and_utc())
+        .map_err(|_| KVStoreError::InvalidRequest(format!("Invalid snapshot id: {}", id)))
+}
+
+fn snapshot_object(name: &str, id: &str) -> String {
+    format!("{}/{}{}", name, id, SNAPSHOT_SUFFIX)
+}
+
+/// Export a namespace as a snapshot taken at `at`
+///
+/// # Returns
+///
+/// The id of the snapshot
+pub async fn backup_namespace(
+    store: &KVStore,
+    objects: &dyn ObjectStore,
+    target: &BackupTarget,
+    at: DateTime<Utc>,
+) -> Result<String> {
+    let id = snapshot_id(at);
+    let records = store.export_namespace(&target.token, "").await?;
+    let body = records.map(|record| record.and_then(|r| r.to_ndjson()).map(String::into_bytes));
+    objects
+        .put(&snapshot_object(&target.name, &id), Box::pin(body))
+        .await?;
+    Ok(id)
+}
+
+/// List the ids of the snapshots of a namespace, oldest first
+pub async fn list_snapshots(objects: &dyn ObjectStore, name: &str) -> Result<Vec<String>> {
+    check_backup_name(name)?;
+    let prefix = format!("{}/", name);
+    let names = objects.list(&prefix).await?;
+
+    Ok(names
+        .iter()
+        .filter_map(|object| object[prefix.len()..].strip_suffix(SNAPSHOT_SUFFIX))
+        .filter(|id| parse_snapshot_id(id).is_ok())
+        .map(String::from)
+        .collect())
+}
+
+/// Delete all but the newest `retention` snapshots of a namespace
+///
+/// # Returns
+///
+/// The number of snapshots deleted
+pub async fn prune_snapshots(
+    objects: &dyn ObjectStore,
+    name: &str,
+    retention: usize,
+) -> Result<usize> {
+    let snapshots = list_snapshots(objects, name).await?;
+    let expired = snapshots.len().saturating_sub(retention);
+    for id in &snapshots[..expired] {
+        objects.delete(&snapshot_object(name, id)).await?;
+    }
+    Ok(expired)
+}
+
+/// Replay a snapshot of the namespace `name` into `token`'s namespace
+///
+/// Restores the newest snapshot if `id` is `None`. With `clean`, every key of
+/// the target namespace is deleted first, so that it ends up as it was when
+/// the snapshot was taken; otherwise existing keys are handled according to
+/// `conflict`. A clean restore reads and validates the whole snapshot before
+/// deleting anything, holding it in memory, so that a corrupt or invalid
+/// snapshot leaves the namespace untouched.
+pub async fn restore_snapshot(
+    store: &KVStore,
+    objects: &dyn ObjectStore,
+    name: &str,
+    id: Option<&str>,
+    token: &str,
+    conflict: ImportConflict,
+    clean: bool,
+) -> Result<ImportSummary> {
+    let id = match id {
+        Some(id) => {
+            parse_snapshot_id(id)?;
+            id.to_string()
+        }
+        None => list_snapshots(objects, name)
+            .await?
+            .pop()
+            .ok_or_else(|| KVStoreError::KeyNotFound(format!("No snapshots of {}", name)))?,
+    };
+    check_backup_name(name)?;
+    let body = objects.get(&snapshot_object(name, &id)).await?;
+
+    tracing::info!("Restoring snapshot {} of {}", id, name);
+    if !clean {
+        return store
+            .import_namespace(token, read_ndjson(body), conflict)
+            .await;
+    }
+
+    let records: Vec<ExportRecord> = read_ndjson(body).collect::<Result<_>>().await?;
+    for record in &records {
+        store.validate_import(token, record).await?;
+    }
+    store.delete_prefix(token, "").await?;
+    store
+        .import_namespace(
+            token,
+            tokio_stream::iter(records.into_iter().map(Ok)),
+            conflict,
+        )
+        .await
+}
+
+/// Backs up namespaces on a schedule
+///
+/// Any number of server instances can run a worker: each scheduled backup of
+/// a namespace is claimed in Redis by a single instance.
+pub struct BackupWorker {
+    store: KVStore,
+    objects: Arc<dyn ObjectStore>,
+    targets: Vec<BackupTarget>,
+    schedule: Schedule,
+    retention: usize,
+}
+
+impl BackupWorker {
+    /// Create a worker backing up `targets` on `schedule`, a cron expression
+    /// with a seconds field, e.g. `0 30 2 * * *` for 02:30 UTC every day
+    pub fn new(
+        store: KVStore,
+        objects: Arc<dyn ObjectStore>,
+        targets: Vec<BackupTarget>,
+        schedule: &str,
+    ) -> Result<Self> {
+        let schedule = Schedule::from_str(schedule).map_err(|e| {
+            KVStoreError::InvalidRequest(format!("Invalid backup schedule '{}': {}", schedule, e))
+        })?;
+
+        Ok(BackupWorker {
+            store,
+            objects,
+            targets,
+            schedule,
+            retention: DEFAULT_BACKUP_RETENTION,
+        })
+    }
+
+    /// Set how many snapshots are kept per namespace
+    pub fn with_retention(mut self, retention: usize) -> Self {
+        self.retention = retention.max(1);
+        self
+    }
+
+    /// Take scheduled backups until the task is dropped
+    pub async fn run(self) {
+        let mut last = Utc::now();
+        while let Some(at) = self.schedule.after(&last).next() {
+            let wait = (at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            last = at;
+
+            for target in &self.targets {
+                if let Err(e) = self.backup(target, at).await {
+                    tracing::error!("Backup of {} failed: {}", target.name, e);
+                }
+            }
+        }
+    }
+
+    async fn backup(&self, target: &BackupTarget, at: DateTime<Utc>) -> Result<()> {
+        if !self.claim(target, at).await? {
+            tracing::debug!(
+                "Backup of {} at {} taken by another instance",
+                target.name,
+                at
+            );
+            return Ok(());
+        }
+
+        let id = backup_namespace(&self.store, self.objects.as_ref(), target, at).await?;
+        let pruned = prune_snapshots(self.objects.as_ref(), &target.name, self.retention).await?;
+        tracing::info!(
+            "Backed up {} as snapshot {} ({} old snapshots pruned)",
+            target.name,
+            id,
+            pruned
+        );
+        Ok(())
+    }
+
+    /// Claim the backup of `target` scheduled at `at` for this instance
+    async fn claim(&self, target: &BackupTarget, at: DateTime<Utc>) -> Result<bool> {
+        let key = format!(
+            "{}:backup:{}:{}",
+            INTERNAL_PREFIX,
+            target.name,
+            snapshot_id(at)
+        );
+        let mut conn = self.store.connection_manager();
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(CLAIM_TTL_SECONDS)
+            .query_async(&mut conn)
+            .await?;
+        Ok(claimed.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(text: &str) -> ByteStream {
+        Box::pin(tokio_stream::iter(vec![Ok(text.as_bytes().to_vec())]))
+    }
+
+    async fn read(objects: &FileObjectStore, name: &str) -> String {
+        let chunks: Vec<_> = objects.get(name).await.unwrap().collect().await;
+        let bytes: Vec<u8> = chunks.into_iter().flat_map(|c| c.unwrap()).collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_file_object_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = FileObjectStore::new(dir.path());
+
+        objects.put("a/1.ndjson", body("one")).await.unwrap();
+        objects.put("a/2.ndjson", body("two")).await.unwrap();
+        objects.put("b/1.ndjson", body("other")).await.unwrap();
+        objects.put("a/1.ndjson", body("replaced")).await.unwrap();
+
+        assert_eq!(read(&objects, "a/1.ndjson").await, "replaced");
+        assert_eq!(
+            objects.list("a/").await.unwrap(),
+            ["a/1.ndjson", "a/2.ndjson"]
+        );
+        assert_eq!(objects.list("").await.unwrap().len(), 3);
+
+        objects.delete("a/1.ndjson").await.unwrap();
+        objects.delete("a/1.ndjson").await.unwrap();
+        assert!(matches!(
+            objects.get("a/1.ndjson").await,
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+
+        for name in ["../escape", "/abs", "a//b", "a/./b"] {
+            assert!(objects.put(name, body("x")).await.is_err(), "{}", name);
+        }
+
+        // A failed write leaves nothing behind
+        let failing: ByteStream = Box::pin(tokio_stream::iter(vec![
+            Ok(b"partial".to_vec()),
+            Err(KVStoreError::Internal("export failed".to_string())),
+        ]));
+        assert!(objects.put("a/3.ndjson", failing).await.is_err());
+        assert_eq!(objects.list("a/").await.unwrap(), ["a/2.ndjson"]);
+    }
+
+    #[tokio::test]
+    async fn test_prune_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = FileObjectStore::new(dir.path());
+
+        let start = parse_snapshot_id("20260101T000000Z").unwrap();
+        for hour in 0..5 {
+            let id = snapshot_id(start + chrono::Duration::hours(hour));
+            objects
+                .put(&snapshot_object("orders", &id), body(""))
+                .await
+                .unwrap();
+        }
+        objects.put("orders/notes.txt", body("")).await.unwrap();
+
+        assert_eq!(prune_snapshots(&objects, "orders", 2).await.unwrap(), 3);
+        assert_eq!(
+            list_snapshots(&objects, "orders").await.unwrap(),
+            ["20260101T030000Z", "20260101T040000Z"]
+        );
+        assert_eq!(prune_snapshots(&objects, "orders", 2).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_backup_target() {
+        let target: BackupTarget = "orders = secret-token".parse().unwrap();
+        assert_eq!(target, BackupTarget::new("orders", "secret-token").unwrap());
+        for invalid in ["orders", "=token", "a/b=token", "..=token", "orders="] {
+            assert!(invalid.parse::<BackupTarget>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_ids_sort_in_time_order() {
+        let earlier = parse_snapshot_id("20261018T090000Z").unwrap();
+        let later = earlier + chrono::Duration::minutes(90);
+        assert_eq!(snapshot_id(later), "20261018T103000Z");
+        assert!(snapshot_id(earlier) < snapshot_id(later));
+        assert!(parse_snapshot_id("latest").is_err());
+        assert!(Schedule::from_str(DEFAULT_BACKUP_SCHEDULE).is_ok());
+    }
+}
@@ -36,6 +36,18 @@ pub enum KVStoreError {
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
 
+    /// Key holds a value of a different data type than the operation expects
+    #[error("Wrong type for key: {0}")]
+    WrongType(String),
+
+    /// Operation lost a race with a concurrent writer or violates a precondition
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    /// Requested data is no longer retained
+    #[error("Gone: {0}")]
+    Gone(String),
+
     /// Internal server error
     #[error("Internal error: {0}")]
     Internal(String),
@@ -68,6 +80,18 @@ impl IntoResponse for KVStoreError {
                 tracing::warn!("Invalid request: {}", msg);
                 (StatusCode::BAD_REQUEST, msg.as_str())
             }
+            KVStoreError::WrongType(ref key) => {
+                tracing::debug!("Wrong type for key: {}", key);
+                (StatusCode::CONFLICT, "Key holds a different data type")
+            }
+            KVStoreError::Conflict(ref msg) => {
+                tracing::debug!("Conflict: {}", msg);
+                (StatusCode::CONFLICT, msg.as_str())
+            }
+            KVStoreError::Gone(ref msg) => {
+                tracing::debug!("Gone: {}", msg);
+                (StatusCode::GONE, msg.as_str())
+            }
             KVStoreError::Internal(ref msg) => {
                 tracing::error!("Internal error: {}", msg);
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal error")
@@ -97,6 +121,11 @@ impl From<KVStoreError> for tonic::Status {
                 tonic::Status::not_found(format!("Key not found: {}", key))
             }
             KVStoreError::InvalidRequest(msg) => tonic::Status::invalid_argument(msg),
+            KVStoreError::WrongType(key) => {
+                tonic::Status::failed_precondition(format!("Wrong type for key: {}", key))
+            }
+            KVStoreError::Conflict(msg) => tonic::Status::aborted(msg),
+            KVStoreError::Gone(msg) => tonic::Status::out_of_range(msg),
             KVStoreError::Internal(msg) => tonic::Status::internal(msg),
             KVStoreError::Utf8(e) => tonic::Status::internal(format!("Encoding error: {}", e)),
         }
@@ -125,5 +154,13 @@ mod tests {
         let error = KVStoreError::Unauthorized("test".to_string());
         let response = error.into_response();
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let error = KVStoreError::WrongType("test".to_string());
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let error = KVStoreError::Gone("test".to_string());
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::GONE);
     }
 }
@@ -2,9 +2,18 @@
 //!
 //! Provides gRPC service for KVStore operations.
 
-use crate::{KVStore, KVStoreError};
-use tokio_stream::StreamExt;
-use tonic::{Request, Response, Status};
+use crate::store::{
+    check_idempotency_key, request_fingerprint, ChangeEvent, ChangeFeedEntry, ChangeKind,
+    ChangeOffset, ExportRecord, ExportedValue, HistoryPolicy, IdempotentResponse, ImportConflict,
+    IndexDefinition, IndexQuery, ListEnd, LockLease, Metadata, Operation, OperationResult,
+    PatchFormat, Precondition, ReplicationStatus, Revision, ScoredMember, SearchMode, SearchQuery,
+    SetOperation, SetOptions, TransactionOutcome, Webhook, WebhookInfo,
+};
+use crate::{short_token, KVStore, KVStoreError};
+use std::future::Future;
+use std::time::Duration;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{metadata::MetadataValue, Code, Request, Response, Status};
 
 // Include generated protobuf code
 pub mod kv_store {
@@ -17,6 +26,333 @@ pub const KVSTORE_FILE_DESCRIPTOR_SET: &[u8] =
 pub use kv_store::kv_store_server;
 pub use kv_store::kv_store_server::KvStoreServer;
 
+impl From<kv_store::ListEnd> for ListEnd {
+    fn from(end: kv_store::ListEnd) -> Self {
+        match end {
+            kv_store::ListEnd::Left => ListEnd::Left,
+            kv_store::ListEnd::Right => ListEnd::Right,
+        }
+    }
+}
+
+impl From<kv_store::SetOperation> for SetOperation {
+    fn from(operation: kv_store::SetOperation) -> Self {
+        match operation {
+            kv_store::SetOperation::Intersection => SetOperation::Intersection,
+            kv_store::SetOperation::Union => SetOperation::Union,
+        }
+    }
+}
+
+impl From<kv_store::PatchFormat> for PatchFormat {
+    fn from(format: kv_store::PatchFormat) -> Self {
+        match format {
+            kv_store::PatchFormat::JsonPatch => PatchFormat::JsonPatch,
+            kv_store::PatchFormat::MergePatch => PatchFormat::MergePatch,
+        }
+    }
+}
+
+impl From<kv_store::SearchMode> for SearchMode {
+    fn from(mode: kv_store::SearchMode) -> Self {
+        match mode {
+            kv_store::SearchMode::Prefix => SearchMode::Prefix,
+            kv_store::SearchMode::Glob => SearchMode::Glob,
+            kv_store::SearchMode::Regex => SearchMode::Regex,
+        }
+    }
+}
+
+impl From<ChangeEvent> for kv_store::WatchEvent {
+    fn from(event: ChangeEvent) -> Self {
+        let change_type = kv_store::ChangeType::from(event.kind);
+        kv_store::WatchEvent {
+            r#type: change_type.into(),
+            key: event.key,
+            value: event.value,
+            version: event.version,
+            content_type: event.content_type,
+            id: event.id,
+            timestamp: event.timestamp,
+            expires_at: event.expires_at,
+            heartbeat: false,
+        }
+    }
+}
+
+impl From<kv_store::WatchEvent> for ChangeEvent {
+    fn from(event: kv_store::WatchEvent) -> Self {
+        ChangeEvent {
+            kind: ChangeKind::from(event.r#type()),
+            key: event.key,
+            value: event.value,
+            version: event.version,
+            content_type: event.content_type,
+            id: event.id,
+            timestamp: event.timestamp,
+            expires_at: event.expires_at,
+        }
+    }
+}
+
+impl From<kv_store::ChangeType> for ChangeKind {
+    fn from(change_type: kv_store::ChangeType) -> Self {
+        match change_type {
+            kv_store::ChangeType::Put => ChangeKind::Put,
+            kv_store::ChangeType::Delete => ChangeKind::Delete,
+            kv_store::ChangeType::Expire => ChangeKind::Expire,
+        }
+    }
+}
+
+impl From<ChangeKind> for kv_store::ChangeType {
+    fn from(kind: ChangeKind) -> Self {
+        match kind {
+            ChangeKind::Put => kv_store::ChangeType::Put,
+            ChangeKind::Delete => kv_store::ChangeType::Delete,
+            ChangeKind::Expire => kv_store::ChangeType::Expire,
+        }
+    }
+}
+
+impl From<WebhookInfo> for kv_store::Webhook {
+    fn from(webhook: WebhookInfo) -> Self {
+        kv_store::Webhook {
+            name: webhook.name,
+            url: webhook.url,
+            prefix: webhook.prefix,
+            events: webhook
+                .events
+                .into_iter()
+                .map(|kind| kv_store::ChangeType::from(kind).into())
+                .collect(),
+            status: Some(kv_store::WebhookStatus {
+                delivered: webhook.status.delivered,
+                failed: webhook.status.failed,
+                last_attempt_at: webhook.status.last_attempt_at,
+                last_status: webhook.status.last_status.map(u32::from),
+                last_error: webhook.status.last_error,
+            }),
+        }
+    }
+}
+
+impl From<LockLease> for kv_store::LockLease {
+    fn from(lease: LockLease) -> Self {
+        kv_store::LockLease {
+            name: lease.name,
+            owner: lease.owner,
+            fencing_token: lease.fencing_token,
+            ttl_seconds: lease.ttl.as_secs_f64(),
+        }
+    }
+}
+
+impl From<Metadata> for kv_store::Metadata {
+    fn from(metadata: Metadata) -> Self {
+        kv_store::Metadata {
+            content_type: metadata.content_type,
+            created_at: metadata.created_at,
+            updated_at: metadata.updated_at,
+            size: metadata.size,
+            tags: metadata.tags,
+            version: metadata.version,
+        }
+    }
+}
+
+impl From<kv_store::HistoryPolicy> for HistoryPolicy {
+    fn from(policy: kv_store::HistoryPolicy) -> Self {
+        HistoryPolicy {
+            max_revisions: policy.max_revisions,
+            max_age_seconds: policy.max_age_seconds,
+        }
+    }
+}
+
+impl From<HistoryPolicy> for kv_store::HistoryPolicy {
+    fn from(policy: HistoryPolicy) -> Self {
+        kv_store::HistoryPolicy {
+            max_revisions: policy.max_revisions,
+            max_age_seconds: policy.max_age_seconds,
+        }
+    }
+}
+
+impl From<Revision> for kv_store::Revision {
+    fn from(revision: Revision) -> Self {
+        kv_store::Revision {
+            revision: revision.revision,
+            timestamp: revision.timestamp,
+            deleted: revision.deleted,
+            value: revision.value,
+            content_type: revision.content_type,
+        }
+    }
+}
+
+impl From<kv_store::TransactionPrecondition> for Precondition {
+    fn from(precondition: kv_store::TransactionPrecondition) -> Self {
+        let key = precondition.key.clone();
+        match precondition.check() {
+            kv_store::PreconditionCheck::Exists => Precondition::Exists { key },
+            kv_store::PreconditionCheck::Missing => Precondition::Missing { key },
+            kv_store::PreconditionCheck::Version => Precondition::Version {
+                key,
+                version: precondition.version,
+            },
+            kv_store::PreconditionCheck::Value => Precondition::Value {
+                key,
+                value: precondition.value,
+            },
+        }
+    }
+}
+
+impl From<kv_store::TransactionOperation> for Operation {
+    fn from(operation: kv_store::TransactionOperation) -> Self {
+        let key = operation.key.clone();
+        match operation.op() {
+            kv_store::OperationType::Set => Operation::Set {
+                key,
+                value: operation.value,
+                ttl_seconds: operation.ttl_seconds,
+                content_type: operation.content_type,
+            },
+            kv_store::OperationType::Delete => Operation::Delete { key },
+            kv_store::OperationType::Incr => Operation::Incr {
+                key,
+                by: operation.by.unwrap_or(1),
+            },
+        }
+    }
+}
+
+impl From<OperationResult> for kv_store::TransactionOperationResult {
+    fn from(result: OperationResult) -> Self {
+        match result {
+            OperationResult::Set { version } => kv_store::TransactionOperationResult {
+                op: kv_store::OperationType::Set.into(),
+                version,
+                ..Default::default()
+            },
+            OperationResult::Delete { deleted } => kv_store::TransactionOperationResult {
+                op: kv_store::OperationType::Delete.into(),
+                deleted,
+                ..Default::default()
+            },
+            OperationResult::Incr { value, version } => kv_store::TransactionOperationResult {
+                op: kv_store::OperationType::Incr.into(),
+                version,
+                value,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl From<ExportRecord> for kv_store::ExportedKey {
+    fn from(record: ExportRecord) -> Self {
+        let mut exported = kv_store::ExportedKey {
+            key: record.key,
+            ttl_ms: record.ttl_ms,
+            metadata: record.metadata.into_iter().collect(),
+            ..Default::default()
+        };
+        let value_type = match record.value {
+            ExportedValue::String(value) => {
+                exported.value = value;
+                kv_store::ValueType::String
+            }
+            ExportedValue::Hash(fields) => {
+                exported.fields = fields.into_iter().collect();
+                kv_store::ValueType::Hash
+            }
+            ExportedValue::List(items) => {
+                exported.items = items;
+                kv_store::ValueType::List
+            }
+            ExportedValue::Set(items) => {
+                exported.items = items;
+                kv_store::ValueType::Set
+            }
+            ExportedValue::SortedSet(members) => {
+                exported.members = members
+                    .into_iter()
+                    .map(|m| kv_store::ScoredMember {
+                        member: m.member,
+                        score: m.score,
+                    })
+                    .collect();
+                kv_store::ValueType::SortedSet
+            }
+        };
+        exported.r#type = value_type.into();
+        exported
+    }
+}
+
+impl From<kv_store::ExportedKey> for ExportRecord {
+    fn from(exported: kv_store::ExportedKey) -> Self {
+        let value = match exported.r#type() {
+            kv_store::ValueType::String => ExportedValue::String(exported.value),
+            kv_store::ValueType::Hash => ExportedValue::Hash(exported.fields.into_iter().collect()),
+            kv_store::ValueType::List => ExportedValue::List(exported.items),
+            kv_store::ValueType::Set => ExportedValue::Set(exported.items),
+            kv_store::ValueType::SortedSet => ExportedValue::SortedSet(
+                exported
+                    .members
+                    .into_iter()
+                    .map(|m| ScoredMember {
+                        member: m.member,
+                        score: m.score,
+                    })
+                    .collect(),
+            ),
+        };
+        ExportRecord {
+            key: exported.key,
+            value,
+            ttl_ms: exported.ttl_ms,
+            metadata: exported.metadata.into_iter().collect(),
+        }
+    }
+}
+
+impl From<ReplicationStatus> for kv_store::ReplicationStatus {
+    fn from(status: ReplicationStatus) -> Self {
+        kv_store::ReplicationStatus {
+            source: status.source,
+            connected: status.connected,
+            offset: status.offset,
+            applied: status.applied,
+            stale: status.stale,
+            lag_ms: status.lag_ms,
+            last_change_at: status.last_change_at,
+            last_error: status.last_error,
+        }
+    }
+}
+
+impl From<kv_store::ImportConflict> for ImportConflict {
+    fn from(conflict: kv_store::ImportConflict) -> Self {
+        match conflict {
+            kv_store::ImportConflict::Fail => ImportConflict::Fail,
+            kv_store::ImportConflict::Skip => ImportConflict::Skip,
+            kv_store::ImportConflict::Overwrite => ImportConflict::Overwrite,
+        }
+    }
+}
+
+/// Parse JSON text received in a request
+fn parse_json(field: &str, text: &str) -> Result<serde_json::Value, Status> {
+    serde_json::from_str(text)
+        .map_err(|e| Status::invalid_argument(format!("Invalid JSON in {}: {}", field, e)))
+}
+
+/// Page size used when a paginated request leaves its limit unset
+const DEFAULT_PAGE_SIZE: usize = 100;
+
 /// gRPC service implementation
 pub struct KVStoreService {
     store: KVStore,
@@ -28,174 +364,2376 @@ impl KVStoreService {
         Self { store }
     }
 
-    /// Validate a request token
-    async fn validate_request_token(&self, token: &str) -> Result<(), Status> {
-        let is_valid = self
+    /// Validate a request token
+    async fn validate_request_token(&self, token: &str) -> Result<(), Status> {
+        let is_valid = self
+            .store
+            .validate_token(token)
+            .await
+            .map_err(|e| Status::internal(format!("Token validation failed: {}", e)))?;
+
+        if !is_valid {
+            return Err(Status::unauthenticated("Invalid token"));
+        }
+
+        Ok(())
+    }
+
+    /// Validate the token of a mutating call, then apply the call at most once
+    /// per idempotency key
+    ///
+    /// Repeats get the first outcome replayed, including errors the client
+    /// caused; transient errors release the key so the call can be retried.
+    async fn idempotent<T: prost::Message + Default>(
+        &self,
+        idempotency: Idempotency,
+        call: impl Future<Output = Result<Response<T>, Status>>,
+    ) -> Result<Response<T>, Status> {
+        // Keys are scoped to the namespace, so only valid tokens claim them
+        self.validate_request_token(&idempotency.token).await?;
+
+        let Idempotency {
+            token,
+            key: Some(key),
+            fingerprint,
+        } = idempotency
+        else {
+            return call.await;
+        };
+
+        if let Some(stored) = self
+            .store
+            .claim_idempotency_key(&token, &key, &fingerprint)
+            .await
+            .map_err(Status::from)?
+        {
+            tracing::debug!("Replaying response for idempotency key {}", key);
+            return replay(stored);
+        }
+
+        let result = self
+            .store
+            .while_claimed(&token, &key, &fingerprint, call)
+            .await;
+        let outcome = match &result {
+            Ok(response) => Some(IdempotentResponse {
+                status: Code::Ok as u16,
+                content_type: String::new(),
+                body: response.get_ref().encode_to_vec(),
+            }),
+            Err(status) if is_transient(status.code()) => None,
+            Err(status) => Some(IdempotentResponse {
+                status: status.code() as u16,
+                content_type: String::new(),
+                body: status.message().as_bytes().to_vec(),
+            }),
+        };
+        let kept = match outcome {
+            Some(stored) => {
+                self.store
+                    .record_idempotent_response(&token, &key, &fingerprint, &stored)
+                    .await
+            }
+            None => {
+                self.store
+                    .release_idempotency_key(&token, &key, &fingerprint)
+                    .await
+            }
+        };
+        if let Err(e) = kept {
+            tracing::error!("Failed to keep outcome of idempotency key {}: {}", key, e);
+        }
+
+        result
+    }
+}
+
+/// Mutating calls, applied through [`KVStoreService::idempotent`] once the
+/// token has been validated
+impl KVStoreService {
+    async fn apply_set(
+        &self,
+        req: kv_store::SetRequest,
+    ) -> Result<Response<kv_store::SetResponse>, Status> {
+        tracing::info!(
+            "gRPC SET {} (token: {}, TTL: {:?})",
+            req.key,
+            short_token(&req.token),
+            req.ttl_seconds
+        );
+
+        // Set the value
+        let options = SetOptions {
+            ttl_seconds: req.ttl_seconds,
+            content_type: req.content_type,
+            tags: req.tags.into_iter().collect(),
+        };
+        self.store
+            .set_with_options(&req.token, &req.key, &req.value, &options)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn apply_delete(
+        &self,
+        req: kv_store::DeleteRequest,
+    ) -> Result<Response<kv_store::DeleteResponse>, Status> {
+        tracing::info!(
+            "gRPC DELETE {} (token: {})",
+            req.key,
+            short_token(&req.token)
+        );
+
+        // Delete the value
+        self.store
+            .delete(&req.token, &req.key)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::DeleteResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn apply_hash_set(
+        &self,
+        req: kv_store::HashSetRequest,
+    ) -> Result<Response<kv_store::HashSetResponse>, Status> {
+        tracing::info!(
+            "gRPC HASH SET {} ({} fields, token: {})",
+            req.key,
+            req.fields.len(),
+            short_token(&req.token)
+        );
+
+        let fields: Vec<(String, String)> = req.fields.into_iter().collect();
+        let added = self
+            .store
+            .hash_set(&req.token, &req.key, &fields)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::HashSetResponse {
+            added: added as u64,
+        }))
+    }
+
+    async fn apply_hash_delete(
+        &self,
+        req: kv_store::HashDeleteRequest,
+    ) -> Result<Response<kv_store::HashDeleteResponse>, Status> {
+        tracing::info!(
+            "gRPC HASH DELETE {} {:?} (token: {})",
+            req.key,
+            req.fields,
+            short_token(&req.token)
+        );
+
+        let removed = self
+            .store
+            .hash_delete(&req.token, &req.key, &req.fields)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::HashDeleteResponse {
+            removed: removed as u64,
+        }))
+    }
+
+    async fn apply_hash_increment(
+        &self,
+        req: kv_store::HashIncrementRequest,
+    ) -> Result<Response<kv_store::HashIncrementResponse>, Status> {
+        tracing::info!(
+            "gRPC HASH INCR {} {} by {} (token: {})",
+            req.key,
+            req.field,
+            req.delta,
+            short_token(&req.token)
+        );
+
+        let value = self
+            .store
+            .hash_increment(&req.token, &req.key, &req.field, req.delta)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::HashIncrementResponse { value }))
+    }
+
+    async fn apply_list_push(
+        &self,
+        req: kv_store::ListPushRequest,
+    ) -> Result<Response<kv_store::ListPushResponse>, Status> {
+        let end = ListEnd::from(req.end());
+
+        tracing::info!(
+            "gRPC LIST PUSH {:?} {} ({} values, token: {})",
+            end,
+            req.key,
+            req.values.len(),
+            short_token(&req.token)
+        );
+
+        let length = self
+            .store
+            .list_push(&req.token, &req.key, &req.values, end)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::ListPushResponse {
+            length: length as u64,
+        }))
+    }
+
+    async fn apply_list_pop(
+        &self,
+        req: kv_store::ListPopRequest,
+    ) -> Result<Response<kv_store::ListPopResponse>, Status> {
+        let end = ListEnd::from(req.end());
+        let count = req.count.max(1) as usize;
+
+        tracing::info!(
+            "gRPC LIST POP {:?} {} (count: {}, token: {})",
+            end,
+            req.key,
+            count,
+            short_token(&req.token)
+        );
+
+        let values = self
+            .store
+            .list_pop(&req.token, &req.key, end, count)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::ListPopResponse { values }))
+    }
+
+    async fn apply_list_blocking_pop(
+        &self,
+        req: kv_store::ListBlockingPopRequest,
+    ) -> Result<Response<kv_store::ListBlockingPopResponse>, Status> {
+        let end = ListEnd::from(req.end());
+
+        tracing::info!(
+            "gRPC LIST BLOCKING POP {:?} {} (timeout: {}s, token: {})",
+            end,
+            req.key,
+            req.timeout_seconds,
+            short_token(&req.token)
+        );
+
+        let timeout = Duration::try_from_secs_f64(req.timeout_seconds)
+            .map_err(|e| Status::invalid_argument(format!("Invalid timeout: {}", e)))?;
+        let value = self
+            .store
+            .list_blocking_pop(&req.token, &req.key, end, timeout)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::ListBlockingPopResponse {
+            found: value.is_some(),
+            value: value.unwrap_or_default(),
+        }))
+    }
+
+    async fn apply_sorted_set_add(
+        &self,
+        req: kv_store::SortedSetAddRequest,
+    ) -> Result<Response<kv_store::SortedSetAddResponse>, Status> {
+        tracing::info!(
+            "gRPC SORTED SET ADD {} ({} members, token: {})",
+            req.key,
+            req.members.len(),
+            short_token(&req.token)
+        );
+
+        let members: Vec<ScoredMember> = req
+            .members
+            .into_iter()
+            .map(|m| ScoredMember {
+                member: m.member,
+                score: m.score,
+            })
+            .collect();
+        let added = self
+            .store
+            .sorted_set_add(&req.token, &req.key, &members)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SortedSetAddResponse {
+            added: added as u64,
+        }))
+    }
+
+    async fn apply_sorted_set_increment(
+        &self,
+        req: kv_store::SortedSetIncrementRequest,
+    ) -> Result<Response<kv_store::SortedSetIncrementResponse>, Status> {
+        tracing::info!(
+            "gRPC SORTED SET INCR {} {} by {} (token: {})",
+            req.key,
+            req.member,
+            req.delta,
+            short_token(&req.token)
+        );
+
+        let score = self
+            .store
+            .sorted_set_increment(&req.token, &req.key, &req.member, req.delta)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SortedSetIncrementResponse {
+            score,
+        }))
+    }
+
+    async fn apply_sorted_set_remove(
+        &self,
+        req: kv_store::SortedSetRemoveRequest,
+    ) -> Result<Response<kv_store::SortedSetRemoveResponse>, Status> {
+        tracing::info!(
+            "gRPC SORTED SET REMOVE {} {:?} (token: {})",
+            req.key,
+            req.members,
+            short_token(&req.token)
+        );
+
+        let removed = self
+            .store
+            .sorted_set_remove(&req.token, &req.key, &req.members)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SortedSetRemoveResponse {
+            removed: removed as u64,
+        }))
+    }
+
+    async fn apply_set_add(
+        &self,
+        req: kv_store::SetAddRequest,
+    ) -> Result<Response<kv_store::SetAddResponse>, Status> {
+        tracing::info!(
+            "gRPC SET ADD {} ({} members, token: {})",
+            req.key,
+            req.members.len(),
+            short_token(&req.token)
+        );
+
+        let added = self
+            .store
+            .set_add(&req.token, &req.key, &req.members)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetAddResponse {
+            added: added as u64,
+        }))
+    }
+
+    async fn apply_set_remove(
+        &self,
+        req: kv_store::SetRemoveRequest,
+    ) -> Result<Response<kv_store::SetRemoveResponse>, Status> {
+        tracing::info!(
+            "gRPC SET REMOVE {} {:?} (token: {})",
+            req.key,
+            req.members,
+            short_token(&req.token)
+        );
+
+        let removed = self
+            .store
+            .set_remove(&req.token, &req.key, &req.members)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetRemoveResponse {
+            removed: removed as u64,
+        }))
+    }
+
+    async fn apply_set_json(
+        &self,
+        req: kv_store::SetJsonRequest,
+    ) -> Result<Response<kv_store::SetJsonResponse>, Status> {
+        tracing::info!(
+            "gRPC SET JSON {} (token: {}, TTL: {:?})",
+            req.key,
+            short_token(&req.token),
+            req.ttl_seconds
+        );
+
+        let document = parse_json("json", &req.json)?;
+        self.store
+            .set_json(&req.token, &req.key, &document, req.ttl_seconds)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetJsonResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn apply_patch_json(
+        &self,
+        req: kv_store::PatchJsonRequest,
+    ) -> Result<Response<kv_store::PatchJsonResponse>, Status> {
+        let format = PatchFormat::from(req.format());
+
+        tracing::info!(
+            "gRPC PATCH JSON {} ({:?}, token: {})",
+            req.key,
+            format,
+            short_token(&req.token)
+        );
+
+        let patch = parse_json("patch", &req.patch)?;
+        let document = self
+            .store
+            .patch_json(&req.token, &req.key, &patch, format)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::PatchJsonResponse {
+            json: document.to_string(),
+        }))
+    }
+
+    async fn apply_restore(
+        &self,
+        req: kv_store::RestoreRequest,
+    ) -> Result<Response<kv_store::RestoreResponse>, Status> {
+        tracing::info!(
+            "gRPC RESTORE {}@{} (token: {})",
+            req.key,
+            req.revision,
+            short_token(&req.token)
+        );
+
+        let revision = self
+            .store
+            .restore(&req.token, &req.key, &req.revision)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::RestoreResponse {
+            revision: Some(revision.into()),
+        }))
+    }
+
+    async fn apply_transaction(
+        &self,
+        req: kv_store::TransactionRequest,
+    ) -> Result<Response<kv_store::TransactionResponse>, Status> {
+        tracing::info!(
+            "gRPC TRANSACTION ({} preconditions, {} operations, token: {})",
+            req.preconditions.len(),
+            req.operations.len(),
+            short_token(&req.token)
+        );
+
+        let preconditions: Vec<Precondition> = req
+            .preconditions
+            .into_iter()
+            .map(Precondition::from)
+            .collect();
+        let operations: Vec<Operation> = req.operations.into_iter().map(Operation::from).collect();
+
+        let outcome = self
+            .store
+            .transaction(&req.token, &preconditions, &operations)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(match outcome {
+            TransactionOutcome::Committed(results) => kv_store::TransactionResponse {
+                committed: true,
+                results: results.into_iter().map(Into::into).collect(),
+                failed_precondition: None,
+            },
+            TransactionOutcome::PreconditionFailed { index, .. } => kv_store::TransactionResponse {
+                committed: false,
+                results: Vec::new(),
+                failed_precondition: Some(index as u32),
+            },
+        }))
+    }
+
+    async fn apply_delete_prefix(
+        &self,
+        req: kv_store::DeletePrefixRequest,
+    ) -> Result<Response<kv_store::DeletePrefixResponse>, Status> {
+        tracing::info!(
+            "gRPC DELETE PREFIX {:?} (dry run: {}, token: {})",
+            req.prefix,
+            req.dry_run,
+            short_token(&req.token)
+        );
+
+        if req.dry_run {
+            let matched = self
+                .store
+                .delete_prefix_dry_run(&req.token, &req.prefix)
+                .await
+                .map_err(Status::from)?;
+            return Ok(Response::new(kv_store::DeletePrefixResponse {
+                count: matched.count,
+                keys: matched.keys,
+                truncated: matched.truncated,
+            }));
+        }
+
+        let count = self
+            .store
+            .delete_prefix(&req.token, &req.prefix)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::DeletePrefixResponse {
+            count,
+            keys: Vec::new(),
+            truncated: false,
+        }))
+    }
+
+    async fn apply_rename(
+        &self,
+        req: kv_store::MoveRequest,
+    ) -> Result<Response<kv_store::MoveResponse>, Status> {
+        tracing::info!(
+            "gRPC RENAME {} -> {} (token: {})",
+            req.from,
+            req.to,
+            short_token(&req.token)
+        );
+
+        self.store
+            .rename(&req.token, &req.from, &req.to, req.overwrite)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::MoveResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn apply_copy(
+        &self,
+        req: kv_store::MoveRequest,
+    ) -> Result<Response<kv_store::MoveResponse>, Status> {
+        tracing::info!(
+            "gRPC COPY {} -> {} (token: {})",
+            req.from,
+            req.to,
+            short_token(&req.token)
+        );
+
+        self.store
+            .copy(&req.token, &req.from, &req.to, req.overwrite)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::MoveResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn apply_rename_prefix(
+        &self,
+        req: kv_store::RenamePrefixRequest,
+    ) -> Result<Response<kv_store::RenamePrefixResponse>, Status> {
+        tracing::info!(
+            "gRPC RENAME PREFIX {:?} -> {:?} (token: {})",
+            req.from_prefix,
+            req.to_prefix,
+            short_token(&req.token)
+        );
+
+        let outcome = self
+            .store
+            .rename_prefix(&req.token, &req.from_prefix, &req.to_prefix, req.overwrite)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::RenamePrefixResponse {
+            renamed: outcome.renamed,
+            skipped: outcome.skipped,
+        }))
+    }
+
+    async fn apply_undelete(
+        &self,
+        req: kv_store::UndeleteRequest,
+    ) -> Result<Response<kv_store::UndeleteResponse>, Status> {
+        tracing::info!(
+            "gRPC UNDELETE {} (token: {})",
+            req.key,
+            short_token(&req.token)
+        );
+
+        self.store
+            .undelete(&req.token, &req.key)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::UndeleteResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+}
+
+/// Metadata key carrying the client's idempotency key on mutating calls
+pub const IDEMPOTENCY_KEY_METADATA: &str = "idempotency-key";
+
+/// Metadata key set on replayed responses
+pub const IDEMPOTENT_REPLAYED_METADATA: &str = "idempotent-replayed";
+
+/// The token of a mutating call, with the idempotency key sent with it and
+/// the call's fingerprint
+struct Idempotency {
+    token: String,
+    key: Option<String>,
+    fingerprint: String,
+}
+
+impl Idempotency {
+    /// Read the idempotency key of a call to `method`, if it has one
+    ///
+    /// The fingerprint is taken from the encoded request, which is canonical:
+    /// map fields of mutating requests are generated as `BTreeMap`s (see
+    /// build.rs), so they always encode in key order.
+    fn from_request<T: prost::Message>(
+        request: &Request<T>,
+        method: &str,
+        token: &str,
+    ) -> Result<Self, Status> {
+        let Some(key) = request.metadata().get(IDEMPOTENCY_KEY_METADATA) else {
+            return Ok(Idempotency {
+                token: token.to_string(),
+                key: None,
+                fingerprint: String::new(),
+            });
+        };
+        let key = key
+            .to_str()
+            .map_err(|_| Status::invalid_argument("Invalid idempotency key"))?;
+        check_idempotency_key(key).map_err(Status::from)?;
+
+        Ok(Idempotency {
+            token: token.to_string(),
+            key: Some(key.to_string()),
+            fingerprint: request_fingerprint([
+                method.as_bytes(),
+                &request.get_ref().encode_to_vec(),
+            ]),
+        })
+    }
+}
+
+/// Whether a call failing with `code` may succeed when retried
+fn is_transient(code: Code) -> bool {
+    matches!(
+        code,
+        Code::Unknown
+            | Code::Internal
+            | Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::Cancelled
+            | Code::ResourceExhausted
+            | Code::Unauthenticated
+    )
+}
+
+/// Rebuild the outcome kept for an idempotency key
+fn replay<T: prost::Message + Default>(stored: IdempotentResponse) -> Result<Response<T>, Status> {
+    let code = Code::from(i32::from(stored.status));
+    if code != Code::Ok {
+        return Err(Status::new(code, String::from_utf8_lossy(&stored.body)));
+    }
+
+    let message = T::decode(stored.body.as_slice())
+        .map_err(|e| Status::internal(format!("Corrupt idempotent response: {}", e)))?;
+    let mut response = Response::new(message);
+    response.metadata_mut().insert(
+        IDEMPOTENT_REPLAYED_METADATA,
+        MetadataValue::from_static("true"),
+    );
+    Ok(response)
+}
+
+#[tonic::async_trait]
+impl kv_store::kv_store_server::KvStore for KVStoreService {
+    async fn get(
+        &self,
+        request: Request<kv_store::GetRequest>,
+    ) -> Result<Response<kv_store::GetResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!("gRPC GET {} (token: {})", req.key, short_token(&req.token));
+
+        // Validate token
+        self.validate_request_token(&req.token).await?;
+
+        // Get the value
+        match self.store.get_entry(&req.token, &req.key).await {
+            Ok(entry) => Ok(Response::new(kv_store::GetResponse {
+                value: entry.value,
+                found: true,
+                metadata: Some(entry.metadata.into()),
+            })),
+            Err(KVStoreError::KeyNotFound(_)) => Ok(Response::new(kv_store::GetResponse {
+                value: String::new(),
+                found: false,
+                metadata: None,
+            })),
+            Err(e) => Err(Status::from(e)),
+        }
+    }
+
+    async fn set(
+        &self,
+        request: Request<kv_store::SetRequest>,
+    ) -> Result<Response<kv_store::SetResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "Set", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_set(request.into_inner()))
+            .await
+    }
+
+    async fn delete(
+        &self,
+        request: Request<kv_store::DeleteRequest>,
+    ) -> Result<Response<kv_store::DeleteResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "Delete", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_delete(request.into_inner()))
+            .await
+    }
+
+    async fn health_check(
+        &self,
+        _request: Request<kv_store::HealthCheckRequest>,
+    ) -> Result<Response<kv_store::HealthCheckResponse>, Status> {
+        tracing::debug!("gRPC health check");
+
+        let healthy = self
+            .store
+            .health_check()
+            .await
+            .map_err(|e| Status::internal(format!("Health check failed: {}", e)))?;
+
+        Ok(Response::new(kv_store::HealthCheckResponse {
+            healthy,
+            message: if healthy {
+                "OK".to_string()
+            } else {
+                "Unhealthy".to_string()
+            },
+        }))
+    }
+
+    type ListStream = std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<kv_store::ListResponse, Status>> + Send>>;
+
+    async fn list(
+        &self,
+        request: Request<kv_store::ListRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC LIST {} (token: {})",
+            req.prefix,
+            short_token(&req.token)
+        );
+
+        // Validate token
+        self.validate_request_token(&req.token).await?;
+
+        // List keys - get a stream
+        let key_stream = self
+            .store
+            .list(&req.token, &req.prefix)
+            .await
+            .map_err(Status::from)?;
+
+        // Map the stream to gRPC responses
+        let response_stream = key_stream.map(|key| Ok(kv_store::ListResponse { key }));
+
+        Ok(Response::new(Box::pin(response_stream)))
+    }
+
+    async fn hash_get(
+        &self,
+        request: Request<kv_store::HashGetRequest>,
+    ) -> Result<Response<kv_store::HashGetResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC HASH GET {} {:?} (token: {})",
+            req.key,
+            req.fields,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let values = self
+            .store
+            .hash_get(&req.token, &req.key, &req.fields)
+            .await
+            .map_err(Status::from)?;
+
+        let values = req
+            .fields
+            .into_iter()
+            .zip(values)
+            .map(|(field, value)| kv_store::HashFieldValue {
+                field,
+                found: value.is_some(),
+                value: value.unwrap_or_default(),
+            })
+            .collect();
+
+        Ok(Response::new(kv_store::HashGetResponse { values }))
+    }
+
+    async fn hash_get_all(
+        &self,
+        request: Request<kv_store::HashGetAllRequest>,
+    ) -> Result<Response<kv_store::HashGetAllResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC HASH GETALL {} (token: {})",
+            req.key,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        match self.store.hash_get_all(&req.token, &req.key).await {
+            Ok(fields) => Ok(Response::new(kv_store::HashGetAllResponse {
+                fields,
+                found: true,
+            })),
+            Err(KVStoreError::KeyNotFound(_)) => Ok(Response::new(kv_store::HashGetAllResponse {
+                fields: Default::default(),
+                found: false,
+            })),
+            Err(e) => Err(Status::from(e)),
+        }
+    }
+
+    async fn hash_set(
+        &self,
+        request: Request<kv_store::HashSetRequest>,
+    ) -> Result<Response<kv_store::HashSetResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "HashSet", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_hash_set(request.into_inner()))
+            .await
+    }
+
+    async fn hash_delete(
+        &self,
+        request: Request<kv_store::HashDeleteRequest>,
+    ) -> Result<Response<kv_store::HashDeleteResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "HashDelete", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_hash_delete(request.into_inner()))
+            .await
+    }
+
+    async fn hash_increment(
+        &self,
+        request: Request<kv_store::HashIncrementRequest>,
+    ) -> Result<Response<kv_store::HashIncrementResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "HashIncrement", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_hash_increment(request.into_inner()))
+            .await
+    }
+
+    async fn list_push(
+        &self,
+        request: Request<kv_store::ListPushRequest>,
+    ) -> Result<Response<kv_store::ListPushResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "ListPush", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_list_push(request.into_inner()))
+            .await
+    }
+
+    async fn list_pop(
+        &self,
+        request: Request<kv_store::ListPopRequest>,
+    ) -> Result<Response<kv_store::ListPopResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "ListPop", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_list_pop(request.into_inner()))
+            .await
+    }
+
+    async fn list_blocking_pop(
+        &self,
+        request: Request<kv_store::ListBlockingPopRequest>,
+    ) -> Result<Response<kv_store::ListBlockingPopResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "ListBlockingPop", &request.get_ref().token)?;
+        self.idempotent(
+            idempotency,
+            self.apply_list_blocking_pop(request.into_inner()),
+        )
+        .await
+    }
+
+    async fn list_range(
+        &self,
+        request: Request<kv_store::ListRangeRequest>,
+    ) -> Result<Response<kv_store::ListRangeResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC LIST RANGE {} [{}, {}] (token: {})",
+            req.key,
+            req.start,
+            req.stop,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let values = self
+            .store
+            .list_range(&req.token, &req.key, req.start as isize, req.stop as isize)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::ListRangeResponse { values }))
+    }
+
+    async fn list_length(
+        &self,
+        request: Request<kv_store::ListLengthRequest>,
+    ) -> Result<Response<kv_store::ListLengthResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC LIST LENGTH {} (token: {})",
+            req.key,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let length = self
+            .store
+            .list_length(&req.token, &req.key)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::ListLengthResponse {
+            length: length as u64,
+        }))
+    }
+
+    async fn sorted_set_add(
+        &self,
+        request: Request<kv_store::SortedSetAddRequest>,
+    ) -> Result<Response<kv_store::SortedSetAddResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "SortedSetAdd", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_sorted_set_add(request.into_inner()))
+            .await
+    }
+
+    async fn sorted_set_increment(
+        &self,
+        request: Request<kv_store::SortedSetIncrementRequest>,
+    ) -> Result<Response<kv_store::SortedSetIncrementResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "SortedSetIncrement", &request.get_ref().token)?;
+        self.idempotent(
+            idempotency,
+            self.apply_sorted_set_increment(request.into_inner()),
+        )
+        .await
+    }
+
+    async fn sorted_set_range(
+        &self,
+        request: Request<kv_store::SortedSetRangeRequest>,
+    ) -> Result<Response<kv_store::SortedSetRangeResponse>, Status> {
+        let req = request.into_inner();
+        let by = req.by();
+        let offset = req.offset as usize;
+        let limit = if req.limit == 0 {
+            DEFAULT_PAGE_SIZE
+        } else {
+            req.limit as usize
+        };
+
+        tracing::info!(
+            "gRPC SORTED SET RANGE {} {:?} (offset: {}, limit: {}, token: {})",
+            req.key,
+            by,
+            offset,
+            limit,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let members = match by {
+            kv_store::SortedSetRangeBy::Rank => {
+                self.store
+                    .sorted_set_range_by_rank(&req.token, &req.key, offset, limit, req.reverse)
+                    .await
+            }
+            kv_store::SortedSetRangeBy::Score => {
+                self.store
+                    .sorted_set_range_by_score(
+                        &req.token,
+                        &req.key,
+                        req.min_score.unwrap_or(f64::NEG_INFINITY),
+                        req.max_score.unwrap_or(f64::INFINITY),
+                        offset,
+                        limit,
+                        req.reverse,
+                    )
+                    .await
+            }
+        }
+        .map_err(Status::from)?;
+
+        let next_offset = (members.len() == limit).then_some(offset.saturating_add(limit) as u64);
+        let members = members
+            .into_iter()
+            .map(|m| kv_store::ScoredMember {
+                member: m.member,
+                score: m.score,
+            })
+            .collect();
+
+        Ok(Response::new(kv_store::SortedSetRangeResponse {
+            members,
+            next_offset,
+        }))
+    }
+
+    async fn sorted_set_rank(
+        &self,
+        request: Request<kv_store::SortedSetRankRequest>,
+    ) -> Result<Response<kv_store::SortedSetRankResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC SORTED SET RANK {} {} (token: {})",
+            req.key,
+            req.member,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let rank = self
+            .store
+            .sorted_set_rank(&req.token, &req.key, &req.member, req.reverse)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(match rank {
+            Some((rank, score)) => kv_store::SortedSetRankResponse {
+                rank: rank as u64,
+                score,
+                found: true,
+            },
+            None => kv_store::SortedSetRankResponse {
+                rank: 0,
+                score: 0.0,
+                found: false,
+            },
+        }))
+    }
+
+    async fn sorted_set_remove(
+        &self,
+        request: Request<kv_store::SortedSetRemoveRequest>,
+    ) -> Result<Response<kv_store::SortedSetRemoveResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "SortedSetRemove", &request.get_ref().token)?;
+        self.idempotent(
+            idempotency,
+            self.apply_sorted_set_remove(request.into_inner()),
+        )
+        .await
+    }
+
+    async fn set_add(
+        &self,
+        request: Request<kv_store::SetAddRequest>,
+    ) -> Result<Response<kv_store::SetAddResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "SetAdd", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_set_add(request.into_inner()))
+            .await
+    }
+
+    async fn set_remove(
+        &self,
+        request: Request<kv_store::SetRemoveRequest>,
+    ) -> Result<Response<kv_store::SetRemoveResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "SetRemove", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_set_remove(request.into_inner()))
+            .await
+    }
+
+    async fn set_is_member(
+        &self,
+        request: Request<kv_store::SetIsMemberRequest>,
+    ) -> Result<Response<kv_store::SetIsMemberResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC SET ISMEMBER {} {} (token: {})",
+            req.key,
+            req.member,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let is_member = self
+            .store
+            .set_is_member(&req.token, &req.key, &req.member)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetIsMemberResponse { is_member }))
+    }
+
+    type SetMembersStream = std::pin::Pin<
+        Box<dyn tokio_stream::Stream<Item = Result<kv_store::SetMembersResponse, Status>> + Send>,
+    >;
+
+    async fn set_members(
+        &self,
+        request: Request<kv_store::SetMembersRequest>,
+    ) -> Result<Response<Self::SetMembersStream>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC SET MEMBERS {} (token: {})",
+            req.key,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let member_stream = self
+            .store
+            .set_members(&req.token, &req.key)
+            .await
+            .map_err(Status::from)?;
+
+        let response_stream =
+            member_stream.map(|member| Ok(kv_store::SetMembersResponse { member }));
+
+        Ok(Response::new(Box::pin(response_stream)))
+    }
+
+    async fn set_cardinality(
+        &self,
+        request: Request<kv_store::SetCardinalityRequest>,
+    ) -> Result<Response<kv_store::SetCardinalityResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC SET CARDINALITY {} (token: {})",
+            req.key,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let cardinality = self
+            .store
+            .set_cardinality(&req.token, &req.key)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetCardinalityResponse {
+            cardinality: cardinality as u64,
+        }))
+    }
+
+    async fn set_combine(
+        &self,
+        request: Request<kv_store::SetCombineRequest>,
+    ) -> Result<Response<kv_store::SetCombineResponse>, Status> {
+        let req = request.into_inner();
+        let operation = SetOperation::from(req.operation());
+
+        tracing::info!(
+            "gRPC SET {:?} {:?} (token: {})",
+            operation,
+            req.keys,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let members = self
+            .store
+            .set_combine(&req.token, &req.keys, operation)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetCombineResponse { members }))
+    }
+
+    async fn set_json(
+        &self,
+        request: Request<kv_store::SetJsonRequest>,
+    ) -> Result<Response<kv_store::SetJsonResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "SetJson", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_set_json(request.into_inner()))
+            .await
+    }
+
+    async fn get_json(
+        &self,
+        request: Request<kv_store::GetJsonRequest>,
+    ) -> Result<Response<kv_store::GetJsonResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC GET JSON {} (path: {:?}, token: {})",
+            req.key,
+            req.path,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        match self
+            .store
+            .get_json(&req.token, &req.key, req.path.as_deref())
+            .await
+        {
+            Ok(value) => Ok(Response::new(kv_store::GetJsonResponse {
+                json: value.to_string(),
+                found: true,
+            })),
+            Err(KVStoreError::KeyNotFound(_)) => Ok(Response::new(kv_store::GetJsonResponse {
+                json: String::new(),
+                found: false,
+            })),
+            Err(e) => Err(Status::from(e)),
+        }
+    }
+
+    async fn patch_json(
+        &self,
+        request: Request<kv_store::PatchJsonRequest>,
+    ) -> Result<Response<kv_store::PatchJsonResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "PatchJson", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_patch_json(request.into_inner()))
+            .await
+    }
+
+    async fn register_schema(
+        &self,
+        request: Request<kv_store::RegisterSchemaRequest>,
+    ) -> Result<Response<kv_store::RegisterSchemaResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC SCHEMA SET {} (token: {})",
+            req.prefix,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let schema = parse_json("schema", &req.schema)?;
+        self.store
+            .register_schema(&req.token, &req.prefix, &schema)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::RegisterSchemaResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn get_schema(
+        &self,
+        request: Request<kv_store::GetSchemaRequest>,
+    ) -> Result<Response<kv_store::GetSchemaResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC SCHEMA GET {} (token: {})",
+            req.prefix,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        match self.store.get_schema(&req.token, &req.prefix).await {
+            Ok(schema) => Ok(Response::new(kv_store::GetSchemaResponse {
+                schema: schema.to_string(),
+                found: true,
+            })),
+            Err(KVStoreError::KeyNotFound(_)) => Ok(Response::new(kv_store::GetSchemaResponse {
+                schema: String::new(),
+                found: false,
+            })),
+            Err(e) => Err(Status::from(e)),
+        }
+    }
+
+    async fn list_schemas(
+        &self,
+        request: Request<kv_store::ListSchemasRequest>,
+    ) -> Result<Response<kv_store::ListSchemasResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!("gRPC SCHEMA LIST (token: {})", short_token(&req.token));
+
+        self.validate_request_token(&req.token).await?;
+
+        let schemas = self
+            .store
+            .list_schemas(&req.token)
+            .await
+            .map_err(Status::from)?
+            .into_iter()
+            .map(|(prefix, schema)| kv_store::Schema {
+                prefix,
+                schema: schema.to_string(),
+            })
+            .collect();
+
+        Ok(Response::new(kv_store::ListSchemasResponse { schemas }))
+    }
+
+    async fn delete_schema(
+        &self,
+        request: Request<kv_store::DeleteSchemaRequest>,
+    ) -> Result<Response<kv_store::DeleteSchemaResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC SCHEMA DELETE {} (token: {})",
+            req.prefix,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        self.store
+            .delete_schema(&req.token, &req.prefix)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::DeleteSchemaResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn set_history_policy(
+        &self,
+        request: Request<kv_store::SetHistoryPolicyRequest>,
+    ) -> Result<Response<kv_store::SetHistoryPolicyResponse>, Status> {
+        let req = request.into_inner();
+        let policy = HistoryPolicy::from(req.policy.unwrap_or_default());
+
+        tracing::info!(
+            "gRPC HISTORY POLICY SET {:?} (token: {})",
+            policy,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        self.store
+            .set_history_policy(&req.token, &policy)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetHistoryPolicyResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn get_history_policy(
+        &self,
+        request: Request<kv_store::GetHistoryPolicyRequest>,
+    ) -> Result<Response<kv_store::GetHistoryPolicyResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC HISTORY POLICY GET (token: {})",
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let policy = self
+            .store
+            .get_history_policy(&req.token)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::GetHistoryPolicyResponse {
+            policy: Some(policy.into()),
+        }))
+    }
+
+    async fn history(
+        &self,
+        request: Request<kv_store::HistoryRequest>,
+    ) -> Result<Response<kv_store::HistoryResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC HISTORY {} (token: {})",
+            req.key,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let revisions = self
+            .store
+            .history(&req.token, &req.key)
+            .await
+            .map_err(Status::from)?
+            .into_iter()
+            .map(kv_store::Revision::from)
+            .collect();
+
+        Ok(Response::new(kv_store::HistoryResponse { revisions }))
+    }
+
+    async fn get_at_revision(
+        &self,
+        request: Request<kv_store::GetAtRevisionRequest>,
+    ) -> Result<Response<kv_store::GetAtRevisionResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC GET {}@{} (token: {})",
+            req.key,
+            req.revision,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        match self
+            .store
+            .get_at_revision(&req.token, &req.key, &req.revision)
+            .await
+        {
+            Ok(revision) => Ok(Response::new(kv_store::GetAtRevisionResponse {
+                revision: Some(revision.into()),
+                found: true,
+            })),
+            Err(KVStoreError::KeyNotFound(_)) => {
+                Ok(Response::new(kv_store::GetAtRevisionResponse {
+                    revision: None,
+                    found: false,
+                }))
+            }
+            Err(e) => Err(Status::from(e)),
+        }
+    }
+
+    async fn restore(
+        &self,
+        request: Request<kv_store::RestoreRequest>,
+    ) -> Result<Response<kv_store::RestoreResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "Restore", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_restore(request.into_inner()))
+            .await
+    }
+
+    async fn transaction(
+        &self,
+        request: Request<kv_store::TransactionRequest>,
+    ) -> Result<Response<kv_store::TransactionResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "Transaction", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_transaction(request.into_inner()))
+            .await
+    }
+
+    async fn delete_prefix(
+        &self,
+        request: Request<kv_store::DeletePrefixRequest>,
+    ) -> Result<Response<kv_store::DeletePrefixResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "DeletePrefix", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_delete_prefix(request.into_inner()))
+            .await
+    }
+
+    async fn rename(
+        &self,
+        request: Request<kv_store::MoveRequest>,
+    ) -> Result<Response<kv_store::MoveResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "Rename", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_rename(request.into_inner()))
+            .await
+    }
+
+    async fn copy(
+        &self,
+        request: Request<kv_store::MoveRequest>,
+    ) -> Result<Response<kv_store::MoveResponse>, Status> {
+        let idempotency = Idempotency::from_request(&request, "Copy", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_copy(request.into_inner()))
+            .await
+    }
+
+    async fn rename_prefix(
+        &self,
+        request: Request<kv_store::RenamePrefixRequest>,
+    ) -> Result<Response<kv_store::RenamePrefixResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "RenamePrefix", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_rename_prefix(request.into_inner()))
+            .await
+    }
+
+    async fn stats(
+        &self,
+        request: Request<kv_store::StatsRequest>,
+    ) -> Result<Response<kv_store::StatsResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!("gRPC STATS (token: {})", short_token(&req.token));
+
+        self.validate_request_token(&req.token).await?;
+
+        let stats = self.store.stats(&req.token).await.map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::StatsResponse {
+            key_count: stats.key_count,
+            total_bytes: stats.total_bytes,
+            keys_with_ttl: stats.keys_with_ttl,
+            largest_keys: stats
+                .largest_keys
+                .into_iter()
+                .map(|size| kv_store::KeySize {
+                    key: size.key,
+                    bytes: size.bytes,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn search(
+        &self,
+        request: Request<kv_store::SearchRequest>,
+    ) -> Result<Response<kv_store::SearchResponse>, Status> {
+        let req = request.into_inner();
+        let mode = SearchMode::from(req.mode());
+
+        tracing::info!(
+            "gRPC SEARCH {:?} {:?} (token: {})",
+            mode,
+            req.pattern,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let page = self
+            .store
+            .search(
+                &req.token,
+                &SearchQuery {
+                    mode,
+                    pattern: req.pattern,
+                    cursor: req.cursor,
+                    limit: req.limit.map(|limit| limit as usize),
+                    timeout: req.timeout_ms.map(|ms| Duration::from_millis(ms.into())),
+                },
+            )
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SearchResponse {
+            keys: page.keys,
+            cursor: page.cursor,
+            timed_out: page.timed_out,
+        }))
+    }
+
+    async fn create_index(
+        &self,
+        request: Request<kv_store::CreateIndexRequest>,
+    ) -> Result<Response<kv_store::CreateIndexResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC INDEX CREATE {} {} {} (token: {})",
+            req.name,
+            req.prefix,
+            req.field,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let indexed = self
+            .store
+            .create_index(
+                &req.token,
+                &req.name,
+                &IndexDefinition {
+                    prefix: req.prefix,
+                    field: req.field,
+                },
+            )
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::CreateIndexResponse { indexed }))
+    }
+
+    async fn list_indexes(
+        &self,
+        request: Request<kv_store::ListIndexesRequest>,
+    ) -> Result<Response<kv_store::ListIndexesResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!("gRPC INDEX LIST (token: {})", short_token(&req.token));
+
+        self.validate_request_token(&req.token).await?;
+
+        let indexes = self
+            .store
+            .list_indexes(&req.token)
+            .await
+            .map_err(Status::from)?
+            .into_iter()
+            .map(|(name, definition)| kv_store::Index {
+                name,
+                prefix: definition.prefix,
+                field: definition.field,
+            })
+            .collect();
+
+        Ok(Response::new(kv_store::ListIndexesResponse { indexes }))
+    }
+
+    async fn drop_index(
+        &self,
+        request: Request<kv_store::DropIndexRequest>,
+    ) -> Result<Response<kv_store::DropIndexResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC INDEX DROP {} (token: {})",
+            req.name,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        self.store
+            .drop_index(&req.token, &req.name)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::DropIndexResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn query_index(
+        &self,
+        request: Request<kv_store::QueryIndexRequest>,
+    ) -> Result<Response<kv_store::QueryIndexResponse>, Status> {
+        let req = request.into_inner();
+        let query = match (&req.equals, req.min, req.max) {
+            (Some(equals), None, None) => IndexQuery::Equals(parse_json("equals", equals)?),
+            (None, min, max) if min.is_some() || max.is_some() => IndexQuery::Range { min, max },
+            _ => {
+                return Err(Status::invalid_argument(
+                    "Either equals or a min/max range is required",
+                ))
+            }
+        };
+
+        tracing::info!(
+            "gRPC INDEX QUERY {} {:?} (token: {})",
+            req.name,
+            query,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let page = self
+            .store
+            .query_index(
+                &req.token,
+                &req.name,
+                &query,
+                req.cursor.as_deref(),
+                req.limit.map(|limit| limit as usize),
+            )
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::QueryIndexResponse {
+            keys: page.keys,
+            cursor: page.cursor,
+        }))
+    }
+
+    type WatchStream = std::pin::Pin<
+        Box<dyn tokio_stream::Stream<Item = Result<kv_store::WatchEvent, Status>> + Send>,
+    >;
+
+    async fn watch(
+        &self,
+        request: Request<kv_store::WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC WATCH {} (prefix: {}, after: {:?}, token: {})",
+            req.key,
+            req.prefix,
+            req.after,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let events = self
+            .store
+            .watch(&req.token, &req.key, req.prefix, req.after.as_deref())
+            .await
+            .map_err(Status::from)?
+            .map(|event| Ok(kv_store::WatchEvent::from(event)));
+
+        Ok(Response::new(Box::pin(authorized_changes(
+            self.store.clone(),
+            req.token,
+            events,
+        ))))
+    }
+
+    type ChangesStream = std::pin::Pin<
+        Box<dyn tokio_stream::Stream<Item = Result<kv_store::WatchEvent, Status>> + Send>,
+    >;
+
+    async fn changes(
+        &self,
+        request: Request<kv_store::ChangesRequest>,
+    ) -> Result<Response<Self::ChangesStream>, Status> {
+        let req = request.into_inner();
+
+        self.validate_request_token(&req.token).await?;
+
+        let from = match (req.from_offset.as_str(), &req.consumer) {
+            ("", Some(consumer)) => self
+                .store
+                .change_offset(&req.token, consumer)
+                .await
+                .map_err(Status::from)?
+                .unwrap_or(ChangeOffset::Oldest),
+            ("", None) => ChangeOffset::Oldest,
+            (offset, _) => offset.parse().map_err(Status::from)?,
+        };
+
+        tracing::info!(
+            "gRPC CHANGES from {} (prefix: {:?}, consumer: {:?}, token: {})",
+            from,
+            req.prefix,
+            req.consumer,
+            short_token(&req.token)
+        );
+
+        let heartbeats = req.heartbeats;
+        let changes = self
+            .store
+            .changes_with_heartbeats(&req.token, &from, &req.prefix)
+            .await
+            .map_err(Status::from)?
+            .filter_map(move |entry| match entry {
+                Ok(ChangeFeedEntry::Change(change)) => Some(Ok(change.into())),
+                Ok(ChangeFeedEntry::Heartbeat { offset, timestamp }) => heartbeats.then(|| {
+                    Ok(kv_store::WatchEvent {
+                        id: Some(offset),
+                        timestamp: Some(timestamp),
+                        heartbeat: true,
+                        ..Default::default()
+                    })
+                }),
+                Err(e) => Some(Err(Status::from(e))),
+            });
+
+        Ok(Response::new(Box::pin(authorized_changes(
+            self.store.clone(),
+            req.token,
+            changes,
+        ))))
+    }
+
+    async fn commit_change_offset(
+        &self,
+        request: Request<kv_store::CommitChangeOffsetRequest>,
+    ) -> Result<Response<kv_store::CommitChangeOffsetResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC CHANGES OFFSET COMMIT {} = {} (token: {})",
+            req.consumer,
+            req.offset,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let offset: ChangeOffset = req.offset.parse().map_err(Status::from)?;
+        self.store
+            .commit_change_offset(&req.token, &req.consumer, &offset)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::CommitChangeOffsetResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn get_change_offset(
+        &self,
+        request: Request<kv_store::GetChangeOffsetRequest>,
+    ) -> Result<Response<kv_store::GetChangeOffsetResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC CHANGES OFFSET GET {} (token: {})",
+            req.consumer,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let offset = self
             .store
-            .validate_token(token)
+            .change_offset(&req.token, &req.consumer)
             .await
-            .map_err(|e| Status::internal(format!("Token validation failed: {}", e)))?;
+            .map_err(Status::from)?;
 
-        if !is_valid {
-            return Err(Status::unauthenticated("Invalid token"));
+        Ok(Response::new(kv_store::GetChangeOffsetResponse {
+            offset: offset.map(|offset| offset.to_string()),
+        }))
+    }
+
+    async fn set_webhook(
+        &self,
+        request: Request<kv_store::SetWebhookRequest>,
+    ) -> Result<Response<kv_store::SetWebhookResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC WEBHOOK SET {} -> {} (token: {})",
+            req.name,
+            req.url,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let mut events: Vec<ChangeKind> = req.events().map(ChangeKind::from).collect();
+        if events.is_empty() {
+            events = vec![ChangeKind::Put, ChangeKind::Delete, ChangeKind::Expire];
         }
+        let webhook = Webhook {
+            url: req.url,
+            prefix: req.prefix,
+            events,
+            secret: req.secret,
+        };
+        self.store
+            .set_webhook(&req.token, &req.name, &webhook)
+            .await
+            .map_err(Status::from)?;
 
-        Ok(())
+        Ok(Response::new(kv_store::SetWebhookResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
     }
-}
 
-#[tonic::async_trait]
-impl kv_store::kv_store_server::KvStore for KVStoreService {
-    async fn get(
+    async fn get_webhook(
         &self,
-        request: Request<kv_store::GetRequest>,
-    ) -> Result<Response<kv_store::GetResponse>, Status> {
+        request: Request<kv_store::GetWebhookRequest>,
+    ) -> Result<Response<kv_store::Webhook>, Status> {
         let req = request.into_inner();
 
         tracing::info!(
-            "gRPC GET {} (token: {})",
-            req.key,
-            &req.token[..req
-                .token
-                .char_indices()
-                .nth(8)
-                .map_or(req.token.len(), |(i, _)| i)]
+            "gRPC WEBHOOK GET {} (token: {})",
+            req.name,
+            short_token(&req.token)
         );
 
-        // Validate token
         self.validate_request_token(&req.token).await?;
 
-        // Get the value
-        match self.store.get(&req.token, &req.key).await {
-            Ok(value) => Ok(Response::new(kv_store::GetResponse { value, found: true })),
-            Err(KVStoreError::KeyNotFound(_)) => Ok(Response::new(kv_store::GetResponse {
-                value: String::new(),
-                found: false,
-            })),
-            Err(e) => Err(Status::from(e)),
-        }
+        let webhook = self
+            .store
+            .get_webhook(&req.token, &req.name)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(webhook.into()))
     }
 
-    async fn set(
+    async fn list_webhooks(
         &self,
-        request: Request<kv_store::SetRequest>,
-    ) -> Result<Response<kv_store::SetResponse>, Status> {
+        request: Request<kv_store::ListWebhooksRequest>,
+    ) -> Result<Response<kv_store::ListWebhooksResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!("gRPC WEBHOOK LIST (token: {})", short_token(&req.token));
+
+        self.validate_request_token(&req.token).await?;
+
+        let webhooks = self
+            .store
+            .list_webhooks(&req.token)
+            .await
+            .map_err(Status::from)?
+            .into_iter()
+            .map(kv_store::Webhook::from)
+            .collect();
+
+        Ok(Response::new(kv_store::ListWebhooksResponse { webhooks }))
+    }
+
+    async fn delete_webhook(
+        &self,
+        request: Request<kv_store::DeleteWebhookRequest>,
+    ) -> Result<Response<kv_store::DeleteWebhookResponse>, Status> {
         let req = request.into_inner();
 
         tracing::info!(
-            "gRPC SET {} (token: {}, TTL: {:?})",
-            req.key,
-            &req.token[..req
-                .token
-                .char_indices()
-                .nth(8)
-                .map_or(req.token.len(), |(i, _)| i)],
-            req.ttl_seconds
+            "gRPC WEBHOOK DELETE {} (token: {})",
+            req.name,
+            short_token(&req.token)
         );
 
-        // Validate token
         self.validate_request_token(&req.token).await?;
 
-        // Set the value
         self.store
-            .set(&req.token, &req.key, &req.value, req.ttl_seconds)
+            .delete_webhook(&req.token, &req.name)
             .await
             .map_err(Status::from)?;
 
-        Ok(Response::new(kv_store::SetResponse {
+        Ok(Response::new(kv_store::DeleteWebhookResponse {
             success: true,
             message: "OK".to_string(),
         }))
     }
 
-    async fn delete(
+    async fn webhook_dead_letters(
         &self,
-        request: Request<kv_store::DeleteRequest>,
-    ) -> Result<Response<kv_store::DeleteResponse>, Status> {
+        request: Request<kv_store::WebhookDeadLettersRequest>,
+    ) -> Result<Response<kv_store::WebhookDeadLettersResponse>, Status> {
         let req = request.into_inner();
 
         tracing::info!(
-            "gRPC DELETE {} (token: {})",
-            req.key,
-            &req.token[..req
-                .token
-                .char_indices()
-                .nth(8)
-                .map_or(req.token.len(), |(i, _)| i)]
+            "gRPC WEBHOOK DEAD LETTERS {} (token: {})",
+            req.name,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let dead_letters = self
+            .store
+            .webhook_dead_letters(&req.token, &req.name)
+            .await
+            .map_err(Status::from)?
+            .into_iter()
+            .map(|letter| kv_store::DeadLetter {
+                event: Some(letter.event.into()),
+                attempts: letter.attempts,
+                error: letter.error,
+                failed_at: letter.failed_at,
+            })
+            .collect();
+
+        Ok(Response::new(kv_store::WebhookDeadLettersResponse {
+            dead_letters,
+        }))
+    }
+
+    async fn acquire_lock(
+        &self,
+        request: Request<kv_store::AcquireLockRequest>,
+    ) -> Result<Response<kv_store::LockLease>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC LOCK ACQUIRE {} by {} (ttl: {}, wait: {}, token: {})",
+            req.name,
+            req.owner,
+            req.ttl_seconds,
+            req.wait_seconds,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let ttl = Duration::try_from_secs_f64(req.ttl_seconds)
+            .map_err(|e| Status::invalid_argument(format!("Invalid ttl: {}", e)))?;
+        let wait = Duration::try_from_secs_f64(req.wait_seconds)
+            .map_err(|e| Status::invalid_argument(format!("Invalid wait: {}", e)))?;
+        let lease = self
+            .store
+            .acquire_lock(&req.token, &req.name, &req.owner, ttl, wait)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(lease.into()))
+    }
+
+    async fn renew_lock(
+        &self,
+        request: Request<kv_store::RenewLockRequest>,
+    ) -> Result<Response<kv_store::LockLease>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC LOCK RENEW {} by {} (ttl: {}, token: {})",
+            req.name,
+            req.owner,
+            req.ttl_seconds,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let ttl = Duration::try_from_secs_f64(req.ttl_seconds)
+            .map_err(|e| Status::invalid_argument(format!("Invalid ttl: {}", e)))?;
+        let lease = self
+            .store
+            .renew_lock(&req.token, &req.name, &req.owner, ttl)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(lease.into()))
+    }
+
+    async fn release_lock(
+        &self,
+        request: Request<kv_store::ReleaseLockRequest>,
+    ) -> Result<Response<kv_store::ReleaseLockResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC LOCK RELEASE {} by {} (token: {})",
+            req.name,
+            req.owner,
+            short_token(&req.token)
         );
 
-        // Validate token
         self.validate_request_token(&req.token).await?;
 
-        // Delete the value
         self.store
-            .delete(&req.token, &req.key)
+            .release_lock(&req.token, &req.name, &req.owner)
             .await
             .map_err(Status::from)?;
 
-        Ok(Response::new(kv_store::DeleteResponse {
+        Ok(Response::new(kv_store::ReleaseLockResponse {
             success: true,
             message: "OK".to_string(),
         }))
     }
 
-    async fn health_check(
+    async fn get_lock(
         &self,
-        _request: Request<kv_store::HealthCheckRequest>,
-    ) -> Result<Response<kv_store::HealthCheckResponse>, Status> {
-        tracing::debug!("gRPC health check");
+        request: Request<kv_store::GetLockRequest>,
+    ) -> Result<Response<kv_store::GetLockResponse>, Status> {
+        let req = request.into_inner();
 
-        let healthy = self
+        tracing::info!(
+            "gRPC LOCK GET {} (token: {})",
+            req.name,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let lease = self
             .store
-            .health_check()
+            .lock_holder(&req.token, &req.name)
             .await
-            .map_err(|e| Status::internal(format!("Health check failed: {}", e)))?;
+            .map_err(Status::from)?;
 
-        Ok(Response::new(kv_store::HealthCheckResponse {
-            healthy,
-            message: if healthy {
-                "OK".to_string()
-            } else {
-                "Unhealthy".to_string()
-            },
+        Ok(Response::new(kv_store::GetLockResponse {
+            lease: lease.map(kv_store::LockLease::from),
         }))
     }
 
-    type ListStream = std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<kv_store::ListResponse, Status>> + Send>>;
+    async fn set_trash_retention(
+        &self,
+        request: Request<kv_store::SetTrashRetentionRequest>,
+    ) -> Result<Response<kv_store::SetTrashRetentionResponse>, Status> {
+        let req = request.into_inner();
 
-    async fn list(
+        tracing::info!(
+            "gRPC SOFT DELETE SET {:?} (token: {})",
+            req.retention_seconds,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        self.store
+            .set_trash_retention(&req.token, req.retention_seconds.map(Duration::from_secs))
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::SetTrashRetentionResponse {
+            success: true,
+            message: "OK".to_string(),
+        }))
+    }
+
+    async fn get_trash_retention(
         &self,
-        request: Request<kv_store::ListRequest>,
-    ) -> Result<Response<Self::ListStream>, Status> {
+        request: Request<kv_store::GetTrashRetentionRequest>,
+    ) -> Result<Response<kv_store::GetTrashRetentionResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!("gRPC SOFT DELETE GET (token: {})", short_token(&req.token));
+
+        self.validate_request_token(&req.token).await?;
+
+        let retention = self
+            .store
+            .trash_retention(&req.token)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::GetTrashRetentionResponse {
+            retention_seconds: retention.map(|retention| retention.as_secs()),
+        }))
+    }
+
+    async fn list_trash(
+        &self,
+        request: Request<kv_store::ListTrashRequest>,
+    ) -> Result<Response<kv_store::ListTrashResponse>, Status> {
         let req = request.into_inner();
 
         tracing::info!(
-            "gRPC LIST {} (token: {})",
+            "gRPC TRASH LIST {:?} (token: {})",
             req.prefix,
-            &req.token[..req
-                .token
-                .char_indices()
-                .nth(8)
-                .map_or(req.token.len(), |(i, _)| i)]
+            short_token(&req.token)
         );
 
-        // Validate token
         self.validate_request_token(&req.token).await?;
 
-        // List keys - get a stream
-        let key_stream = self
+        let keys = self
             .store
-            .list(&req.token, &req.prefix)
+            .list_trash(&req.token, &req.prefix)
+            .await
+            .map_err(Status::from)?
+            .into_iter()
+            .map(|trashed| kv_store::TrashedKey {
+                key: trashed.key,
+                deleted_at: trashed.deleted_at,
+                purge_at: trashed.purge_at,
+                metadata: Some(trashed.metadata.into()),
+            })
+            .collect();
+
+        Ok(Response::new(kv_store::ListTrashResponse { keys }))
+    }
+
+    async fn undelete(
+        &self,
+        request: Request<kv_store::UndeleteRequest>,
+    ) -> Result<Response<kv_store::UndeleteResponse>, Status> {
+        let idempotency =
+            Idempotency::from_request(&request, "Undelete", &request.get_ref().token)?;
+        self.idempotent(idempotency, self.apply_undelete(request.into_inner()))
+            .await
+    }
+
+    async fn purge_trash(
+        &self,
+        request: Request<kv_store::PurgeTrashRequest>,
+    ) -> Result<Response<kv_store::PurgeTrashResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC TRASH PURGE {:?} (token: {})",
+            req.key,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let purged = self
+            .store
+            .purge_trash(&req.token, req.key.as_deref())
             .await
             .map_err(Status::from)?;
 
-        // Map the stream to gRPC responses
-        let response_stream = key_stream.map(|key| Ok(kv_store::ListResponse { key }));
+        Ok(Response::new(kv_store::PurgeTrashResponse { purged }))
+    }
 
-        Ok(Response::new(Box::pin(response_stream)))
+    type ExportStream = std::pin::Pin<
+        Box<dyn tokio_stream::Stream<Item = Result<kv_store::ExportedKey, Status>> + Send>,
+    >;
+
+    async fn export(
+        &self,
+        request: Request<kv_store::ExportRequest>,
+    ) -> Result<Response<Self::ExportStream>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC EXPORT prefix={} (token: {})",
+            req.prefix,
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let records = self
+            .store
+            .export_namespace(&req.token, &req.prefix)
+            .await
+            .map_err(Status::from)?
+            .map(|record| record.map(Into::into).map_err(Status::from));
+
+        Ok(Response::new(Box::pin(records)))
+    }
+
+    async fn import(
+        &self,
+        request: Request<tonic::Streaming<kv_store::ImportRequest>>,
+    ) -> Result<Response<kv_store::ImportResponse>, Status> {
+        let mut messages = request.into_inner();
+        let mut first = messages
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("Import stream is empty"))?;
+        let conflict = ImportConflict::from(first.conflict());
+
+        tracing::info!(
+            "gRPC IMPORT conflict={:?} (token: {})",
+            conflict,
+            short_token(&first.token)
+        );
+
+        self.validate_request_token(&first.token).await?;
+
+        let rest = messages.filter_map(|message| match message {
+            Ok(message) => message.key.map(|key| Ok(key.into())),
+            Err(status) => Some(Err(KVStoreError::InvalidRequest(format!(
+                "Failed to read import: {}",
+                status.message()
+            )))),
+        });
+        let records = tokio_stream::iter(first.key.take().map(|key| Ok(key.into()))).chain(rest);
+        let summary = self
+            .store
+            .import_namespace(&first.token, records, conflict)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::ImportResponse {
+            imported: summary.imported,
+            skipped: summary.skipped,
+        }))
+    }
+
+    async fn get_replication_status(
+        &self,
+        request: Request<kv_store::GetReplicationStatusRequest>,
+    ) -> Result<Response<kv_store::GetReplicationStatusResponse>, Status> {
+        let req = request.into_inner();
+
+        tracing::info!(
+            "gRPC REPLICATION STATUS (token: {})",
+            short_token(&req.token)
+        );
+
+        self.validate_request_token(&req.token).await?;
+
+        let status = self
+            .store
+            .replication_status(&req.token)
+            .await
+            .map_err(Status::from)?;
+
+        Ok(Response::new(kv_store::GetReplicationStatusResponse {
+            status: status.map(Into::into),
+        }))
     }
 }
 
+/// Forward a feed of changes of `token`'s namespace as gRPC events
+///
+/// Tokens may be revoked while a feed is open, so every event is authorized
+/// before it is sent. The feed ends after the first error.
+fn authorized_changes(
+    store: KVStore,
+    token: String,
+    changes: impl tokio_stream::Stream<Item = Result<kv_store::WatchEvent, Status>> + Send + 'static,
+) -> ReceiverStream<Result<kv_store::WatchEvent, Status>> {
+    let mut changes = Box::pin(changes);
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(async move {
+        while let Some(change) = changes.next().await {
+            let response = match (change, store.validate_token(&token).await) {
+                (Ok(change), Ok(true)) => Ok(change),
+                (_, Ok(false)) => Err(Status::unauthenticated("Invalid token")),
+                (_, Err(e)) => Err(Status::internal(format!("Token validation failed: {}", e))),
+                (Err(status), Ok(true)) => Err(status),
+            };
+            let failed = response.is_err();
+            if tx.send(response).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
 /// Create a gRPC service from a KVStore
 pub fn create_service(store: KVStore) -> KvStoreServer<KVStoreService> {
     KvStoreServer::new(KVStoreService::new(store))
@@ -211,9 +2749,39 @@ pub fn create_reflection_service(
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_service_creation() {
         // This is a basic smoke test
         // Integration tests would require a running Redis instance
     }
+
+    #[test]
+    fn test_idempotency_fingerprint_ignores_map_order() {
+        let fingerprint = |tags: &[(&str, &str)]| {
+            let mut request = Request::new(kv_store::SetRequest {
+                key: "key".to_string(),
+                value: "value".to_string(),
+                token: "token".to_string(),
+                tags: tags
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                ..Default::default()
+            });
+            request
+                .metadata_mut()
+                .insert(IDEMPOTENCY_KEY_METADATA, MetadataValue::from_static("k1"));
+            Idempotency::from_request(&request, "Set", "token")
+                .unwrap()
+                .fingerprint
+        };
+
+        let tags = [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")];
+        let mut reversed = tags;
+        reversed.reverse();
+        assert_eq!(fingerprint(&tags), fingerprint(&reversed));
+        assert_ne!(fingerprint(&tags), fingerprint(&tags[1..]));
+    }
 }
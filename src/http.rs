@@ -2,9 +2,10 @@
 //!
 //! Provides REST API handlers for KVStore operations.
 
-use crate::{error::Result, KVStore, KVStoreError};
+use crate::store::{Entry, PatchFormat, SetOptions, JSON_CONTENT_TYPE};
+use crate::{error::Result, short_token, KVStore, KVStoreError};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{header, HeaderMap, Request, StatusCode},
     middleware::{from_fn_with_state, Next},
     response::{IntoResponse, Response},
@@ -13,40 +14,151 @@ use axum::{
 };
 use axum_macros::debug_handler;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tower_http::{compression::CompressionLayer, trace::TraceLayer};
 
+pub mod changes;
+pub mod export;
+pub mod hash;
+pub mod history;
+pub mod idempotency;
+pub mod index;
+pub mod list;
+pub mod lock;
+pub mod prefix;
+pub mod rename;
+pub mod replication;
+pub mod schema;
+pub mod search;
+pub mod set;
+pub mod sorted_set;
+pub mod stats;
+pub mod transaction;
+pub mod trash;
+pub mod watch;
+pub mod webhook;
+
 /// Creates a new HTTP router with all routes configured
 ///
 /// The router includes:
 /// - GET /healthz - Health check endpoint
-/// - GET /{key} - Get a value
+/// - GET /{key} - Get a value and its metadata, or part of a JSON document with `?path=`
 /// - POST /{key} - Set a value
+/// - PATCH /{key} - Patch a JSON document
 /// - DELETE /{key} - Delete a value
+/// - GET /?mode=prefix|glob|regex&pattern= - Search keys, a page at a time
+/// - DELETE /?prefix= - Delete every key starting with a prefix, or preview with `&dry_run=true`
+/// - POST /{key}/rename, POST /{key}/copy - Rename or copy a key with its TTL and metadata
+/// - POST /_rename - Rename every key starting with a prefix
+/// - GET|POST /{key}/fields - Get or set hash fields
+/// - GET|POST|DELETE /{key}/fields/{field} - Get, set or delete a hash field
+/// - POST /{key}/fields/{field}/incr - Increment a hash field
+/// - GET|POST /{key}/items - Read a range of or push onto a list
+/// - POST /{key}/items/pop - Pop from a list, optionally blocking
+/// - GET /{key}/items/length - Get the length of a list
+/// - GET|POST /{key}/scores - Page through or add to a sorted set
+/// - GET|DELETE /{key}/scores/{member} - Get the rank of or remove a member
+/// - POST /{key}/scores/{member}/incr - Increment a member's score
+/// - GET|POST /{key}/members - Page through or add to a set
+/// - GET|DELETE /{key}/members/{member} - Check or remove set membership
+/// - GET /{key}/cardinality - Get the size of a set
+/// - POST /_sets/intersection, POST /_sets/union - Combine sets
+/// - GET /{key}/history - List the revisions of a key
+/// - GET /{key}/history/{revision} - Read a key as of a revision or timestamp
+/// - POST /{key}/history/{revision}/restore - Restore a key to a revision
+/// - GET|POST /_history - Get or set the namespace's history policy
+/// - GET /_schemas - List the JSON Schemas bound to key prefixes
+/// - GET|POST|DELETE /_schemas/{prefix} - Get, register or remove a schema
+/// - GET /_indexes - List the secondary indexes on JSON fields
+/// - POST|DELETE /_indexes/{name} - Create or drop an index
+/// - POST /_indexes/{name}/query - Look up keys by an indexed field
+/// - GET|POST /_locks/{name} - Inspect or acquire a lock, optionally waiting for it
+/// - POST /_locks/{name}/renew, POST /_locks/{name}/release - Renew or release a lock the caller holds
+/// - GET|POST /_soft_delete - Get or set how long deleted keys are kept in the trash
+/// - GET|DELETE /_trash - List or purge the trash
+/// - DELETE /_trash/{key} - Purge a key from the trash
+/// - POST /{key}/undelete - Restore a deleted key from the trash
+/// - GET /_export?prefix= - Stream the namespace's keys as NDJSON
+/// - POST /_import?conflict= - Import NDJSON keys, skipping, overwriting or failing on existing ones
+/// - GET /_replication - Get the progress and lag of replication from another deployment
+/// - GET /_stats - Get usage statistics of the namespace
+/// - POST /_txn - Atomically apply operations to several keys
+/// - GET /_watch?key=|prefix= - Stream changes as Server-Sent Events
+/// - GET /_watch/ws?key=|prefix= - Stream changes over a WebSocket
+/// - GET /_changes?from= - Replay the change log from an offset, then follow it
+/// - GET|POST /_changes/offsets/{consumer} - Get or commit a consumer's offset
+/// - GET /_webhooks - List the webhooks with their delivery status
+/// - GET|POST|DELETE /_webhooks/{name} - Get, register or remove a webhook
+/// - GET /_webhooks/{name}/dead_letters - List deliveries that failed every attempt
 ///
-/// All endpoints except /healthz require Bearer token authentication.
+/// All endpoints except /healthz require Bearer token authentication. Requests
+/// other than GET may carry an `Idempotency-Key` header to be applied at most
+/// once.
 pub fn create_router(store: KVStore) -> Router {
-    Router::new()
-        .route("/healthz", get(healthcheck))
+    let authenticated = Router::new()
         .route(
             "/{key}",
             get(get_key)
                 .post(post_value)
-                .delete(delete_key)
-                .layer(from_fn_with_state(store.clone(), auth_middleware)),
+                .patch(patch_value)
+                .delete(delete_key),
         )
+        .merge(changes::routes())
+        .merge(export::routes())
+        .merge(hash::routes())
+        .merge(history::routes())
+        .merge(index::routes())
+        .merge(list::routes())
+        .merge(lock::routes())
+        .merge(prefix::routes())
+        .merge(rename::routes())
+        .merge(replication::routes())
+        .merge(schema::routes())
+        .merge(search::routes())
+        .merge(set::routes())
+        .merge(sorted_set::routes())
+        .merge(stats::routes())
+        .merge(transaction::routes())
+        .merge(trash::routes())
+        .merge(watch::routes())
+        .merge(webhook::routes())
+        // Layers run bottom-up, so requests are authenticated first
+        .route_layer(from_fn_with_state(
+            store.clone(),
+            idempotency::idempotency_middleware,
+        ))
+        .route_layer(from_fn_with_state(store.clone(), auth_middleware));
+
+    Router::new()
+        .route("/healthz", get(healthcheck))
+        .merge(authenticated)
         .layer(CompressionLayer::new())
         .layer(TraceLayer::new_for_http())
         .with_state(store)
 }
 
+/// Content type of RFC 6902 JSON Patch bodies
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+/// Content type of RFC 7386 JSON Merge Patch bodies
+const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+
 /// Request payload for setting a value
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SetValueRequest {
-    /// The value to store
-    pub value: String,
+    /// The value to store; strings are stored as plain text, any other JSON
+    /// value is stored as a JSON document
+    pub value: serde_json::Value,
     /// Optional TTL in seconds
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ttl_seconds: Option<i64>,
+    /// Content type of a string value (defaults to `text/plain`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Tags to record with the value, replacing those of the previous write
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tags: HashMap<String, String>,
 }
 
 /// Response for successful operations
@@ -55,10 +167,24 @@ pub struct SuccessResponse {
     pub message: String,
 }
 
+/// Query parameters for get operations
+#[derive(Debug, Deserialize)]
+pub struct GetQuery {
+    /// JSONPath selecting part of a JSON document, e.g. `$.user.name`
+    pub path: Option<String>,
+    /// Return the bare value with its stored `Content-Type` instead of a JSON envelope
+    #[serde(default)]
+    pub raw: bool,
+}
+
 /// Response for get operations
 #[derive(Debug, Serialize)]
 pub struct GetResponse {
-    pub value: String,
+    /// A string for plain values, the document (or selected part) for JSON documents
+    pub value: serde_json::Value,
+    /// Metadata of the key, omitted for patch results
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<crate::store::Metadata>,
 }
 
 /// Health check endpoint
@@ -82,22 +208,110 @@ async fn healthcheck(State(store): State<KVStore>) -> Result<impl IntoResponse>
 
 /// Get a value by key
 ///
+/// Responds with `Last-Modified` and honors `If-Modified-Since`. With
+/// `?raw=true` the bare value is returned with its stored `Content-Type`.
+///
 /// Requires authentication via Bearer token
 #[debug_handler]
 async fn get_key(
     Extension(token): Extension<String>,
     State(store): State<KVStore>,
     Path(key): Path<String>,
-) -> Result<impl IntoResponse> {
+    Query(query): Query<GetQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
     tracing::info!(
-        "GET {} (token: {})",
+        "GET {} (path: {:?}, token: {})",
         key,
-        &token[..token.char_indices().nth(8).map_or(token.len(), |(i, _)| i)]
+        query.path,
+        short_token(&token)
     );
 
-    let value = store.get(&token, &key).await?;
+    let entry = store.get_entry(&token, &key).await?;
+    let last_modified = entry
+        .metadata
+        .updated_at
+        .map(|millis| UNIX_EPOCH + Duration::from_secs(millis / 1000));
+
+    if let Some(last_modified) = last_modified {
+        if !modified_since(&headers, last_modified) {
+            return Ok((
+                StatusCode::NOT_MODIFIED,
+                [(
+                    header::LAST_MODIFIED,
+                    httpdate::fmt_http_date(last_modified),
+                )],
+            )
+                .into_response());
+        }
+    }
+
+    let mut response = match (query.path, query.raw) {
+        (Some(path), raw) => {
+            let value = entry.json(&key, Some(&path))?;
+            if raw {
+                (
+                    [(header::CONTENT_TYPE, JSON_CONTENT_TYPE)],
+                    value.to_string(),
+                )
+                    .into_response()
+            } else {
+                Json(GetResponse {
+                    value,
+                    metadata: Some(entry.metadata),
+                })
+                .into_response()
+            }
+        }
+        (None, true) => (
+            [(header::CONTENT_TYPE, entry.metadata.content_type.clone())],
+            entry.value,
+        )
+            .into_response(),
+        (None, false) => {
+            let value = envelope_value(&key, &entry)?;
+            Json(GetResponse {
+                value,
+                metadata: Some(entry.metadata),
+            })
+            .into_response()
+        }
+    };
+
+    if let Some(last_modified) = last_modified {
+        if let Ok(value) = httpdate::fmt_http_date(last_modified).parse() {
+            response.headers_mut().insert(header::LAST_MODIFIED, value);
+        }
+    }
+
+    Ok(response)
+}
+
+/// Render an entry as it appears in the `value` field of a [`GetResponse`]
+fn envelope_value(key: &str, entry: &Entry) -> Result<serde_json::Value> {
+    if entry.is_json() {
+        entry
+            .json(key, None)
+            .map_err(|e| KVStoreError::Internal(format!("Corrupt document at {}: {}", key, e)))
+    } else {
+        Ok(serde_json::Value::String(entry.value.clone()))
+    }
+}
+
+/// Whether a resource last modified at `last_modified` should be sent in full
+///
+/// Returns `false` only when the request carries a valid `If-Modified-Since`
+/// that is not older than `last_modified` (HTTP dates have one-second precision).
+fn modified_since(headers: &HeaderMap, last_modified: SystemTime) -> bool {
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
 
-    Ok((StatusCode::OK, Json(GetResponse { value })))
+    match since {
+        Some(since) => last_modified > since,
+        None => true,
+    }
 }
 
 /// Set a value for a key
@@ -113,12 +327,38 @@ async fn post_value(
     tracing::info!(
         "SET {} (token: {}, TTL: {:?})",
         key,
-        &token[..token.char_indices().nth(8).map_or(token.len(), |(i, _)| i)],
+        short_token(&token),
         payload.ttl_seconds
     );
 
+    let (value, content_type) = match payload.value {
+        serde_json::Value::String(value) => (value, payload.content_type),
+        document => {
+            if payload
+                .content_type
+                .as_deref()
+                .is_some_and(|content_type| content_type != JSON_CONTENT_TYPE)
+            {
+                return Err(KVStoreError::InvalidRequest(
+                    "Only string values may have a content type other than application/json"
+                        .to_string(),
+                ));
+            }
+            (document.to_string(), Some(JSON_CONTENT_TYPE.to_string()))
+        }
+    };
+
     store
-        .set(&token, &key, &payload.value, payload.ttl_seconds)
+        .set_with_options(
+            &token,
+            &key,
+            &value,
+            &SetOptions {
+                ttl_seconds: payload.ttl_seconds,
+                content_type,
+                tags: payload.tags,
+            },
+        )
         .await?;
 
     Ok((
@@ -129,21 +369,70 @@ async fn post_value(
     ))
 }
 
-/// Delete a value by key
+/// Atomically patch a JSON document
+///
+/// The body is an RFC 7386 merge patch when sent as
+/// `application/merge-patch+json`, and an RFC 6902 JSON Patch otherwise.
+/// Returns the patched document.
 ///
 /// Requires authentication via Bearer token
 #[debug_handler]
-async fn delete_key(
+async fn patch_value(
     Extension(token): Extension<String>,
     State(store): State<KVStore>,
     Path(key): Path<String>,
+    headers: HeaderMap,
+    Json(patch): Json<serde_json::Value>,
 ) -> Result<impl IntoResponse> {
+    let format = patch_format(&headers)?;
     tracing::info!(
-        "DELETE {} (token: {})",
+        "PATCH {} ({:?}, token: {})",
         key,
-        &token[..token.char_indices().nth(8).map_or(token.len(), |(i, _)| i)]
+        format,
+        short_token(&token)
     );
 
+    let value = store.patch_json(&token, &key, &patch, format).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(GetResponse {
+            value,
+            metadata: None,
+        }),
+    ))
+}
+
+/// Pick the patch format from the request's `Content-Type`
+fn patch_format(headers: &HeaderMap) -> Result<PatchFormat> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim)
+        .unwrap_or_default();
+
+    match content_type {
+        MERGE_PATCH_CONTENT_TYPE => Ok(PatchFormat::MergePatch),
+        JSON_PATCH_CONTENT_TYPE | "" | "application/json" => Ok(PatchFormat::JsonPatch),
+        other => Err(KVStoreError::InvalidRequest(format!(
+            "Unsupported patch content type: {}",
+            other
+        ))),
+    }
+}
+
+/// Delete a value by key
+///
+/// Requires authentication via Bearer token
+#[debug_handler]
+async fn delete_key(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("DELETE {} (token: {})", key, short_token(&token));
+
     store.delete(&token, &key).await?;
 
     Ok((
@@ -237,4 +526,28 @@ mod tests {
 
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
     }
+
+    #[test]
+    fn test_modified_since() {
+        let last_modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let header_for = |time: SystemTime| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::IF_MODIFIED_SINCE,
+                httpdate::fmt_http_date(time).parse().unwrap(),
+            );
+            headers
+        };
+
+        assert!(modified_since(&HeaderMap::new(), last_modified));
+        assert!(!modified_since(&header_for(last_modified), last_modified));
+        assert!(modified_since(
+            &header_for(last_modified - Duration::from_secs(1)),
+            last_modified
+        ));
+
+        let mut invalid = HeaderMap::new();
+        invalid.insert(header::IF_MODIFIED_SINCE, "yesterday".parse().unwrap());
+        assert!(modified_since(&invalid, last_modified));
+    }
 }
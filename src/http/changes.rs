@@ -0,0 +1,144 @@
+//! HTTP handlers for the durable change log
+
+use super::watch::{authorized, last_event_id, sse_event};
+use crate::store::ChangeOffset;
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::get,
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+/// Routes for reading the change log and tracking consumer offsets
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new().route("/_changes", get(changes)).route(
+        "/_changes/offsets/{consumer}",
+        get(get_offset).post(commit_offset),
+    )
+}
+
+/// Query parameters for reading the change log
+#[derive(Debug, Deserialize)]
+pub struct ChangesParams {
+    /// `0` for the oldest change kept, `$` for new changes only, or the ID of
+    /// the last change processed
+    pub from: Option<String>,
+    /// Only return changes of keys starting with this prefix
+    #[serde(default)]
+    pub prefix: String,
+    /// Resume from this consumer's committed offset when `from` is omitted
+    pub consumer: Option<String>,
+}
+
+/// Request payload for committing a consumer offset
+#[derive(Debug, Deserialize)]
+pub struct CommitOffsetRequest {
+    pub offset: String,
+}
+
+/// Response for reading a consumer offset
+#[derive(Debug, Serialize)]
+pub struct OffsetResponse {
+    pub consumer: String,
+    /// `null` if the consumer has not committed an offset
+    pub offset: Option<String>,
+}
+
+/// Stream the change log as Server-Sent Events, starting from an offset
+#[debug_handler]
+async fn changes(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    headers: HeaderMap,
+    Query(params): Query<ChangesParams>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>>> {
+    // A reconnecting EventSource resumes where it left off
+    let from = match last_event_id(&headers).or(params.from) {
+        Some(offset) => offset.parse()?,
+        None => match &params.consumer {
+            Some(consumer) => store.change_offset(&token, consumer).await?,
+            None => None,
+        }
+        .unwrap_or(ChangeOffset::Oldest),
+    };
+    tracing::info!(
+        "CHANGES from {} (prefix: {:?}, consumer: {:?}, token: {})",
+        from,
+        params.prefix,
+        params.consumer,
+        short_token(&token)
+    );
+
+    let changes = store.changes(&token, &from, &params.prefix).await?;
+    let events = authorized(store, token, changes).map(|change| match change {
+        Ok(change) => sse_event(&change),
+        // The feed ends after an error, e.g. when the consumer fell behind
+        // the log
+        Err(e) => Event::default()
+            .event("error")
+            .json_data(serde_json::json!({ "error": e.to_string() })),
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Get the offset a consumer last committed
+#[debug_handler]
+async fn get_offset(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(consumer): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "CHANGES OFFSET GET {} (token: {})",
+        consumer,
+        short_token(&token)
+    );
+
+    let offset = store.change_offset(&token, &consumer).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(OffsetResponse {
+            consumer,
+            offset: offset.map(|offset| offset.to_string()),
+        }),
+    ))
+}
+
+/// Record how far a consumer has processed the change log
+#[debug_handler]
+async fn commit_offset(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(consumer): Path<String>,
+    Json(payload): Json<CommitOffsetRequest>,
+) -> Result<impl IntoResponse> {
+    let offset: ChangeOffset = payload.offset.parse()?;
+    tracing::info!(
+        "CHANGES OFFSET COMMIT {} = {} (token: {})",
+        consumer,
+        offset,
+        short_token(&token)
+    );
+
+    store
+        .commit_change_offset(&token, &consumer, &offset)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(OffsetResponse {
+            consumer,
+            offset: Some(offset.to_string()),
+        }),
+    ))
+}
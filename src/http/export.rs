@@ -0,0 +1,89 @@
+//! HTTP handlers for exporting and importing a namespace as NDJSON
+
+use crate::store::{read_ndjson, ImportConflict, ImportSummary};
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::Deserialize;
+use tokio_stream::StreamExt;
+
+/// Content type of exports, one JSON record per line
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Routes for streaming a namespace out and back in
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/_export", get(export))
+        .route("/_import", post(import))
+}
+
+/// Query parameters for exporting a namespace
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    /// Only export keys starting with this prefix
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Query parameters for importing into a namespace
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    /// What to do with keys that already exist: skip, overwrite or fail
+    #[serde(default)]
+    pub conflict: ImportConflict,
+}
+
+/// Stream the caller's namespace as NDJSON
+///
+/// The body is streamed as keys are read; a failure part-way through aborts
+/// the response.
+#[debug_handler]
+async fn export(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "EXPORT prefix={} (token: {})",
+        params.prefix,
+        short_token(&token)
+    );
+
+    let records = store.export_namespace(&token, &params.prefix).await?;
+    let lines = records.map(|record| record.and_then(|record| record.to_ndjson()));
+
+    Ok((
+        [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        Body::from_stream(lines),
+    ))
+}
+
+/// Import NDJSON records streamed in the request body into the caller's
+/// namespace
+#[debug_handler]
+async fn import(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Query(params): Query<ImportParams>,
+    body: Body,
+) -> Result<Json<ImportSummary>> {
+    tracing::info!(
+        "IMPORT conflict={:?} (token: {})",
+        params.conflict,
+        short_token(&token)
+    );
+
+    let records = read_ndjson(body.into_data_stream());
+    let summary = store
+        .import_namespace(&token, records, params.conflict)
+        .await?;
+
+    Ok(Json(summary))
+}
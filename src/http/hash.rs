@@ -0,0 +1,235 @@
+//! HTTP handlers for hash-valued keys
+
+use super::SuccessResponse;
+use crate::{error::Result, short_token, KVStore, KVStoreError};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Routes for field-level access to hash-valued keys
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/{key}/fields", get(get_fields).post(set_fields))
+        .route(
+            "/{key}/fields/{field}",
+            get(get_field).post(set_field).delete(delete_field),
+        )
+        .route("/{key}/fields/{field}/incr", post(increment_field))
+}
+
+/// Query parameters for reading several fields at once
+#[derive(Debug, Deserialize)]
+pub struct FieldsQuery {
+    /// Comma-separated field names; all fields are returned when omitted
+    pub fields: Option<String>,
+}
+
+/// Request payload for setting several fields at once
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetFieldsRequest {
+    /// Field/value pairs to write
+    pub fields: HashMap<String, String>,
+}
+
+/// Request payload for setting a single field
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetFieldRequest {
+    /// The value to store
+    pub value: String,
+}
+
+/// Request payload for incrementing a field
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IncrementFieldRequest {
+    /// Amount to add (defaults to 1, may be negative)
+    #[serde(default = "default_increment")]
+    pub by: i64,
+}
+
+fn default_increment() -> i64 {
+    1
+}
+
+/// Response for multi-field reads
+///
+/// Missing fields are reported as `null` when specific fields were requested.
+#[derive(Debug, Serialize)]
+pub struct FieldsResponse {
+    pub fields: HashMap<String, Option<String>>,
+}
+
+/// Response for single-field reads
+#[derive(Debug, Serialize)]
+pub struct FieldResponse {
+    pub value: String,
+}
+
+/// Response for multi-field writes
+#[derive(Debug, Serialize)]
+pub struct SetFieldsResponse {
+    /// Number of fields that did not exist before
+    pub added: usize,
+}
+
+/// Response for increments
+#[derive(Debug, Serialize)]
+pub struct IncrementResponse {
+    pub value: i64,
+}
+
+/// Get all fields of a hash, or the subset named in `?fields=`
+#[debug_handler]
+async fn get_fields(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Query(query): Query<FieldsQuery>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("HASH GET {} (token: {})", key, short_token(&token));
+
+    let fields = match query.fields {
+        Some(names) => {
+            let names: Vec<String> = names
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect();
+            let values = store.hash_get(&token, &key, &names).await?;
+            names.into_iter().zip(values).collect()
+        }
+        None => store
+            .hash_get_all(&token, &key)
+            .await?
+            .into_iter()
+            .map(|(field, value)| (field, Some(value)))
+            .collect(),
+    };
+
+    Ok((StatusCode::OK, Json(FieldsResponse { fields })))
+}
+
+/// Set several fields of a hash
+#[debug_handler]
+async fn set_fields(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Json(payload): Json<SetFieldsRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "HASH SET {} ({} fields, token: {})",
+        key,
+        payload.fields.len(),
+        short_token(&token)
+    );
+
+    let fields: Vec<(String, String)> = payload.fields.into_iter().collect();
+    let added = store.hash_set(&token, &key, &fields).await?;
+
+    Ok((StatusCode::OK, Json(SetFieldsResponse { added })))
+}
+
+/// Get a single field of a hash
+#[debug_handler]
+async fn get_field(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, field)): Path<(String, String)>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "HASH GET {}.{} (token: {})",
+        key,
+        field,
+        short_token(&token)
+    );
+
+    let value = store
+        .hash_get(&token, &key, std::slice::from_ref(&field))
+        .await?
+        .pop()
+        .flatten()
+        .ok_or_else(|| KVStoreError::KeyNotFound(format!("{}.{}", key, field)))?;
+
+    Ok((StatusCode::OK, Json(FieldResponse { value })))
+}
+
+/// Set a single field of a hash
+#[debug_handler]
+async fn set_field(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, field)): Path<(String, String)>,
+    Json(payload): Json<SetFieldRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "HASH SET {}.{} (token: {})",
+        key,
+        field,
+        short_token(&token)
+    );
+
+    store
+        .hash_set(&token, &key, &[(field, payload.value)])
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Delete a single field of a hash
+#[debug_handler]
+async fn delete_field(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, field)): Path<(String, String)>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "HASH DELETE {}.{} (token: {})",
+        key,
+        field,
+        short_token(&token)
+    );
+
+    store.hash_delete(&token, &key, &[field]).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Atomically increment an integer field of a hash
+#[debug_handler]
+async fn increment_field(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, field)): Path<(String, String)>,
+    Json(payload): Json<IncrementFieldRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "HASH INCR {}.{} by {} (token: {})",
+        key,
+        field,
+        payload.by,
+        short_token(&token)
+    );
+
+    let value = store
+        .hash_increment(&token, &key, &field, payload.by)
+        .await?;
+
+    Ok((StatusCode::OK, Json(IncrementResponse { value })))
+}
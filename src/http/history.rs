@@ -0,0 +1,116 @@
+//! HTTP handlers for key history and point-in-time reads
+
+use super::SuccessResponse;
+use crate::store::{HistoryPolicy, Revision};
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::Serialize;
+
+/// Routes for history policies, revisions and restores
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route(
+            "/_history",
+            get(get_history_policy).post(set_history_policy),
+        )
+        .route("/{key}/history", get(list_revisions))
+        .route("/{key}/history/{revision}", get(get_at_revision))
+        .route("/{key}/history/{revision}/restore", post(restore))
+}
+
+/// Response for listing revisions
+#[derive(Debug, Serialize)]
+pub struct HistoryResponse {
+    /// Revisions, newest first
+    pub revisions: Vec<Revision>,
+}
+
+/// Get the history policy of the caller's namespace
+#[debug_handler]
+async fn get_history_policy(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("HISTORY POLICY GET (token: {})", short_token(&token));
+
+    let policy = store.get_history_policy(&token).await?;
+
+    Ok((StatusCode::OK, Json(policy)))
+}
+
+/// Set the history policy of the caller's namespace
+#[debug_handler]
+async fn set_history_policy(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Json(policy): Json<HistoryPolicy>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "HISTORY POLICY SET {:?} (token: {})",
+        policy,
+        short_token(&token)
+    );
+
+    store.set_history_policy(&token, &policy).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// List the revisions of a key
+#[debug_handler]
+async fn list_revisions(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("HISTORY {} (token: {})", key, short_token(&token));
+
+    let revisions = store.history(&token, &key).await?;
+
+    Ok((StatusCode::OK, Json(HistoryResponse { revisions })))
+}
+
+/// Read a key as of a revision or millisecond timestamp
+#[debug_handler]
+async fn get_at_revision(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, revision)): Path<(String, String)>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("GET {}@{} (token: {})", key, revision, short_token(&token));
+
+    let revision = store.get_at_revision(&token, &key, &revision).await?;
+
+    Ok((StatusCode::OK, Json(revision)))
+}
+
+/// Restore a key to the value it had at a revision
+#[debug_handler]
+async fn restore(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, revision)): Path<(String, String)>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "RESTORE {}@{} (token: {})",
+        key,
+        revision,
+        short_token(&token)
+    );
+
+    let revision = store.restore(&token, &key, &revision).await?;
+
+    Ok((StatusCode::OK, Json(revision)))
+}
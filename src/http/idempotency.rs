@@ -0,0 +1,132 @@
+//! `Idempotency-Key` support for mutating HTTP requests
+//!
+//! Requests other than GET carrying the header are applied at most once per
+//! key; repeats get the first response replayed with `Idempotent-Replayed:
+//! true`. Responses to client errors are replayed too, as retrying them can't
+//! succeed, but server errors release the key so the request can be retried.
+
+use crate::store::{request_fingerprint, IdempotentResponse};
+use crate::{error::Result, KVStore, KVStoreError};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+
+/// Header carrying the client's idempotency key
+pub const IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Header set on replayed responses
+pub const IDEMPOTENT_REPLAYED_HEADER: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// Largest request or response body kept for idempotent requests
+const MAX_IDEMPOTENT_BODY: usize = 16 * 1024 * 1024;
+
+/// Apply requests with an `Idempotency-Key` at most once per key
+///
+/// Runs after authentication, as keys are scoped to the caller's namespace.
+pub(super) async fn idempotency_middleware(
+    State(store): State<KVStore>,
+    Extension(token): Extension<String>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    if matches!(*request.method(), Method::GET | Method::HEAD) {
+        return Ok(next.run(request).await);
+    }
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(next.run(request).await);
+    };
+    let key = key
+        .to_str()
+        .map_err(|_| KVStoreError::InvalidRequest("Invalid Idempotency-Key header".to_string()))?
+        .to_string();
+
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, MAX_IDEMPOTENT_BODY)
+        .await
+        .map_err(|e| KVStoreError::InvalidRequest(format!("Failed to read body: {}", e)))?;
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .map_or(&b""[..], HeaderValue::as_bytes);
+    let fingerprint = request_fingerprint([
+        parts.method.as_str().as_bytes(),
+        parts.uri.to_string().as_bytes(),
+        content_type,
+        &body,
+    ]);
+
+    if let Some(stored) = store
+        .claim_idempotency_key(&token, &key, &fingerprint)
+        .await?
+    {
+        tracing::debug!("Replaying response for idempotency key {}", key);
+        return Ok(replay(stored));
+    }
+
+    let response = store
+        .while_claimed(
+            &token,
+            &key,
+            &fingerprint,
+            next.run(Request::from_parts(parts, Body::from(body))),
+        )
+        .await;
+    let status = response.status();
+    if status.is_server_error() {
+        store
+            .release_idempotency_key(&token, &key, &fingerprint)
+            .await?;
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_IDEMPOTENT_BODY).await {
+        Ok(body) => body,
+        Err(e) => {
+            store
+                .release_idempotency_key(&token, &key, &fingerprint)
+                .await?;
+            return Err(KVStoreError::Internal(format!(
+                "Failed to read response: {}",
+                e
+            )));
+        }
+    };
+    let stored = IdempotentResponse {
+        status: status.as_u16(),
+        content_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+        body: body.to_vec(),
+    };
+    store
+        .record_idempotent_response(&token, &key, &fingerprint, &stored)
+        .await?;
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Rebuild a kept response
+fn replay(stored: IdempotentResponse) -> Response {
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let mut response = (status, stored.body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    match HeaderValue::from_str(&stored.content_type) {
+        Ok(content_type) if !stored.content_type.is_empty() => {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+        _ => {
+            headers.remove(header::CONTENT_TYPE);
+        }
+    }
+    response
+}
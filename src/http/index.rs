@@ -0,0 +1,169 @@
+//! HTTP handlers for secondary indexes on JSON fields
+
+use super::SuccessResponse;
+use crate::store::{IndexDefinition, IndexPage, IndexQuery};
+use crate::{error::Result, short_token, KVStore, KVStoreError};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Routes for declaring and querying indexes
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/_indexes", get(list_indexes))
+        .route("/_indexes/{name}", post(create_index).delete(drop_index))
+        .route("/_indexes/{name}/query", post(query_index))
+}
+
+/// An index together with its name
+#[derive(Debug, Serialize)]
+pub struct IndexResponse {
+    pub name: String,
+    #[serde(flatten)]
+    pub definition: IndexDefinition,
+}
+
+/// Response for listing indexes
+#[derive(Debug, Serialize)]
+pub struct IndexesResponse {
+    pub indexes: Vec<IndexResponse>,
+}
+
+/// Response for creating an index
+#[derive(Debug, Serialize)]
+pub struct CreateIndexResponse {
+    /// Number of existing documents indexed
+    pub indexed: u64,
+}
+
+/// Request payload for querying an index
+///
+/// Either `equals` or a `min`/`max` range must be given.
+#[derive(Debug, Deserialize)]
+pub struct QueryIndexRequest {
+    /// Value the field must equal; may be null
+    #[serde(default, deserialize_with = "present")]
+    pub equals: Option<Value>,
+    /// Lower bound of a numeric range, inclusive
+    pub min: Option<f64>,
+    /// Upper bound of a numeric range, inclusive
+    pub max: Option<f64>,
+    /// Cursor returned with the previous page
+    pub cursor: Option<String>,
+    /// Maximum number of keys to return
+    pub limit: Option<usize>,
+}
+
+/// Deserialize a field that may be null, telling null apart from absent
+fn present<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+impl QueryIndexRequest {
+    fn query(&self) -> Result<IndexQuery> {
+        match (&self.equals, self.min, self.max) {
+            (Some(value), None, None) => Ok(IndexQuery::Equals(value.clone())),
+            (None, min, max) if min.is_some() || max.is_some() => {
+                Ok(IndexQuery::Range { min, max })
+            }
+            _ => Err(KVStoreError::InvalidRequest(
+                "Either equals or a min/max range is required".to_string(),
+            )),
+        }
+    }
+}
+
+/// List the indexes of the caller's namespace
+#[debug_handler]
+async fn list_indexes(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("INDEX LIST (token: {})", short_token(&token));
+
+    let indexes = store
+        .list_indexes(&token)
+        .await?
+        .into_iter()
+        .map(|(name, definition)| IndexResponse { name, definition })
+        .collect();
+
+    Ok((StatusCode::OK, Json(IndexesResponse { indexes })))
+}
+
+/// Declare an index and index the existing documents
+#[debug_handler]
+async fn create_index(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+    Json(definition): Json<IndexDefinition>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "INDEX CREATE {} {:?} (token: {})",
+        name,
+        definition,
+        short_token(&token)
+    );
+
+    let indexed = store.create_index(&token, &name, &definition).await?;
+
+    Ok((StatusCode::OK, Json(CreateIndexResponse { indexed })))
+}
+
+/// Remove an index
+#[debug_handler]
+async fn drop_index(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("INDEX DROP {} (token: {})", name, short_token(&token));
+
+    store.drop_index(&token, &name).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Look up keys by their indexed field, a page at a time
+#[debug_handler]
+async fn query_index(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+    Json(payload): Json<QueryIndexRequest>,
+) -> Result<Json<IndexPage>> {
+    let query = payload.query()?;
+    tracing::info!(
+        "INDEX QUERY {} {:?} (token: {})",
+        name,
+        query,
+        short_token(&token)
+    );
+
+    let page = store
+        .query_index(
+            &token,
+            &name,
+            &query,
+            payload.cursor.as_deref(),
+            payload.limit,
+        )
+        .await?;
+
+    Ok(Json(page))
+}
@@ -0,0 +1,184 @@
+//! HTTP handlers for list-valued keys
+
+use crate::store::ListEnd;
+use crate::{error::Result, short_token, KVStore, KVStoreError};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Routes for list-valued keys
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/{key}/items", get(get_range).post(push_items))
+        .route("/{key}/items/pop", post(pop_items))
+        .route("/{key}/items/length", get(get_length))
+}
+
+/// Query parameters for reading a range of a list
+#[derive(Debug, Deserialize)]
+pub struct RangeQuery {
+    /// First index to return (defaults to 0)
+    #[serde(default)]
+    pub start: isize,
+    /// Last index to return, inclusive (defaults to -1, the tail)
+    #[serde(default = "default_stop")]
+    pub stop: isize,
+}
+
+fn default_stop() -> isize {
+    -1
+}
+
+/// Request payload for pushing values onto a list
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PushRequest {
+    /// Values to push, in order
+    pub values: Vec<String>,
+    /// `left` or `right` (defaults to `right`, i.e. enqueue at the tail)
+    #[serde(default = "default_push_end")]
+    pub end: String,
+}
+
+fn default_push_end() -> String {
+    "right".to_string()
+}
+
+/// Request payload for popping values from a list
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PopRequest {
+    /// `left` or `right` (defaults to `left`, i.e. dequeue from the head)
+    #[serde(default)]
+    pub end: Option<String>,
+    /// Number of values to pop (defaults to 1)
+    #[serde(default)]
+    pub count: Option<usize>,
+    /// Wait up to this many seconds for a value instead of returning immediately
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<f64>,
+}
+
+/// Response carrying list values
+#[derive(Debug, Serialize)]
+pub struct ItemsResponse {
+    pub items: Vec<String>,
+}
+
+/// Response carrying a list length
+#[derive(Debug, Serialize)]
+pub struct LengthResponse {
+    pub length: usize,
+}
+
+/// Get a range of values from a list
+#[debug_handler]
+async fn get_range(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Query(query): Query<RangeQuery>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "LIST RANGE {} [{}, {}] (token: {})",
+        key,
+        query.start,
+        query.stop,
+        short_token(&token)
+    );
+
+    let items = store
+        .list_range(&token, &key, query.start, query.stop)
+        .await?;
+
+    Ok((StatusCode::OK, Json(ItemsResponse { items })))
+}
+
+/// Push values onto a list
+#[debug_handler]
+async fn push_items(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Json(payload): Json<PushRequest>,
+) -> Result<impl IntoResponse> {
+    let end: ListEnd = payload.end.parse()?;
+
+    tracing::info!(
+        "LIST PUSH {:?} {} ({} values, token: {})",
+        end,
+        key,
+        payload.values.len(),
+        short_token(&token)
+    );
+
+    let length = store.list_push(&token, &key, &payload.values, end).await?;
+
+    Ok((StatusCode::OK, Json(LengthResponse { length })))
+}
+
+/// Pop values from a list, optionally waiting for one to arrive
+///
+/// With `timeout_seconds` set this long-polls for a single value and returns
+/// an empty `items` array if none arrived in time.
+#[debug_handler]
+async fn pop_items(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Json(payload): Json<PopRequest>,
+) -> Result<impl IntoResponse> {
+    let end: ListEnd = match payload.end {
+        Some(end) => end.parse()?,
+        None => ListEnd::Left,
+    };
+    let count = payload.count.unwrap_or(1);
+
+    tracing::info!(
+        "LIST POP {:?} {} (count: {}, timeout: {:?}, token: {})",
+        end,
+        key,
+        count,
+        payload.timeout_seconds,
+        short_token(&token)
+    );
+
+    let items = match payload.timeout_seconds {
+        Some(seconds) => {
+            if count != 1 {
+                return Err(KVStoreError::InvalidRequest(
+                    "Blocking pops return a single value".to_string(),
+                ));
+            }
+            let timeout = Duration::try_from_secs_f64(seconds)
+                .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid timeout: {}", e)))?;
+            store
+                .list_blocking_pop(&token, &key, end, timeout)
+                .await?
+                .into_iter()
+                .collect()
+        }
+        None => store.list_pop(&token, &key, end, count).await?,
+    };
+
+    Ok((StatusCode::OK, Json(ItemsResponse { items })))
+}
+
+/// Get the length of a list
+#[debug_handler]
+async fn get_length(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("LIST LENGTH {} (token: {})", key, short_token(&token));
+
+    let length = store.list_length(&token, &key).await?;
+
+    Ok((StatusCode::OK, Json(LengthResponse { length })))
+}
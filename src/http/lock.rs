@@ -0,0 +1,160 @@
+//! HTTP handlers for distributed locks
+
+use super::SuccessResponse;
+use crate::store::LockLease;
+use crate::{error::Result, short_token, KVStore, KVStoreError};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Routes for acquiring, renewing, releasing and inspecting locks
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/_locks/{name}", get(get_lock).post(acquire_lock))
+        .route("/_locks/{name}/renew", post(renew_lock))
+        .route("/_locks/{name}/release", post(release_lock))
+}
+
+/// Request payload for acquiring or renewing a lock
+#[derive(Debug, Deserialize)]
+pub struct LockRequest {
+    /// Identifies the holder; only it can renew or release the lock
+    pub owner: String,
+    /// Length of the lease
+    pub ttl_seconds: f64,
+    /// Wait up to this many seconds for the lock to become free instead of
+    /// failing immediately (acquire only)
+    #[serde(default)]
+    pub wait_seconds: Option<f64>,
+}
+
+/// Request payload for releasing a lock
+#[derive(Debug, Deserialize)]
+pub struct ReleaseLockRequest {
+    pub owner: String,
+}
+
+/// A lock's current lease
+#[derive(Debug, Serialize)]
+pub struct LockResponse {
+    pub name: String,
+    pub owner: String,
+    pub fencing_token: u64,
+    /// Time left before the lease expires
+    pub ttl_seconds: f64,
+}
+
+impl From<LockLease> for LockResponse {
+    fn from(lease: LockLease) -> Self {
+        LockResponse {
+            name: lease.name,
+            owner: lease.owner,
+            fencing_token: lease.fencing_token,
+            ttl_seconds: lease.ttl.as_secs_f64(),
+        }
+    }
+}
+
+fn seconds(field: &str, seconds: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid {}: {}", field, e)))
+}
+
+/// Get the current holder of a lock
+#[debug_handler]
+async fn get_lock(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+) -> Result<Json<LockResponse>> {
+    tracing::info!("LOCK GET {} (token: {})", name, short_token(&token));
+
+    let lease = store
+        .lock_holder(&token, &name)
+        .await?
+        .ok_or_else(|| KVStoreError::KeyNotFound(format!("lock {}", name)))?;
+
+    Ok(Json(lease.into()))
+}
+
+/// Acquire a lock, optionally waiting for it to become free
+///
+/// Responds `409 Conflict` if another owner still holds the lock.
+#[debug_handler]
+async fn acquire_lock(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+    Json(payload): Json<LockRequest>,
+) -> Result<Json<LockResponse>> {
+    tracing::info!(
+        "LOCK ACQUIRE {} by {} (ttl: {}, wait: {:?}, token: {})",
+        name,
+        payload.owner,
+        payload.ttl_seconds,
+        payload.wait_seconds,
+        short_token(&token)
+    );
+
+    let ttl = seconds("ttl", payload.ttl_seconds)?;
+    let wait = seconds("wait", payload.wait_seconds.unwrap_or(0.0))?;
+    let lease = store
+        .acquire_lock(&token, &name, &payload.owner, ttl, wait)
+        .await?;
+
+    Ok(Json(lease.into()))
+}
+
+/// Extend the lease of a lock the caller holds
+#[debug_handler]
+async fn renew_lock(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+    Json(payload): Json<LockRequest>,
+) -> Result<Json<LockResponse>> {
+    tracing::info!(
+        "LOCK RENEW {} by {} (ttl: {}, token: {})",
+        name,
+        payload.owner,
+        payload.ttl_seconds,
+        short_token(&token)
+    );
+
+    let ttl = seconds("ttl", payload.ttl_seconds)?;
+    let lease = store.renew_lock(&token, &name, &payload.owner, ttl).await?;
+
+    Ok(Json(lease.into()))
+}
+
+/// Release a lock the caller holds
+#[debug_handler]
+async fn release_lock(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+    Json(payload): Json<ReleaseLockRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "LOCK RELEASE {} by {} (token: {})",
+        name,
+        payload.owner,
+        short_token(&token)
+    );
+
+    store.release_lock(&token, &name, &payload.owner).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
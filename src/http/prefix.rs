@@ -0,0 +1,77 @@
+//! HTTP handlers for operations over key prefixes
+
+use crate::{error::Result, short_token, KVStore, KVStoreError};
+use axum::{
+    extract::{Query, State},
+    routing::delete,
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+
+/// Routes for prefix operations
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new().route("/", delete(delete_prefix))
+}
+
+/// Query parameters for deleting by prefix
+#[derive(Debug, Deserialize)]
+pub struct DeletePrefixQuery {
+    /// Prefix of the keys to delete; required, and empty to purge the namespace
+    pub prefix: Option<String>,
+    /// Report what would be deleted without deleting anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Response for deleting by prefix
+#[derive(Debug, Serialize)]
+pub struct DeletePrefixResponse {
+    /// Number of keys deleted, or that would be deleted in a dry run
+    pub count: u64,
+    pub dry_run: bool,
+    /// Names of the keys that would be deleted, in a dry run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<String>>,
+    /// Whether `keys` was cut short
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<bool>,
+}
+
+/// Delete every key starting with a prefix
+#[debug_handler]
+async fn delete_prefix(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Query(query): Query<DeletePrefixQuery>,
+) -> Result<Json<DeletePrefixResponse>> {
+    // Requiring the parameter keeps a bare `DELETE /` from purging a namespace
+    let prefix = query.prefix.ok_or_else(|| {
+        KVStoreError::InvalidRequest("The prefix query parameter is required".to_string())
+    })?;
+
+    tracing::info!(
+        "DELETE PREFIX {:?} (dry run: {}, token: {})",
+        prefix,
+        query.dry_run,
+        short_token(&token)
+    );
+
+    if query.dry_run {
+        let matched = store.delete_prefix_dry_run(&token, &prefix).await?;
+        return Ok(Json(DeletePrefixResponse {
+            count: matched.count,
+            dry_run: true,
+            keys: Some(matched.keys),
+            truncated: Some(matched.truncated),
+        }));
+    }
+
+    let count = store.delete_prefix(&token, &prefix).await?;
+    Ok(Json(DeletePrefixResponse {
+        count,
+        dry_run: false,
+        keys: None,
+        truncated: None,
+    }))
+}
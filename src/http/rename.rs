@@ -0,0 +1,121 @@
+//! HTTP handlers for renaming and copying keys
+
+use super::SuccessResponse;
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::post,
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+
+/// Routes for renames and copies
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/{key}/rename", post(rename))
+        .route("/{key}/copy", post(copy))
+        .route("/_rename", post(rename_prefix))
+}
+
+/// Request payload for renaming or copying a key
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MoveRequest {
+    /// The new key
+    pub to: String,
+    /// Replace the new key if it already exists
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+/// Request payload for renaming every key with a prefix
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RenamePrefixRequest {
+    pub from_prefix: String,
+    pub to_prefix: String,
+    /// Replace keys that already exist under the new prefix
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+/// Rename a key, keeping its TTL and metadata
+#[debug_handler]
+async fn rename(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Json(payload): Json<MoveRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "RENAME {} -> {} (token: {})",
+        key,
+        payload.to,
+        short_token(&token)
+    );
+
+    store
+        .rename(&token, &key, &payload.to, payload.overwrite)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Copy a key, including its TTL and metadata
+#[debug_handler]
+async fn copy(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Json(payload): Json<MoveRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "COPY {} -> {} (token: {})",
+        key,
+        payload.to,
+        short_token(&token)
+    );
+
+    store
+        .copy(&token, &key, &payload.to, payload.overwrite)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Rename every key starting with a prefix
+#[debug_handler]
+async fn rename_prefix(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Json(payload): Json<RenamePrefixRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "RENAME PREFIX {:?} -> {:?} (token: {})",
+        payload.from_prefix,
+        payload.to_prefix,
+        short_token(&token)
+    );
+
+    let outcome = store
+        .rename_prefix(
+            &token,
+            &payload.from_prefix,
+            &payload.to_prefix,
+            payload.overwrite,
+        )
+        .await?;
+
+    Ok((StatusCode::OK, Json(outcome)))
+}
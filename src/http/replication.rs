@@ -0,0 +1,32 @@
+//! HTTP handler for the replication status of a namespace
+
+use crate::store::ReplicationStatus;
+use crate::{error::Result, short_token, KVStore};
+use axum::{extract::State, routing::get, Extension, Json, Router};
+use axum_macros::debug_handler;
+use serde::Serialize;
+
+/// Route for inspecting replication from another deployment
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new().route("/_replication", get(replication_status))
+}
+
+/// Response for the replication status
+#[derive(Debug, Serialize)]
+pub struct ReplicationResponse {
+    /// Null if the namespace has never been replicated
+    pub status: Option<ReplicationStatus>,
+}
+
+/// Get the progress and lag of the replication of the caller's namespace
+#[debug_handler]
+async fn replication_status(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+) -> Result<Json<ReplicationResponse>> {
+    tracing::info!("REPLICATION STATUS (token: {})", short_token(&token));
+
+    let status = store.replication_status(&token).await?;
+
+    Ok(Json(ReplicationResponse { status }))
+}
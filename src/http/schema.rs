@@ -0,0 +1,114 @@
+//! HTTP handlers for JSON Schemas bound to key prefixes
+
+use super::SuccessResponse;
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+
+/// Routes for registering and inspecting schemas
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new().route("/_schemas", get(list_schemas)).route(
+        "/_schemas/{prefix}",
+        get(get_schema).post(register_schema).delete(delete_schema),
+    )
+}
+
+/// Request payload for registering a schema
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegisterSchemaRequest {
+    /// The JSON Schema document
+    pub schema: serde_json::Value,
+}
+
+/// A schema together with the prefix it is bound to
+#[derive(Debug, Serialize)]
+pub struct SchemaResponse {
+    pub prefix: String,
+    pub schema: serde_json::Value,
+}
+
+/// Response for listing schemas
+#[derive(Debug, Serialize)]
+pub struct SchemasResponse {
+    pub schemas: Vec<SchemaResponse>,
+}
+
+/// List every schema of the caller's namespace
+#[debug_handler]
+async fn list_schemas(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("SCHEMA LIST (token: {})", short_token(&token));
+
+    let schemas = store
+        .list_schemas(&token)
+        .await?
+        .into_iter()
+        .map(|(prefix, schema)| SchemaResponse { prefix, schema })
+        .collect();
+
+    Ok((StatusCode::OK, Json(SchemasResponse { schemas })))
+}
+
+/// Get the schema bound to a prefix
+#[debug_handler]
+async fn get_schema(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(prefix): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("SCHEMA GET {} (token: {})", prefix, short_token(&token));
+
+    let schema = store.get_schema(&token, &prefix).await?;
+
+    Ok((StatusCode::OK, Json(SchemaResponse { prefix, schema })))
+}
+
+/// Bind a schema to a prefix
+#[debug_handler]
+async fn register_schema(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(prefix): Path<String>,
+    Json(payload): Json<RegisterSchemaRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("SCHEMA SET {} (token: {})", prefix, short_token(&token));
+
+    store
+        .register_schema(&token, &prefix, &payload.schema)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Remove the schema bound to a prefix
+#[debug_handler]
+async fn delete_schema(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(prefix): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("SCHEMA DELETE {} (token: {})", prefix, short_token(&token));
+
+    store.delete_schema(&token, &prefix).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
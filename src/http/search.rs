@@ -0,0 +1,64 @@
+//! HTTP handler for key search
+
+use crate::store::{SearchMode, SearchPage, SearchQuery};
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Routes for key search
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new().route("/", get(search))
+}
+
+/// Query parameters for searching keys
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    /// How `pattern` is matched: `prefix` (default), `glob` or `regex`
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// The prefix, glob or regex to match; may also be given as `prefix`
+    #[serde(default, alias = "prefix")]
+    pub pattern: String,
+    /// Cursor returned with the previous page
+    pub cursor: Option<String>,
+    /// Maximum number of keys to return
+    pub limit: Option<usize>,
+    /// Time to spend scanning, in milliseconds
+    pub timeout_ms: Option<u64>,
+}
+
+/// List the keys matching a prefix, glob or regex, a page at a time
+#[debug_handler]
+async fn search(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<SearchPage>> {
+    tracing::info!(
+        "SEARCH {:?} {:?} (token: {})",
+        params.mode,
+        params.pattern,
+        short_token(&token)
+    );
+
+    let page = store
+        .search(
+            &token,
+            &SearchQuery {
+                mode: params.mode,
+                pattern: params.pattern,
+                cursor: params.cursor,
+                limit: params.limit,
+                timeout: params.timeout_ms.map(Duration::from_millis),
+            },
+        )
+        .await?;
+
+    Ok(Json(page))
+}
@@ -0,0 +1,235 @@
+//! HTTP handlers for set-valued keys
+
+use super::SuccessResponse;
+use crate::store::SetOperation;
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+
+/// Routes for set-valued keys
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/{key}/members", get(scan_members).post(add_members))
+        .route(
+            "/{key}/members/{member}",
+            get(is_member).delete(remove_member),
+        )
+        .route("/{key}/cardinality", get(get_cardinality))
+        .route("/_sets/intersection", post(intersection))
+        .route("/_sets/union", post(union))
+}
+
+/// Query parameters for paging through a set
+#[derive(Debug, Deserialize)]
+pub struct ScanQuery {
+    /// `0` to start, then the `next_cursor` of the previous page
+    #[serde(default)]
+    pub cursor: u64,
+    /// Hint for how many members to return per page (defaults to 100)
+    #[serde(default = "default_count")]
+    pub count: usize,
+}
+
+fn default_count() -> usize {
+    100
+}
+
+/// Request payload for adding members
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AddMembersRequest {
+    pub members: Vec<String>,
+}
+
+/// Request payload for intersections and unions
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CombineRequest {
+    /// Keys of the sets to combine, all within the caller's namespace
+    pub keys: Vec<String>,
+}
+
+/// A page of set members
+#[derive(Debug, Serialize)]
+pub struct ScanResponse {
+    pub members: Vec<String>,
+    /// Cursor for the next page, or `null` once iteration is complete
+    pub next_cursor: Option<u64>,
+}
+
+/// Response for adds
+#[derive(Debug, Serialize)]
+pub struct AddMembersResponse {
+    /// Number of members that did not exist before
+    pub added: usize,
+}
+
+/// Response for membership checks
+#[derive(Debug, Serialize)]
+pub struct MembershipResponse {
+    pub member: String,
+    pub is_member: bool,
+}
+
+/// Response for cardinality queries
+#[derive(Debug, Serialize)]
+pub struct CardinalityResponse {
+    pub cardinality: usize,
+}
+
+/// Response for intersections and unions
+#[derive(Debug, Serialize)]
+pub struct MembersResponse {
+    pub members: Vec<String>,
+}
+
+/// Get a page of set members using a `SSCAN` cursor
+#[debug_handler]
+async fn scan_members(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Query(query): Query<ScanQuery>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SET SCAN {} (cursor: {}, token: {})",
+        key,
+        query.cursor,
+        short_token(&token)
+    );
+
+    let (cursor, members) = store
+        .set_scan(&token, &key, query.cursor, query.count)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(ScanResponse {
+            members,
+            next_cursor: (cursor != 0).then_some(cursor),
+        }),
+    ))
+}
+
+/// Add members to a set
+#[debug_handler]
+async fn add_members(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Json(payload): Json<AddMembersRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SET ADD {} ({} members, token: {})",
+        key,
+        payload.members.len(),
+        short_token(&token)
+    );
+
+    let added = store.set_add(&token, &key, &payload.members).await?;
+
+    Ok((StatusCode::OK, Json(AddMembersResponse { added })))
+}
+
+/// Check whether a member belongs to a set
+#[debug_handler]
+async fn is_member(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, member)): Path<(String, String)>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SET ISMEMBER {} {} (token: {})",
+        key,
+        member,
+        short_token(&token)
+    );
+
+    let is_member = store.set_is_member(&token, &key, &member).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(MembershipResponse { member, is_member }),
+    ))
+}
+
+/// Remove a member from a set
+#[debug_handler]
+async fn remove_member(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, member)): Path<(String, String)>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SET REMOVE {} {} (token: {})",
+        key,
+        member,
+        short_token(&token)
+    );
+
+    store.set_remove(&token, &key, &[member]).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Get the number of members in a set
+#[debug_handler]
+async fn get_cardinality(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("SET CARDINALITY {} (token: {})", key, short_token(&token));
+
+    let cardinality = store.set_cardinality(&token, &key).await?;
+
+    Ok((StatusCode::OK, Json(CardinalityResponse { cardinality })))
+}
+
+/// Intersect sets of the caller's namespace
+#[debug_handler]
+async fn intersection(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Json(payload): Json<CombineRequest>,
+) -> Result<impl IntoResponse> {
+    combine(&store, &token, &payload.keys, SetOperation::Intersection).await
+}
+
+/// Union sets of the caller's namespace
+#[debug_handler]
+async fn union(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Json(payload): Json<CombineRequest>,
+) -> Result<impl IntoResponse> {
+    combine(&store, &token, &payload.keys, SetOperation::Union).await
+}
+
+async fn combine(
+    store: &KVStore,
+    token: &str,
+    keys: &[String],
+    operation: SetOperation,
+) -> Result<(StatusCode, Json<MembersResponse>)> {
+    tracing::info!(
+        "SET {:?} {:?} (token: {})",
+        operation,
+        keys,
+        short_token(token)
+    );
+
+    let members = store.set_combine(token, keys, operation).await?;
+
+    Ok((StatusCode::OK, Json(MembersResponse { members })))
+}
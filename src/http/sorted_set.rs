@@ -0,0 +1,258 @@
+//! HTTP handlers for sorted-set-valued keys
+
+use super::SuccessResponse;
+use crate::store::ScoredMember;
+use crate::{error::Result, short_token, KVStore, KVStoreError};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+
+/// Routes for sorted-set-valued keys
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/{key}/scores", get(get_range).post(add_members))
+        .route(
+            "/{key}/scores/{member}",
+            get(get_member).delete(remove_member),
+        )
+        .route("/{key}/scores/{member}/incr", post(increment_member))
+}
+
+/// Query parameters for reading a page of a sorted set
+#[derive(Debug, Deserialize)]
+pub struct RangeQuery {
+    /// `rank` (default) or `score`
+    #[serde(default)]
+    pub by: Option<String>,
+    /// Lowest score to include (score ranges only, unbounded when omitted)
+    pub min: Option<f64>,
+    /// Highest score to include (score ranges only, unbounded when omitted)
+    pub max: Option<f64>,
+    /// Number of members to skip
+    #[serde(default)]
+    pub offset: usize,
+    /// Page size (defaults to 100, at most 1000)
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /// Order from the highest score instead
+    #[serde(default)]
+    pub rev: bool,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+/// Query parameters for looking up a member
+#[derive(Debug, Deserialize)]
+pub struct RankQuery {
+    /// Rank from the highest score instead
+    #[serde(default)]
+    pub rev: bool,
+}
+
+/// Request payload for adding or updating members
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AddMembersRequest {
+    pub members: Vec<ScoredMember>,
+}
+
+/// Request payload for incrementing a member's score
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IncrementMemberRequest {
+    /// Amount to add (defaults to 1, may be negative or fractional)
+    #[serde(default = "default_increment")]
+    pub by: f64,
+}
+
+fn default_increment() -> f64 {
+    1.0
+}
+
+/// A page of sorted set members
+#[derive(Debug, Serialize)]
+pub struct RangeResponse {
+    pub members: Vec<ScoredMember>,
+    /// Offset of the next page, or `null` when this was the last page
+    pub next_offset: Option<usize>,
+}
+
+/// Response for adds
+#[derive(Debug, Serialize)]
+pub struct AddMembersResponse {
+    /// Number of members that did not exist before
+    pub added: usize,
+}
+
+/// Rank and score of a single member
+#[derive(Debug, Serialize)]
+pub struct MemberResponse {
+    pub member: String,
+    pub score: f64,
+    pub rank: usize,
+}
+
+/// Response for increments
+#[derive(Debug, Serialize)]
+pub struct ScoreResponse {
+    pub score: f64,
+}
+
+/// Get a page of members by rank or by score
+#[debug_handler]
+async fn get_range(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Query(query): Query<RangeQuery>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SORTED SET RANGE {} by {:?} (offset: {}, limit: {}, token: {})",
+        key,
+        query.by,
+        query.offset,
+        query.limit,
+        short_token(&token)
+    );
+
+    let members = match query.by.as_deref() {
+        None | Some("rank") => {
+            store
+                .sorted_set_range_by_rank(&token, &key, query.offset, query.limit, query.rev)
+                .await?
+        }
+        Some("score") => {
+            store
+                .sorted_set_range_by_score(
+                    &token,
+                    &key,
+                    query.min.unwrap_or(f64::NEG_INFINITY),
+                    query.max.unwrap_or(f64::INFINITY),
+                    query.offset,
+                    query.limit,
+                    query.rev,
+                )
+                .await?
+        }
+        Some(other) => {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Invalid range type: {}. Must be one of: rank, score",
+                other
+            )))
+        }
+    };
+
+    let next_offset =
+        (members.len() == query.limit).then_some(query.offset.saturating_add(query.limit));
+
+    Ok((
+        StatusCode::OK,
+        Json(RangeResponse {
+            members,
+            next_offset,
+        }),
+    ))
+}
+
+/// Add members or update their scores
+#[debug_handler]
+async fn add_members(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+    Json(payload): Json<AddMembersRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SORTED SET ADD {} ({} members, token: {})",
+        key,
+        payload.members.len(),
+        short_token(&token)
+    );
+
+    let added = store.sorted_set_add(&token, &key, &payload.members).await?;
+
+    Ok((StatusCode::OK, Json(AddMembersResponse { added })))
+}
+
+/// Get the rank and score of a member
+#[debug_handler]
+async fn get_member(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, member)): Path<(String, String)>,
+    Query(query): Query<RankQuery>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SORTED SET RANK {} {} (token: {})",
+        key,
+        member,
+        short_token(&token)
+    );
+
+    let (rank, score) = store
+        .sorted_set_rank(&token, &key, &member, query.rev)
+        .await?
+        .ok_or_else(|| KVStoreError::KeyNotFound(format!("{}.{}", key, member)))?;
+
+    Ok((
+        StatusCode::OK,
+        Json(MemberResponse {
+            member,
+            score,
+            rank,
+        }),
+    ))
+}
+
+/// Remove a member
+#[debug_handler]
+async fn remove_member(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, member)): Path<(String, String)>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SORTED SET REMOVE {} {} (token: {})",
+        key,
+        member,
+        short_token(&token)
+    );
+
+    store.sorted_set_remove(&token, &key, &[member]).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Atomically increment a member's score
+#[debug_handler]
+async fn increment_member(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path((key, member)): Path<(String, String)>,
+    Json(payload): Json<IncrementMemberRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SORTED SET INCR {} {} by {} (token: {})",
+        key,
+        member,
+        payload.by,
+        short_token(&token)
+    );
+
+    let score = store
+        .sorted_set_increment(&token, &key, &member, payload.by)
+        .await?;
+
+    Ok((StatusCode::OK, Json(ScoreResponse { score })))
+}
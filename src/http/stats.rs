@@ -0,0 +1,25 @@
+//! HTTP handler for namespace statistics
+
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::State, http::StatusCode, response::IntoResponse, routing::get, Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+
+/// Routes for namespace statistics
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new().route("/_stats", get(stats))
+}
+
+/// Get usage statistics of the caller's namespace
+#[debug_handler]
+async fn stats(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("STATS (token: {})", short_token(&token));
+
+    let stats = store.stats(&token).await?;
+
+    Ok((StatusCode::OK, Json(stats)))
+}
@@ -0,0 +1,93 @@
+//! HTTP handler for multi-key transactions
+
+use crate::store::{Operation, OperationResult, Precondition, TransactionOutcome};
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::State, http::StatusCode, response::IntoResponse, routing::post, Extension, Json,
+    Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+
+/// Routes for transactions
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new().route("/_txn", post(transaction))
+}
+
+/// Request payload for a transaction
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TransactionRequest {
+    /// Conditions that must all hold for the operations to be applied
+    #[serde(default)]
+    pub preconditions: Vec<Precondition>,
+    /// Operations applied in order, all or nothing
+    pub operations: Vec<Operation>,
+}
+
+/// The precondition that prevented a transaction from being applied
+#[derive(Debug, Serialize)]
+pub struct FailedPrecondition {
+    /// Position of the precondition in the request
+    pub index: usize,
+    #[serde(flatten)]
+    pub precondition: Precondition,
+}
+
+/// Response for transactions
+#[derive(Debug, Serialize)]
+pub struct TransactionResponse {
+    pub committed: bool,
+    /// Per-operation results, in operation order, when committed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<OperationResult>>,
+    /// The failed precondition, when not committed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_precondition: Option<FailedPrecondition>,
+}
+
+/// Atomically apply operations to several keys
+///
+/// Responds with 409 Conflict and the failed precondition if any
+/// precondition does not hold.
+#[debug_handler]
+async fn transaction(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Json(payload): Json<TransactionRequest>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "TRANSACTION ({} preconditions, {} operations, token: {})",
+        payload.preconditions.len(),
+        payload.operations.len(),
+        short_token(&token)
+    );
+
+    let outcome = store
+        .transaction(&token, &payload.preconditions, &payload.operations)
+        .await?;
+
+    Ok(match outcome {
+        TransactionOutcome::Committed(results) => (
+            StatusCode::OK,
+            Json(TransactionResponse {
+                committed: true,
+                results: Some(results),
+                failed_precondition: None,
+            }),
+        ),
+        TransactionOutcome::PreconditionFailed {
+            index,
+            precondition,
+        } => (
+            StatusCode::CONFLICT,
+            Json(TransactionResponse {
+                committed: false,
+                results: None,
+                failed_precondition: Some(FailedPrecondition {
+                    index,
+                    precondition,
+                }),
+            }),
+        ),
+    })
+}
@@ -0,0 +1,156 @@
+//! HTTP handlers for soft delete and the trash
+
+use super::SuccessResponse;
+use crate::store::TrashedKey;
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Routes for the trash policy, listing, restoring and purging trashed keys
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/_soft_delete", get(get_soft_delete).post(set_soft_delete))
+        .route("/_trash", get(list_trash).delete(purge_all))
+        .route("/_trash/{key}", delete(purge_key))
+        .route("/{key}/undelete", post(undelete))
+}
+
+/// A namespace's soft delete setting
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SoftDeletePolicy {
+    /// How long deleted keys are kept in the trash; null turns soft delete off
+    pub retention_seconds: Option<u64>,
+}
+
+/// Query parameters for listing the trash
+#[derive(Debug, Deserialize)]
+pub struct TrashParams {
+    /// Only list keys starting with this prefix
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Response for listing the trash
+#[derive(Debug, Serialize)]
+pub struct TrashResponse {
+    /// Trashed keys, oldest deletion first
+    pub keys: Vec<TrashedKey>,
+}
+
+/// Response for purging the trash
+#[derive(Debug, Serialize)]
+pub struct PurgeResponse {
+    pub purged: u64,
+}
+
+/// Get the soft delete setting of the caller's namespace
+#[debug_handler]
+async fn get_soft_delete(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+) -> Result<Json<SoftDeletePolicy>> {
+    tracing::info!("SOFT DELETE GET (token: {})", short_token(&token));
+
+    let retention = store.trash_retention(&token).await?;
+
+    Ok(Json(SoftDeletePolicy {
+        retention_seconds: retention.map(|retention| retention.as_secs()),
+    }))
+}
+
+/// Turn soft delete on or off for the caller's namespace
+#[debug_handler]
+async fn set_soft_delete(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Json(policy): Json<SoftDeletePolicy>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "SOFT DELETE SET {:?} (token: {})",
+        policy,
+        short_token(&token)
+    );
+
+    store
+        .set_trash_retention(&token, policy.retention_seconds.map(Duration::from_secs))
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// List the keys in the caller's trash
+#[debug_handler]
+async fn list_trash(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Query(params): Query<TrashParams>,
+) -> Result<Json<TrashResponse>> {
+    tracing::info!(
+        "TRASH LIST {:?} (token: {})",
+        params.prefix,
+        short_token(&token)
+    );
+
+    let keys = store.list_trash(&token, &params.prefix).await?;
+
+    Ok(Json(TrashResponse { keys }))
+}
+
+/// Permanently remove every key in the caller's trash
+#[debug_handler]
+async fn purge_all(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+) -> Result<Json<PurgeResponse>> {
+    tracing::info!("TRASH PURGE (token: {})", short_token(&token));
+
+    let purged = store.purge_trash(&token, None).await?;
+
+    Ok(Json(PurgeResponse { purged }))
+}
+
+/// Permanently remove a key from the caller's trash
+#[debug_handler]
+async fn purge_key(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+) -> Result<Json<PurgeResponse>> {
+    tracing::info!("TRASH PURGE {} (token: {})", key, short_token(&token));
+
+    let purged = store.purge_trash(&token, Some(&key)).await?;
+
+    Ok(Json(PurgeResponse { purged }))
+}
+
+/// Restore a deleted key from the trash
+#[debug_handler]
+async fn undelete(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("UNDELETE {} (token: {})", key, short_token(&token));
+
+    store.undelete(&token, &key).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
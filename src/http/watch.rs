@@ -0,0 +1,202 @@
+//! HTTP change feeds over Server-Sent Events and WebSockets
+
+use crate::store::{ChangeEvent, ChangeKind};
+use crate::{error::Result, short_token, KVStore, KVStoreError};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::get,
+    Extension, Router,
+};
+use axum_macros::debug_handler;
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+
+/// Header browsers send when an `EventSource` reconnects
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// Routes for watching keys
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/_watch", get(watch_events))
+        .route("/_watch/ws", get(watch_socket))
+}
+
+/// Query parameters for watching keys
+///
+/// Without `key` or `prefix` the whole namespace is watched.
+#[derive(Debug, Deserialize)]
+pub struct WatchParams {
+    /// Watch a single key
+    pub key: Option<String>,
+    /// Watch every key starting with this prefix
+    pub prefix: Option<String>,
+    /// Resume after this event ID, for clients that can't send `Last-Event-ID`
+    pub last_event_id: Option<String>,
+}
+
+/// What to watch and where to resume
+struct Watch {
+    key: String,
+    prefix: bool,
+    after: Option<String>,
+}
+
+impl Watch {
+    fn new(params: WatchParams, headers: &HeaderMap) -> Result<Self> {
+        let (key, prefix) = match (params.key, params.prefix) {
+            (Some(_), Some(_)) => {
+                return Err(KVStoreError::InvalidRequest(
+                    "Only one of key and prefix may be given".to_string(),
+                ))
+            }
+            (Some(key), None) => (key, false),
+            (None, prefix) => (prefix.unwrap_or_default(), true),
+        };
+        let after = last_event_id(headers).or(params.last_event_id);
+
+        Ok(Watch { key, prefix, after })
+    }
+
+    /// Start watching, ending the feed once the token has been revoked
+    async fn changes(
+        &self,
+        store: KVStore,
+        token: String,
+    ) -> Result<impl Stream<Item = ChangeEvent> + Send + 'static> {
+        let changes = store
+            .watch(&token, &self.key, self.prefix, self.after.as_deref())
+            .await?;
+
+        Ok(authorized(store, token, changes))
+    }
+}
+
+/// Pass on the items of a change feed while `token` remains valid
+///
+/// Tokens may be revoked while a feed is open, so the token is checked
+/// before every item and the feed ends once it fails.
+pub(super) fn authorized<T: Send + 'static>(
+    store: KVStore,
+    token: String,
+    items: impl Stream<Item = T> + Send + 'static,
+) -> impl Stream<Item = T> + Send + 'static {
+    items
+        .then(move |item| {
+            let store = store.clone();
+            let token = token.clone();
+            async move { matches!(store.validate_token(&token).await, Ok(true)).then_some(item) }
+        })
+        .take_while(|item| futures::future::ready(item.is_some()))
+        .filter_map(futures::future::ready)
+}
+
+/// Read the event ID a reconnecting `EventSource` resumes after
+pub(super) fn last_event_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(LAST_EVENT_ID)
+        .and_then(|id| id.to_str().ok())
+        .map(String::from)
+}
+
+/// Build the Server-Sent Event for a change, named after its type
+pub(super) fn sse_event(change: &ChangeEvent) -> std::result::Result<Event, axum::Error> {
+    let name = match change.kind {
+        ChangeKind::Put => "put",
+        ChangeKind::Delete => "delete",
+        ChangeKind::Expire => "expire",
+    };
+    let event = Event::default().event(name);
+    // Expirations aren't logged, so they can't be resumed from
+    let event = match &change.id {
+        Some(id) => event.id(id),
+        None => event,
+    };
+    event.json_data(change)
+}
+
+/// Stream changes as Server-Sent Events
+#[debug_handler]
+async fn watch_events(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    headers: HeaderMap,
+    Query(params): Query<WatchParams>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>>> {
+    let watch = Watch::new(params, &headers)?;
+    tracing::info!(
+        "WATCH SSE {} (prefix: {}, after: {:?}, token: {})",
+        watch.key,
+        watch.prefix,
+        watch.after,
+        short_token(&token)
+    );
+
+    let events = watch
+        .changes(store, token)
+        .await?
+        .map(|change| sse_event(&change));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Stream changes as JSON text messages over a WebSocket
+#[debug_handler]
+async fn watch_socket(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    headers: HeaderMap,
+    Query(params): Query<WatchParams>,
+    upgrade: WebSocketUpgrade,
+) -> Result<impl IntoResponse> {
+    let watch = Watch::new(params, &headers)?;
+    tracing::info!(
+        "WATCH WS {} (prefix: {}, after: {:?}, token: {})",
+        watch.key,
+        watch.prefix,
+        watch.after,
+        short_token(&token)
+    );
+
+    // Start watching before upgrading so errors get a proper HTTP response
+    let changes = watch.changes(store, token).await?;
+
+    Ok(upgrade.on_upgrade(move |socket| send_changes(socket, changes)))
+}
+
+/// Forward changes to a WebSocket until either side goes away
+async fn send_changes(mut socket: WebSocket, changes: impl Stream<Item = ChangeEvent>) {
+    let mut changes = std::pin::pin!(changes);
+    loop {
+        tokio::select! {
+            change = changes.next() => {
+                let Some(change) = change else {
+                    break;
+                };
+                let text = match serde_json::to_string(&change) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        tracing::error!("Failed to serialize change: {}", e);
+                        continue;
+                    }
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    return;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // Clients have nothing to say; pings are answered by the socket
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
@@ -0,0 +1,126 @@
+//! HTTP handlers for outbound webhooks on key changes
+
+use super::SuccessResponse;
+use crate::store::{DeadLetter, Webhook, WebhookInfo};
+use crate::{error::Result, short_token, KVStore};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
+use axum_macros::debug_handler;
+use serde::Serialize;
+
+/// Routes for registering webhooks and inspecting their deliveries
+pub(super) fn routes() -> Router<KVStore> {
+    Router::new()
+        .route("/_webhooks", get(list_webhooks))
+        .route(
+            "/_webhooks/{name}",
+            get(get_webhook).post(set_webhook).delete(delete_webhook),
+        )
+        .route("/_webhooks/{name}/dead_letters", get(dead_letters))
+}
+
+/// Response for listing webhooks
+#[derive(Debug, Serialize)]
+pub struct WebhooksResponse {
+    pub webhooks: Vec<WebhookInfo>,
+}
+
+/// Response for listing a webhook's dead letters
+#[derive(Debug, Serialize)]
+pub struct DeadLettersResponse {
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+/// List the webhooks of the caller's namespace with their delivery status
+#[debug_handler]
+async fn list_webhooks(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+) -> Result<Json<WebhooksResponse>> {
+    tracing::info!("WEBHOOK LIST (token: {})", short_token(&token));
+
+    let webhooks = store.list_webhooks(&token).await?;
+
+    Ok(Json(WebhooksResponse { webhooks }))
+}
+
+/// Get a webhook with its delivery status
+#[debug_handler]
+async fn get_webhook(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+) -> Result<Json<WebhookInfo>> {
+    tracing::info!("WEBHOOK GET {} (token: {})", name, short_token(&token));
+
+    let webhook = store.get_webhook(&token, &name).await?;
+
+    Ok(Json(webhook))
+}
+
+/// Register or replace a webhook
+#[debug_handler]
+async fn set_webhook(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+    Json(webhook): Json<Webhook>,
+) -> Result<impl IntoResponse> {
+    tracing::info!(
+        "WEBHOOK SET {} -> {} (token: {})",
+        name,
+        webhook.url,
+        short_token(&token)
+    );
+
+    store.set_webhook(&token, &name, &webhook).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// Remove a webhook and drop its queued deliveries
+#[debug_handler]
+async fn delete_webhook(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse> {
+    tracing::info!("WEBHOOK DELETE {} (token: {})", name, short_token(&token));
+
+    store.delete_webhook(&token, &name).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            message: "OK".to_string(),
+        }),
+    ))
+}
+
+/// List the deliveries of a webhook that failed every attempt
+#[debug_handler]
+async fn dead_letters(
+    Extension(token): Extension<String>,
+    State(store): State<KVStore>,
+    Path(name): Path<String>,
+) -> Result<Json<DeadLettersResponse>> {
+    tracing::info!(
+        "WEBHOOK DEAD LETTERS {} (token: {})",
+        name,
+        short_token(&token)
+    );
+
+    let dead_letters = store.webhook_dead_letters(&token, &name).await?;
+
+    Ok(Json(DeadLettersResponse { dead_letters }))
+}
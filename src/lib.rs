@@ -30,9 +30,11 @@
 //! }
 //! ```
 
+pub mod backup;
 pub mod error;
 pub mod grpc;
 pub mod http;
+pub mod replication;
 pub mod store;
 
 pub use error::{KVStoreError, Result};
@@ -96,6 +98,11 @@ pub fn create_grpc_reflection_service(
     grpc::create_reflection_service()
 }
 
+/// Shorten a token for logging so full credentials never reach the logs
+pub(crate) fn short_token(token: &str) -> &str {
+    &token[..token.char_indices().nth(8).map_or(token.len(), |(i, _)| i)]
+}
+
 /// Default Redis tokens set name
 pub const REDIS_TOKENS_TABLE: &str = "tokens";
 
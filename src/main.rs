@@ -6,20 +6,56 @@
 //!
 //! ```bash
 //! cargo run -- --mode=http|grpc|dual
+//! cargo run -- export --token=TOKEN [--prefix=PREFIX] [--output=FILE]
+//! cargo run -- import --token=TOKEN [--input=FILE] [--conflict=skip|overwrite|fail]
+//! cargo run -- snapshots --name=NAME [--dir=DIR]
+//! cargo run -- restore --name=NAME --token=TOKEN [--snapshot=ID] [--clean] [--dir=DIR]
 //! ```
 //!
+//! `export` writes a namespace to NDJSON, one key per line, and `import` reads
+//! it back; both talk to Redis directly and default to stdout and stdin.
+//! `snapshots` lists the scheduled backups of a namespace and `restore`
+//! replays one into a namespace.
+//!
 //! ## Environment Variables
 //!
 //! - `REDIS_URL`: Redis connection URL (default: "redis://127.0.0.1:6379")
 //! - `HTTP_PORT`: HTTP server port (default: 3000)
 //! - `GRPC_PORT`: gRPC server port (default: 50051)
+//! - `MAX_KEY_LENGTH`: Maximum key length in bytes (default: 1024)
+//! - `MAX_VALUE_SIZE`: Maximum value size in bytes (default: 1048576)
+//! - `KEY_CHARSET`: Characters allowed in keys, any|printable|safe (default: printable)
+//! - `RESERVED_KEY_PREFIXES`: Comma-separated prefixes keys must not start with (default: none)
+//! - `IDEMPOTENCY_WINDOW_SECONDS`: How long responses to requests with an idempotency key are kept (default: 86400)
+//! - `WEBHOOK_MAX_ATTEMPTS`: Attempts made at a webhook delivery before it is dead-lettered (default: 8)
+//! - `BACKUP_DIR`: Directory backup snapshots are written to; backups are off unless set
+//! - `BACKUP_NAMESPACES`: Comma-separated `name=token` pairs of the namespaces to back up
+//! - `BACKUP_SCHEDULE`: Cron expression, with seconds, of when backups run (default: "0 0 * * * *")
+//! - `BACKUP_RETENTION`: Number of snapshots kept per namespace (default: 24)
+//! - `REPLICATION_SOURCE`: gRPC address of an instance to replicate from; replication is off unless set
+//! - `REPLICATION_NAMESPACES`: Comma-separated `token` or `source_token=token` namespaces to replicate
+//! - `REPLICATION_PREFIXES`: Comma-separated key prefixes to replicate (default: every key)
 //! - `RUST_LOG`: Logging level (default: "kvstore=info,tower_http=info")
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use kvstore::backup::{
+    list_snapshots, read_chunks, restore_snapshot, BackupTarget, BackupWorker, FileObjectStore,
+    DEFAULT_BACKUP_SCHEDULE,
+};
+use kvstore::replication::{ReplicatedNamespace, Replicator};
+use kvstore::store::{
+    read_ndjson, ImportConflict, KeyCharset, KeyPolicy, WebhookWorker, DEFAULT_WEBHOOK_BACKOFF,
+};
 use kvstore::{create_grpc_server, create_http_server, KVStore};
 use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
 use tonic::transport::Server;
 use tonic_reflection::server::Builder as ReflectionBuilder;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -48,10 +84,78 @@ impl std::str::FromStr for Mode {
 #[derive(Parser, Debug)]
 #[command(name = "kvstore")]
 #[command(about = "A production-ready key-value storage server with HTTP and gRPC support")]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
     /// Select which server(s) to start
     #[arg(long, value_name = "MODE", required = true)]
-    mode: Mode,
+    mode: Option<Mode>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write every key of a namespace as NDJSON
+    Export {
+        /// Token of the namespace to export
+        #[arg(long)]
+        token: String,
+        /// Only export keys starting with this prefix
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// File to write to instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Read NDJSON keys, as written by export, into a namespace
+    Import {
+        /// Token of the namespace to import into
+        #[arg(long)]
+        token: String,
+        /// File to read from instead of stdin
+        #[arg(long, short)]
+        input: Option<PathBuf>,
+        /// What to do with keys that already exist: skip, overwrite or fail
+        #[arg(long, default_value = "fail")]
+        conflict: ImportConflict,
+    },
+    /// List the backup snapshots of a namespace, oldest first
+    Snapshots {
+        /// Name the namespace is backed up under
+        #[arg(long)]
+        name: String,
+        /// Backup directory, instead of BACKUP_DIR
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Replay a backup snapshot into a namespace
+    Restore {
+        /// Name the namespace is backed up under
+        #[arg(long)]
+        name: String,
+        /// Token of the namespace to restore into
+        #[arg(long)]
+        token: String,
+        /// Snapshot id to restore, instead of the newest
+        #[arg(long)]
+        snapshot: Option<String>,
+        /// What to do with keys that already exist: skip, overwrite or fail
+        #[arg(long, default_value = "overwrite")]
+        conflict: ImportConflict,
+        /// Delete every key of the namespace before restoring
+        #[arg(long)]
+        clean: bool,
+        /// Backup directory, instead of BACKUP_DIR
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+/// The backup directory given on the command line, or else by BACKUP_DIR
+fn backup_dir(dir: Option<PathBuf>) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    dir.or_else(|| std::env::var_os("BACKUP_DIR").map(PathBuf::from))
+        .ok_or_else(|| "Set --dir or BACKUP_DIR".into())
 }
 
 async fn run_http(
@@ -112,23 +216,218 @@ async fn run_dual(
     Ok(())
 }
 
+/// Run an export or import against `store`
+async fn run_command(
+    store: KVStore,
+    command: Command,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let token = match &command {
+        Command::Export { token, .. }
+        | Command::Import { token, .. }
+        | Command::Restore { token, .. } => Some(token),
+        Command::Snapshots { .. } => None,
+    };
+    if let Some(token) = token {
+        if !store.validate_token(token).await? {
+            return Err(format!("Unknown token: {}", token).into());
+        }
+    }
+
+    match command {
+        Command::Export {
+            token,
+            prefix,
+            output,
+        } => {
+            let output: Box<dyn AsyncWrite + Unpin + Send> = match output {
+                Some(path) => Box::new(tokio::fs::File::create(path).await?),
+                None => Box::new(tokio::io::stdout()),
+            };
+            let mut output = tokio::io::BufWriter::new(output);
+            let mut records = std::pin::pin!(store.export_namespace(&token, &prefix).await?);
+            let mut count = 0u64;
+            while let Some(record) = records.next().await {
+                output.write_all(record?.to_ndjson()?.as_bytes()).await?;
+                count += 1;
+            }
+            output.flush().await?;
+            tracing::info!("Exported {} keys", count);
+        }
+        Command::Import {
+            token,
+            input,
+            conflict,
+        } => {
+            let input: Box<dyn AsyncRead + Unpin + Send> = match input {
+                Some(path) => Box::new(tokio::fs::File::open(path).await?),
+                None => Box::new(tokio::io::stdin()),
+            };
+            let summary = store
+                .import_namespace(&token, read_ndjson(read_chunks(input)), conflict)
+                .await?;
+            tracing::info!(
+                "Imported {} keys, skipped {}",
+                summary.imported,
+                summary.skipped
+            );
+        }
+        Command::Snapshots { name, dir } => {
+            let objects = FileObjectStore::new(backup_dir(dir)?);
+            for id in list_snapshots(&objects, &name).await? {
+                println!("{}", id);
+            }
+        }
+        Command::Restore {
+            name,
+            token,
+            snapshot,
+            conflict,
+            clean,
+            dir,
+        } => {
+            let objects = FileObjectStore::new(backup_dir(dir)?);
+            let summary = restore_snapshot(
+                &store,
+                &objects,
+                &name,
+                snapshot.as_deref(),
+                &token,
+                conflict,
+                clean,
+            )
+            .await?;
+            tracing::info!(
+                "Restored {} keys, skipped {}",
+                summary.imported,
+                summary.skipped
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the key and value limits from the environment, keeping the defaults
+/// for unset variables
+fn key_policy_from_env() -> Result<KeyPolicy, Box<dyn std::error::Error + Send + Sync>> {
+    let mut policy = KeyPolicy::default();
+
+    if let Ok(length) = std::env::var("MAX_KEY_LENGTH") {
+        policy.max_key_length = length
+            .parse()
+            .map_err(|_| format!("Invalid MAX_KEY_LENGTH: {}", length))?;
+    }
+    if let Ok(size) = std::env::var("MAX_VALUE_SIZE") {
+        policy.max_value_size = size
+            .parse()
+            .map_err(|_| format!("Invalid MAX_VALUE_SIZE: {}", size))?;
+    }
+    if let Ok(charset) = std::env::var("KEY_CHARSET") {
+        policy.charset = charset.parse::<KeyCharset>()?;
+    }
+    if let Ok(prefixes) = std::env::var("RESERVED_KEY_PREFIXES") {
+        policy.reserved_prefixes = prefixes
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+    }
+
+    Ok(policy)
+}
+
+/// Build the backup worker configured by the environment, if backups are on
+fn backup_worker_from_env(
+    store: &KVStore,
+) -> Result<Option<BackupWorker>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(dir) = std::env::var_os("BACKUP_DIR") else {
+        return Ok(None);
+    };
+    let targets = std::env::var("BACKUP_NAMESPACES")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|t| !t.trim().is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<BackupTarget>, _>>()?;
+    if targets.is_empty() {
+        return Err("BACKUP_DIR is set but BACKUP_NAMESPACES lists no namespaces".into());
+    }
+    let schedule =
+        std::env::var("BACKUP_SCHEDULE").unwrap_or_else(|_| DEFAULT_BACKUP_SCHEDULE.to_string());
+
+    let objects = Arc::new(FileObjectStore::new(PathBuf::from(dir)));
+    let mut worker = BackupWorker::new(store.clone(), objects, targets, &schedule)?;
+    if let Ok(retention) = std::env::var("BACKUP_RETENTION") {
+        let retention = retention
+            .parse()
+            .map_err(|_| format!("Invalid BACKUP_RETENTION: {}", retention))?;
+        worker = worker.with_retention(retention);
+    }
+    tracing::info!("Backing up namespaces on schedule {}", schedule);
+
+    Ok(Some(worker))
+}
+
+/// Build a follower for every namespace the environment says to replicate
+fn replicators_from_env(
+    store: &KVStore,
+) -> Result<Vec<Replicator>, Box<dyn std::error::Error + Send + Sync>> {
+    let Ok(source) = std::env::var("REPLICATION_SOURCE") else {
+        return Ok(Vec::new());
+    };
+    let list = |var: &str| -> Vec<String> {
+        std::env::var(var)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    let namespaces = list("REPLICATION_NAMESPACES")
+        .iter()
+        .map(|namespace| namespace.parse())
+        .collect::<Result<Vec<ReplicatedNamespace>, _>>()?;
+    if namespaces.is_empty() {
+        return Err(
+            "REPLICATION_SOURCE is set but REPLICATION_NAMESPACES lists no namespaces".into(),
+        );
+    }
+    let prefixes = list("REPLICATION_PREFIXES");
+    tracing::info!(
+        "Replicating {} namespaces from {}",
+        namespaces.len(),
+        source
+    );
+
+    Ok(namespaces
+        .into_iter()
+        .map(|namespace| {
+            Replicator::new(store.clone(), source.clone(), namespace)
+                .with_prefixes(prefixes.clone())
+        })
+        .collect())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Initialize tracing
+    // Parse command line arguments
+    let args = Args::parse();
+
+    // Initialize tracing, keeping stdout free for exports
+    let writer = match args.command {
+        Some(_) => BoxMakeWriter::new(std::io::stderr),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "kvstore=info,tower_http=info".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(writer))
         .init();
 
-    // Parse command line arguments
-    let args = Args::parse();
-    let mode = args.mode;
-
-    tracing::info!("Starting KVStore server in {:?} mode", mode);
-
     // Get configuration from environment
     let redis_url =
         std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
@@ -141,9 +440,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .and_then(|p| p.parse().ok())
         .unwrap_or(kvstore::DEFAULT_GRPC_PORT);
 
+    let key_policy = key_policy_from_env()?;
+
     // Create KVStore instance
     tracing::info!("Connecting to Redis at {}", redis_url);
-    let store = KVStore::new(&redis_url).await?;
+    let mut store = KVStore::new(&redis_url).await?.with_key_policy(key_policy);
+    if let Ok(window) = std::env::var("IDEMPOTENCY_WINDOW_SECONDS") {
+        let seconds: u64 = window
+            .parse()
+            .map_err(|_| format!("Invalid IDEMPOTENCY_WINDOW_SECONDS: {}", window))?;
+        store = store.with_idempotency_window(Duration::from_secs(seconds));
+    }
+    if let Ok(allow) = std::env::var("WEBHOOK_ALLOW_PRIVATE") {
+        let allow = allow
+            .parse()
+            .map_err(|_| format!("Invalid WEBHOOK_ALLOW_PRIVATE: {}", allow))?;
+        store = store.with_private_webhooks(allow);
+    }
     tracing::info!("Successfully connected to Redis");
 
     // Verify health
@@ -152,6 +465,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         return Err("Redis connection unhealthy".into());
     }
 
+    let mode = match (args.command, args.mode) {
+        (Some(command), _) => return run_command(store, command).await,
+        (None, Some(mode)) => mode,
+        (None, None) => return Err("--mode is required".into()),
+    };
+    tracing::info!("Starting KVStore server in {:?} mode", mode);
+
+    // Deliver webhooks in the background whichever servers are started
+    let mut webhooks = WebhookWorker::new(store.clone())?;
+    if let Ok(attempts) = std::env::var("WEBHOOK_MAX_ATTEMPTS") {
+        let attempts = attempts
+            .parse()
+            .map_err(|_| format!("Invalid WEBHOOK_MAX_ATTEMPTS: {}", attempts))?;
+        webhooks = webhooks.with_retries(attempts, DEFAULT_WEBHOOK_BACKOFF);
+    }
+    tokio::spawn(webhooks.run());
+
+    if let Some(backups) = backup_worker_from_env(&store)? {
+        tokio::spawn(backups.run());
+    }
+
+    for replicator in replicators_from_env(&store)? {
+        tokio::spawn(replicator.run());
+    }
+
     // Start servers based on mode
     match mode {
         Mode::Http => {
@@ -193,4 +531,43 @@ mod tests {
     fn test_mode_from_str_invalid() {
         assert!("invalid".parse::<Mode>().is_err());
     }
+
+    #[test]
+    fn test_args_subcommands() {
+        assert!(Args::try_parse_from(["kvstore"]).is_err());
+
+        let args = Args::try_parse_from(["kvstore", "--mode", "dual"]).unwrap();
+        assert_eq!(args.mode, Some(Mode::Dual));
+        assert!(args.command.is_none());
+
+        let args =
+            Args::try_parse_from(["kvstore", "import", "--token", "t", "--conflict", "skip"])
+                .unwrap();
+        match args.command {
+            Some(Command::Import {
+                token, conflict, ..
+            }) => {
+                assert_eq!(token, "t");
+                assert_eq!(conflict, ImportConflict::Skip);
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+        assert!(Args::try_parse_from(["kvstore", "export"]).is_err());
+
+        let args = Args::try_parse_from(["kvstore", "restore", "--name", "orders", "--token", "t"])
+            .unwrap();
+        match args.command {
+            Some(Command::Restore {
+                snapshot,
+                conflict,
+                clean,
+                ..
+            }) => {
+                assert_eq!(snapshot, None);
+                assert_eq!(conflict, ImportConflict::Overwrite);
+                assert!(!clean);
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,342 @@
+//! Replication from another kvstore deployment
+//!
+//! A [`Replicator`] follows the change log of a namespace of another instance
+//! over gRPC and applies its changes to a local namespace with
+//! last-writer-wins (see [`KVStore::apply_replicated_change`]). It starts
+//! with a full copy of the namespace, then resumes from the last change it
+//! read, which is kept in the local namespace's replication status together
+//! with the replication lag. The source's heartbeats keep both up to date
+//! while no change is replicated. If the follower falls so far behind that
+//! the source has trimmed changes it hasn't read, it starts over with a full
+//! copy.
+//!
+//! Replication covers writes and deletes of string and JSON values, which
+//! expire when they do at the source. Hashes, lists, sets and sorted sets are
+//! not replicated: they are left out of the full copy and their changes are
+//! skipped. Expirations aren't replicated either, and deletions missed while
+//! starting over are not caught up.
+
+use crate::error::{KVStoreError, Result};
+use crate::grpc::kv_store::{
+    kv_store_client::KvStoreClient, ChangesRequest, ExportRequest, ValueType,
+};
+use crate::store::{now_millis, ChangeEvent, ChangeKind};
+use crate::KVStore;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tonic::transport::Channel;
+use tonic::{Code, Status};
+
+/// Delay before the first reconnection attempt after replication stops
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// A namespace replicated from another deployment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicatedNamespace {
+    /// Token of the namespace at the source
+    pub source_token: String,
+    /// Token of the local namespace changes are applied to
+    pub token: String,
+}
+
+/// Parses `token`, for a namespace with the same token on both sides, or
+/// `source_token=token`
+impl FromStr for ReplicatedNamespace {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (source_token, token) = s.split_once('=').unwrap_or((s, s));
+        let (source_token, token) = (source_token.trim(), token.trim());
+        if source_token.is_empty() || token.is_empty() {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Expected token or source_token=token, got {}",
+                s
+            )));
+        }
+        Ok(ReplicatedNamespace {
+            source_token: source_token.to_string(),
+            token: token.to_string(),
+        })
+    }
+}
+
+/// Turn an error returned by the source into a store error
+fn source_error(status: Status) -> KVStoreError {
+    match status.code() {
+        Code::OutOfRange => KVStoreError::Gone(status.message().to_string()),
+        Code::Unauthenticated => KVStoreError::Unauthorized(status.message().to_string()),
+        _ => KVStoreError::Internal(format!("Source error: {}", status)),
+    }
+}
+
+/// Follows a namespace of another instance and applies its changes locally
+pub struct Replicator {
+    store: KVStore,
+    source: String,
+    namespace: ReplicatedNamespace,
+    prefixes: Vec<String>,
+    retry_delay: Duration,
+}
+
+impl Replicator {
+    /// Create a follower of `namespace` at `source`, the address of the other
+    /// instance's gRPC server such as `http://primary:50051`
+    pub fn new(store: KVStore, source: impl Into<String>, namespace: ReplicatedNamespace) -> Self {
+        Replicator {
+            store,
+            source: source.into(),
+            namespace,
+            prefixes: Vec::new(),
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+
+    /// Only replicate keys starting with one of `prefixes`; every key is
+    /// replicated if there are none
+    pub fn with_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    /// Set the delay before the first reconnection attempt; later attempts
+    /// back off exponentially
+    pub fn with_retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Replicate until the task is dropped, reconnecting whenever the source
+    /// goes away
+    pub async fn run(self) {
+        let token = &self.namespace.token;
+        let mut delay = self.retry_delay;
+        loop {
+            let mut connected = false;
+            let error = match self.follow(&mut connected).await {
+                Ok(()) => KVStoreError::Internal("Source closed the change feed".to_string()),
+                Err(e) => e,
+            };
+            tracing::warn!("Replication from {} stopped: {}", self.source, error);
+
+            // Start over with a full copy if changes were trimmed before we
+            // could read them
+            let reset = matches!(error, KVStoreError::Gone(_));
+            if let Err(e) = self
+                .store
+                .record_replication_error(token, &error.to_string(), reset)
+                .await
+            {
+                tracing::error!("Failed to record replication status: {}", e);
+            }
+
+            if connected {
+                delay = self.retry_delay;
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Connect to the source and apply its changes until the feed ends
+    async fn follow(&self, connected: &mut bool) -> Result<()> {
+        let token = &self.namespace.token;
+        let mut client = KvStoreClient::connect(self.source.clone())
+            .await
+            .map_err(|e| {
+                KVStoreError::Internal(format!("Failed to connect to {}: {}", self.source, e))
+            })?;
+
+        let offset = self
+            .store
+            .replication_status(token)
+            .await?
+            .and_then(|status| status.offset);
+        // Without an offset, subscribe before copying so that no change made
+        // during the copy is missed
+        let request = ChangesRequest {
+            token: self.namespace.source_token.clone(),
+            from_offset: offset.clone().unwrap_or_else(|| "$".to_string()),
+            prefix: match self.prefixes.as_slice() {
+                [prefix] => prefix.clone(),
+                _ => String::new(),
+            },
+            consumer: None,
+            heartbeats: true,
+        };
+        let mut changes = client
+            .changes(request)
+            .await
+            .map_err(source_error)?
+            .into_inner();
+
+        self.store
+            .record_replication_connected(token, &self.source)
+            .await?;
+        *connected = true;
+        tracing::info!(
+            "Replicating from {} (offset: {:?})",
+            self.source,
+            offset.as_deref().unwrap_or("full copy")
+        );
+
+        if offset.is_none() {
+            // The feed starts with a heartbeat at the change it follows from,
+            // where replication resumes once the copy is complete
+            let start = match changes.next().await {
+                Some(Ok(event)) if event.heartbeat => event.id,
+                Some(Err(status)) => return Err(source_error(status)),
+                _ => None,
+            }
+            .ok_or_else(|| {
+                KVStoreError::Internal(format!(
+                    "{} did not report where its change feed starts",
+                    self.source
+                ))
+            })?;
+            self.copy(&mut client).await?;
+            self.store
+                .record_replication_offset(token, &start, None)
+                .await?;
+        }
+
+        while let Some(event) = changes.next().await {
+            let event = event.map_err(source_error)?;
+            if event.heartbeat {
+                if let Some(offset) = &event.id {
+                    self.store
+                        .record_replication_offset(token, offset, event.timestamp)
+                        .await?;
+                }
+                continue;
+            }
+
+            let change = ChangeEvent::from(event);
+            if self.matches(&change.key) && change.kind != ChangeKind::Expire {
+                self.apply(&change).await?;
+            } else if let Some(offset) = &change.id {
+                // Move past changes that aren't replicated
+                self.store
+                    .record_replication_offset(token, offset, None)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy every replicated string value from the source
+    async fn copy(&self, client: &mut KvStoreClient<Channel>) -> Result<()> {
+        let prefixes = if self.prefixes.is_empty() {
+            vec![String::new()]
+        } else {
+            self.prefixes.clone()
+        };
+
+        for prefix in prefixes {
+            let mut keys = client
+                .export(ExportRequest {
+                    token: self.namespace.source_token.clone(),
+                    prefix,
+                })
+                .await
+                .map_err(source_error)?
+                .into_inner();
+
+            while let Some(key) = keys.next().await {
+                let key = key.map_err(source_error)?;
+                if key.r#type() != ValueType::String {
+                    continue;
+                }
+                // Keys written without metadata, such as those from before
+                // it was kept or imported without any, lose to any local write
+                let number = |field: &str| {
+                    key.metadata
+                        .get(field)
+                        .and_then(|n| n.parse().ok())
+                        .or(Some(0))
+                };
+                let change = ChangeEvent {
+                    expires_at: key.ttl_ms.map(|ttl| now_millis() + ttl),
+                    id: None,
+                    kind: ChangeKind::Put,
+                    version: number("version"),
+                    timestamp: number("updated_at"),
+                    content_type: key.metadata.get("content_type").cloned(),
+                    value: Some(key.value),
+                    key: key.key,
+                };
+                self.apply(&change).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply one change and record it in the replication status
+    async fn apply(&self, change: &ChangeEvent) -> Result<()> {
+        let token = &self.namespace.token;
+        // Only puts of strings and JSON values carry a value
+        if change.kind == ChangeKind::Put && change.value.is_none() {
+            tracing::debug!("Not replicating {}: not a string value", change.key);
+            if let Some(offset) = &change.id {
+                self.store
+                    .record_replication_offset(token, offset, None)
+                    .await?;
+            }
+            return Ok(());
+        }
+
+        let applied = self.store.apply_replicated_change(token, change).await?;
+        tracing::debug!(
+            "Replicated {:?} of {} (applied: {})",
+            change.kind,
+            change.key,
+            applied
+        );
+
+        self.store
+            .record_replicated(token, change.id.as_deref(), applied)
+            .await
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replicated_namespace() {
+        let same: ReplicatedNamespace = "config".parse().unwrap();
+        assert_eq!(
+            (same.source_token.as_str(), same.token.as_str()),
+            ("config", "config")
+        );
+        let mapped: ReplicatedNamespace = "primary = replica".parse().unwrap();
+        assert_eq!(
+            (mapped.source_token.as_str(), mapped.token.as_str()),
+            ("primary", "replica")
+        );
+        assert!("=replica".parse::<ReplicatedNamespace>().is_err());
+        assert!("".parse::<ReplicatedNamespace>().is_err());
+    }
+
+    #[test]
+    fn test_source_errors() {
+        assert!(matches!(
+            source_error(Status::out_of_range("trimmed")),
+            KVStoreError::Gone(_)
+        ));
+        assert!(matches!(
+            source_error(Status::unavailable("down")),
+            KVStoreError::Internal(_)
+        ));
+    }
+}
@@ -5,9 +5,129 @@
 use crate::error::{KVStoreError, Result};
 use crate::REDIS_TOKENS_TABLE;
 use futures::StreamExt;
-use redis::{aio::ConnectionManager, AsyncCommands};
+use redis::{aio::ConnectionManager, AsyncCommands, RedisError};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio_stream::{wrappers::ReceiverStream, Stream};
 
+mod changes;
+mod export;
+mod hash;
+mod history;
+mod idempotency;
+mod index;
+mod json;
+mod list;
+mod lock;
+mod metadata;
+mod policy;
+mod prefix;
+mod rename;
+mod replication;
+mod schema;
+mod search;
+mod set;
+mod sorted_set;
+mod stats;
+mod transaction;
+mod trash;
+mod watch;
+mod webhook;
+
+pub use changes::{ChangeFeedEntry, ChangeOffset, CHANGE_LOG_LENGTH};
+pub use export::{
+    read_ndjson, ExportRecord, ExportedValue, ImportConflict, ImportSummary, MAX_IMPORT_LINE,
+};
+pub use history::{HistoryPolicy, Revision};
+pub use idempotency::{
+    check_idempotency_key, request_fingerprint, IdempotentResponse, DEFAULT_IDEMPOTENCY_WINDOW,
+    MAX_IDEMPOTENCY_KEY_LENGTH,
+};
+pub use index::{
+    IndexDefinition, IndexPage, IndexQuery, DEFAULT_INDEX_QUERY_LIMIT, MAX_INDEX_QUERY_LIMIT,
+};
+pub use json::{PatchFormat, JSON_CONTENT_TYPE};
+pub use list::{ListEnd, MAX_BLOCKING_POP_TIMEOUT};
+pub use lock::{LockLease, MAX_LOCK_TTL, MAX_LOCK_WAIT};
+pub use metadata::{Entry, Metadata, SetOptions};
+pub use policy::{
+    KeyCharset, KeyPolicy, DEFAULT_MAX_KEY_LENGTH, DEFAULT_MAX_VALUE_SIZE, HTTP_ROUTE_KEYS,
+};
+pub use prefix::{PrefixMatch, MAX_DRY_RUN_KEYS};
+pub use rename::PrefixRename;
+pub use replication::ReplicationStatus;
+pub use search::{
+    SearchMode, SearchPage, SearchQuery, DEFAULT_SEARCH_LIMIT, DEFAULT_SEARCH_TIMEOUT,
+    MAX_SEARCH_LIMIT, MAX_SEARCH_TIMEOUT,
+};
+pub use set::{SetOperation, MAX_SET_OPERANDS};
+pub use sorted_set::{ScoredMember, MAX_RANGE_LIMIT};
+pub use stats::{KeySize, NamespaceStats, STATS_LARGEST_KEYS};
+pub use transaction::{
+    Operation, OperationResult, Precondition, TransactionOutcome, MAX_TRANSACTION_OPERATIONS,
+};
+pub use trash::{TrashedKey, MAX_TRASH_RETENTION};
+pub use watch::{ChangeEvent, ChangeKind};
+pub use webhook::{
+    webhook_signature, DeadLetter, Webhook, WebhookInfo, WebhookStatus, WebhookWorker,
+    DEFAULT_WEBHOOK_BACKOFF, DEFAULT_WEBHOOK_MAX_ATTEMPTS, MAX_DEAD_LETTERS, WEBHOOK_EVENT_HEADER,
+    WEBHOOK_SIGNATURE_HEADER,
+};
+
+pub(crate) use webhook::now_millis;
+
+/// Content type reported for values written as plain strings
+pub const TEXT_CONTENT_TYPE: &str = "text/plain";
+
+/// Prefix of the keys kvstore keeps for its own bookkeeping
+///
+/// Internal keys live outside every token namespace, so they never show up in
+/// `list` results and cannot be addressed through the public API.
+pub(crate) const INTERNAL_PREFIX: &str = "_kvstore";
+
+/// Delete a key and its metadata, recording the deletion of string values, or
+/// move them to the trash if the namespace keeps one
+///
+/// KEYS[1] - value key, KEYS[2] - metadata key, KEYS[3] - history key,
+/// KEYS[4] - history policy key, KEYS[5] - index definitions key,
+/// KEYS[6] - trashed value key, KEYS[7] - trashed metadata key
+const DELETE_SCRIPT: &str = r#"
+return delete_value(KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6], KEYS[7])
+"#;
+
+/// Build the Redis key for `key` inside the namespace owned by `token`
+pub(crate) fn namespaced_key(token: &str, key: &str) -> String {
+    format!("{}:{}", token, key)
+}
+
+/// Build the Redis key of internal `kind` data kept for `key` of `token`
+pub(crate) fn internal_key(token: &str, kind: &str, key: &str) -> String {
+    format!("{}:{}:{}:{}", INTERNAL_PREFIX, token, kind, key)
+}
+
+/// Build the Redis key of the metadata hash kept alongside `key`
+pub(crate) fn meta_key(token: &str, key: &str) -> String {
+    internal_key(token, "meta", key)
+}
+
+/// Convert a Redis error raised while operating on `key`
+///
+/// Type mismatches (e.g. a hash command against a string key) and non-numeric
+/// increments are caller errors, so they are reported as such instead of as
+/// database failures.
+pub(crate) fn redis_error(key: &str, e: RedisError) -> KVStoreError {
+    if e.code() == Some("WRONGTYPE") {
+        return KVStoreError::WrongType(key.to_string());
+    }
+    if e.kind() == redis::ErrorKind::ResponseError
+        && e.detail()
+            .is_some_and(|d| d.contains("not an integer") || d.contains("not a valid float"))
+    {
+        return KVStoreError::InvalidRequest(format!("Value at {} is not a number", key));
+    }
+    e.into()
+}
+
 /// Main KVStore struct that manages Redis connections and operations
 ///
 /// This struct is cheaply cloneable (uses Arc internally) and can be safely
@@ -37,6 +157,15 @@ use tokio_stream::{wrappers::ReceiverStream, Stream};
 #[derive(Clone)]
 pub struct KVStore {
     conn: ConnectionManager,
+    /// Client used to open dedicated connections for blocking commands
+    client: Option<redis::Client>,
+    /// Limits on the keys and values accepted by writes
+    policy: Arc<KeyPolicy>,
+    /// How long responses to requests with an idempotency key are kept
+    idempotency_window: Duration,
+    /// Whether webhooks may target loopback, private and other non-public
+    /// addresses
+    private_webhooks: bool,
 }
 
 impl KVStore {
@@ -69,21 +198,84 @@ impl KVStore {
             e
         })?;
 
-        let conn = ConnectionManager::new(client).await.map_err(|e| {
+        let conn = ConnectionManager::new(client.clone()).await.map_err(|e| {
             tracing::error!("Failed to create connection manager: {}", e);
             e
         })?;
 
         tracing::info!("Successfully connected to Redis");
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            client: Some(client),
+            policy: Arc::default(),
+            idempotency_window: DEFAULT_IDEMPOTENCY_WINDOW,
+            private_webhooks: false,
+        })
     }
 
     /// Create a KVStore from an existing ConnectionManager
     ///
     /// Useful for testing or when you want to manage the connection yourself.
+    /// Blocking operations such as [`KVStore::list_blocking_pop`] need their own
+    /// connections and are unavailable on a store created this way.
     pub fn from_connection_manager(conn: ConnectionManager) -> Self {
-        Self { conn }
+        Self {
+            conn,
+            client: None,
+            policy: Arc::default(),
+            idempotency_window: DEFAULT_IDEMPOTENCY_WINDOW,
+            private_webhooks: false,
+        }
+    }
+
+    /// Replace the limits on the keys and values this store accepts
+    ///
+    /// Stores start out with [`KeyPolicy::default`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use kvstore::store::{KeyPolicy, KVStore};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let store = KVStore::new("redis://127.0.0.1:6379")
+    ///         .await?
+    ///         .with_key_policy(KeyPolicy {
+    ///             max_value_size: 64 * 1024,
+    ///             ..Default::default()
+    ///         });
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_key_policy(mut self, policy: KeyPolicy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Replace how long responses to requests with an idempotency key are
+    /// kept for replay
+    ///
+    /// Stores start out with [`DEFAULT_IDEMPOTENCY_WINDOW`].
+    pub fn with_idempotency_window(mut self, window: Duration) -> Self {
+        self.idempotency_window = window;
+        self
+    }
+
+    /// Allow webhooks to target loopback, private, link-local and other
+    /// non-public addresses
+    ///
+    /// Stores refuse them by default, so that webhooks can't be used to reach
+    /// services on the server's network.
+    pub fn with_private_webhooks(mut self, allow: bool) -> Self {
+        self.private_webhooks = allow;
+        self
+    }
+
+    /// Get the limits on the keys and values this store accepts
+    pub fn key_policy(&self) -> &KeyPolicy {
+        &self.policy
     }
 
     /// Get a clone of the underlying connection manager
@@ -123,7 +315,7 @@ impl KVStore {
     ///
     /// The value if found, or an error if the key doesn't exist
     pub async fn get(&self, token: &str, key: &str) -> Result<String> {
-        let namespaced_key = format!("{}:{}", token, key);
+        let namespaced_key = namespaced_key(token, key);
         tracing::debug!("GET {}", namespaced_key);
 
         let mut conn = self.conn.clone();
@@ -146,7 +338,8 @@ impl KVStore {
     ///
     /// # Returns
     ///
-    /// `Ok(())` on success
+    /// `Ok(())` on success, or [`KVStoreError::InvalidRequest`] if the value
+    /// violates the JSON Schema registered for the key's prefix
     pub async fn set(
         &self,
         token: &str,
@@ -154,32 +347,24 @@ impl KVStore {
         value: &str,
         ttl_seconds: Option<i64>,
     ) -> Result<()> {
-        let namespaced_key = format!("{}:{}", token, key);
-        tracing::debug!("SET {} (TTL: {:?})", namespaced_key, ttl_seconds);
-
-        let mut conn = self.conn.clone();
-
-        if let Some(ttl) = ttl_seconds {
-            conn.set_ex::<_, _, ()>(&namespaced_key, value, ttl as u64)
-                .await
-                .map_err(|e| {
-                    tracing::error!("Failed to set key {} with TTL: {}", namespaced_key, e);
-                    e
-                })?;
-        } else {
-            conn.set::<_, _, ()>(&namespaced_key, value)
-                .await
-                .map_err(|e| {
-                    tracing::error!("Failed to set key {}: {}", namespaced_key, e);
-                    e
-                })?;
-        }
-
-        Ok(())
+        self.set_with_options(
+            token,
+            key,
+            value,
+            &SetOptions {
+                ttl_seconds,
+                ..Default::default()
+            },
+        )
+        .await
     }
 
     /// Delete a value from the store
     ///
+    /// If the namespace has a trash retention set (see
+    /// [`KVStore::set_trash_retention`]) the value is moved to the trash and
+    /// can be restored with [`KVStore::undelete`].
+    ///
     /// # Arguments
     ///
     /// * `token` - Authentication token (used as namespace prefix)
@@ -189,14 +374,27 @@ impl KVStore {
     ///
     /// `Ok(())` on success
     pub async fn delete(&self, token: &str, key: &str) -> Result<()> {
-        let namespaced_key = format!("{}:{}", token, key);
+        let namespaced_key = namespaced_key(token, key);
         tracing::debug!("DELETE {}", namespaced_key);
 
+        let script = metadata::value_script(DELETE_SCRIPT);
         let mut conn = self.conn.clone();
-        conn.del::<_, ()>(&namespaced_key).await.map_err(|e| {
-            tracing::error!("Failed to delete key {}: {}", namespaced_key, e);
-            e
-        })?;
+        let mut invocation = script.key(&namespaced_key);
+        invocation
+            .key(meta_key(token, key))
+            .key(history::history_key(token, key))
+            .key(history::history_policy_key(token))
+            .key(index::indexes_key(token))
+            .key(trash::trash_key(token, key))
+            .key(trash::trash_meta_key(token, key));
+        metadata::add_namespace(&mut invocation, token);
+        invocation
+            .invoke_async::<()>(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to delete key {}: {}", namespaced_key, e);
+                e
+            })?;
 
         Ok(())
     }
@@ -212,11 +410,7 @@ impl KVStore {
     ///
     /// A stream of keys (without the token namespace)
     pub async fn list(&self, token: &str, prefix: &str) -> Result<impl Stream<Item = String>> {
-        let pattern = if prefix.is_empty() {
-            format!("{}:*", token)
-        } else {
-            format!("{}:{}*", token, prefix)
-        };
+        let pattern = prefix::prefix_pattern(token, prefix);
         tracing::debug!("LIST {}", pattern);
 
         let conn = self.conn.clone();
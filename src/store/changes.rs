@@ -0,0 +1,377 @@
+//! Durable change log of a namespace's keys
+//!
+//! Every write and delete of a value is appended to a Redis Stream of its
+//! namespace by the same Lua script that makes the change, so the log never
+//! misses or reorders a change. Consumers read the log from an offset (the ID
+//! of the last change they processed) and then follow it as it grows; by
+//! committing offsets after processing, they see every change exactly once
+//! across restarts. Expirations are not logged.
+//!
+//! Followers that filter by prefix can ask for heartbeats, which report how
+//! far the log has been read so that they can save their position even while
+//! no change is of interest to them.
+//!
+//! The log keeps the last [`CHANGE_LOG_LENGTH`] changes. Reading from an
+//! offset that has since been trimmed fails with [`KVStoreError::Gone`]
+//! rather than silently skipping changes.
+
+use super::watch::{change_log_key, parse_id, ChangeEvent, WatchTarget, WATCH_BUFFER};
+use super::webhook::now_millis;
+use super::{redis_error, KVStore, INTERNAL_PREFIX};
+use crate::error::{KVStoreError, Result};
+use redis::streams::StreamRangeReply;
+use redis::AsyncCommands;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+/// Number of changes kept in the change log of each namespace
+///
+/// Redis trims the log lazily, so slightly more may be kept.
+pub const CHANGE_LOG_LENGTH: usize = 10_000;
+
+/// Number of changes read from the log at a time
+pub(super) const CHANGES_BATCH_SIZE: usize = 100;
+
+/// How long a follower waits for new changes before checking again
+const CHANGES_BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Read a batch of changes after an ID, unless some have been trimmed
+///
+/// Trimming is detected with the stream's `max-deleted-entry-id`, so it needs
+/// Redis 7; older servers never report trimmed offsets.
+///
+/// KEYS[1] - change log key
+/// ARGV[1] - ID to read after, ARGV[2] - batch size,
+/// ARGV[3] - '1' to check that nothing after ARGV[1] was trimmed
+/// Returns the entries, or false if changes after ARGV[1] were trimmed
+const READ_CHANGES_SCRIPT: &str = r#"
+local function parse_id(id)
+    local millis, seq = string.match(id, '^(%d+)-(%d+)$')
+    return tonumber(millis), tonumber(seq)
+end
+
+if ARGV[3] == '1' and redis.call('EXISTS', KEYS[1]) == 1 then
+    local info = redis.call('XINFO', 'STREAM', KEYS[1])
+    for i = 1, #info, 2 do
+        if info[i] == 'max-deleted-entry-id' then
+            local after_millis, after_seq = parse_id(ARGV[1])
+            local deleted_millis, deleted_seq = parse_id(info[i + 1])
+            if after_millis < deleted_millis
+                or (after_millis == deleted_millis and after_seq < deleted_seq) then
+                return false
+            end
+        end
+    end
+end
+return redis.call('XRANGE', KEYS[1], '(' .. ARGV[1], '+', 'COUNT', ARGV[2])
+"#;
+
+/// Where to start reading a change log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeOffset {
+    /// The oldest change still kept (`0`)
+    Oldest,
+    /// Only changes made from now on (`$`)
+    Latest,
+    /// The changes after the one with this ID
+    After(String),
+}
+
+impl FromStr for ChangeOffset {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "0" => Ok(ChangeOffset::Oldest),
+            "$" => Ok(ChangeOffset::Latest),
+            id if parse_id(id).is_some() => Ok(ChangeOffset::After(id.to_string())),
+            _ => Err(KVStoreError::InvalidRequest(format!(
+                "Invalid change offset {:?}: expected 0, $ or a change ID",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ChangeOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeOffset::Oldest => f.write_str("0"),
+            ChangeOffset::Latest => f.write_str("$"),
+            ChangeOffset::After(id) => f.write_str(id),
+        }
+    }
+}
+
+/// An entry of a change feed followed with
+/// [`KVStore::changes_with_heartbeats`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeFeedEntry {
+    Change(ChangeEvent),
+    /// The log has been read up to `offset`, including changes filtered out
+    /// by prefix; sent when the feed starts and whenever it catches up with
+    /// the log
+    Heartbeat {
+        offset: String,
+        /// When the heartbeat was sent, in milliseconds since the Unix epoch
+        timestamp: u64,
+    },
+}
+
+/// Build the Redis key of the hash of committed consumer offsets of `token`
+fn offsets_key(token: &str) -> String {
+    format!("{}:{}:change_offsets", INTERNAL_PREFIX, token)
+}
+
+fn trimmed(offset: &str) -> KVStoreError {
+    KVStoreError::Gone(format!(
+        "Changes after {} are no longer in the change log",
+        offset
+    ))
+}
+
+impl KVStore {
+    /// Read the changes of a namespace from `from` on, then follow the change
+    /// log as new changes are made
+    ///
+    /// Only changes to keys starting with `prefix` are returned. Each change
+    /// carries its ID, which can be committed with
+    /// [`KVStore::commit_change_offset`] or passed back as
+    /// [`ChangeOffset::After`] to resume. The stream ends with
+    /// [`KVStoreError::Gone`] if the consumer falls so far behind that
+    /// changes it hasn't read are trimmed from the log.
+    ///
+    /// # Returns
+    ///
+    /// [`KVStoreError::Gone`] if changes after `from` have already been
+    /// trimmed
+    pub async fn changes(
+        &self,
+        token: &str,
+        from: &ChangeOffset,
+        prefix: &str,
+    ) -> Result<impl Stream<Item = Result<ChangeEvent>>> {
+        let entries = self.changes_with_heartbeats(token, from, prefix).await?;
+        Ok(entries.filter_map(|entry| match entry {
+            Ok(ChangeFeedEntry::Change(change)) => Some(Ok(change)),
+            Ok(ChangeFeedEntry::Heartbeat { .. }) => None,
+            Err(e) => Some(Err(e)),
+        }))
+    }
+
+    /// Follow the changes of a namespace like [`KVStore::changes`],
+    /// interleaved with heartbeats
+    ///
+    /// The feed starts with a heartbeat at `from`, resolved to an ID for
+    /// [`ChangeOffset::Latest`], and sends another whenever it has caught up
+    /// with the log, at least every 5 seconds while nothing changes.
+    pub async fn changes_with_heartbeats(
+        &self,
+        token: &str,
+        from: &ChangeOffset,
+        prefix: &str,
+    ) -> Result<impl Stream<Item = Result<ChangeFeedEntry>>> {
+        let client = self.client.as_ref().ok_or_else(|| {
+            KVStoreError::Internal(
+                "Following changes requires a KVStore created with KVStore::new".to_string(),
+            )
+        })?;
+
+        let log_key = change_log_key(token);
+        tracing::debug!("CHANGES {} from {} (prefix: {})", log_key, from, prefix);
+
+        let mut after = match from {
+            ChangeOffset::Oldest => "0-0".to_string(),
+            ChangeOffset::Latest => self.last_change_id(&log_key).await?,
+            ChangeOffset::After(id) => id.clone(),
+        };
+        // Changes before the oldest one kept are expected to be gone
+        let mut check = !matches!(from, ChangeOffset::Oldest);
+        let mut batch = self
+            .read_changes(&log_key, &after, check)
+            .await?
+            .ok_or_else(|| trimmed(&after))?;
+
+        let target = WatchTarget::new(token, prefix, true);
+        let store = self.clone();
+        let mut conn = client.get_multiplexed_async_connection().await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(WATCH_BUFFER);
+
+        tokio::spawn(async move {
+            let heartbeat = |offset: &str| {
+                Ok(ChangeFeedEntry::Heartbeat {
+                    offset: offset.to_string(),
+                    timestamp: now_millis(),
+                })
+            };
+            if tx.send(heartbeat(&after)).await.is_err() {
+                return;
+            }
+            loop {
+                let full = batch.len() == CHANGES_BATCH_SIZE;
+                for (id, text) in batch {
+                    after.clone_from(&id);
+                    check = true;
+                    if let Some(event) = target.logged(id, &text) {
+                        if tx.send(Ok(ChangeFeedEntry::Change(event))).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+
+                if !full {
+                    if tx.send(heartbeat(&after)).await.is_err() {
+                        return;
+                    }
+                    // Wait on a dedicated connection for the log to grow
+                    let mut read = redis::cmd("XREAD");
+                    read.arg("BLOCK")
+                        .arg(CHANGES_BLOCK_TIMEOUT.as_millis() as u64)
+                        .arg("COUNT")
+                        .arg(1)
+                        .arg("STREAMS")
+                        .arg(&log_key)
+                        .arg(&after);
+                    let wait: redis::RedisResult<redis::Value> = tokio::select! {
+                        result = read.query_async(&mut conn) => result,
+                        _ = tx.closed() => return,
+                    };
+                    if let Err(e) = wait {
+                        let _ = tx.send(Err(redis_error(&log_key, e))).await;
+                        return;
+                    }
+                }
+
+                batch = match store.read_changes(&log_key, &after, check).await {
+                    Ok(Some(batch)) => batch,
+                    Ok(None) => {
+                        let _ = tx.send(Err(trimmed(&after))).await;
+                        return;
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                };
+            }
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Read up to [`CHANGES_BATCH_SIZE`] logged changes after `after`, as
+    /// entry IDs and change JSON
+    ///
+    /// With `check_trimmed` set, returns `None` instead if changes after
+    /// `after` have been trimmed from the log.
+    pub(super) async fn read_changes(
+        &self,
+        log_key: &str,
+        after: &str,
+        check_trimmed: bool,
+    ) -> Result<Option<Vec<(String, String)>>> {
+        let mut conn = self.conn.clone();
+        let reply: Option<StreamRangeReply> = redis::Script::new(READ_CHANGES_SCRIPT)
+            .key(log_key)
+            .arg(after)
+            .arg(CHANGES_BATCH_SIZE)
+            .arg(if check_trimmed { "1" } else { "0" })
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to read change log {}: {}", log_key, e);
+                redis_error(log_key, e)
+            })?;
+
+        Ok(reply.map(|reply| {
+            reply
+                .ids
+                .into_iter()
+                .filter_map(|entry| {
+                    let text: String = entry.get("change")?;
+                    Some((entry.id, text))
+                })
+                .collect()
+        }))
+    }
+
+    /// Get the ID of the newest logged change, or `0-0` if there is none
+    async fn last_change_id(&self, log_key: &str) -> Result<String> {
+        let mut conn = self.conn.clone();
+        let reply: StreamRangeReply = conn
+            .xrevrange_count(log_key, "+", "-", 1)
+            .await
+            .map_err(|e| redis_error(log_key, e))?;
+
+        Ok(reply
+            .ids
+            .into_iter()
+            .next()
+            .map_or_else(|| "0-0".to_string(), |entry| entry.id))
+    }
+
+    /// Record the offset up to which `consumer` has processed the changes of
+    /// a namespace
+    ///
+    /// Consumers resume from their committed offset with
+    /// [`KVStore::change_offset`]. Names are chosen by the consumers.
+    pub async fn commit_change_offset(
+        &self,
+        token: &str,
+        consumer: &str,
+        offset: &ChangeOffset,
+    ) -> Result<()> {
+        if consumer.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "Consumer name must not be empty".to_string(),
+            ));
+        }
+        let key = offsets_key(token);
+        tracing::debug!("COMMIT OFFSET {} {} = {}", key, consumer, offset);
+
+        let mut conn = self.conn.clone();
+        conn.hset::<_, _, _, ()>(&key, consumer, offset.to_string())
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to commit offset of {}: {}", consumer, e);
+                e
+            })?;
+
+        Ok(())
+    }
+
+    /// Get the offset last committed by `consumer`, if any
+    pub async fn change_offset(&self, token: &str, consumer: &str) -> Result<Option<ChangeOffset>> {
+        let key = offsets_key(token);
+        let mut conn = self.conn.clone();
+        let offset: Option<String> = conn.hget(&key, consumer).await?;
+
+        offset.map(|offset| offset.parse()).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_offset() {
+        assert_eq!("0".parse::<ChangeOffset>().unwrap(), ChangeOffset::Oldest);
+        assert_eq!("$".parse::<ChangeOffset>().unwrap(), ChangeOffset::Latest);
+        assert_eq!(
+            "1700000000000-1".parse::<ChangeOffset>().unwrap(),
+            ChangeOffset::After("1700000000000-1".to_string())
+        );
+        assert!("".parse::<ChangeOffset>().is_err());
+        assert!("later".parse::<ChangeOffset>().is_err());
+    }
+
+    #[test]
+    fn test_offset_round_trip() {
+        for offset in ["0", "$", "5-0"] {
+            assert_eq!(offset.parse::<ChangeOffset>().unwrap().to_string(), offset);
+        }
+    }
+}
@@ -0,0 +1,614 @@
+//! Namespace export and import
+//!
+//! A namespace is exported as a stream of [`ExportRecord`]s, one per key,
+//! carrying the value, its remaining TTL and its metadata. Records serialize
+//! to one JSON object per line (NDJSON), which is the portable format used by
+//! the HTTP endpoints and the `kvstore export` and `kvstore import` commands.
+//! Only keys are exported: history, indexes, webhooks, locks and the trash
+//! are not part of the format.
+
+use super::metadata::{add_namespace, value_script};
+use super::prefix::{prefix_pattern, SCAN_BATCH_SIZE};
+use super::sorted_set::score_bound;
+use super::{
+    history, index, meta_key, namespaced_key, ScoredMember, JSON_CONTENT_TYPE, TEXT_CONTENT_TYPE,
+};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+
+/// Longest NDJSON line accepted on import
+pub const MAX_IMPORT_LINE: usize = 64 * 1024 * 1024;
+
+/// Read a batch of keys with their TTL and metadata
+///
+/// KEYS - (value, metadata) key pairs
+/// Returns a {type, pttl, metadata fields, value} entry per pair; the type is
+/// 'none' for keys that expired since they were scanned
+const EXPORT_SCRIPT: &str = r#"
+local out = {}
+for i = 1, #KEYS, 2 do
+    local kind = redis.call('TYPE', KEYS[i]).ok
+    local value
+    if kind == 'string' then
+        value = {redis.call('GET', KEYS[i])}
+    elseif kind == 'hash' then
+        value = redis.call('HGETALL', KEYS[i])
+    elseif kind == 'list' then
+        value = redis.call('LRANGE', KEYS[i], 0, -1)
+    elseif kind == 'set' then
+        value = redis.call('SMEMBERS', KEYS[i])
+    elseif kind == 'zset' then
+        value = redis.call('ZRANGE', KEYS[i], 0, -1, 'WITHSCORES')
+    else
+        kind = 'none'
+        value = {}
+    end
+    out[#out + 1] = {kind, redis.call('PTTL', KEYS[i]), redis.call('HGETALL', KEYS[i + 1]), value}
+end
+return out
+"#;
+
+/// Write one imported key with its metadata and TTL
+///
+/// KEYS[1] - value key, KEYS[2] - metadata key, KEYS[3] - history key,
+/// KEYS[4] - history policy key, KEYS[5] - index definitions key
+/// ARGV[1] - conflict policy, ARGV[2] - value type, ARGV[3] - TTL in
+/// milliseconds (0 for none), ARGV[4] - number of metadata arguments, then
+/// the metadata field/value pairs, then the value's items
+/// Returns 'imported', or 'skipped' or 'exists' without changing anything
+const IMPORT_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    if ARGV[1] == 'skip' then
+        return 'skipped'
+    elseif ARGV[1] == 'fail' then
+        return 'exists'
+    end
+end
+redis.call('UNLINK', KEYS[1], KEYS[2])
+
+local kind = ARGV[2]
+local first = 5 + tonumber(ARGV[4])
+if kind == 'string' then
+    redis.call('SET', KEYS[1], ARGV[first])
+else
+    local command = ({hash = 'HSET', list = 'RPUSH', set = 'SADD', sorted_set = 'ZADD'})[kind]
+    for i = first, ARG_COUNT, 1000 do
+        redis.call(command, KEYS[1], unpack(ARGV, i, math.min(i + 999, ARG_COUNT)))
+    end
+end
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return 'skipped'
+end
+
+for i = 5, first - 1, 2 do
+    redis.call('HSET', KEYS[2], ARGV[i], ARGV[i + 1])
+end
+local ttl = tonumber(ARGV[3])
+if ttl > 0 then
+    redis.call('PEXPIRE', KEYS[1], ttl)
+    redis.call('PEXPIRE', KEYS[2], ttl)
+end
+
+local version = tonumber(redis.call('HGET', KEYS[2], 'version')) or 0
+if kind == 'string' then
+    local value = ARGV[first]
+    local content_type = redis.call('HGET', KEYS[2], 'content_type') or 'text/plain'
+    record_revision(KEYS[3], KEYS[4], 'set', value, content_type)
+    update_indexes(KEYS[5], KEYS[1], value)
+    publish_change('put', KEYS[1], KEYS[2], value, version, content_type)
+else
+    update_indexes(KEYS[5], KEYS[1], false)
+    publish_change('put', KEYS[1], KEYS[2], nil, version)
+end
+return 'imported'
+"#;
+
+/// The value of an exported key, tagged with its type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum ExportedValue {
+    String(String),
+    Hash(BTreeMap<String, String>),
+    List(Vec<String>),
+    Set(Vec<String>),
+    SortedSet(Vec<ScoredMember>),
+}
+
+impl ExportedValue {
+    /// The value's type, as passed to the import script
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Hash(_) => "hash",
+            Self::List(_) => "list",
+            Self::Set(_) => "set",
+            Self::SortedSet(_) => "sorted_set",
+        }
+    }
+
+    /// The value's items as Redis arguments: field/value pairs for hashes and
+    /// score/member pairs for sorted sets
+    fn items(&self) -> Vec<String> {
+        match self {
+            Self::String(value) => vec![value.clone()],
+            Self::Hash(fields) => fields
+                .iter()
+                .flat_map(|(field, value)| [field.clone(), value.clone()])
+                .collect(),
+            Self::List(items) | Self::Set(items) => items.clone(),
+            Self::SortedSet(members) => members
+                .iter()
+                .flat_map(|m| [score_bound(m.score), m.member.clone()])
+                .collect(),
+        }
+    }
+}
+
+/// One exported key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportRecord {
+    pub key: String,
+    #[serde(flatten)]
+    pub value: ExportedValue,
+    /// Remaining time to live in milliseconds, if the key expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<u64>,
+    /// The key's raw metadata fields
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ExportRecord {
+    /// Serialize the record as one NDJSON line, including the newline
+    pub fn to_ndjson(&self) -> Result<String> {
+        let mut line = serde_json::to_string(self)
+            .map_err(|e| KVStoreError::Internal(format!("Failed to serialize record: {}", e)))?;
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+/// What to do when an imported key already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportConflict {
+    /// Keep the existing key and carry on
+    Skip,
+    /// Replace the existing key
+    Overwrite,
+    /// Stop the import
+    #[default]
+    Fail,
+}
+
+impl ImportConflict {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Skip => "skip",
+            Self::Overwrite => "overwrite",
+            Self::Fail => "fail",
+        }
+    }
+}
+
+impl FromStr for ImportConflict {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "fail" => Ok(Self::Fail),
+            _ => Err(KVStoreError::InvalidRequest(format!(
+                "Unknown conflict policy '{}', expected skip, overwrite or fail",
+                s
+            ))),
+        }
+    }
+}
+
+/// The outcome of an import
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportSummary {
+    /// Keys written
+    pub imported: u64,
+    /// Keys left alone because they already existed
+    pub skipped: u64,
+}
+
+/// Decode NDJSON records from a stream of byte chunks
+///
+/// Blank lines are ignored. A read error or an invalid line ends the stream
+/// with an error naming the line.
+pub fn read_ndjson<S, B, E>(chunks: S) -> impl Stream<Item = Result<ExportRecord>>
+where
+    S: Stream<Item = std::result::Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: std::fmt::Display,
+{
+    let state = (Box::pin(chunks), Vec::new(), 0usize, false);
+    futures::stream::unfold(
+        state,
+        |(mut chunks, mut buffer, mut line_no, mut done)| async move {
+            loop {
+                let line = match buffer.iter().position(|&b| b == b'\n') {
+                    Some(end) => Some(buffer.drain(..=end).collect::<Vec<u8>>()),
+                    None if done => Some(std::mem::take(&mut buffer)),
+                    None => None,
+                };
+                if let Some(line) = line {
+                    if line.is_empty() {
+                        return None;
+                    }
+                    line_no += 1;
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    let record = serde_json::from_slice(&line).map_err(|e| {
+                        KVStoreError::InvalidRequest(format!(
+                            "Invalid record on line {}: {}",
+                            line_no, e
+                        ))
+                    });
+                    if record.is_err() {
+                        buffer.clear();
+                        done = true;
+                    }
+                    return Some((record, (chunks, buffer, line_no, done)));
+                }
+
+                if buffer.len() > MAX_IMPORT_LINE {
+                    let error = KVStoreError::InvalidRequest(format!(
+                        "Line {} is longer than {} bytes",
+                        line_no + 1,
+                        MAX_IMPORT_LINE
+                    ));
+                    return Some((Err(error), (chunks, Vec::new(), line_no, true)));
+                }
+                match chunks.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(chunk.as_ref()),
+                    Some(Err(e)) => {
+                        let error =
+                            KVStoreError::InvalidRequest(format!("Failed to read import: {}", e));
+                        return Some((Err(error), (chunks, Vec::new(), line_no, true)));
+                    }
+                    None => done = true,
+                }
+            }
+        },
+    )
+}
+
+/// Build an export record from one entry of `EXPORT_SCRIPT`'s reply
+fn export_record(
+    key: String,
+    kind: &str,
+    pttl: i64,
+    metadata: Vec<String>,
+    items: Vec<String>,
+) -> Result<Option<ExportRecord>> {
+    let value = match kind {
+        "string" => ExportedValue::String(items.into_iter().next().unwrap_or_default()),
+        "hash" => ExportedValue::Hash(pairs(items).collect()),
+        "list" => ExportedValue::List(items),
+        "set" => ExportedValue::Set(items),
+        "zset" => ExportedValue::SortedSet(
+            pairs(items)
+                .map(|(member, score)| {
+                    let score = score.parse().map_err(|_| {
+                        KVStoreError::Internal(format!("Invalid score {} of {}", score, key))
+                    })?;
+                    Ok(ScoredMember { member, score })
+                })
+                .collect::<Result<_>>()?,
+        ),
+        // Expired since it was scanned, or a type the store doesn't manage
+        _ => return Ok(None),
+    };
+
+    Ok(Some(ExportRecord {
+        key,
+        value,
+        ttl_ms: u64::try_from(pttl).ok(),
+        metadata: pairs(metadata).collect(),
+    }))
+}
+
+/// Pair up a flat list of Redis field/value replies
+fn pairs(items: Vec<String>) -> impl Iterator<Item = (String, String)> {
+    let mut items = items.into_iter();
+    std::iter::from_fn(move || Some((items.next()?, items.next()?)))
+}
+
+type ExportEntry = (String, i64, Vec<String>, Vec<String>);
+
+impl KVStore {
+    /// Export every key of a namespace that starts with `prefix`
+    ///
+    /// Keys are scanned in batches and each batch is read atomically, so
+    /// every record is a consistent snapshot of its key, but the export as a
+    /// whole isn't a snapshot of the namespace.
+    pub async fn export_namespace(
+        &self,
+        token: &str,
+        prefix: &str,
+    ) -> Result<impl Stream<Item = Result<ExportRecord>>> {
+        let pattern = prefix_pattern(token, prefix);
+        tracing::debug!("EXPORT {}", pattern);
+
+        let store = self.clone();
+        let token = token.to_string();
+        let prefix_len = token.len() + 1;
+        let (tx, rx) = tokio::sync::mpsc::channel(SCAN_BATCH_SIZE);
+
+        tokio::spawn(async move {
+            let script = redis::Script::new(EXPORT_SCRIPT);
+            let mut seen = HashSet::new();
+            let mut cursor = 0;
+            loop {
+                let batch = async {
+                    let (next, keys) = store.scan_batch(&pattern, cursor).await?;
+                    // SCAN may return a key more than once
+                    let keys: Vec<String> = keys
+                        .into_iter()
+                        .map(|k| k[prefix_len..].to_string())
+                        .filter(|k| seen.insert(k.clone()))
+                        .collect();
+                    if keys.is_empty() {
+                        return Ok((next, Vec::new()));
+                    }
+
+                    let mut invocation = script.prepare_invoke();
+                    for key in &keys {
+                        invocation
+                            .key(namespaced_key(&token, key))
+                            .key(meta_key(&token, key));
+                    }
+                    let mut conn = store.conn.clone();
+                    let entries: Vec<ExportEntry> = invocation.invoke_async(&mut conn).await?;
+
+                    let mut records = Vec::with_capacity(keys.len());
+                    for (key, (kind, pttl, metadata, items)) in keys.into_iter().zip(entries) {
+                        records.extend(export_record(key, &kind, pttl, metadata, items)?);
+                    }
+                    Ok::<_, KVStoreError>((next, records))
+                };
+
+                match batch.await {
+                    Ok((next, records)) => {
+                        for record in records {
+                            if tx.send(Ok(record)).await.is_err() {
+                                return;
+                            }
+                        }
+                        if next == 0 {
+                            return;
+                        }
+                        cursor = next;
+                    }
+                    Err(e) => {
+                        tracing::error!("Export of pattern {} failed: {}", pattern, e);
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Check that a record can be imported into a namespace
+    ///
+    /// String values are validated like any other write, against the key
+    /// policy, their content type and the namespace's JSON Schemas; the keys
+    /// and items of other types against the key policy. Only strings can be
+    /// JSON documents.
+    pub(crate) async fn validate_import(&self, token: &str, record: &ExportRecord) -> Result<()> {
+        let content_type = record.metadata.get("content_type").map(String::as_str);
+        match &record.value {
+            ExportedValue::String(value) => {
+                let content_type = content_type.unwrap_or(TEXT_CONTENT_TYPE);
+                self.validate_write(token, &record.key, value, content_type)
+                    .await
+                    .map_err(|e| match e {
+                        KVStoreError::InvalidRequest(message) => KVStoreError::InvalidRequest(
+                            format!("Invalid value of {}: {}", record.key, message),
+                        ),
+                        e => e,
+                    })
+            }
+            _ if content_type == Some(JSON_CONTENT_TYPE) => {
+                Err(KVStoreError::InvalidRequest(format!(
+                    "Only string values can have content type {} ({} is a {})",
+                    JSON_CONTENT_TYPE,
+                    record.key,
+                    record.value.kind()
+                )))
+            }
+            value => {
+                self.policy.check_key(&record.key)?;
+                self.policy
+                    .check_values(value.items().iter().map(String::as_str))
+            }
+        }
+    }
+
+    /// Import records into a namespace, one key at a time
+    ///
+    /// Every record is validated before it is written: string values like
+    /// any other write, including their JSON Schema, and other values against
+    /// the key policy.
+    /// Imported string values are recorded in history and indexed, and every
+    /// imported key is reported as a put to watchers, the change log and
+    /// webhooks. With [`ImportConflict::Fail`], the import stops with
+    /// [`KVStoreError::Conflict`] at the first key that already exists; keys
+    /// imported before it are kept.
+    pub async fn import_namespace(
+        &self,
+        token: &str,
+        records: impl Stream<Item = Result<ExportRecord>>,
+        conflict: ImportConflict,
+    ) -> Result<ImportSummary> {
+        tracing::debug!("IMPORT {} ({})", token, conflict.as_str());
+
+        let script = value_script(IMPORT_SCRIPT);
+        let history_policy_key = history::history_policy_key(token);
+        let indexes_key = index::indexes_key(token);
+        let mut conn = self.conn.clone();
+        let mut summary = ImportSummary::default();
+
+        let mut records = std::pin::pin!(records);
+        while let Some(record) = records.next().await {
+            let record = record?;
+            self.validate_import(token, &record).await?;
+            let items = record.value.items();
+
+            let mut invocation = script.prepare_invoke();
+            invocation
+                .key(namespaced_key(token, &record.key))
+                .key(meta_key(token, &record.key))
+                .key(history::history_key(token, &record.key))
+                .key(&history_policy_key)
+                .key(&indexes_key)
+                .arg(conflict.as_str())
+                .arg(record.value.kind())
+                .arg(record.ttl_ms.unwrap_or(0))
+                .arg(record.metadata.len() * 2);
+            for (field, value) in &record.metadata {
+                invocation.arg(field).arg(value);
+            }
+            for item in &items {
+                invocation.arg(item);
+            }
+            add_namespace(&mut invocation, token);
+            let outcome: String = invocation.invoke_async(&mut conn).await?;
+
+            match outcome.as_str() {
+                "imported" => summary.imported += 1,
+                "skipped" => summary.skipped += 1,
+                _ => {
+                    return Err(KVStoreError::Conflict(format!(
+                        "Key {} already exists ({} keys imported before it)",
+                        record.key, summary.imported
+                    )))
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_format() {
+        let record = ExportRecord {
+            key: "scores".to_string(),
+            value: ExportedValue::SortedSet(vec![ScoredMember {
+                member: "alice".to_string(),
+                score: 1.5,
+            }]),
+            ttl_ms: Some(1000),
+            metadata: BTreeMap::from([("version".to_string(), "2".to_string())]),
+        };
+        let line = record.to_ndjson().unwrap();
+        assert_eq!(
+            line,
+            "{\"key\":\"scores\",\"type\":\"sorted_set\",\"value\":[{\"member\":\"alice\",\"score\":1.5}],\
+             \"ttl_ms\":1000,\"metadata\":{\"version\":\"2\"}}\n"
+        );
+        assert_eq!(serde_json::from_str::<ExportRecord>(&line).unwrap(), record);
+
+        // Infinite scores, which JSON numbers can't hold, are written as strings
+        let infinite = ExportRecord {
+            key: "bounds".to_string(),
+            value: ExportedValue::SortedSet(vec![
+                ScoredMember {
+                    member: "top".to_string(),
+                    score: f64::INFINITY,
+                },
+                ScoredMember {
+                    member: "bottom".to_string(),
+                    score: f64::NEG_INFINITY,
+                },
+            ]),
+            ttl_ms: None,
+            metadata: BTreeMap::new(),
+        };
+        let line = infinite.to_ndjson().unwrap();
+        assert!(line.contains("{\"member\":\"top\",\"score\":\"inf\"}"));
+        assert!(line.contains("{\"member\":\"bottom\",\"score\":\"-inf\"}"));
+        assert_eq!(
+            serde_json::from_str::<ExportRecord>(&line).unwrap(),
+            infinite
+        );
+        assert_eq!(
+            infinite.value.items(),
+            vec!["+inf", "top", "-inf", "bottom"]
+        );
+
+        let plain: ExportRecord =
+            serde_json::from_str(r#"{"key":"a","type":"string","value":"x"}"#).unwrap();
+        assert_eq!(plain.value, ExportedValue::String("x".to_string()));
+        assert_eq!(plain.ttl_ms, None);
+        assert!(plain.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_value_items() {
+        let hash = ExportedValue::Hash(BTreeMap::from([("f".to_string(), "v".to_string())]));
+        assert_eq!(hash.items(), vec!["f", "v"]);
+        let zset = ExportedValue::SortedSet(vec![ScoredMember {
+            member: "m".to_string(),
+            score: 2.0,
+        }]);
+        assert_eq!(zset.items(), vec!["2", "m"]);
+        assert_eq!(zset.kind(), "sorted_set");
+        assert!(IMPORT_SCRIPT.contains("sorted_set = 'ZADD'"));
+    }
+
+    #[test]
+    fn test_conflict_policy() {
+        for policy in [
+            ImportConflict::Skip,
+            ImportConflict::Overwrite,
+            ImportConflict::Fail,
+        ] {
+            assert_eq!(policy.as_str().parse::<ImportConflict>().unwrap(), policy);
+        }
+        assert!("replace".parse::<ImportConflict>().is_err());
+        assert_eq!(ImportConflict::default(), ImportConflict::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_read_ndjson() {
+        let chunks: Vec<std::result::Result<&[u8], std::io::Error>> = vec![
+            Ok(b"{\"key\":\"a\",\"type\":\"string\",\"va"),
+            Ok(b"lue\":\"1\"}\n\n{\"key\":\"b\",\"type\":\"list\",\"value\":[\"x\"]}"),
+        ];
+        let records: Vec<_> = read_ndjson(futures::stream::iter(chunks)).collect().await;
+        let records: Vec<ExportRecord> = records.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].key, "a");
+        assert_eq!(records[1].value, ExportedValue::List(vec!["x".to_string()]));
+
+        let chunks: Vec<std::result::Result<&[u8], std::io::Error>> = vec![Ok(
+            b"{\"key\":\"a\",\"type\":\"string\",\"value\":\"1\"}\nnot json\n{}\n",
+        )];
+        let records: Vec<_> = read_ndjson(futures::stream::iter(chunks)).collect().await;
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+        assert!(
+            matches!(&records[1], Err(KVStoreError::InvalidRequest(m)) if m.contains("line 2"))
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Hash-valued keys
+//!
+//! Stores small objects as Redis hashes so individual fields can be read and
+//! updated without rewriting the whole value.
+
+use super::watch::CollectionWrite;
+use super::{namespaced_key, redis_error};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+
+impl KVStore {
+    /// Get one or more fields of a hash
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Authentication token (used as namespace prefix)
+    /// * `key` - The hash key
+    /// * `fields` - Field names to read
+    ///
+    /// # Returns
+    ///
+    /// The value of each requested field, in order, or `None` for missing fields
+    pub async fn hash_get(
+        &self,
+        token: &str,
+        key: &str,
+        fields: &[String],
+    ) -> Result<Vec<Option<String>>> {
+        if fields.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "At least one field is required".to_string(),
+            ));
+        }
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("HMGET {} {:?}", namespaced_key, fields);
+
+        let mut conn = self.conn.clone();
+        let values: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(&namespaced_key)
+            .arg(fields)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get fields of {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(values)
+    }
+
+    /// Get all fields of a hash
+    ///
+    /// # Returns
+    ///
+    /// All fields and their values, or an error if the key doesn't exist
+    pub async fn hash_get_all(&self, token: &str, key: &str) -> Result<HashMap<String, String>> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("HGETALL {}", namespaced_key);
+
+        let mut conn = self.conn.clone();
+        let fields: HashMap<String, String> = conn.hgetall(&namespaced_key).await.map_err(|e| {
+            tracing::error!("Failed to get fields of {}: {}", namespaced_key, e);
+            redis_error(key, e)
+        })?;
+
+        // Redis never keeps empty hashes, so no fields means no key
+        if fields.is_empty() {
+            return Err(KVStoreError::KeyNotFound(key.to_string()));
+        }
+
+        Ok(fields)
+    }
+
+    /// Set one or more fields of a hash, creating the hash if needed
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Authentication token (used as namespace prefix)
+    /// * `key` - The hash key
+    /// * `fields` - Field/value pairs to write
+    ///
+    /// # Returns
+    ///
+    /// The number of fields that were newly added
+    pub async fn hash_set(
+        &self,
+        token: &str,
+        key: &str,
+        fields: &[(String, String)],
+    ) -> Result<usize> {
+        if fields.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "At least one field is required".to_string(),
+            ));
+        }
+
+        self.policy.check_key(key)?;
+        self.policy
+            .check_values(fields.iter().map(|(_, value)| value.as_str()))?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("HSET {} ({} fields)", namespaced_key, fields.len());
+
+        let added: usize = self
+            .write_collection(token, key, CollectionWrite::Always, Some("HSET"), fields)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to set fields of {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(added)
+    }
+
+    /// Delete one or more fields of a hash
+    ///
+    /// # Returns
+    ///
+    /// The number of fields that were removed
+    pub async fn hash_delete(&self, token: &str, key: &str, fields: &[String]) -> Result<usize> {
+        if fields.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "At least one field is required".to_string(),
+            ));
+        }
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("HDEL {} {:?}", namespaced_key, fields);
+
+        let removed: usize = self
+            .write_collection(token, key, CollectionWrite::Counted, Some("HDEL"), fields)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to delete fields of {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(removed)
+    }
+
+    /// Atomically increment an integer field of a hash
+    ///
+    /// Missing fields (and missing hashes) start at zero.
+    ///
+    /// # Returns
+    ///
+    /// The value of the field after the increment
+    pub async fn hash_increment(
+        &self,
+        token: &str,
+        key: &str,
+        field: &str,
+        delta: i64,
+    ) -> Result<i64> {
+        self.policy.check_key(key)?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("HINCRBY {} {} {}", namespaced_key, field, delta);
+
+        let value: i64 = self
+            .write_collection(
+                token,
+                key,
+                CollectionWrite::Always,
+                Some("HINCRBY"),
+                (field, delta),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to increment {} of {}: {}", field, namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(value)
+    }
+}
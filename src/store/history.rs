@@ -0,0 +1,333 @@
+//! Versioned key history and point-in-time reads
+//!
+//! History is opt-in per namespace. Once a [`HistoryPolicy`] is set, every
+//! write and delete of a string or document value appends a revision to a Redis
+//! Stream kept for the key. Revisions are identified by their stream entry ID
+//! (`<milliseconds>-<sequence>`), so they are ordered by time and a bare
+//! millisecond timestamp can be used to read the value as of that moment.
+
+use super::{internal_key, redis_error, SetOptions, INTERNAL_PREFIX};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use redis::streams::{StreamId, StreamRangeReply};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+/// Lua function appending a revision to a key's history
+///
+/// Included in every script built with `value_script`, which may call
+/// `record_revision(history_key, policy_key, op, value, content_type)`.
+/// Nothing is recorded while the namespace has no history policy.
+pub(super) const RECORD_REVISION_LUA: &str = r#"
+local function record_revision(history_key, policy_key, op, value, content_type)
+    local policy = redis.call('HMGET', policy_key, 'max_revisions', 'max_age_seconds')
+    local max_revisions = tonumber(policy[1]) or 0
+    local max_age = tonumber(policy[2]) or 0
+    if max_revisions == 0 and max_age == 0 then
+        return
+    end
+
+    redis.call('XADD', history_key, '*', 'op', op, 'value', value, 'content_type', content_type)
+    if max_revisions > 0 then
+        redis.call('XTRIM', history_key, 'MAXLEN', max_revisions)
+    end
+    if max_age > 0 then
+        local time = redis.call('TIME')
+        local cutoff = time[1] * 1000 + math.floor(time[2] / 1000) - max_age * 1000
+        redis.call('XTRIM', history_key, 'MINID', string.format('%d', cutoff))
+        redis.call('PEXPIRE', history_key, max_age * 1000)
+    end
+end
+"#;
+
+/// Build the Redis key of the revision stream kept for `key`
+pub(super) fn history_key(token: &str, key: &str) -> String {
+    internal_key(token, "history", key)
+}
+
+/// Build the Redis key of the history policy of `token`'s namespace
+pub(super) fn history_policy_key(token: &str) -> String {
+    format!("{}:{}:history_policy", INTERNAL_PREFIX, token)
+}
+
+/// How much history a namespace keeps
+///
+/// Revisions beyond either limit are dropped; with neither limit set, history
+/// is disabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryPolicy {
+    /// Keep at most this many revisions per key
+    pub max_revisions: Option<u64>,
+    /// Keep revisions for at most this many seconds
+    pub max_age_seconds: Option<u64>,
+}
+
+impl HistoryPolicy {
+    /// Whether any history is kept
+    pub fn is_enabled(&self) -> bool {
+        self.max_revisions.is_some() || self.max_age_seconds.is_some()
+    }
+}
+
+/// A recorded revision of a key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Revision {
+    /// Stream entry ID identifying the revision
+    pub revision: String,
+    /// When the revision was recorded, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Whether the revision deleted the key
+    pub deleted: bool,
+    /// The value written, `None` for deletions
+    pub value: Option<String>,
+    /// Content type of the value, `None` for deletions
+    pub content_type: Option<String>,
+}
+
+impl Revision {
+    fn from_stream_id(entry: StreamId) -> Self {
+        let timestamp = entry
+            .id
+            .split('-')
+            .next()
+            .and_then(|millis| millis.parse().ok())
+            .unwrap_or_default();
+        let deleted = entry.get::<String>("op").as_deref() == Some("delete");
+
+        Revision {
+            timestamp,
+            deleted,
+            value: (!deleted).then(|| entry.get("value")).flatten(),
+            content_type: (!deleted).then(|| entry.get("content_type")).flatten(),
+            revision: entry.id,
+        }
+    }
+}
+
+/// Check that `revision` is a stream entry ID or a millisecond timestamp
+fn check_revision(revision: &str) -> Result<()> {
+    let (millis, sequence) = match revision.split_once('-') {
+        Some((millis, sequence)) => (millis, Some(sequence)),
+        None => (revision, None),
+    };
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+    if !is_number(millis) || !sequence.into_iter().all(is_number) {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Invalid revision: {}",
+            revision
+        )));
+    }
+    Ok(())
+}
+
+impl KVStore {
+    /// Set how much history the namespace keeps
+    ///
+    /// A policy with neither limit disables history. Existing revisions are
+    /// trimmed to the new limits on the next write of each key.
+    pub async fn set_history_policy(&self, token: &str, policy: &HistoryPolicy) -> Result<()> {
+        if policy.max_revisions == Some(0) || policy.max_age_seconds == Some(0) {
+            return Err(KVStoreError::InvalidRequest(
+                "History limits must be positive".to_string(),
+            ));
+        }
+
+        let policy_key = history_policy_key(token);
+        tracing::debug!("SET HISTORY POLICY {} {:?}", policy_key, policy);
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&policy_key).ignore();
+        if let Some(max_revisions) = policy.max_revisions {
+            pipe.hset(&policy_key, "max_revisions", max_revisions)
+                .ignore();
+        }
+        if let Some(max_age_seconds) = policy.max_age_seconds {
+            pipe.hset(&policy_key, "max_age_seconds", max_age_seconds)
+                .ignore();
+        }
+
+        let mut conn = self.conn.clone();
+        pipe.query_async::<()>(&mut conn).await.map_err(|e| {
+            tracing::error!("Failed to set history policy {}: {}", policy_key, e);
+            e
+        })?;
+
+        Ok(())
+    }
+
+    /// Get the history policy of the namespace
+    pub async fn get_history_policy(&self, token: &str) -> Result<HistoryPolicy> {
+        let policy_key = history_policy_key(token);
+        let mut conn = self.conn.clone();
+        let (max_revisions, max_age_seconds): (Option<u64>, Option<u64>) = conn
+            .hget(&policy_key, &["max_revisions", "max_age_seconds"])
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get history policy {}: {}", policy_key, e);
+                e
+            })?;
+
+        Ok(HistoryPolicy {
+            max_revisions,
+            max_age_seconds,
+        })
+    }
+
+    /// List the recorded revisions of a key
+    ///
+    /// # Returns
+    ///
+    /// Revisions newest first, including deletions. Keys without recorded
+    /// history return an empty list.
+    pub async fn history(&self, token: &str, key: &str) -> Result<Vec<Revision>> {
+        let history_key = history_key(token, key);
+        tracing::debug!("XREVRANGE {}", history_key);
+
+        let mut conn = self.conn.clone();
+        let reply: StreamRangeReply = conn.xrevrange_all(&history_key).await.map_err(|e| {
+            tracing::error!("Failed to read history {}: {}", history_key, e);
+            redis_error(key, e)
+        })?;
+
+        Ok(reply
+            .ids
+            .into_iter()
+            .map(Revision::from_stream_id)
+            .collect())
+    }
+
+    /// Read a key as of a revision or point in time
+    ///
+    /// # Arguments
+    ///
+    /// * `revision` - A revision ID, or a millisecond timestamp
+    ///
+    /// # Returns
+    ///
+    /// The latest revision at or before `revision`, or
+    /// [`KVStoreError::KeyNotFound`] if the key had no value at that point
+    pub async fn get_at_revision(
+        &self,
+        token: &str,
+        key: &str,
+        revision: &str,
+    ) -> Result<Revision> {
+        check_revision(revision)?;
+
+        let history_key = history_key(token, key);
+        tracing::debug!("XREVRANGE {} {} - COUNT 1", history_key, revision);
+
+        let mut conn = self.conn.clone();
+        let reply: StreamRangeReply = conn
+            .xrevrange_count(&history_key, revision, "-", 1)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to read history {}: {}", history_key, e);
+                redis_error(key, e)
+            })?;
+
+        reply
+            .ids
+            .into_iter()
+            .next()
+            .map(Revision::from_stream_id)
+            .filter(|revision| !revision.deleted)
+            .ok_or_else(|| KVStoreError::KeyNotFound(format!("{}@{}", key, revision)))
+    }
+
+    /// Restore a key to the value it had at a revision
+    ///
+    /// The restored value is written like any other value: it is validated
+    /// against the prefix's schema and recorded as a new revision. Restoring a
+    /// deletion deletes the key.
+    ///
+    /// # Returns
+    ///
+    /// The revision that was restored
+    pub async fn restore(&self, token: &str, key: &str, revision: &str) -> Result<Revision> {
+        check_revision(revision)?;
+
+        let history_key = history_key(token, key);
+        tracing::debug!("RESTORE {} {}", history_key, revision);
+
+        let mut conn = self.conn.clone();
+        let reply: StreamRangeReply = conn
+            .xrange(&history_key, revision, revision)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to read history {}: {}", history_key, e);
+                redis_error(key, e)
+            })?;
+
+        let revision = reply
+            .ids
+            .into_iter()
+            .next()
+            .map(Revision::from_stream_id)
+            .ok_or_else(|| KVStoreError::KeyNotFound(format!("{}@{}", key, revision)))?;
+
+        match &revision.value {
+            Some(value) => {
+                let options = SetOptions {
+                    content_type: revision.content_type.clone(),
+                    ..Default::default()
+                };
+                self.set_with_options(token, key, value, &options).await?;
+            }
+            None => self.delete(token, key).await?,
+        }
+
+        Ok(revision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_check_revision() {
+        assert!(check_revision("1700000000000-0").is_ok());
+        assert!(check_revision("1700000000000").is_ok());
+        assert!(check_revision("").is_err());
+        assert!(check_revision("-1").is_err());
+        assert!(check_revision("latest").is_err());
+        assert!(check_revision("1700000000000-x").is_err());
+    }
+
+    #[test]
+    fn test_revision_from_stream_id() {
+        let entry = StreamId {
+            id: "1700000000000-1".to_string(),
+            map: HashMap::from([
+                ("op".to_string(), redis::Value::BulkString(b"set".to_vec())),
+                (
+                    "value".to_string(),
+                    redis::Value::BulkString(b"hello".to_vec()),
+                ),
+                (
+                    "content_type".to_string(),
+                    redis::Value::BulkString(b"text/plain".to_vec()),
+                ),
+            ]),
+        };
+
+        let revision = Revision::from_stream_id(entry);
+        assert_eq!(revision.timestamp, 1_700_000_000_000);
+        assert!(!revision.deleted);
+        assert_eq!(revision.value.as_deref(), Some("hello"));
+
+        let tombstone = StreamId {
+            id: "1700000000001-0".to_string(),
+            map: HashMap::from([(
+                "op".to_string(),
+                redis::Value::BulkString(b"delete".to_vec()),
+            )]),
+        };
+        let revision = Revision::from_stream_id(tombstone);
+        assert!(revision.deleted);
+        assert_eq!(revision.value, None);
+    }
+}
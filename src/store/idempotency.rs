@@ -0,0 +1,313 @@
+//! Idempotency keys for mutating requests
+//!
+//! Clients retrying a request after a timeout can't tell whether the first
+//! attempt was applied. Sending the same idempotency key with each attempt
+//! lets the server apply the request once: the first attempt claims the key,
+//! and once it finishes its response is kept for the store's idempotency
+//! window and replayed to every repeat. Each key is bound to a fingerprint of
+//! the request it was first used with, so reusing it for a different request
+//! is rejected instead of silently replaying an unrelated response.
+//!
+//! Responses are opaque to the store; the HTTP and gRPC servers decide what
+//! to keep and how to replay it.
+
+use super::internal_key;
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::time::Duration;
+
+/// How long responses are kept for replay, unless configured otherwise with
+/// [`KVStore::with_idempotency_window`]
+pub const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest accepted idempotency key
+pub const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// How long a claimed key waits for its response before it may be claimed
+/// again, so a server dying mid-request doesn't block retries for the whole
+/// window
+///
+/// Claims are renewed while their request runs (see
+/// [`KVStore::while_claimed`]), so requests that take longer, such as
+/// blocking pops, keep theirs.
+const IDEMPOTENCY_CLAIM_TTL: Duration = Duration::from_secs(60);
+
+/// How often claims are renewed while their request runs
+const IDEMPOTENCY_CLAIM_RENEWAL: Duration = Duration::from_secs(20);
+
+/// Claim an idempotency key, or read the response kept for it
+///
+/// KEYS[1] - idempotency record
+/// ARGV[1] - request fingerprint, ARGV[2] - claim TTL in milliseconds
+/// Returns {'claimed'}, {'mismatch'}, {'pending'} or {'done', status,
+/// content type, body}
+const CLAIM_SCRIPT: &str = r#"
+local record = redis.call('HMGET', KEYS[1], 'fingerprint', 'status', 'content_type', 'body')
+if not record[1] then
+    redis.call('HSET', KEYS[1], 'fingerprint', ARGV[1])
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return {'claimed'}
+end
+if record[1] ~= ARGV[1] then
+    return {'mismatch'}
+end
+if not record[2] then
+    return {'pending'}
+end
+return {'done', record[2], record[3], record[4]}
+"#;
+
+/// Keep the response to a claimed idempotency key
+///
+/// KEYS[1] - idempotency record
+/// ARGV[1] - request fingerprint, ARGV[2] - window in milliseconds,
+/// ARGV[3] - status, ARGV[4] - content type, ARGV[5] - body
+const RECORD_SCRIPT: &str = r#"
+if redis.call('HGET', KEYS[1], 'fingerprint') ~= ARGV[1] then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'status', ARGV[3], 'content_type', ARGV[4], 'body', ARGV[5])
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return 1
+"#;
+
+/// Extend a claim on an idempotency key, unless it was claimed again or
+/// answered since
+///
+/// KEYS[1] - idempotency record
+/// ARGV[1] - request fingerprint, ARGV[2] - claim TTL in milliseconds
+const RENEW_SCRIPT: &str = r#"
+local record = redis.call('HMGET', KEYS[1], 'fingerprint', 'status')
+if record[1] ~= ARGV[1] or record[2] then
+    return 0
+end
+return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+"#;
+
+/// Give up a claimed idempotency key, unless it was claimed again since
+///
+/// KEYS[1] - idempotency record
+/// ARGV[1] - request fingerprint
+const RELEASE_SCRIPT: &str = r#"
+local record = redis.call('HMGET', KEYS[1], 'fingerprint', 'status')
+if record[1] ~= ARGV[1] or record[2] then
+    return 0
+end
+return redis.call('DEL', KEYS[1])
+"#;
+
+/// A response kept for replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotentResponse {
+    /// HTTP status, or gRPC status code
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/// Fingerprint a request from the parts that determine its effect
+///
+/// Parts are length-prefixed so that moving bytes from one part to the next
+/// changes the fingerprint.
+pub fn request_fingerprint<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn record_key(token: &str, key: &str) -> String {
+    internal_key(token, "idempotency", key)
+}
+
+/// Check an idempotency key sent by a client
+pub fn check_idempotency_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Idempotency keys must be 1 to {} bytes long",
+            MAX_IDEMPOTENCY_KEY_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+impl KVStore {
+    /// Claim an idempotency key for a request before applying it
+    ///
+    /// # Returns
+    ///
+    /// `None` if the caller claimed the key and should apply the request,
+    /// then [`KVStore::record_idempotent_response`] or
+    /// [`KVStore::release_idempotency_key`]; the kept response if the request
+    /// was already applied; [`KVStoreError::InvalidRequest`] if the key was
+    /// used for a different request, or [`KVStoreError::Conflict`] while the
+    /// first request with the key is still being applied
+    pub async fn claim_idempotency_key(
+        &self,
+        token: &str,
+        key: &str,
+        fingerprint: &str,
+    ) -> Result<Option<IdempotentResponse>> {
+        check_idempotency_key(key)?;
+
+        let record_key = record_key(token, key);
+        tracing::debug!("IDEMPOTENCY CLAIM {}", record_key);
+
+        let mut conn = self.conn.clone();
+        let reply: Vec<redis::Value> = redis::Script::new(CLAIM_SCRIPT)
+            .key(&record_key)
+            .arg(fingerprint)
+            .arg(IDEMPOTENCY_CLAIM_TTL.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await?;
+
+        let (state, response) = reply.split_first().ok_or_else(|| {
+            KVStoreError::Internal("Empty reply from idempotency claim".to_string())
+        })?;
+        let state: String = redis::from_redis_value(state)?;
+        match state.as_str() {
+            "claimed" => Ok(None),
+            "mismatch" => Err(KVStoreError::InvalidRequest(
+                "Idempotency key was already used for a different request".to_string(),
+            )),
+            "pending" => Err(KVStoreError::Conflict(
+                "A request with this idempotency key is still in progress".to_string(),
+            )),
+            _ => {
+                let (status, content_type, body): (u16, String, Vec<u8>) =
+                    redis::from_redis_value(&redis::Value::Array(response.to_vec()))?;
+                Ok(Some(IdempotentResponse {
+                    status,
+                    content_type,
+                    body,
+                }))
+            }
+        }
+    }
+
+    /// Keep the response to a request applied under a claimed idempotency key
+    /// for replay to repeats
+    pub async fn record_idempotent_response(
+        &self,
+        token: &str,
+        key: &str,
+        fingerprint: &str,
+        response: &IdempotentResponse,
+    ) -> Result<()> {
+        let record_key = record_key(token, key);
+        tracing::debug!(
+            "IDEMPOTENCY RECORD {} (status: {})",
+            record_key,
+            response.status
+        );
+
+        let mut conn = self.conn.clone();
+        let recorded: bool = redis::Script::new(RECORD_SCRIPT)
+            .key(&record_key)
+            .arg(fingerprint)
+            .arg(self.idempotency_window.as_millis() as u64)
+            .arg(response.status)
+            .arg(&response.content_type)
+            .arg(&response.body)
+            .invoke_async(&mut conn)
+            .await?;
+
+        if !recorded {
+            tracing::warn!(
+                "Idempotency key {} expired before its response was recorded",
+                record_key
+            );
+        }
+        Ok(())
+    }
+
+    /// Run `request` under a claimed idempotency key, renewing the claim until
+    /// it finishes so that no retry claims the key in the meantime
+    pub async fn while_claimed<F: Future>(
+        &self,
+        token: &str,
+        key: &str,
+        fingerprint: &str,
+        request: F,
+    ) -> F::Output {
+        let record_key = record_key(token, key);
+        let mut renewal = tokio::time::interval(IDEMPOTENCY_CLAIM_RENEWAL);
+        // The claim was just made, so the first renewal is due a period later
+        renewal.reset();
+        tokio::pin!(request);
+
+        loop {
+            tokio::select! {
+                output = &mut request => return output,
+                _ = renewal.tick() => {
+                    tracing::debug!("IDEMPOTENCY RENEW {}", record_key);
+                    let mut conn = self.conn.clone();
+                    let renewed = redis::Script::new(RENEW_SCRIPT)
+                        .key(&record_key)
+                        .arg(fingerprint)
+                        .arg(IDEMPOTENCY_CLAIM_TTL.as_millis() as u64)
+                        .invoke_async::<()>(&mut conn)
+                        .await;
+                    if let Err(e) = renewed {
+                        tracing::warn!("Failed to renew idempotency key {}: {}", record_key, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Give up a claimed idempotency key, e.g. after a transient failure, so
+    /// the request can be retried with it
+    ///
+    /// Leaves the key alone if it now belongs to a different request, or
+    /// already has a response, e.g. because the claim expired and was taken
+    /// over while this request was being applied.
+    pub async fn release_idempotency_key(
+        &self,
+        token: &str,
+        key: &str,
+        fingerprint: &str,
+    ) -> Result<()> {
+        let record_key = record_key(token, key);
+        tracing::debug!("IDEMPOTENCY RELEASE {}", record_key);
+
+        let mut conn = self.conn.clone();
+        redis::Script::new(RELEASE_SCRIPT)
+            .key(&record_key)
+            .arg(fingerprint)
+            .invoke_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_fingerprint() {
+        let fingerprint = request_fingerprint([b"POST".as_slice(), b"/a", b"1"]);
+        assert_eq!(fingerprint.len(), 64);
+        assert_eq!(
+            fingerprint,
+            request_fingerprint([b"POST".as_slice(), b"/a", b"1"])
+        );
+        assert_ne!(
+            fingerprint,
+            request_fingerprint([b"POST".as_slice(), b"/a1", b""])
+        );
+    }
+
+    #[test]
+    fn test_check_idempotency_key() {
+        assert!(check_idempotency_key("retry-1").is_ok());
+        assert!(check_idempotency_key("").is_err());
+        assert!(check_idempotency_key(&"k".repeat(MAX_IDEMPOTENCY_KEY_LENGTH + 1)).is_err());
+    }
+}
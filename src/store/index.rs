@@ -0,0 +1,534 @@
+//! Secondary indexes on JSON document fields
+//!
+//! A namespace may declare indexes on a field of the JSON documents stored
+//! under a key prefix. Indexes are updated by the same Lua scripts that write
+//! and delete values, so they never disagree with the values they cover.
+//! Keys that expire are dropped from the indexes by the
+//! [`WebhookWorker`](super::WebhookWorker)s, which listen for expirations
+//! when Redis keyspace notifications are enabled, and otherwise when a query
+//! comes across them.
+//!
+//! Each index keeps three Redis keys next to its definition: a sorted set of
+//! `<encoded value>\0<key>` members for equality lookups on strings, booleans
+//! and null, a sorted set of keys scored by their numeric field values, and a
+//! hash from each indexed key to its encoded value, used to remove stale
+//! entries.
+
+use super::sorted_set::score_bound;
+use super::{namespaced_key, prefix, redis_error, KVStore, INTERNAL_PREFIX};
+use crate::error::{KVStoreError, Result};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Number of keys returned per page unless a query asks for another limit
+pub const DEFAULT_INDEX_QUERY_LIMIT: usize = 100;
+
+/// Maximum number of keys returned per page
+pub const MAX_INDEX_QUERY_LIMIT: usize = 1000;
+
+/// Maximum length of an index or webhook name
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Lua functions maintaining the indexes of a namespace
+///
+/// Included in every script built with `value_script`, which may call
+/// `update_indexes(indexes_key, value_key, value)` after writing `value` to
+/// `value_key`, or with `value` set to false after deleting it. Index data
+/// keys are derived from `indexes_key`, the hash of index definitions.
+pub(super) const INDEX_LUA: &str = r#"
+-- Read the field at path (a list of member names) of a JSON document
+local function index_field(value, path)
+    local ok, field = pcall(cjson.decode, value)
+    if not ok then
+        return nil
+    end
+    for _, name in ipairs(path) do
+        if type(field) ~= 'table' then
+            return nil
+        end
+        field = field[name]
+    end
+    return field
+end
+
+local function unindex_value(indexes_key, name, value_key)
+    local base = indexes_key .. ':' .. name
+    local old = redis.call('HGET', base .. ':entries', value_key)
+    if old then
+        redis.call('ZREM', base .. ':eq', old .. '\0' .. value_key)
+        redis.call('ZREM', base .. ':num', value_key)
+        redis.call('HDEL', base .. ':entries', value_key)
+    end
+end
+
+-- Replace the entry of value_key in index name; value is false once deleted
+local function index_value(indexes_key, name, index, value_key, value)
+    if string.sub(value_key, 1, string.len(index.key_prefix)) ~= index.key_prefix then
+        return false
+    end
+    unindex_value(indexes_key, name, value_key)
+    if not value then
+        return false
+    end
+
+    local base = indexes_key .. ':' .. name
+    local field = index_field(value, index.path)
+    local entry
+    if type(field) == 'number' then
+        entry = 'n'
+        redis.call('ZADD', base .. ':num', string.format('%.17g', field), value_key)
+    elseif type(field) == 'string' and not string.find(field, '\0', 1, true) then
+        entry = 's:' .. field
+    elseif type(field) == 'boolean' then
+        entry = field and 'b:true' or 'b:false'
+    elseif field == cjson.null then
+        entry = 'null'
+    else
+        return false
+    end
+    if entry ~= 'n' then
+        redis.call('ZADD', base .. ':eq', 0, entry .. '\0' .. value_key)
+    end
+    redis.call('HSET', base .. ':entries', value_key, entry)
+    return true
+end
+
+local function update_indexes(indexes_key, value_key, value)
+    local indexes = redis.call('HGETALL', indexes_key)
+    for i = 1, #indexes, 2 do
+        index_value(indexes_key, indexes[i], cjson.decode(indexes[i + 1]), value_key, value)
+    end
+end
+"#;
+
+/// Build a script whose `body` may call the index Lua functions
+fn index_script(body: &str) -> redis::Script {
+    redis::Script::new(&[INDEX_LUA, body].concat())
+}
+
+/// Index the current values of a batch of keys
+///
+/// KEYS[1] - index definitions key, KEYS[2..] - value keys
+/// ARGV[1] - index name
+const INDEX_BATCH_SCRIPT: &str = r#"
+local index = cjson.decode(redis.call('HGET', KEYS[1], ARGV[1]))
+local indexed = 0
+for i = 2, #KEYS do
+    if redis.call('TYPE', KEYS[i]).ok == 'string'
+        and index_value(KEYS[1], ARGV[1], index, KEYS[i], redis.call('GET', KEYS[i])) then
+        indexed = indexed + 1
+    end
+end
+return indexed
+"#;
+
+/// Read a page of an index, dropping entries of keys that have expired
+///
+/// KEYS[1] - index definitions key
+/// ARGV[1] - index name, ARGV[2] - "eq" or "num", ARGV[3] and ARGV[4] -
+/// ZRANGEBYLEX or ZRANGEBYSCORE bounds, ARGV[5] - offset, ARGV[6] - limit
+///
+/// Returns the offset of the next page (-1 after the last page) and the keys.
+const QUERY_SCRIPT: &str = r#"
+local base = KEYS[1] .. ':' .. ARGV[1]
+local offset = tonumber(ARGV[5])
+local limit = tonumber(ARGV[6])
+local command = ARGV[2] == 'eq' and 'ZRANGEBYLEX' or 'ZRANGEBYSCORE'
+local members = redis.call(command, base .. ':' .. ARGV[2], ARGV[3], ARGV[4], 'LIMIT', offset, limit)
+
+local keys = {}
+local dropped = 0
+for _, member in ipairs(members) do
+    local value_key = member
+    if ARGV[2] == 'eq' then
+        value_key = string.sub(member, string.find(member, '\0', 1, true) + 1)
+    end
+    if redis.call('EXISTS', value_key) == 1 then
+        keys[#keys + 1] = value_key
+    else
+        unindex_value(KEYS[1], ARGV[1], value_key)
+        dropped = dropped + 1
+    end
+end
+
+local next_offset = -1
+if #members == limit then
+    next_offset = offset + limit - dropped
+end
+return {next_offset, keys}
+"#;
+
+/// An index on a field of the JSON documents under a key prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexDefinition {
+    /// Prefix of the keys covered by the index; empty for every key
+    pub prefix: String,
+    /// The indexed field, as dot-separated member names, e.g. `order.status`
+    pub field: String,
+}
+
+/// An index definition as stored for the Lua scripts
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredIndex {
+    prefix: String,
+    field: String,
+    /// Namespaced key prefix of the covered keys
+    key_prefix: String,
+    /// `field` split into member names
+    path: Vec<String>,
+}
+
+impl From<StoredIndex> for IndexDefinition {
+    fn from(index: StoredIndex) -> Self {
+        IndexDefinition {
+            prefix: index.prefix,
+            field: index.field,
+        }
+    }
+}
+
+/// Keys to look up in an index
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexQuery {
+    /// Keys whose field equals a string, number, boolean or null
+    Equals(Value),
+    /// Keys whose field is a number between `min` and `max`, inclusive;
+    /// missing bounds are unbounded
+    Range { min: Option<f64>, max: Option<f64> },
+}
+
+/// A page of index query results
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IndexPage {
+    /// Matching keys, ordered by value and then by key
+    pub keys: Vec<String>,
+    /// Cursor to pass to get the next page, `None` after the last page
+    pub cursor: Option<String>,
+}
+
+/// Build the Redis key of the hash of index definitions of `token`
+///
+/// The data of each index lives in keys starting with this key and the
+/// index name.
+pub(super) fn indexes_key(token: &str) -> String {
+    format!("{}:{}:indexes", INTERNAL_PREFIX, token)
+}
+
+/// Check the name of an index or other named namespace object
+pub(super) fn check_name(kind: &str, name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > MAX_NAME_LENGTH
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "{} names must be 1 to {} ASCII letters, digits, '-' or '_': {:?}",
+            kind, MAX_NAME_LENGTH, name
+        )));
+    }
+    Ok(())
+}
+
+/// Split a dot-separated field into member names
+fn parse_field(field: &str) -> Result<Vec<String>> {
+    let path: Vec<String> = field.split('.').map(String::from).collect();
+    if path.iter().any(String::is_empty) {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Invalid index field: {:?}",
+            field
+        )));
+    }
+    Ok(path)
+}
+
+/// Build the script arguments selecting the entries matching `query`
+///
+/// Returns `None` for values that are never indexed, which match no keys.
+fn query_bounds(query: &IndexQuery) -> Result<Option<(&'static str, String, String)>> {
+    let entry = match query {
+        IndexQuery::Range { min, max } => {
+            let min = min.unwrap_or(f64::NEG_INFINITY);
+            let max = max.unwrap_or(f64::INFINITY);
+            if min.is_nan() || max.is_nan() {
+                return Err(KVStoreError::InvalidRequest(
+                    "Range bounds must be numbers".to_string(),
+                ));
+            }
+            return Ok(Some(("num", score_bound(min), score_bound(max))));
+        }
+        IndexQuery::Equals(Value::Number(n)) => {
+            let n = n.as_f64().ok_or_else(|| {
+                KVStoreError::InvalidRequest(format!("Unsupported number: {}", n))
+            })?;
+            return Ok(Some(("num", score_bound(n), score_bound(n))));
+        }
+        IndexQuery::Equals(Value::String(s)) if s.contains('\0') => return Ok(None),
+        IndexQuery::Equals(Value::String(s)) => format!("s:{}", s),
+        IndexQuery::Equals(Value::Bool(b)) => format!("b:{}", b),
+        IndexQuery::Equals(Value::Null) => "null".to_string(),
+        IndexQuery::Equals(_) => {
+            return Err(KVStoreError::InvalidRequest(
+                "Only strings, numbers, booleans and null can be looked up".to_string(),
+            ))
+        }
+    };
+    Ok(Some((
+        "eq",
+        format!("[{}\0", entry),
+        format!("({}\u{1}", entry),
+    )))
+}
+
+impl KVStore {
+    /// Declare an index on `definition.field` of the documents under
+    /// `definition.prefix`, replacing any index of the same name
+    ///
+    /// Existing documents are indexed before this returns; later writes keep
+    /// the index up to date. Values that aren't JSON, or whose field is
+    /// missing or not a string, number, boolean or null, are not indexed.
+    ///
+    /// # Returns
+    ///
+    /// The number of existing documents indexed
+    pub async fn create_index(
+        &self,
+        token: &str,
+        name: &str,
+        definition: &IndexDefinition,
+    ) -> Result<u64> {
+        check_name("Index", name)?;
+        let stored = StoredIndex {
+            prefix: definition.prefix.clone(),
+            field: definition.field.clone(),
+            key_prefix: namespaced_key(token, &definition.prefix),
+            path: parse_field(&definition.field)?,
+        };
+        let stored = serde_json::to_string(&stored)
+            .map_err(|e| KVStoreError::Internal(format!("Failed to encode index: {}", e)))?;
+
+        let indexes_key = indexes_key(token);
+        tracing::debug!("CREATE INDEX {} {} {:?}", indexes_key, name, definition);
+
+        let mut conn = self.conn.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for data_key in index_data_keys(&indexes_key, name) {
+            pipe.unlink(data_key).ignore();
+        }
+        pipe.hset(&indexes_key, name, stored).ignore();
+        pipe.query_async::<()>(&mut conn).await.map_err(|e| {
+            tracing::error!("Failed to create index {}: {}", name, e);
+            e
+        })?;
+
+        let pattern = prefix::prefix_pattern(token, &definition.prefix);
+        let script = index_script(INDEX_BATCH_SCRIPT);
+        let mut cursor = 0u64;
+        let mut indexed = 0u64;
+        loop {
+            let (next, batch) = self.scan_batch(&pattern, cursor).await?;
+
+            if !batch.is_empty() {
+                let mut invocation = script.key(&indexes_key);
+                invocation.key(&batch).arg(name);
+                let count: u64 = invocation.invoke_async(&mut conn).await.map_err(|e| {
+                    tracing::error!("Failed to build index {}: {}", name, e);
+                    e
+                })?;
+                indexed += count;
+            }
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        Ok(indexed)
+    }
+
+    /// List the indexes of a namespace, ordered by name
+    pub async fn list_indexes(&self, token: &str) -> Result<Vec<(String, IndexDefinition)>> {
+        let indexes_key = indexes_key(token);
+        let mut conn = self.conn.clone();
+        let indexes: HashMap<String, String> = conn.hgetall(&indexes_key).await.map_err(|e| {
+            tracing::error!("Failed to list indexes {}: {}", indexes_key, e);
+            e
+        })?;
+
+        let mut indexes = indexes
+            .into_iter()
+            .map(|(name, index)| {
+                let index: StoredIndex = serde_json::from_str(&index).map_err(|e| {
+                    KVStoreError::Internal(format!("Corrupt index {}: {}", name, e))
+                })?;
+                Ok((name, index.into()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        indexes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(indexes)
+    }
+
+    /// Remove an index and its data
+    ///
+    /// # Returns
+    ///
+    /// [`KVStoreError::KeyNotFound`] if there is no index called `name`
+    pub async fn drop_index(&self, token: &str, name: &str) -> Result<()> {
+        let indexes_key = indexes_key(token);
+        tracing::debug!("DROP INDEX {} {}", indexes_key, name);
+
+        let mut conn = self.conn.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic().hdel(&indexes_key, name);
+        for data_key in index_data_keys(&indexes_key, name) {
+            pipe.unlink(data_key).ignore();
+        }
+        let (removed,): (usize,) = pipe.query_async(&mut conn).await.map_err(|e| {
+            tracing::error!("Failed to drop index {}: {}", name, e);
+            redis_error(name, e)
+        })?;
+
+        if removed == 0 {
+            return Err(KVStoreError::KeyNotFound(name.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Look up the keys whose indexed field matches `query`, a page at a time
+    ///
+    /// Pass the cursor of the previous page to continue. Pages may hold fewer
+    /// keys than `limit` when expired keys are skipped.
+    pub async fn query_index(
+        &self,
+        token: &str,
+        name: &str,
+        query: &IndexQuery,
+        cursor: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<IndexPage> {
+        let limit = limit.unwrap_or(DEFAULT_INDEX_QUERY_LIMIT);
+        if limit == 0 || limit > MAX_INDEX_QUERY_LIMIT {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Limit must be between 1 and {}",
+                MAX_INDEX_QUERY_LIMIT
+            )));
+        }
+        let offset: u64 = match cursor {
+            Some(cursor) => cursor
+                .parse()
+                .map_err(|_| KVStoreError::InvalidRequest(format!("Invalid cursor: {}", cursor)))?,
+            None => 0,
+        };
+
+        let indexes_key = indexes_key(token);
+        let mut conn = self.conn.clone();
+        let exists: bool = conn.hexists(&indexes_key, name).await.map_err(|e| {
+            tracing::error!("Failed to get index {}: {}", name, e);
+            e
+        })?;
+        if !exists {
+            return Err(KVStoreError::KeyNotFound(name.to_string()));
+        }
+
+        let Some((kind, min, max)) = query_bounds(query)? else {
+            return Ok(IndexPage::default());
+        };
+        tracing::debug!(
+            "QUERY INDEX {} {} {} {} at {}",
+            name,
+            kind,
+            min,
+            max,
+            offset
+        );
+
+        let (next, keys): (i64, Vec<String>) = index_script(QUERY_SCRIPT)
+            .key(&indexes_key)
+            .arg(name)
+            .arg(kind)
+            .arg(min)
+            .arg(max)
+            .arg(offset)
+            .arg(limit)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to query index {}: {}", name, e);
+                e
+            })?;
+
+        let prefix_len = token.len() + 1; // +1 for the colon
+        Ok(IndexPage {
+            keys: keys
+                .into_iter()
+                .map(|key| key[prefix_len..].to_string())
+                .collect(),
+            cursor: (next >= 0).then(|| next.to_string()),
+        })
+    }
+}
+
+/// The Redis keys holding the data of index `name`
+fn index_data_keys(indexes_key: &str, name: &str) -> [String; 3] {
+    ["eq", "num", "entries"].map(|kind| format!("{}:{}:{}", indexes_key, name, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("Index", "by-status_2").is_ok());
+        assert!(check_name("Index", "").is_err());
+        assert!(check_name("Index", "by status").is_err());
+        assert!(check_name("Index", &"n".repeat(MAX_NAME_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_field() {
+        assert_eq!(parse_field("status").unwrap(), vec!["status"]);
+        assert_eq!(
+            parse_field("order.status").unwrap(),
+            vec!["order", "status"]
+        );
+        assert!(parse_field("order..status").is_err());
+        assert!(parse_field("").is_err());
+    }
+
+    #[test]
+    fn test_query_bounds() {
+        assert_eq!(
+            query_bounds(&IndexQuery::Equals(json!("pending"))).unwrap(),
+            Some((
+                "eq",
+                "[s:pending\0".to_string(),
+                "(s:pending\u{1}".to_string()
+            ))
+        );
+        assert_eq!(
+            query_bounds(&IndexQuery::Equals(json!(42))).unwrap(),
+            Some(("num", "42".to_string(), "42".to_string()))
+        );
+        assert_eq!(
+            query_bounds(&IndexQuery::Range {
+                min: Some(1.5),
+                max: None
+            })
+            .unwrap(),
+            Some(("num", "1.5".to_string(), "+inf".to_string()))
+        );
+        assert_eq!(
+            query_bounds(&IndexQuery::Equals(json!("a\u{0}b"))).unwrap(),
+            None
+        );
+        assert!(query_bounds(&IndexQuery::Equals(json!({"a": 1}))).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! JSON document values
+//!
+//! Documents are stored as JSON text under the regular key and flagged with
+//! the JSON content type in the key's metadata hash, so they can be queried by
+//! path and patched in place.
+
+use super::history::{history_key, history_policy_key};
+use super::index::indexes_key;
+use super::metadata::{add_namespace, value_script};
+use super::schema::validate_against;
+use super::{meta_key, namespaced_key, redis_error, Entry, SetOptions};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use redis::AsyncCommands;
+use serde_json::Value;
+use serde_json_path::JsonPath;
+
+/// Content type reported for values written as JSON documents
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// How many times a patch is retried when the document changes underneath it
+const MAX_PATCH_ATTEMPTS: usize = 16;
+
+/// Replace the document only if it still holds the value the patch was computed from
+///
+/// Creation time, TTL and tags are kept; modification time, size and version
+/// are updated.
+///
+/// KEYS[1] - value key, KEYS[2] - metadata key, KEYS[3] - history key,
+/// KEYS[4] - history policy key, KEYS[5] - index definitions key
+/// ARGV[1] - expected current value, ARGV[2] - new value, ARGV[3] - content type
+const COMPARE_AND_SET_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'KEEPTTL')
+touch_value(KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], ARGV[3])
+return 1
+"#;
+
+/// The kind of patch document passed to [`KVStore::patch_json`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// RFC 6902 JSON Patch: an array of operations
+    JsonPatch,
+    /// RFC 7386 JSON Merge Patch: a partial document
+    MergePatch,
+}
+
+/// Parse a stored value as a JSON document
+fn parse_document(key: &str, value: &str) -> Result<Value> {
+    serde_json::from_str(value).map_err(|_| {
+        KVStoreError::InvalidRequest(format!("Value at {} is not a JSON document", key))
+    })
+}
+
+/// Select the nodes of `document` matched by the JSONPath expression `path`
+///
+/// A path matching exactly one node returns that node; a path matching
+/// several nodes returns them as an array.
+pub(crate) fn query_path(key: &str, document: &Value, path: &str) -> Result<Value> {
+    let path = JsonPath::parse(path)
+        .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid JSON path: {}", e)))?;
+
+    let nodes = path.query(document);
+    match nodes.len() {
+        0 => Err(KVStoreError::KeyNotFound(format!("{} at {}", key, path))),
+        1 => Ok(nodes.exactly_one().cloned().unwrap_or(Value::Null)),
+        _ => Ok(Value::Array(nodes.all().into_iter().cloned().collect())),
+    }
+}
+
+/// Parse `value` as a JSON document and optionally select a JSONPath from it
+fn select(key: &str, value: &str, path: Option<&str>) -> Result<Value> {
+    let document = parse_document(key, value)?;
+    match path {
+        Some(path) => query_path(key, &document, path),
+        None => Ok(document),
+    }
+}
+
+impl Entry {
+    /// Interpret the value as a JSON document
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key the entry was read from, used in error messages
+    /// * `path` - Optional JSONPath selecting part of the document
+    pub fn json(&self, key: &str, path: Option<&str>) -> Result<Value> {
+        select(key, &self.value, path)
+    }
+}
+
+/// Apply `patch` to `document`
+pub(crate) fn apply_patch(document: &mut Value, patch: &Value, format: PatchFormat) -> Result<()> {
+    match format {
+        PatchFormat::JsonPatch => {
+            let patch: json_patch::Patch = serde_json::from_value(patch.clone())
+                .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid JSON Patch: {}", e)))?;
+            json_patch::patch(document, &patch)
+                .map_err(|e| KVStoreError::InvalidRequest(format!("Patch failed: {}", e)))
+        }
+        PatchFormat::MergePatch => {
+            json_patch::merge(document, patch);
+            Ok(())
+        }
+    }
+}
+
+impl KVStore {
+    /// Store a JSON document
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Authentication token (used as namespace prefix)
+    /// * `key` - The key to set
+    /// * `document` - Any JSON value
+    /// * `ttl_seconds` - Optional TTL in seconds
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or [`KVStoreError::InvalidRequest`] if the document
+    /// violates the JSON Schema registered for the key's prefix
+    pub async fn set_json(
+        &self,
+        token: &str,
+        key: &str,
+        document: &Value,
+        ttl_seconds: Option<i64>,
+    ) -> Result<()> {
+        let text = serde_json::to_string(document)
+            .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid document: {}", e)))?;
+
+        self.set_with_options(
+            token,
+            key,
+            &text,
+            &SetOptions {
+                ttl_seconds,
+                content_type: Some(JSON_CONTENT_TYPE.to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Get a JSON document, or the part of it selected by a JSONPath expression
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Optional JSONPath (RFC 9535), e.g. `$.user.name`
+    ///
+    /// # Returns
+    ///
+    /// The selected value. Paths matching several nodes return them as an
+    /// array; paths matching nothing return [`KVStoreError::KeyNotFound`], and
+    /// malformed paths or non-JSON values return [`KVStoreError::InvalidRequest`].
+    pub async fn get_json(&self, token: &str, key: &str, path: Option<&str>) -> Result<Value> {
+        self.get(token, key)
+            .await
+            .and_then(|value| select(key, &value, path))
+    }
+
+    /// Atomically patch a JSON document
+    ///
+    /// The patch is applied to the current document and written back only if
+    /// no other writer changed the document in the meantime; conflicting
+    /// writes are retried. The key's TTL is preserved, and the patched document
+    /// must satisfy the JSON Schema registered for the key's prefix.
+    ///
+    /// # Returns
+    ///
+    /// The patched document
+    pub async fn patch_json(
+        &self,
+        token: &str,
+        key: &str,
+        patch: &Value,
+        format: PatchFormat,
+    ) -> Result<Value> {
+        let namespaced_key = namespaced_key(token, key);
+        let meta_key = meta_key(token, key);
+        let history_key = history_key(token, key);
+        let policy_key = history_policy_key(token);
+        let indexes_key = indexes_key(token);
+        tracing::debug!("PATCH JSON {} ({:?})", namespaced_key, format);
+
+        let schema = self.schema_for(token, key).await?;
+        let script = value_script(COMPARE_AND_SET_SCRIPT);
+        let mut conn = self.conn.clone();
+
+        for _ in 0..MAX_PATCH_ATTEMPTS {
+            let current: Option<String> = conn.get(&namespaced_key).await.map_err(|e| {
+                tracing::error!("Failed to get document {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+            let current = current.ok_or_else(|| KVStoreError::KeyNotFound(key.to_string()))?;
+
+            let mut document = parse_document(key, &current)?;
+            apply_patch(&mut document, patch, format)?;
+            validate_against(key, schema.as_ref(), &document)?;
+            let updated = serde_json::to_string(&document)
+                .map_err(|e| KVStoreError::Internal(format!("Failed to encode document: {}", e)))?;
+            self.policy.check_value(&updated)?;
+
+            let mut invocation = script.key(&namespaced_key);
+            invocation
+                .key(&meta_key)
+                .key(&history_key)
+                .key(&policy_key)
+                .key(&indexes_key)
+                .arg(&current)
+                .arg(&updated)
+                .arg(JSON_CONTENT_TYPE);
+            add_namespace(&mut invocation, token);
+            let applied: bool = invocation.invoke_async(&mut conn).await.map_err(|e| {
+                tracing::error!("Failed to patch document {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+            if applied {
+                return Ok(document);
+            }
+
+            tracing::debug!("Document {} changed during patch, retrying", namespaced_key);
+        }
+
+        Err(KVStoreError::Conflict(format!(
+            "{} is being modified concurrently",
+            key
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_query_path_single_and_multiple() {
+        let document = json!({"user": {"name": "Alice", "tags": ["a", "b"]}});
+
+        assert_eq!(
+            query_path("k", &document, "$.user.name").unwrap(),
+            json!("Alice")
+        );
+        assert_eq!(
+            query_path("k", &document, "$.user.tags[*]").unwrap(),
+            json!(["a", "b"])
+        );
+    }
+
+    #[test]
+    fn test_query_path_errors() {
+        let document = json!({"user": {"name": "Alice"}});
+
+        assert!(matches!(
+            query_path("k", &document, "user.name"),
+            Err(KVStoreError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            query_path("k", &document, "$.user.email"),
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let mut document = json!({"user": {"name": "Alice", "age": 30}});
+
+        apply_patch(
+            &mut document,
+            &json!([{"op": "replace", "path": "/user/name", "value": "Bob"}]),
+            PatchFormat::JsonPatch,
+        )
+        .unwrap();
+        assert_eq!(document["user"]["name"], "Bob");
+
+        apply_patch(
+            &mut document,
+            &json!({"user": {"age": null}}),
+            PatchFormat::MergePatch,
+        )
+        .unwrap();
+        assert_eq!(document, json!({"user": {"name": "Bob"}}));
+
+        let result = apply_patch(
+            &mut document,
+            &json!([{"op": "remove", "path": "/missing"}]),
+            PatchFormat::JsonPatch,
+        );
+        assert!(matches!(result, Err(KVStoreError::InvalidRequest(_))));
+    }
+}
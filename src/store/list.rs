@@ -0,0 +1,343 @@
+//! List-valued keys
+//!
+//! Stores ordered sequences as Redis lists so they can be used as lightweight
+//! per-namespace queues.
+
+use super::watch::CollectionWrite;
+use super::{internal_key, namespaced_key, redis_error};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use redis::AsyncCommands;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+/// Longest time a blocking pop may wait for an item
+pub const MAX_BLOCKING_POP_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The end of a list an operation applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListEnd {
+    /// The head of the list
+    #[default]
+    Left,
+    /// The tail of the list
+    Right,
+}
+
+impl std::str::FromStr for ListEnd {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "left" => Ok(ListEnd::Left),
+            "right" => Ok(ListEnd::Right),
+            _ => Err(KVStoreError::InvalidRequest(format!(
+                "Invalid list end: {}. Must be one of: left, right",
+                s
+            ))),
+        }
+    }
+}
+
+impl ListEnd {
+    fn as_arg(self) -> &'static str {
+        match self {
+            ListEnd::Left => "LEFT",
+            ListEnd::Right => "RIGHT",
+        }
+    }
+}
+
+/// A blocking pop that has not yet handed its value back to the caller
+///
+/// Dropping it cleans up the staging list: once delivered the staged value is
+/// discarded and the pop reported as a change of the list, otherwise the
+/// waiting connection is killed (so a pending `BLMOVE` can no longer take a
+/// value) and the staged value is returned to the list.
+struct PendingPop {
+    store: KVStore,
+    client_id: i64,
+    staging: String,
+    token: String,
+    key: String,
+    end: ListEnd,
+    delivered: bool,
+    popped: bool,
+}
+
+impl Drop for PendingPop {
+    fn drop(&mut self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let store = self.store.clone();
+        let client_id = self.client_id;
+        let staging = std::mem::take(&mut self.staging);
+        let token = std::mem::take(&mut self.token);
+        let key = std::mem::take(&mut self.key);
+        let end = self.end;
+        let (delivered, popped) = (self.delivered, self.popped);
+
+        runtime.spawn(async move {
+            let mut conn = store.conn.clone();
+            let result: redis::RedisResult<()> = async {
+                if delivered {
+                    conn.del::<_, ()>(&staging).await?;
+                    if popped {
+                        store
+                            .write_collection::<()>(
+                                &token,
+                                &key,
+                                CollectionWrite::Always,
+                                None,
+                                Vec::<String>::new(),
+                            )
+                            .await?;
+                    }
+                    return Ok(());
+                }
+                let _: redis::RedisResult<()> = redis::cmd("CLIENT")
+                    .arg("KILL")
+                    .arg("ID")
+                    .arg(client_id)
+                    .query_async(&mut conn)
+                    .await;
+                let _: Option<String> = redis::cmd("LMOVE")
+                    .arg(&staging)
+                    .arg(namespaced_key(&token, &key))
+                    .arg("LEFT")
+                    .arg(end.as_arg())
+                    .query_async(&mut conn)
+                    .await?;
+                Ok(())
+            }
+            .await;
+            if let Err(e) = result {
+                tracing::error!("Failed to clean up blocking pop of {}: {}", key, e);
+            }
+        });
+    }
+}
+
+impl KVStore {
+    /// Push one or more values onto a list, creating the list if needed
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Authentication token (used as namespace prefix)
+    /// * `key` - The list key
+    /// * `values` - Values to push, in order
+    /// * `end` - Which end of the list to push onto
+    ///
+    /// # Returns
+    ///
+    /// The length of the list after the push
+    pub async fn list_push(
+        &self,
+        token: &str,
+        key: &str,
+        values: &[String],
+        end: ListEnd,
+    ) -> Result<usize> {
+        if values.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "At least one value is required".to_string(),
+            ));
+        }
+        self.policy.check_key(key)?;
+        self.policy
+            .check_values(values.iter().map(String::as_str))?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!(
+            "PUSH {:?} {} ({} values)",
+            end,
+            namespaced_key,
+            values.len()
+        );
+
+        let command = match end {
+            ListEnd::Left => "LPUSH",
+            ListEnd::Right => "RPUSH",
+        };
+        self.write_collection(token, key, CollectionWrite::Always, Some(command), values)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to push onto {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })
+    }
+
+    /// Pop up to `count` values from a list
+    ///
+    /// # Returns
+    ///
+    /// The popped values, or an empty vector if the list doesn't exist
+    pub async fn list_pop(
+        &self,
+        token: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        let count = NonZeroUsize::new(count).ok_or_else(|| {
+            KVStoreError::InvalidRequest("Count must be greater than zero".to_string())
+        })?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("POP {:?} {} (count: {})", end, namespaced_key, count);
+
+        let command = match end {
+            ListEnd::Left => "LPOP",
+            ListEnd::Right => "RPOP",
+        };
+        let result: redis::RedisResult<Option<Vec<String>>> = self
+            .write_collection(
+                token,
+                key,
+                CollectionWrite::Counted,
+                Some(command),
+                count.get(),
+            )
+            .await;
+
+        let values = result.map_err(|e| {
+            tracing::error!("Failed to pop from {}: {}", namespaced_key, e);
+            redis_error(key, e)
+        })?;
+
+        Ok(values.unwrap_or_default())
+    }
+
+    /// Pop a single value, waiting up to `timeout` for one to arrive
+    ///
+    /// Waits with `BLMOVE` on a dedicated connection so other operations on
+    /// this store are not held up. The value is moved to a staging list rather
+    /// than removed outright: if the returned future is dropped before the
+    /// value is handed back (e.g. because the caller disconnected), the waiting
+    /// connection is killed and anything staged is pushed back onto the end of
+    /// the list it came from. A value that has been returned is considered
+    /// delivered; callers that lose it afterwards cannot get it back.
+    ///
+    /// # Returns
+    ///
+    /// The popped value, or `None` if the timeout elapsed first
+    pub async fn list_blocking_pop(
+        &self,
+        token: &str,
+        key: &str,
+        end: ListEnd,
+        timeout: Duration,
+    ) -> Result<Option<String>> {
+        if timeout.is_zero() || timeout > MAX_BLOCKING_POP_TIMEOUT {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Timeout must be between 0 and {} seconds",
+                MAX_BLOCKING_POP_TIMEOUT.as_secs()
+            )));
+        }
+
+        let client = self.client.as_ref().ok_or_else(|| {
+            KVStoreError::Internal(
+                "Blocking operations require a KVStore created with KVStore::new".to_string(),
+            )
+        })?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!(
+            "BLOCKING POP {:?} {} (timeout: {:?})",
+            end,
+            namespaced_key,
+            timeout
+        );
+
+        let mut blocking = client.get_multiplexed_async_connection().await?;
+        let client_id: i64 = redis::cmd("CLIENT")
+            .arg("ID")
+            .query_async(&mut blocking)
+            .await?;
+
+        let mut pending = PendingPop {
+            store: self.clone(),
+            client_id,
+            staging: internal_key(token, "popping", &client_id.to_string()),
+            token: token.to_string(),
+            key: key.to_string(),
+            end,
+            delivered: false,
+            popped: false,
+        };
+
+        let popped: Option<String> = redis::cmd("BLMOVE")
+            .arg(&namespaced_key)
+            .arg(&pending.staging)
+            .arg(end.as_arg())
+            .arg("LEFT")
+            .arg(timeout.as_secs_f64())
+            .query_async(&mut blocking)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to blocking pop from {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        pending.delivered = true;
+        pending.popped = popped.is_some();
+        Ok(popped)
+    }
+
+    /// Get a range of values from a list
+    ///
+    /// `start` and `stop` are inclusive, zero-based indexes; negative indexes
+    /// count from the tail (`-1` is the last element).
+    pub async fn list_range(
+        &self,
+        token: &str,
+        key: &str,
+        start: isize,
+        stop: isize,
+    ) -> Result<Vec<String>> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("LRANGE {} {} {}", namespaced_key, start, stop);
+
+        let mut conn = self.conn.clone();
+        let values: Vec<String> = conn
+            .lrange(&namespaced_key, start, stop)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to read range of {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(values)
+    }
+
+    /// Get the length of a list
+    ///
+    /// # Returns
+    ///
+    /// The number of values in the list, or zero if it doesn't exist
+    pub async fn list_length(&self, token: &str, key: &str) -> Result<usize> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("LLEN {}", namespaced_key);
+
+        let mut conn = self.conn.clone();
+        let length: usize = conn.llen(&namespaced_key).await.map_err(|e| {
+            tracing::error!("Failed to get length of {}: {}", namespaced_key, e);
+            redis_error(key, e)
+        })?;
+
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_end_from_str() {
+        assert_eq!("left".parse::<ListEnd>().unwrap(), ListEnd::Left);
+        assert_eq!("right".parse::<ListEnd>().unwrap(), ListEnd::Right);
+        assert!("middle".parse::<ListEnd>().is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! Distributed locks with leases and fencing tokens
+//!
+//! A lock is held by one owner at a time for a lease that expires unless it
+//! is renewed, so a crashed holder can't keep a lock forever. Every
+//! acquisition gets a fencing token that is greater than that of every
+//! earlier acquisition of the same lock; resources guarded by the lock should
+//! reject writes carrying a smaller token than one they have already seen,
+//! which keeps a holder whose lease ran out while it was paused from doing
+//! damage. Renewing and releasing check the owner atomically in Lua.
+
+use super::{internal_key, INTERNAL_PREFIX};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use std::time::{Duration, Instant};
+
+/// Longest lease a lock may be acquired or renewed for
+pub const MAX_LOCK_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest time an acquisition may wait for a lock to become free
+pub const MAX_LOCK_WAIT: Duration = Duration::from_secs(300);
+
+/// How often a waiting acquisition checks whether the lock became free
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Acquire a lock, or extend the lease of its current owner
+///
+/// KEYS[1] - lock hash, KEYS[2] - hash of fencing token counters
+/// ARGV[1] - owner, ARGV[2] - lease in milliseconds, ARGV[3] - lock name
+/// Returns {acquired, owner, fencing token, remaining lease in milliseconds}
+/// with the current holder if the lock is held by someone else
+const ACQUIRE_SCRIPT: &str = r#"
+local holder = redis.call('HGET', KEYS[1], 'owner')
+if holder and holder ~= ARGV[1] then
+    return {0, holder, tonumber(redis.call('HGET', KEYS[1], 'fence')), redis.call('PTTL', KEYS[1])}
+end
+local fence
+if holder then
+    fence = tonumber(redis.call('HGET', KEYS[1], 'fence'))
+else
+    fence = redis.call('HINCRBY', KEYS[2], ARGV[3], 1)
+    redis.call('HSET', KEYS[1], 'owner', ARGV[1], 'fence', fence)
+end
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return {1, ARGV[1], fence, tonumber(ARGV[2])}
+"#;
+
+/// Extend the lease of a lock if `owner` holds it
+///
+/// KEYS[1] - lock hash
+/// ARGV[1] - owner, ARGV[2] - lease in milliseconds
+/// Returns {renewed, owner, fencing token, remaining lease in milliseconds},
+/// with an empty owner if the lock is free
+const RENEW_SCRIPT: &str = r#"
+local holder = redis.call('HGET', KEYS[1], 'owner')
+if not holder then
+    return {0, '', 0, 0}
+end
+local fence = tonumber(redis.call('HGET', KEYS[1], 'fence'))
+if holder ~= ARGV[1] then
+    return {0, holder, fence, redis.call('PTTL', KEYS[1])}
+end
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return {1, holder, fence, tonumber(ARGV[2])}
+"#;
+
+/// Release a lock if `owner` holds it
+///
+/// KEYS[1] - lock hash
+/// ARGV[1] - owner
+/// Returns the owner the lock was held by, empty if it was free
+const RELEASE_SCRIPT: &str = r#"
+local holder = redis.call('HGET', KEYS[1], 'owner')
+if not holder then
+    return ''
+end
+if holder == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+end
+return holder
+"#;
+
+/// The lease of a lock held by an owner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockLease {
+    pub name: String,
+    pub owner: String,
+    /// Greater than the fencing token of every earlier acquisition of the lock
+    pub fencing_token: u64,
+    /// Time left before the lease expires
+    pub ttl: Duration,
+}
+
+/// Build the Redis key of the hash counting acquisitions of `token`'s locks
+fn fences_key(token: &str) -> String {
+    format!("{}:{}:lock_fences", INTERNAL_PREFIX, token)
+}
+
+fn lock_key(token: &str, name: &str) -> String {
+    internal_key(token, "lock", name)
+}
+
+fn check_owner(owner: &str) -> Result<()> {
+    if owner.is_empty() {
+        return Err(KVStoreError::InvalidRequest(
+            "Lock owner must not be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn check_ttl(ttl: Duration) -> Result<()> {
+    if ttl.as_millis() == 0 || ttl > MAX_LOCK_TTL {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Lease must be between 1 millisecond and {} seconds",
+            MAX_LOCK_TTL.as_secs()
+        )));
+    }
+    Ok(())
+}
+
+impl KVStore {
+    /// Acquire a lock for `owner` with a lease of `ttl`
+    ///
+    /// Acquiring a lock the owner already holds extends its lease and keeps
+    /// its fencing token. While another owner holds the lock this waits up to
+    /// `wait` for it to be released or to expire; pass `Duration::ZERO` to
+    /// fail fast.
+    ///
+    /// # Returns
+    ///
+    /// The lease, or [`KVStoreError::Conflict`] if the lock was still held by
+    /// someone else once `wait` elapsed
+    pub async fn acquire_lock(
+        &self,
+        token: &str,
+        name: &str,
+        owner: &str,
+        ttl: Duration,
+        wait: Duration,
+    ) -> Result<LockLease> {
+        self.policy.check_key(name)?;
+        check_owner(owner)?;
+        check_ttl(ttl)?;
+        if wait > MAX_LOCK_WAIT {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Wait must be at most {} seconds",
+                MAX_LOCK_WAIT.as_secs()
+            )));
+        }
+
+        let key = lock_key(token, name);
+        tracing::debug!(
+            "LOCK ACQUIRE {} by {} (ttl: {:?}, wait: {:?})",
+            key,
+            owner,
+            ttl,
+            wait
+        );
+
+        let deadline = Instant::now() + wait;
+        let script = redis::Script::new(ACQUIRE_SCRIPT);
+        let mut conn = self.conn.clone();
+        loop {
+            let (acquired, holder, fencing_token, remaining): (bool, String, u64, i64) = script
+                .key(&key)
+                .key(fences_key(token))
+                .arg(owner)
+                .arg(ttl.as_millis() as u64)
+                .arg(name)
+                .invoke_async(&mut conn)
+                .await?;
+
+            if acquired {
+                return Ok(LockLease {
+                    name: name.to_string(),
+                    owner: holder,
+                    fencing_token,
+                    ttl,
+                });
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(KVStoreError::Conflict(format!(
+                    "Lock {} is held by {}",
+                    name, holder
+                )));
+            }
+            // No point checking again before the holder's lease runs out,
+            // unless it releases the lock first
+            let expiry = Duration::from_millis(remaining.max(1) as u64);
+            tokio::time::sleep(LOCK_RETRY_INTERVAL.min(expiry).min(left)).await;
+        }
+    }
+
+    /// Extend the lease of a lock `owner` holds to `ttl` from now
+    ///
+    /// # Returns
+    ///
+    /// The renewed lease, or [`KVStoreError::Conflict`] if `owner` doesn't
+    /// hold the lock, e.g. because its lease already expired
+    pub async fn renew_lock(
+        &self,
+        token: &str,
+        name: &str,
+        owner: &str,
+        ttl: Duration,
+    ) -> Result<LockLease> {
+        check_owner(owner)?;
+        check_ttl(ttl)?;
+
+        let key = lock_key(token, name);
+        tracing::debug!("LOCK RENEW {} by {} (ttl: {:?})", key, owner, ttl);
+
+        let mut conn = self.conn.clone();
+        let (renewed, holder, fencing_token, _): (bool, String, u64, i64) =
+            redis::Script::new(RENEW_SCRIPT)
+                .key(&key)
+                .arg(owner)
+                .arg(ttl.as_millis() as u64)
+                .invoke_async(&mut conn)
+                .await?;
+
+        if !renewed {
+            return Err(not_held(name, owner, &holder));
+        }
+        Ok(LockLease {
+            name: name.to_string(),
+            owner: holder,
+            fencing_token,
+            ttl,
+        })
+    }
+
+    /// Release a lock `owner` holds
+    ///
+    /// # Returns
+    ///
+    /// [`KVStoreError::Conflict`] if `owner` doesn't hold the lock, leaving
+    /// any other holder's lease untouched
+    pub async fn release_lock(&self, token: &str, name: &str, owner: &str) -> Result<()> {
+        check_owner(owner)?;
+
+        let key = lock_key(token, name);
+        tracing::debug!("LOCK RELEASE {} by {}", key, owner);
+
+        let mut conn = self.conn.clone();
+        let holder: String = redis::Script::new(RELEASE_SCRIPT)
+            .key(&key)
+            .arg(owner)
+            .invoke_async(&mut conn)
+            .await?;
+
+        if holder != owner {
+            return Err(not_held(name, owner, &holder));
+        }
+        Ok(())
+    }
+
+    /// Get the current lease of a lock, or `None` if it is free
+    pub async fn lock_holder(&self, token: &str, name: &str) -> Result<Option<LockLease>> {
+        let key = lock_key(token, name);
+
+        let mut conn = self.conn.clone();
+        let (owner, fencing_token, remaining): (Option<String>, Option<u64>, i64) = redis::pipe()
+            .hget(&key, "owner")
+            .hget(&key, "fence")
+            .pttl(&key)
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(match (owner, fencing_token) {
+            (Some(owner), Some(fencing_token)) if remaining > 0 => Some(LockLease {
+                name: name.to_string(),
+                owner,
+                fencing_token,
+                ttl: Duration::from_millis(remaining as u64),
+            }),
+            _ => None,
+        })
+    }
+}
+
+fn not_held(name: &str, owner: &str, holder: &str) -> KVStoreError {
+    if holder.is_empty() {
+        KVStoreError::Conflict(format!("Lock {} is not held", name))
+    } else {
+        KVStoreError::Conflict(format!(
+            "Lock {} is held by {}, not {}",
+            name, holder, owner
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_ttl() {
+        assert!(check_ttl(Duration::from_secs(30)).is_ok());
+        assert!(check_ttl(Duration::ZERO).is_err());
+        assert!(check_ttl(Duration::from_micros(500)).is_err());
+        assert!(check_ttl(MAX_LOCK_TTL + Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_not_held() {
+        assert_eq!(
+            not_held("jobs", "a", "").to_string(),
+            "Conflict: Lock jobs is not held"
+        );
+        assert_eq!(
+            not_held("jobs", "a", "b").to_string(),
+            "Conflict: Lock jobs is held by b, not a"
+        );
+    }
+}
@@ -0,0 +1,495 @@
+//! Per-key metadata
+//!
+//! Every string or document write records its content type, creation and
+//! modification times, size and user-supplied tags in a metadata hash kept
+//! alongside the value. The hash shares the value's TTL, so both expire
+//! together.
+
+use super::history::{history_key, history_policy_key, RECORD_REVISION_LUA};
+use super::index::{indexes_key, INDEX_LUA};
+use super::trash::{trash_index_key, trash_policy_key, TRASH_LUA};
+use super::watch::{change_log_key, changes_channel, CHANGES_LUA};
+use super::webhook::{webhook_queue_key, webhooks_key, WEBHOOK_LUA};
+use super::{meta_key, namespaced_key, redis_error, JSON_CONTENT_TYPE, TEXT_CONTENT_TYPE};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Prefix of the metadata hash fields holding user-supplied tags
+const TAG_FIELD_PREFIX: &str = "tag:";
+
+/// Lua functions changing values while maintaining their metadata, history
+/// and indexes
+///
+/// Timestamps come from the Redis clock so that every server instance agrees
+/// on them. Every change bumps the key's version, which starts at 1 when the
+/// key is created. Scripts are built with [`value_script`].
+const VALUE_LUA: &str = r#"
+local function now_millis()
+    local time = redis.call('TIME')
+    return string.format('%d', time[1] * 1000 + math.floor(time[2] / 1000))
+end
+
+-- Replace a value. The creation time survives overwrites of a live key;
+-- content type, size and tags always describe the latest write.
+-- ttl is in milliseconds (0 for none), tags a flat list of name/value pairs.
+local function write_value(value_key, meta_key, history_key, policy_key, indexes_key, value, ttl, content_type, tags)
+    local now = now_millis()
+    local created = now
+    local version = 1
+    if redis.call('EXISTS', value_key) == 1 then
+        created = redis.call('HGET', meta_key, 'created_at')
+        version = (tonumber(redis.call('HGET', meta_key, 'version')) or 0) + 1
+    end
+
+    if ttl > 0 then
+        redis.call('SET', value_key, value, 'PX', ttl)
+    else
+        redis.call('SET', value_key, value)
+    end
+
+    redis.call('DEL', meta_key)
+    redis.call('HSET', meta_key, 'content_type', content_type, 'updated_at', now,
+        'size', string.len(value), 'version', version)
+    if created then
+        redis.call('HSET', meta_key, 'created_at', created)
+    end
+    for i = 1, #tags, 2 do
+        redis.call('HSET', meta_key, 'tag:' .. tags[i], tags[i + 1])
+    end
+    if ttl > 0 then
+        redis.call('PEXPIRE', meta_key, ttl)
+    end
+
+    record_revision(history_key, policy_key, 'set', value, content_type)
+    update_indexes(indexes_key, value_key, value)
+    publish_change('put', value_key, meta_key, value, version, content_type)
+    return version
+end
+
+-- Record an in-place change of a value, keeping its TTL, creation time and
+-- tags. content_type may be false to keep the current one.
+local function touch_value(value_key, meta_key, history_key, policy_key, indexes_key, content_type)
+    local value = redis.call('GET', value_key)
+    content_type = content_type or redis.call('HGET', meta_key, 'content_type') or 'text/plain'
+    local version = (tonumber(redis.call('HGET', meta_key, 'version')) or 0) + 1
+
+    redis.call('HSET', meta_key, 'content_type', content_type, 'updated_at', now_millis(),
+        'size', string.len(value), 'version', version)
+    local ttl = redis.call('PTTL', value_key)
+    if ttl > 0 then
+        redis.call('PEXPIRE', meta_key, ttl)
+    end
+
+    record_revision(history_key, policy_key, 'set', value, content_type)
+    update_indexes(indexes_key, value_key, value)
+    publish_change('put', value_key, meta_key, value, version, content_type)
+    return version
+end
+
+-- Delete a value and its metadata, recording the deletion of string values.
+-- Given trash keys, the value goes to the trash if the namespace keeps one.
+local function delete_value(value_key, meta_key, history_key, policy_key, indexes_key, trash_key, trash_meta)
+    local is_string = redis.call('TYPE', value_key).ok == 'string'
+    local version = tonumber(redis.call('HGET', meta_key, 'version'))
+    local deleted = 1
+    if not (trash_key and trash_value(value_key, meta_key, trash_key, trash_meta)) then
+        deleted = redis.call('UNLINK', value_key)
+        redis.call('UNLINK', meta_key)
+    end
+    if is_string then
+        record_revision(history_key, policy_key, 'delete', '', '')
+        update_indexes(indexes_key, value_key, false)
+    end
+    if deleted == 1 then
+        publish_change('delete', value_key, meta_key, nil, version)
+    end
+    return deleted
+end
+
+-- Copy a value, its TTL and its metadata to another key, optionally removing
+-- the source. The destination's version continues from its previous value.
+-- expected_version may be '' to skip checking the source's version.
+-- Returns 'ok', or 'missing', 'changed' or 'exists' without changing anything.
+local function move_value(src_key, src_meta, src_history, dst_key, dst_meta, dst_history, policy_key, indexes_key, remove_source, overwrite, expected_version)
+    if redis.call('EXISTS', src_key) == 0 then
+        return 'missing'
+    end
+    if expected_version ~= '' and (redis.call('HGET', src_meta, 'version') or '0') ~= expected_version then
+        return 'changed'
+    end
+    local version = 1
+    if redis.call('EXISTS', dst_key) == 1 then
+        if not overwrite then
+            return 'exists'
+        end
+        version = (tonumber(redis.call('HGET', dst_meta, 'version')) or 0) + 1
+    end
+
+    local is_string = redis.call('TYPE', src_key).ok == 'string'
+    redis.call('COPY', src_key, dst_key, 'REPLACE')
+    redis.call('UNLINK', dst_meta)
+    if redis.call('COPY', src_meta, dst_meta) == 1 then
+        redis.call('HSET', dst_meta, 'version', version)
+    end
+    if is_string then
+        local value = redis.call('GET', dst_key)
+        local content_type = redis.call('HGET', dst_meta, 'content_type') or 'text/plain'
+        record_revision(dst_history, policy_key, 'set', value, content_type)
+        update_indexes(indexes_key, dst_key, value)
+        publish_change('put', dst_key, dst_meta, value, version, content_type)
+    else
+        update_indexes(indexes_key, dst_key, false)
+        publish_change('put', dst_key, dst_meta, nil, version)
+    end
+
+    if remove_source then
+        delete_value(src_key, src_meta, src_history, policy_key, indexes_key, false, false)
+    end
+    return 'ok'
+end
+"#;
+
+/// Write a value and rebuild its metadata
+///
+/// KEYS[1] - value key, KEYS[2] - metadata key, KEYS[3] - history key,
+/// KEYS[4] - history policy key, KEYS[5] - index definitions key
+/// ARGV[1] - value, ARGV[2] - TTL in milliseconds (0 for none),
+/// ARGV[3] - content type, ARGV[4..] - tag name/value pairs
+const WRITE_SCRIPT: &str = r#"
+local tags = {}
+for i = 4, ARG_COUNT do
+    tags[#tags + 1] = ARGV[i]
+end
+return write_value(KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], ARGV[1], tonumber(ARGV[2]), ARGV[3], tags)
+"#;
+
+/// Lua prelude reading the namespace keys and arguments passed by
+/// [`add_namespace`]
+///
+/// They come after a script's own keys and arguments, so scripts count their
+/// own with `KEY_COUNT` and `ARG_COUNT` rather than `#KEYS` and `#ARGV`.
+pub(super) const NAMESPACE_LUA: &str = r#"
+local KEY_COUNT = #KEYS - 5
+local ARG_COUNT = #ARGV - 2
+local namespace = {
+    change_log = KEYS[KEY_COUNT + 1],
+    webhooks = KEYS[KEY_COUNT + 2],
+    webhook_queue = KEYS[KEY_COUNT + 3],
+    trash_policy = KEYS[KEY_COUNT + 4],
+    trash = KEYS[KEY_COUNT + 5],
+    token = ARGV[ARG_COUNT + 1],
+    channel = ARGV[ARG_COUNT + 2],
+}
+"#;
+
+/// Pass the keys and arguments of `token`'s namespace that the Lua value
+/// functions use
+///
+/// Every invocation of a script built with [`value_script`] needs them, added
+/// after all of the script's own keys and arguments.
+pub(super) fn add_namespace(invocation: &mut redis::ScriptInvocation<'_>, token: &str) {
+    invocation
+        .key(change_log_key(token))
+        .key(webhooks_key(token))
+        .key(webhook_queue_key())
+        .key(trash_policy_key(token))
+        .key(trash_index_key(token))
+        .arg(token)
+        .arg(changes_channel(token));
+}
+
+/// Build a script whose `body` may call the value, history, index, change
+/// notification, webhook and trash Lua functions
+///
+/// Invocations must pass the namespace with [`add_namespace`].
+pub(super) fn value_script(body: &str) -> redis::Script {
+    redis::Script::new(
+        &[
+            NAMESPACE_LUA,
+            RECORD_REVISION_LUA,
+            INDEX_LUA,
+            WEBHOOK_LUA,
+            CHANGES_LUA,
+            TRASH_LUA,
+            VALUE_LUA,
+            body,
+        ]
+        .concat(),
+    )
+}
+
+/// Metadata recorded for a key
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Metadata {
+    /// Content type given on write, [`TEXT_CONTENT_TYPE`] by default
+    pub content_type: String,
+    /// When the key was first written, in milliseconds since the Unix epoch
+    ///
+    /// `None` for keys written before metadata was recorded.
+    pub created_at: Option<u64>,
+    /// When the value last changed, in milliseconds since the Unix epoch
+    pub updated_at: Option<u64>,
+    /// Size of the stored value in bytes
+    pub size: Option<u64>,
+    /// Number of changes since the key was created, starting at 1
+    ///
+    /// `0` for keys written before versions were recorded.
+    pub version: u64,
+    /// User-supplied tags
+    pub tags: HashMap<String, String>,
+}
+
+impl Metadata {
+    /// Build metadata from the fields of a metadata hash
+    pub(super) fn from_fields(fields: HashMap<String, String>) -> Self {
+        let mut metadata = Metadata {
+            content_type: TEXT_CONTENT_TYPE.to_string(),
+            ..Default::default()
+        };
+
+        for (field, value) in fields {
+            match field.as_str() {
+                "content_type" => metadata.content_type = value,
+                "created_at" => metadata.created_at = value.parse().ok(),
+                "updated_at" => metadata.updated_at = value.parse().ok(),
+                "size" => metadata.size = value.parse().ok(),
+                "version" => metadata.version = value.parse().unwrap_or_default(),
+                _ => {
+                    if let Some(tag) = field.strip_prefix(TAG_FIELD_PREFIX) {
+                        metadata.tags.insert(tag.to_string(), value);
+                    }
+                }
+            }
+        }
+
+        metadata
+    }
+}
+
+/// A stored value together with its metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The raw stored value (JSON text for documents)
+    pub value: String,
+    pub metadata: Metadata,
+}
+
+impl Entry {
+    /// Whether the value was written as a JSON document
+    pub fn is_json(&self) -> bool {
+        self.metadata.content_type == JSON_CONTENT_TYPE
+    }
+}
+
+/// Options for [`KVStore::set_with_options`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetOptions {
+    /// Optional TTL in seconds
+    pub ttl_seconds: Option<i64>,
+    /// Content type of the value, [`TEXT_CONTENT_TYPE`] when omitted
+    ///
+    /// Values written as [`JSON_CONTENT_TYPE`] must be valid JSON text and are
+    /// treated as JSON documents.
+    pub content_type: Option<String>,
+    /// Tags to record, replacing those of the previous write
+    pub tags: HashMap<String, String>,
+}
+
+/// Convert an optional TTL in seconds to the milliseconds passed to
+/// `write_value`, where 0 means no TTL
+pub(super) fn ttl_millis(ttl_seconds: Option<i64>) -> Result<i64> {
+    match ttl_seconds {
+        Some(ttl) if ttl <= 0 => Err(KVStoreError::InvalidRequest(
+            "TTL must be positive".to_string(),
+        )),
+        Some(ttl) => Ok(ttl.saturating_mul(1000)),
+        None => Ok(0),
+    }
+}
+
+impl KVStore {
+    /// Set a value together with its content type and tags
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Authentication token (used as namespace prefix)
+    /// * `key` - The key to set
+    /// * `value` - The value to store
+    /// * `options` - TTL, content type and tags
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or [`KVStoreError::InvalidRequest`] if the value
+    /// violates the JSON Schema registered for the key's prefix
+    pub async fn set_with_options(
+        &self,
+        token: &str,
+        key: &str,
+        value: &str,
+        options: &SetOptions,
+    ) -> Result<()> {
+        let content_type = options.content_type.as_deref().unwrap_or(TEXT_CONTENT_TYPE);
+        self.validate_write(token, key, value, content_type).await?;
+        let ttl_millis = ttl_millis(options.ttl_seconds)?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!(
+            "SET {} ({}, TTL: {:?}, {} tags)",
+            namespaced_key,
+            content_type,
+            options.ttl_seconds,
+            options.tags.len()
+        );
+
+        let script = value_script(WRITE_SCRIPT);
+        let mut invocation = script.key(&namespaced_key);
+        invocation
+            .key(meta_key(token, key))
+            .key(history_key(token, key))
+            .key(history_policy_key(token))
+            .key(indexes_key(token))
+            .arg(value)
+            .arg(ttl_millis)
+            .arg(content_type);
+        for (name, tag) in &options.tags {
+            invocation.arg(name).arg(tag);
+        }
+        add_namespace(&mut invocation, token);
+
+        let mut conn = self.conn.clone();
+        invocation
+            .invoke_async::<()>(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to set key {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(())
+    }
+
+    /// Check a value about to be written with `content_type`
+    ///
+    /// The key and value must satisfy the store's
+    /// [`KeyPolicy`](super::KeyPolicy), JSON documents must be valid JSON, and
+    /// every value must satisfy the JSON Schema registered for the key's prefix.
+    pub(super) async fn validate_write(
+        &self,
+        token: &str,
+        key: &str,
+        value: &str,
+        content_type: &str,
+    ) -> Result<()> {
+        self.policy.check_key(key)?;
+        self.policy.check_value(value)?;
+        if content_type.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "Content type must not be empty".to_string(),
+            ));
+        }
+
+        if content_type == JSON_CONTENT_TYPE {
+            let document = serde_json::from_str(value).map_err(|e| {
+                KVStoreError::InvalidRequest(format!("Value is not valid JSON: {}", e))
+            })?;
+            self.validate_document(token, key, &document).await
+        } else {
+            self.validate_text(token, key, value).await
+        }
+    }
+
+    /// Get a value from the store together with its metadata
+    ///
+    /// # Returns
+    ///
+    /// The entry if found, or an error if the key doesn't exist
+    pub async fn get_entry(&self, token: &str, key: &str) -> Result<Entry> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("GET {} (with metadata)", namespaced_key);
+
+        let mut conn = self.conn.clone();
+        let (value, fields): (Option<String>, HashMap<String, String>) = redis::pipe()
+            .atomic()
+            .get(&namespaced_key)
+            .hgetall(meta_key(token, key))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get key {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        let value = value.ok_or_else(|| KVStoreError::KeyNotFound(key.to_string()))?;
+
+        Ok(Entry {
+            value,
+            metadata: Metadata::from_fields(fields),
+        })
+    }
+
+    /// Get the metadata of a key without its value
+    ///
+    /// # Returns
+    ///
+    /// The metadata if the key exists, or an error if it doesn't
+    pub async fn get_metadata(&self, token: &str, key: &str) -> Result<Metadata> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("GET METADATA {}", namespaced_key);
+
+        let mut conn = self.conn.clone();
+        let (exists, fields): (bool, HashMap<String, String>) = redis::pipe()
+            .atomic()
+            .exists(&namespaced_key)
+            .hgetall(meta_key(token, key))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get metadata of {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        if !exists {
+            return Err(KVStoreError::KeyNotFound(key.to_string()));
+        }
+
+        Ok(Metadata::from_fields(fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_from_fields() {
+        let fields = HashMap::from([
+            ("content_type".to_string(), "text/csv".to_string()),
+            ("created_at".to_string(), "1700000000000".to_string()),
+            ("updated_at".to_string(), "1700000005000".to_string()),
+            ("size".to_string(), "42".to_string()),
+            ("version".to_string(), "3".to_string()),
+            ("tag:owner".to_string(), "billing".to_string()),
+        ]);
+
+        let metadata = Metadata::from_fields(fields);
+        assert_eq!(metadata.content_type, "text/csv");
+        assert_eq!(metadata.created_at, Some(1_700_000_000_000));
+        assert_eq!(metadata.updated_at, Some(1_700_000_005_000));
+        assert_eq!(metadata.size, Some(42));
+        assert_eq!(metadata.version, 3);
+        assert_eq!(
+            metadata.tags.get("owner").map(String::as_str),
+            Some("billing")
+        );
+    }
+
+    #[test]
+    fn test_metadata_defaults_to_text() {
+        let metadata = Metadata::from_fields(HashMap::new());
+        assert_eq!(metadata.content_type, TEXT_CONTENT_TYPE);
+        assert_eq!(metadata.created_at, None);
+        assert!(metadata.tags.is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+//! Limits on the keys and values a store accepts
+//!
+//! Keys are checked when they are written to, so keys written before a policy
+//! was tightened stay readable and deletable. Keys under `_kvstore:` are
+//! refused whatever the policy, as that prefix is kvstore's own.
+
+use super::INTERNAL_PREFIX;
+use crate::error::{KVStoreError, Result};
+use std::str::FromStr;
+
+/// Default maximum key length in bytes
+pub const DEFAULT_MAX_KEY_LENGTH: usize = 1024;
+
+/// Default maximum value size in bytes
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 1024 * 1024;
+
+/// Key names the HTTP API uses for its own routes, such as `/_stats`, which
+/// would shadow keys of the same name
+pub const HTTP_ROUTE_KEYS: &[&str] = &[
+    "_changes",
+    "_export",
+    "_history",
+    "_import",
+    "_indexes",
+    "_locks",
+    "_rename",
+    "_replication",
+    "_schemas",
+    "_sets",
+    "_soft_delete",
+    "_stats",
+    "_trash",
+    "_txn",
+    "_watch",
+    "_webhooks",
+];
+
+/// Characters allowed in key names
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyCharset {
+    /// Any character
+    Any,
+    /// Any character except control characters such as newlines
+    #[default]
+    Printable,
+    /// ASCII letters and digits and `-_.:/@`
+    Safe,
+}
+
+impl KeyCharset {
+    fn allows(self, c: char) -> bool {
+        match self {
+            KeyCharset::Any => true,
+            KeyCharset::Printable => !c.is_control(),
+            KeyCharset::Safe => c.is_ascii_alphanumeric() || "-_.:/@".contains(c),
+        }
+    }
+}
+
+impl FromStr for KeyCharset {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "any" => Ok(KeyCharset::Any),
+            "printable" => Ok(KeyCharset::Printable),
+            "safe" => Ok(KeyCharset::Safe),
+            _ => Err(format!(
+                "Invalid key charset: {}. Valid options: any, printable, safe",
+                s
+            )),
+        }
+    }
+}
+
+/// Limits on the keys and values a store accepts
+///
+/// # Example
+///
+/// ```rust
+/// use kvstore::store::{KeyCharset, KeyPolicy};
+///
+/// let policy = KeyPolicy {
+///     max_key_length: 128,
+///     charset: KeyCharset::Safe,
+///     ..Default::default()
+/// };
+/// assert!(policy.check_key("user:42").is_ok());
+/// assert!(policy.check_key("user 42").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPolicy {
+    /// Maximum key length in bytes
+    pub max_key_length: usize,
+    /// Maximum size in bytes of a value, or of each hash field value, list
+    /// item and set member
+    pub max_value_size: usize,
+    /// Characters allowed in keys
+    pub charset: KeyCharset,
+    /// Prefixes that keys must not start with, besides `_kvstore:`
+    pub reserved_prefixes: Vec<String>,
+    /// Names that keys must not have, by default [`HTTP_ROUTE_KEYS`]
+    pub reserved_keys: Vec<String>,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_key_length: DEFAULT_MAX_KEY_LENGTH,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            charset: KeyCharset::default(),
+            reserved_prefixes: Vec::new(),
+            reserved_keys: HTTP_ROUTE_KEYS.iter().map(|key| key.to_string()).collect(),
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// Check that `key` may be written to
+    pub fn check_key(&self, key: &str) -> Result<()> {
+        if key.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "Key must not be empty".to_string(),
+            ));
+        }
+        if key.len() > self.max_key_length {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Key is {} bytes long, the limit is {}",
+                key.len(),
+                self.max_key_length
+            )));
+        }
+        if let Some(c) = key.chars().find(|&c| !self.charset.allows(c)) {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Key {:?} contains the disallowed character {:?}",
+                key, c
+            )));
+        }
+        if key
+            .strip_prefix(INTERNAL_PREFIX)
+            .is_some_and(|rest| rest.starts_with(':'))
+        {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Key {} uses the reserved prefix '{}:'",
+                key, INTERNAL_PREFIX
+            )));
+        }
+        if self.reserved_keys.iter().any(|reserved| reserved == key) {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Key {} is reserved",
+                key
+            )));
+        }
+        if let Some(prefix) = self
+            .reserved_prefixes
+            .iter()
+            .find(|p| !p.is_empty() && key.starts_with(p.as_str()))
+        {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Key {} uses the reserved prefix '{}'",
+                key, prefix
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that `value` is small enough to be stored
+    pub fn check_value(&self, value: &str) -> Result<()> {
+        if value.len() > self.max_value_size {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Value is {} bytes, the limit is {}",
+                value.len(),
+                self.max_value_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check every value in `values` with [`KeyPolicy::check_value`]
+    pub(crate) fn check_values<'a>(&self, values: impl IntoIterator<Item = &'a str>) -> Result<()> {
+        values
+            .into_iter()
+            .try_for_each(|value| self.check_value(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policy() {
+        let policy = KeyPolicy::default();
+        assert!(policy.check_key("user:42").is_ok());
+        assert!(policy.check_key("with space*?[]").is_ok());
+        assert!(policy.check_key("").is_err());
+        assert!(policy.check_key("line\nbreak").is_err());
+        assert!(policy.check_key("_stats").is_err());
+        assert!(policy.check_key("_stats:daily").is_ok());
+        assert!(policy.check_key("_kvstore").is_ok());
+        assert!(policy.check_key("_kvstore:replication").is_err());
+        assert!(policy
+            .check_key(&"k".repeat(DEFAULT_MAX_KEY_LENGTH))
+            .is_ok());
+        assert!(policy
+            .check_key(&"k".repeat(DEFAULT_MAX_KEY_LENGTH + 1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_safe_charset() {
+        let policy = KeyPolicy {
+            charset: KeyCharset::Safe,
+            ..Default::default()
+        };
+        assert!(policy.check_key("user-1.v2:a/b@c").is_ok());
+        assert!(policy.check_key("user*").is_err());
+        assert!(policy.check_key("üser").is_err());
+    }
+
+    #[test]
+    fn test_reserved_prefixes() {
+        let policy = KeyPolicy {
+            reserved_prefixes: vec!["sys:".to_string(), String::new()],
+            ..Default::default()
+        };
+        assert!(policy.check_key("_private").is_ok());
+        assert!(policy.check_key("sys:config").is_err());
+        assert!(policy.check_key("_kvstore:config").is_err());
+
+        let policy = KeyPolicy {
+            reserved_keys: Vec::new(),
+            ..Default::default()
+        };
+        assert!(policy.check_key("_stats").is_ok());
+    }
+
+    #[test]
+    fn test_value_size() {
+        let policy = KeyPolicy {
+            max_value_size: 3,
+            ..Default::default()
+        };
+        assert!(policy.check_value("abc").is_ok());
+        assert!(policy.check_value("abcd").is_err());
+        assert!(policy.check_values(["a", "abcd"]).is_err());
+    }
+
+    #[test]
+    fn test_key_charset_from_str() {
+        assert_eq!("safe".parse::<KeyCharset>().unwrap(), KeyCharset::Safe);
+        assert_eq!("ANY".parse::<KeyCharset>().unwrap(), KeyCharset::Any);
+        assert!("ascii".parse::<KeyCharset>().is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Bulk operations over every key sharing a prefix
+
+use super::{history, index, meta_key, metadata, namespaced_key, trash, KVStore};
+use crate::error::Result;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// Number of keys requested from each SCAN and deleted per script call
+pub(super) const SCAN_BATCH_SIZE: usize = 500;
+
+/// Maximum number of key names reported by a dry run
+pub const MAX_DRY_RUN_KEYS: usize = 1000;
+
+/// Delete a batch of keys and their metadata, recording deletions in history
+///
+/// KEYS[1] - history policy key, KEYS[2] - index definitions key, then the
+/// value, metadata, history, trashed value and trashed metadata keys of every
+/// key to delete
+const DELETE_BATCH_SCRIPT: &str = r#"
+local deleted = 0
+for i = 3, KEY_COUNT, 5 do
+    deleted = deleted + delete_value(KEYS[i], KEYS[i + 1], KEYS[i + 2], KEYS[1], KEYS[2],
+        KEYS[i + 3], KEYS[i + 4])
+end
+return deleted
+"#;
+
+/// Keys that a prefix deletion would remove
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PrefixMatch {
+    /// Number of matching keys
+    pub count: u64,
+    /// Names of the matching keys in sorted order, at most [`MAX_DRY_RUN_KEYS`]
+    pub keys: Vec<String>,
+    /// Whether `keys` was cut short
+    pub truncated: bool,
+}
+
+/// Escape the glob metacharacters of `s` so a pattern matches it literally
+pub(super) fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// SCAN pattern matching exactly the keys of `token` that start with `prefix`
+pub(super) fn prefix_pattern(token: &str, prefix: &str) -> String {
+    format!("{}*", escape_glob(&namespaced_key(token, prefix)))
+}
+
+impl KVStore {
+    /// Delete every key of a namespace that starts with `prefix`
+    ///
+    /// Keys are found with SCAN and unlinked server-side in batches, together
+    /// with their metadata, so there is no cap on how many keys are removed.
+    /// An empty prefix purges the whole namespace. Keys written while the
+    /// deletion runs may survive it. Namespaces with a trash keep the deleted
+    /// keys there, like [`KVStore::delete`].
+    ///
+    /// # Returns
+    ///
+    /// The number of keys deleted
+    pub async fn delete_prefix(&self, token: &str, prefix: &str) -> Result<u64> {
+        let pattern = prefix_pattern(token, prefix);
+        tracing::debug!("DELETE PREFIX {}", pattern);
+
+        let script = metadata::value_script(DELETE_BATCH_SCRIPT);
+        let policy_key = history::history_policy_key(token);
+        let indexes_key = index::indexes_key(token);
+        let prefix_len = token.len() + 1; // +1 for the colon
+        let mut conn = self.conn.clone();
+        let mut cursor = 0u64;
+        let mut deleted = 0u64;
+
+        loop {
+            let (next, batch) = self.scan_batch(&pattern, cursor).await?;
+
+            if !batch.is_empty() {
+                let mut invocation = script.key(&policy_key);
+                invocation.key(&indexes_key);
+                for redis_key in &batch {
+                    let key = &redis_key[prefix_len..];
+                    invocation
+                        .key(redis_key)
+                        .key(meta_key(token, key))
+                        .key(history::history_key(token, key))
+                        .key(trash::trash_key(token, key))
+                        .key(trash::trash_meta_key(token, key));
+                }
+                metadata::add_namespace(&mut invocation, token);
+                let count: u64 = invocation.invoke_async(&mut conn).await.map_err(|e| {
+                    tracing::error!("Failed to delete keys matching {}: {}", pattern, e);
+                    e
+                })?;
+                deleted += count;
+            }
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Report which keys [`KVStore::delete_prefix`] would delete, without
+    /// deleting anything
+    pub async fn delete_prefix_dry_run(&self, token: &str, prefix: &str) -> Result<PrefixMatch> {
+        let pattern = prefix_pattern(token, prefix);
+        tracing::debug!("DELETE PREFIX (dry run) {}", pattern);
+
+        let prefix_len = token.len() + 1; // +1 for the colon
+        let mut cursor = 0u64;
+        let mut count = 0u64;
+        // SCAN may return a key more than once, so only its first sighting counts
+        let mut seen = BTreeSet::new();
+
+        loop {
+            let (next, batch) = self.scan_batch(&pattern, cursor).await?;
+
+            for redis_key in batch {
+                if seen.insert(redis_key[prefix_len..].to_string()) {
+                    count += 1;
+                }
+            }
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        let truncated = seen.len() > MAX_DRY_RUN_KEYS;
+        Ok(PrefixMatch {
+            count,
+            keys: seen.into_iter().take(MAX_DRY_RUN_KEYS).collect(),
+            truncated,
+        })
+    }
+
+    /// Run one SCAN step over `pattern`, returning the next cursor and the keys
+    pub(super) async fn scan_batch(
+        &self,
+        pattern: &str,
+        cursor: u64,
+    ) -> Result<(u64, Vec<String>)> {
+        let mut conn = self.conn.clone();
+        let batch = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(SCAN_BATCH_SIZE)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to SCAN keys with pattern {}: {}", pattern, e);
+                e
+            })?;
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_pattern() {
+        assert_eq!(prefix_pattern("tok", "user:"), "tok:user:*");
+        assert_eq!(prefix_pattern("tok", ""), "tok:*");
+    }
+
+    #[test]
+    fn test_prefix_pattern_escapes_glob_metacharacters() {
+        assert_eq!(prefix_pattern("tok", "a*b?"), r"tok:a\*b\?*");
+        assert_eq!(prefix_pattern("t[1]", r"x\"), r"t\[1\]:x\\*");
+    }
+}
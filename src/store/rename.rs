@@ -0,0 +1,301 @@
+//! Renaming and copying keys within a namespace
+//!
+//! Values move together with their TTL and metadata, in one atomic step per
+//! key. Keys never leave the caller's namespace.
+
+use super::{history, index, meta_key, metadata, namespaced_key, prefix, KVStore};
+use crate::error::{KVStoreError, Result};
+use serde::Serialize;
+
+/// Copy or rename a single key
+///
+/// KEYS[1] - source value key, KEYS[2] - source metadata key,
+/// KEYS[3] - source history key, KEYS[4] - destination value key,
+/// KEYS[5] - destination metadata key, KEYS[6] - destination history key,
+/// KEYS[7] - history policy key, KEYS[8] - index definitions key
+/// ARGV[1] - "1" to remove the source, ARGV[2] - "1" to overwrite,
+/// ARGV[3] - expected source version, or "" for any
+const MOVE_SCRIPT: &str = r#"
+return move_value(KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6], KEYS[7], KEYS[8],
+    ARGV[1] == '1', ARGV[2] == '1', ARGV[3])
+"#;
+
+/// Rename a batch of keys, skipping those that can't be moved
+///
+/// KEYS[1] - history policy key, KEYS[2] - index definitions key, then the
+/// six source and destination keys of each rename in the order of
+/// [`MOVE_SCRIPT`]
+/// ARGV[1] - "1" to overwrite, then the expected source version of each rename
+const RENAME_BATCH_SCRIPT: &str = r#"
+local renamed = 0
+local skipped = 0
+for i = 3, KEY_COUNT, 6 do
+    local result = move_value(KEYS[i], KEYS[i + 1], KEYS[i + 2], KEYS[i + 3], KEYS[i + 4],
+        KEYS[i + 5], KEYS[1], KEYS[2], true, ARGV[1] == '1', ARGV[(i - 3) / 6 + 2])
+    if result == 'ok' then
+        renamed = renamed + 1
+    elseif result ~= 'missing' then
+        skipped = skipped + 1
+    end
+end
+return {renamed, skipped}
+"#;
+
+/// Outcome of renaming every key with a prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PrefixRename {
+    /// Number of keys renamed
+    pub renamed: u64,
+    /// Number of keys left in place because their new name was taken, or
+    /// because they changed or were written while being renamed
+    pub skipped: u64,
+}
+
+/// Reject copying or renaming a key onto itself
+fn check_distinct(from: &str, to: &str) -> Result<()> {
+    if from == to {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Source and destination are both {}",
+            from
+        )));
+    }
+    Ok(())
+}
+
+/// Reject prefix renames whose results could match the source prefix again,
+/// which the scan of the rename in progress may then rename a second time
+fn check_prefixes(from_prefix: &str, to_prefix: &str) -> Result<()> {
+    if to_prefix.starts_with(from_prefix) || from_prefix.starts_with(to_prefix) {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Prefixes '{}' and '{}' must not start with one another",
+            from_prefix, to_prefix
+        )));
+    }
+    Ok(())
+}
+
+/// Add the source and destination keys of a move to `invocation`
+fn add_move_keys(invocation: &mut redis::ScriptInvocation<'_>, token: &str, from: &str, to: &str) {
+    invocation
+        .key(namespaced_key(token, from))
+        .key(meta_key(token, from))
+        .key(history::history_key(token, from))
+        .key(namespaced_key(token, to))
+        .key(meta_key(token, to))
+        .key(history::history_key(token, to));
+}
+
+impl KVStore {
+    /// Rename a key, keeping its value, TTL and metadata
+    ///
+    /// # Errors
+    ///
+    /// [`KVStoreError::KeyNotFound`] if `from` doesn't exist, and
+    /// [`KVStoreError::Conflict`] if `to` exists and `overwrite` is false
+    pub async fn rename(&self, token: &str, from: &str, to: &str, overwrite: bool) -> Result<()> {
+        self.move_key(token, from, to, true, overwrite).await
+    }
+
+    /// Copy a key, including its TTL and metadata
+    ///
+    /// # Errors
+    ///
+    /// [`KVStoreError::KeyNotFound`] if `from` doesn't exist, and
+    /// [`KVStoreError::Conflict`] if `to` exists and `overwrite` is false
+    pub async fn copy(&self, token: &str, from: &str, to: &str, overwrite: bool) -> Result<()> {
+        self.move_key(token, from, to, false, overwrite).await
+    }
+
+    /// Rename every key starting with `from_prefix` to start with `to_prefix`
+    ///
+    /// Keys are renamed in batches, each key atomically; keys whose new name
+    /// is taken are skipped unless `overwrite` is set. Keys written while the
+    /// rename runs may be left behind.
+    ///
+    /// # Errors
+    ///
+    /// [`KVStoreError::InvalidRequest`] if any new key name breaks the key
+    /// policy; nothing is renamed then
+    pub async fn rename_prefix(
+        &self,
+        token: &str,
+        from_prefix: &str,
+        to_prefix: &str,
+        overwrite: bool,
+    ) -> Result<PrefixRename> {
+        check_prefixes(from_prefix, to_prefix)?;
+
+        let pattern = prefix::prefix_pattern(token, from_prefix);
+        tracing::debug!("RENAME PREFIX {} -> {}", pattern, to_prefix);
+        let prefix_len = token.len() + 1 + from_prefix.len(); // +1 for the colon
+        let target = |redis_key: &str| format!("{}{}", to_prefix, &redis_key[prefix_len..]);
+
+        // Check every new name before moving anything, so that a rejected key
+        // doesn't leave the rename half done
+        let mut cursor = 0u64;
+        loop {
+            let (next, batch) = self.scan_batch(&pattern, cursor).await?;
+            for redis_key in &batch {
+                self.policy.check_key(&target(redis_key))?;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        let script = metadata::value_script(RENAME_BATCH_SCRIPT);
+        let policy_key = history::history_policy_key(token);
+        let indexes_key = index::indexes_key(token);
+        let validate = !self.list_schemas(token).await?.is_empty();
+        let mut conn = self.conn.clone();
+        let mut outcome = PrefixRename {
+            renamed: 0,
+            skipped: 0,
+        };
+        cursor = 0;
+
+        loop {
+            let (next, batch) = self.scan_batch(&pattern, cursor).await?;
+
+            // Keys written since the check above are skipped rather than failing
+            // a rename that has already started
+            let mut moves = Vec::with_capacity(batch.len());
+            for redis_key in &batch {
+                let to = target(redis_key);
+                if self.policy.check_key(&to).is_ok() {
+                    moves.push((&redis_key[token.len() + 1..], to));
+                } else {
+                    outcome.skipped += 1;
+                }
+            }
+
+            if !moves.is_empty() {
+                let mut invocation = script.key(&policy_key);
+                invocation
+                    .key(&indexes_key)
+                    .arg(if overwrite { "1" } else { "0" });
+                for (from, to) in &moves {
+                    let expected = if validate {
+                        self.check_move(token, from, to).await?
+                    } else {
+                        None
+                    };
+                    add_move_keys(&mut invocation, token, from, to);
+                    invocation.arg(expected.map(|v| v.to_string()).unwrap_or_default());
+                }
+                metadata::add_namespace(&mut invocation, token);
+
+                let (renamed, skipped): (u64, u64) =
+                    invocation.invoke_async(&mut conn).await.map_err(|e| {
+                        tracing::error!("Failed to rename keys matching {}: {}", pattern, e);
+                        e
+                    })?;
+                outcome.renamed += renamed;
+                outcome.skipped += skipped;
+            }
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        Ok(outcome)
+    }
+
+    async fn move_key(
+        &self,
+        token: &str,
+        from: &str,
+        to: &str,
+        remove_source: bool,
+        overwrite: bool,
+    ) -> Result<()> {
+        check_distinct(from, to)?;
+        self.policy.check_key(to)?;
+        tracing::debug!(
+            "{} {} -> {}",
+            if remove_source { "RENAME" } else { "COPY" },
+            namespaced_key(token, from),
+            namespaced_key(token, to)
+        );
+
+        let expected = self.check_move(token, from, to).await?;
+
+        let script = metadata::value_script(MOVE_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        add_move_keys(&mut invocation, token, from, to);
+        invocation
+            .key(history::history_policy_key(token))
+            .key(index::indexes_key(token))
+            .arg(if remove_source { "1" } else { "0" })
+            .arg(if overwrite { "1" } else { "0" })
+            .arg(expected.map(|v| v.to_string()).unwrap_or_default());
+        metadata::add_namespace(&mut invocation, token);
+
+        let mut conn = self.conn.clone();
+        let result: String = invocation.invoke_async(&mut conn).await.map_err(|e| {
+            tracing::error!("Failed to move key {} to {}: {}", from, to, e);
+            e
+        })?;
+
+        match result.as_str() {
+            "ok" => Ok(()),
+            "missing" => Err(KVStoreError::KeyNotFound(from.to_string())),
+            "exists" => Err(KVStoreError::Conflict(format!("Key {} already exists", to))),
+            "changed" => Err(KVStoreError::Conflict(format!(
+                "Key {} changed while being moved",
+                from
+            ))),
+            other => Err(KVStoreError::Internal(format!(
+                "Unexpected move result: {}",
+                other
+            ))),
+        }
+    }
+
+    /// Validate the value of `from` against the schema bound to `to`
+    ///
+    /// Returns the version of the validated value, which the move must still
+    /// find, or `None` if no validation applies: `to` has no schema, or `from`
+    /// isn't a string value.
+    async fn check_move(&self, token: &str, from: &str, to: &str) -> Result<Option<u64>> {
+        if self.schema_for(token, to).await?.is_none() {
+            return Ok(None);
+        }
+
+        let entry = match self.get_entry(token, from).await {
+            Ok(entry) => entry,
+            Err(KVStoreError::WrongType(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.validate_write(token, to, &entry.value, &entry.metadata.content_type)
+            .await?;
+        Ok(Some(entry.metadata.version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_distinct() {
+        assert!(check_distinct("a", "b").is_ok());
+        assert!(matches!(
+            check_distinct("a", "a"),
+            Err(KVStoreError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_prefixes_rejects_nested_targets() {
+        assert!(check_prefixes("old:", "new:").is_ok());
+        assert!(check_prefixes("user:", "use").is_err());
+        assert!(check_prefixes("user:", "user:v2:").is_err());
+        assert!(check_prefixes("x:y:", "x:").is_err());
+        assert!(check_prefixes("", "any:").is_err());
+        assert!(check_prefixes("any:", "").is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! Applying changes replicated from another deployment
+//!
+//! A follower (see `crate::replication`) reads another instance's change log
+//! and applies each change here with last-writer-wins: a change is applied
+//! unless the local value has a higher version, or the same version written
+//! later. Replicated writes keep the version and timestamp they had at the
+//! source, so a change echoed back to where it came from is recognized as
+//! already applied, and two deployments can follow each other.
+//!
+//! The follower's progress is kept in a status hash of the namespace, which
+//! holds the offset to resume from and the replication lag.
+
+use super::metadata::{add_namespace, value_script};
+use super::watch::parse_id;
+use super::webhook::now_millis;
+use super::{
+    history, index, meta_key, namespaced_key, trash, ChangeEvent, ChangeKind, INTERNAL_PREFIX,
+};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use redis::AsyncCommands;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Apply a replicated put or delete, unless the local value is newer
+///
+/// KEYS[1] - value key, KEYS[2] - metadata key, KEYS[3] - history key,
+/// KEYS[4] - history policy key, KEYS[5] - index definitions key,
+/// KEYS[6] - trashed value key, KEYS[7] - trashed metadata key
+/// ARGV[1] - 'put' or 'delete', ARGV[2] - version ('' if unknown),
+/// ARGV[3] - timestamp, ARGV[4] - value, ARGV[5] - content type,
+/// ARGV[6] - when the value expires ('' if it doesn't)
+/// Returns 'applied', or 'stale' without changing anything
+const REPLICATE_SCRIPT: &str = r#"
+local version = tonumber(ARGV[2])
+local timestamp = tonumber(ARGV[3])
+local exists = redis.call('EXISTS', KEYS[1]) == 1
+local local_version = tonumber(redis.call('HGET', KEYS[2], 'version')) or 0
+local local_time = tonumber(redis.call('HGET', KEYS[2], 'updated_at')) or 0
+
+if ARGV[1] == 'delete' then
+    if not exists then
+        return 'stale'
+    end
+    if version and (version < local_version
+        or (version == local_version and timestamp < local_time)) then
+        return 'stale'
+    end
+    delete_value(KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6], KEYS[7])
+    return 'applied'
+end
+
+if exists and (version < local_version
+    or (version == local_version and timestamp <= local_time)) then
+    return 'stale'
+end
+-- A value that expired on its way here is gone at the source too
+local expires_at = tonumber(ARGV[6])
+if expires_at and expires_at <= tonumber(now_millis()) then
+    delete_value(KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6], KEYS[7])
+    return 'applied'
+end
+local created = exists and redis.call('HGET', KEYS[2], 'created_at') or timestamp
+redis.call('SET', KEYS[1], ARGV[4])
+redis.call('HSET', KEYS[2], 'content_type', ARGV[5], 'updated_at', timestamp,
+    'size', string.len(ARGV[4]), 'version', version, 'created_at', created)
+if expires_at then
+    redis.call('PEXPIREAT', KEYS[1], expires_at)
+    redis.call('PEXPIREAT', KEYS[2], expires_at)
+else
+    redis.call('PERSIST', KEYS[2])
+end
+record_revision(KEYS[3], KEYS[4], 'set', ARGV[4], ARGV[5])
+update_indexes(KEYS[5], KEYS[1], ARGV[4])
+publish_change('put', KEYS[1], KEYS[2], ARGV[4], version, ARGV[5])
+return 'applied'
+"#;
+
+/// Progress of the replication of a namespace from another deployment
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReplicationStatus {
+    /// Address of the instance followed
+    pub source: String,
+    /// Whether the follower is currently connected to the source
+    pub connected: bool,
+    /// ID of the last change read from the source's change log
+    pub offset: Option<String>,
+    /// Changes applied
+    pub applied: u64,
+    /// Changes skipped because the local value was newer
+    pub stale: u64,
+    /// Time between the last change being logged, or the last heartbeat
+    /// being sent, at the source and being read here, in milliseconds
+    pub lag_ms: Option<u64>,
+    /// When the last change was logged at the source, in milliseconds since
+    /// the Unix epoch
+    pub last_change_at: Option<u64>,
+    /// The error that last interrupted replication
+    pub last_error: Option<String>,
+}
+
+impl ReplicationStatus {
+    fn from_fields(fields: HashMap<String, String>) -> Self {
+        let number = |field: &str| fields.get(field).and_then(|n| n.parse().ok());
+        ReplicationStatus {
+            source: fields.get("source").cloned().unwrap_or_default(),
+            connected: fields.get("connected").is_some_and(|c| c == "1"),
+            offset: fields.get("offset").cloned(),
+            applied: number("applied").unwrap_or_default(),
+            stale: number("stale").unwrap_or_default(),
+            lag_ms: number("lag_ms"),
+            last_change_at: number("last_change_at"),
+            last_error: fields.get("last_error").cloned(),
+        }
+    }
+}
+
+/// Build the Redis key of the replication status hash of `token`'s namespace
+fn replication_key(token: &str) -> String {
+    format!("{}:{}:replication", INTERNAL_PREFIX, token)
+}
+
+/// Add the offset of a change read from the source, when it was logged and
+/// the resulting lag to a status update
+fn record_change_offset(pipe: &mut redis::Pipeline, key: &str, offset: &str) {
+    pipe.hset(key, "offset", offset);
+    if let Some((logged_at, _)) = parse_id(offset) {
+        pipe.hset(key, "last_change_at", logged_at).hset(
+            key,
+            "lag_ms",
+            now_millis().saturating_sub(logged_at),
+        );
+    }
+}
+
+impl KVStore {
+    /// Apply a change read from another deployment's change log, with
+    /// last-writer-wins by version, then timestamp
+    ///
+    /// Puts must carry the value, version and timestamp they had at the
+    /// source; they replace the local value and its metadata, and expire when
+    /// the source's value does. Deletes go through the trash like local ones,
+    /// as do puts that have already expired.
+    ///
+    /// # Returns
+    ///
+    /// Whether the change was applied, or skipped because the local value is
+    /// newer
+    pub async fn apply_replicated_change(&self, token: &str, change: &ChangeEvent) -> Result<bool> {
+        self.policy.check_key(&change.key)?;
+        let timestamp = change.timestamp.ok_or_else(|| {
+            KVStoreError::InvalidRequest(format!("Change of {} has no timestamp", change.key))
+        })?;
+        let (kind, value) = match change.kind {
+            ChangeKind::Put => {
+                let value = change.value.as_deref().ok_or_else(|| {
+                    KVStoreError::InvalidRequest(format!("Put of {} has no value", change.key))
+                })?;
+                if change.version.is_none() {
+                    return Err(KVStoreError::InvalidRequest(format!(
+                        "Put of {} has no version",
+                        change.key
+                    )));
+                }
+                self.policy.check_value(value)?;
+                ("put", value)
+            }
+            ChangeKind::Delete => ("delete", ""),
+            ChangeKind::Expire => {
+                return Err(KVStoreError::InvalidRequest(
+                    "Expirations are not replicated".to_string(),
+                ))
+            }
+        };
+
+        let namespaced_key = namespaced_key(token, &change.key);
+        tracing::debug!("REPLICATE {} {}", kind, namespaced_key);
+
+        let script = value_script(REPLICATE_SCRIPT);
+        let mut invocation = script.key(&namespaced_key);
+        invocation
+            .key(meta_key(token, &change.key))
+            .key(history::history_key(token, &change.key))
+            .key(history::history_policy_key(token))
+            .key(index::indexes_key(token))
+            .key(trash::trash_key(token, &change.key))
+            .key(trash::trash_meta_key(token, &change.key))
+            .arg(kind)
+            .arg(change.version.map(|v| v.to_string()).unwrap_or_default())
+            .arg(timestamp)
+            .arg(value)
+            .arg(
+                change
+                    .content_type
+                    .as_deref()
+                    .unwrap_or(super::TEXT_CONTENT_TYPE),
+            )
+            .arg(
+                change
+                    .expires_at
+                    .map(|at| at.to_string())
+                    .unwrap_or_default(),
+            );
+        add_namespace(&mut invocation, token);
+
+        let mut conn = self.conn.clone();
+        let outcome: String = invocation.invoke_async(&mut conn).await?;
+
+        Ok(outcome == "applied")
+    }
+
+    /// Get the progress of the replication of a namespace, or `None` if it
+    /// has never been replicated
+    pub async fn replication_status(&self, token: &str) -> Result<Option<ReplicationStatus>> {
+        let mut conn = self.conn.clone();
+        let fields: HashMap<String, String> = conn.hgetall(replication_key(token)).await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(ReplicationStatus::from_fields(fields)))
+    }
+
+    /// Record that the follower of a namespace connected to `source`
+    pub(crate) async fn record_replication_connected(
+        &self,
+        token: &str,
+        source: &str,
+    ) -> Result<()> {
+        let key = replication_key(token);
+        let mut conn = self.conn.clone();
+        redis::pipe()
+            .atomic()
+            .hset(&key, "source", source)
+            .hset(&key, "connected", 1)
+            .hdel(&key, "last_error")
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Record that replication of a namespace was interrupted by `error`
+    ///
+    /// With `reset` set, the offset is dropped so that the follower starts
+    /// over with a full copy.
+    pub(crate) async fn record_replication_error(
+        &self,
+        token: &str,
+        error: &str,
+        reset: bool,
+    ) -> Result<()> {
+        let key = replication_key(token);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset(&key, "connected", 0)
+            .hset(&key, "last_error", error);
+        if reset {
+            pipe.hdel(&key, "offset");
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Record that a change was read from the source, and whether it was
+    /// applied
+    ///
+    /// `offset` is the change's ID in the source's change log, which is also
+    /// when it was logged there; changes without one (from a full copy) don't
+    /// move the offset or the lag.
+    pub(crate) async fn record_replicated(
+        &self,
+        token: &str,
+        offset: Option<&str>,
+        applied: bool,
+    ) -> Result<()> {
+        let key = replication_key(token);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hincr(&key, if applied { "applied" } else { "stale" }, 1);
+        if let Some(offset) = offset {
+            record_change_offset(&mut pipe, &key, offset);
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Record that the source's change log has been read up to `offset`
+    /// without applying anything
+    ///
+    /// With `heartbeat_at`, when the source sent a heartbeat at `offset`, the
+    /// lag is measured from the heartbeat; otherwise `offset` is the ID of a
+    /// change that wasn't replicated.
+    pub(crate) async fn record_replication_offset(
+        &self,
+        token: &str,
+        offset: &str,
+        heartbeat_at: Option<u64>,
+    ) -> Result<()> {
+        let key = replication_key(token);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        match heartbeat_at {
+            Some(sent_at) => {
+                pipe.hset(&key, "offset", offset).hset(
+                    &key,
+                    "lag_ms",
+                    now_millis().saturating_sub(sent_at),
+                );
+            }
+            None => record_change_offset(&mut pipe, &key, offset),
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_from_fields() {
+        let fields = HashMap::from([
+            ("source".to_string(), "http://primary:50051".to_string()),
+            ("connected".to_string(), "1".to_string()),
+            ("offset".to_string(), "1700000000000-0".to_string()),
+            ("applied".to_string(), "5".to_string()),
+            ("lag_ms".to_string(), "12".to_string()),
+        ]);
+        let status = ReplicationStatus::from_fields(fields);
+        assert!(status.connected);
+        assert_eq!(status.offset.as_deref(), Some("1700000000000-0"));
+        assert_eq!((status.applied, status.stale), (5, 0));
+        assert_eq!(status.lag_ms, Some(12));
+        assert_eq!(status.last_error, None);
+    }
+}
@@ -0,0 +1,266 @@
+//! JSON Schema validation per key prefix
+//!
+//! A namespace may bind JSON Schemas to key prefixes such as `user:`. Writes to
+//! a key are validated against the schema of the longest registered prefix
+//! matching it; keys matching no prefix are not validated.
+
+use super::{redis_error, INTERNAL_PREFIX};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use redis::AsyncCommands;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Build the Redis key of the hash mapping prefixes to schemas for `token`
+fn schemas_key(token: &str) -> String {
+    format!("{}:{}:schemas", INTERNAL_PREFIX, token)
+}
+
+/// Pick the schema of the longest prefix matching `key`
+fn matching_schema(schemas: HashMap<String, String>, key: &str) -> Option<(String, String)> {
+    schemas
+        .into_iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+}
+
+/// Compile a schema, rejecting documents that aren't valid JSON Schemas
+fn compile(schema: &Value) -> Result<jsonschema::Validator> {
+    jsonschema::validator_for(schema)
+        .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid JSON Schema: {}", e)))
+}
+
+/// Validate `document` against `schema`, listing every violation
+fn check_document(key: &str, prefix: &str, schema: &Value, document: &Value) -> Result<()> {
+    let validator = compile(schema)?;
+    let errors: Vec<String> = validator
+        .iter_errors(document)
+        .map(|error| {
+            let path = error.instance_path().to_string();
+            let path = if path.is_empty() { "/" } else { &path };
+            format!("{}: {}", path, error)
+        })
+        .collect();
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(KVStoreError::InvalidRequest(format!(
+        "Value for {} violates the schema for prefix '{}': {}",
+        key,
+        prefix,
+        errors.join("; ")
+    )))
+}
+
+/// Parse a schema read back from Redis
+fn parse_schema(prefix: &str, schema: &str) -> Result<Value> {
+    serde_json::from_str(schema)
+        .map_err(|e| KVStoreError::Internal(format!("Corrupt schema for {}: {}", prefix, e)))
+}
+
+/// Validate `document` against a schema already resolved with `schema_for`
+pub(crate) fn validate_against(
+    key: &str,
+    schema: Option<&(String, Value)>,
+    document: &Value,
+) -> Result<()> {
+    match schema {
+        Some((prefix, schema)) => check_document(key, prefix, schema, document),
+        None => Ok(()),
+    }
+}
+
+impl KVStore {
+    /// Bind a JSON Schema to a key prefix, replacing any schema already bound to it
+    ///
+    /// Only subsequent writes are validated; existing values are left as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Authentication token (used as namespace prefix)
+    /// * `prefix` - Key prefix the schema applies to, e.g. `user:`
+    /// * `schema` - The JSON Schema document
+    pub async fn register_schema(&self, token: &str, prefix: &str, schema: &Value) -> Result<()> {
+        if prefix.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "Schema prefix must not be empty".to_string(),
+            ));
+        }
+        compile(schema)?;
+
+        let schemas_key = schemas_key(token);
+        tracing::debug!("HSET {} {}", schemas_key, prefix);
+
+        let mut conn = self.conn.clone();
+        conn.hset::<_, _, _, ()>(&schemas_key, prefix, schema.to_string())
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to register schema for {}: {}", prefix, e);
+                redis_error(prefix, e)
+            })?;
+
+        Ok(())
+    }
+
+    /// Get the schema bound to exactly `prefix`
+    pub async fn get_schema(&self, token: &str, prefix: &str) -> Result<Value> {
+        let mut conn = self.conn.clone();
+        let schema: Option<String> = conn.hget(schemas_key(token), prefix).await.map_err(|e| {
+            tracing::error!("Failed to get schema for {}: {}", prefix, e);
+            redis_error(prefix, e)
+        })?;
+
+        let schema = schema.ok_or_else(|| KVStoreError::KeyNotFound(prefix.to_string()))?;
+        parse_schema(prefix, &schema)
+    }
+
+    /// List every registered schema of a namespace
+    ///
+    /// # Returns
+    ///
+    /// Prefix/schema pairs ordered by prefix
+    pub async fn list_schemas(&self, token: &str) -> Result<Vec<(String, Value)>> {
+        let mut schemas = self
+            .load_schemas(token)
+            .await?
+            .into_iter()
+            .map(|(prefix, schema)| {
+                let schema = parse_schema(&prefix, &schema)?;
+                Ok((prefix, schema))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        schemas.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(schemas)
+    }
+
+    /// Remove the schema bound to `prefix`
+    ///
+    /// # Returns
+    ///
+    /// [`KVStoreError::KeyNotFound`] if no schema was bound to the prefix
+    pub async fn delete_schema(&self, token: &str, prefix: &str) -> Result<()> {
+        let mut conn = self.conn.clone();
+        let removed: usize = conn.hdel(schemas_key(token), prefix).await.map_err(|e| {
+            tracing::error!("Failed to delete schema for {}: {}", prefix, e);
+            redis_error(prefix, e)
+        })?;
+
+        if removed == 0 {
+            return Err(KVStoreError::KeyNotFound(prefix.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Validate a JSON document about to be written to `key`
+    pub(crate) async fn validate_document(
+        &self,
+        token: &str,
+        key: &str,
+        document: &Value,
+    ) -> Result<()> {
+        match self.schema_for(token, key).await? {
+            Some((prefix, schema)) => check_document(key, &prefix, &schema, document),
+            None => Ok(()),
+        }
+    }
+
+    /// Validate a plain value about to be written to `key`
+    ///
+    /// Values under a schema-bound prefix are parsed as JSON text; values that
+    /// aren't JSON are validated as JSON strings.
+    pub(crate) async fn validate_text(&self, token: &str, key: &str, value: &str) -> Result<()> {
+        let Some((prefix, schema)) = self.schema_for(token, key).await? else {
+            return Ok(());
+        };
+
+        let document =
+            serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+        check_document(key, &prefix, &schema, &document)
+    }
+
+    /// Find the prefix and schema that apply to `key`, if any
+    pub(crate) async fn schema_for(
+        &self,
+        token: &str,
+        key: &str,
+    ) -> Result<Option<(String, Value)>> {
+        match matching_schema(self.load_schemas(token).await?, key) {
+            Some((prefix, schema)) => {
+                let schema = parse_schema(&prefix, &schema)?;
+                Ok(Some((prefix, schema)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn load_schemas(&self, token: &str) -> Result<HashMap<String, String>> {
+        let schemas_key = schemas_key(token);
+        let mut conn = self.conn.clone();
+        let schemas: HashMap<String, String> = conn.hgetall(&schemas_key).await.map_err(|e| {
+            tracing::error!("Failed to load schemas {}: {}", schemas_key, e);
+            e
+        })?;
+
+        Ok(schemas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_matching_schema_prefers_longest_prefix() {
+        let schemas = HashMap::from([
+            ("user:".to_string(), "a".to_string()),
+            ("user:admin:".to_string(), "b".to_string()),
+            ("config:".to_string(), "c".to_string()),
+        ]);
+
+        assert_eq!(
+            matching_schema(schemas.clone(), "user:admin:1").map(|(p, _)| p),
+            Some("user:admin:".to_string())
+        );
+        assert_eq!(
+            matching_schema(schemas.clone(), "user:1").map(|(p, _)| p),
+            Some("user:".to_string())
+        );
+        assert_eq!(matching_schema(schemas, "session:1"), None);
+    }
+
+    #[test]
+    fn test_check_document_lists_every_violation() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer", "minimum": 0}
+            },
+            "required": ["name"]
+        });
+
+        assert!(check_document("user:1", "user:", &schema, &json!({"name": "Alice"})).is_ok());
+
+        let Err(KVStoreError::InvalidRequest(message)) =
+            check_document("user:1", "user:", &schema, &json!({"age": -1}))
+        else {
+            panic!("expected a validation error");
+        };
+        assert!(message.contains("user:"));
+        assert!(message.contains("/age"));
+        assert!(message.contains("name"));
+    }
+
+    #[test]
+    fn test_compile_rejects_invalid_schema() {
+        assert!(matches!(
+            compile(&json!({"type": 12})),
+            Err(KVStoreError::InvalidRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,249 @@
+//! Key search by prefix, glob or regular expression
+//!
+//! Searches walk the namespace with SCAN a batch at a time. Each call returns
+//! at most one page of keys and gives up after a timeout, handing back a
+//! cursor to continue from, so broad patterns can't stall Redis.
+
+use super::{namespaced_key, prefix, KVStore};
+use crate::error::{KVStoreError, Result};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Number of keys returned per page unless the query asks for another limit
+pub const DEFAULT_SEARCH_LIMIT: usize = 100;
+
+/// Maximum number of keys returned per page
+pub const MAX_SEARCH_LIMIT: usize = 1000;
+
+/// Time spent scanning per page unless the query asks for another timeout
+pub const DEFAULT_SEARCH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum time spent scanning per page
+pub const MAX_SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum compiled size of a search regex, in bytes
+const MAX_REGEX_SIZE: usize = 1 << 20;
+
+/// How a search pattern is matched against keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// Keys starting with the pattern
+    #[default]
+    Prefix,
+    /// Keys matching a Redis glob pattern, e.g. `session:*:cart`
+    Glob,
+    /// Keys containing a match of a regular expression; anchor it with `^`
+    /// and `$` to match whole keys
+    Regex,
+}
+
+/// A key search
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub mode: SearchMode,
+    pub pattern: String,
+    /// Cursor returned with the previous page, `None` to start over
+    pub cursor: Option<String>,
+    /// Maximum number of keys to return, [`DEFAULT_SEARCH_LIMIT`] by default
+    pub limit: Option<usize>,
+    /// Time to spend scanning, [`DEFAULT_SEARCH_TIMEOUT`] by default
+    pub timeout: Option<Duration>,
+}
+
+/// A page of search results
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SearchPage {
+    /// Matching keys, in no particular order
+    pub keys: Vec<String>,
+    /// Cursor to pass to get the next page, `None` once the search is complete
+    pub cursor: Option<String>,
+    /// Whether the page was cut short by the timeout
+    pub timed_out: bool,
+}
+
+/// Position in a search: the SCAN cursor to resume from and how many matching
+/// keys of that SCAN batch were already returned
+///
+/// Keys written or deleted between pages may be missed or returned twice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Position {
+    scan: u64,
+    skip: usize,
+}
+
+impl Position {
+    fn parse(cursor: &str) -> Result<Self> {
+        cursor
+            .split_once('-')
+            .and_then(|(scan, skip)| {
+                Some(Self {
+                    scan: scan.parse().ok()?,
+                    skip: skip.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| KVStoreError::InvalidRequest(format!("Invalid cursor: {}", cursor)))
+    }
+
+    fn encode(self) -> String {
+        format!("{}-{}", self.scan, self.skip)
+    }
+}
+
+/// Build the SCAN pattern and, for regex searches, the regex keys must match
+fn compile(token: &str, mode: SearchMode, pattern: &str) -> Result<(String, Option<Regex>)> {
+    match mode {
+        SearchMode::Prefix => Ok((prefix::prefix_pattern(token, pattern), None)),
+        SearchMode::Glob => Ok((
+            format!(
+                "{}{}",
+                prefix::escape_glob(&namespaced_key(token, "")),
+                pattern
+            ),
+            None,
+        )),
+        SearchMode::Regex => {
+            let regex = RegexBuilder::new(pattern)
+                .size_limit(MAX_REGEX_SIZE)
+                .build()
+                .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid regex: {}", e)))?;
+            Ok((prefix::prefix_pattern(token, ""), Some(regex)))
+        }
+    }
+}
+
+/// Check a limit or timeout against its maximum, applying the default
+fn bounded<T: PartialOrd + Copy + std::fmt::Debug>(
+    name: &str,
+    value: Option<T>,
+    default: T,
+    zero: T,
+    max: T,
+) -> Result<T> {
+    let value = value.unwrap_or(default);
+    if value <= zero || value > max {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "The {} must be positive and at most {:?}",
+            name, max
+        )));
+    }
+    Ok(value)
+}
+
+impl KVStore {
+    /// Search the keys of a namespace by prefix, glob or regular expression
+    ///
+    /// Returns a page of at most the query's limit of keys. Pass the page's
+    /// cursor back in the query to continue; a page may come back short, or
+    /// even empty, with a cursor when the timeout runs out first.
+    pub async fn search(&self, token: &str, query: &SearchQuery) -> Result<SearchPage> {
+        let limit = bounded(
+            "limit",
+            query.limit,
+            DEFAULT_SEARCH_LIMIT,
+            0,
+            MAX_SEARCH_LIMIT,
+        )?;
+        let timeout = bounded(
+            "timeout",
+            query.timeout,
+            DEFAULT_SEARCH_TIMEOUT,
+            Duration::ZERO,
+            MAX_SEARCH_TIMEOUT,
+        )?;
+        let mut position = match &query.cursor {
+            Some(cursor) => Position::parse(cursor)?,
+            None => Position::default(),
+        };
+        let (pattern, regex) = compile(token, query.mode, &query.pattern)?;
+        tracing::debug!(
+            "SEARCH {:?} {} from {:?} (limit: {})",
+            query.mode,
+            pattern,
+            position,
+            limit
+        );
+
+        let prefix_len = token.len() + 1; // +1 for the colon
+        let deadline = Instant::now() + timeout;
+        let mut page = SearchPage::default();
+
+        loop {
+            let (next, batch) = self.scan_batch(&pattern, position.scan).await?;
+            let matches = batch
+                .iter()
+                .map(|redis_key| &redis_key[prefix_len..])
+                .filter(|key| match &regex {
+                    Some(regex) => regex.is_match(key),
+                    None => true,
+                });
+
+            for (index, key) in matches.enumerate().skip(position.skip) {
+                if page.keys.len() == limit {
+                    position.skip = index;
+                    page.cursor = Some(position.encode());
+                    return Ok(page);
+                }
+                page.keys.push(key.to_string());
+            }
+
+            if next == 0 {
+                return Ok(page);
+            }
+            position = Position {
+                scan: next,
+                skip: 0,
+            };
+            if page.keys.len() == limit {
+                page.cursor = Some(position.encode());
+                return Ok(page);
+            }
+            if Instant::now() >= deadline {
+                page.cursor = Some(position.encode());
+                page.timed_out = true;
+                return Ok(page);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_round_trip() {
+        let position = Position { scan: 42, skip: 3 };
+        assert_eq!(Position::parse(&position.encode()).unwrap(), position);
+        assert!(Position::parse("42").is_err());
+        assert!(Position::parse("a-1").is_err());
+    }
+
+    #[test]
+    fn test_compile_patterns() {
+        let (pattern, regex) = compile("tok", SearchMode::Prefix, "a*").unwrap();
+        assert_eq!(pattern, r"tok:a\**");
+        assert!(regex.is_none());
+
+        let (pattern, _) = compile("t*k", SearchMode::Glob, "session:*:cart").unwrap();
+        assert_eq!(pattern, r"t\*k:session:*:cart");
+
+        let (pattern, regex) = compile("tok", SearchMode::Regex, "^user:[0-9]+$").unwrap();
+        assert_eq!(pattern, "tok:*");
+        assert!(regex.unwrap().is_match("user:42"));
+
+        assert!(matches!(
+            compile("tok", SearchMode::Regex, "("),
+            Err(KVStoreError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_bounded() {
+        assert_eq!(bounded("limit", None, 100, 0, 1000).unwrap(), 100);
+        assert_eq!(bounded("limit", Some(5), 100, 0, 1000).unwrap(), 5);
+        assert!(bounded("limit", Some(0), 100, 0, 1000).is_err());
+        assert!(bounded("limit", Some(1001), 100, 0, 1000).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Set-valued keys
+//!
+//! Stores unordered collections of unique members, e.g. tags or group
+//! membership.
+
+use super::watch::CollectionWrite;
+use super::{namespaced_key, redis_error};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use redis::AsyncCommands;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+
+/// Largest number of keys a single intersection or union may combine
+pub const MAX_SET_OPERANDS: usize = 32;
+
+/// How the members of several sets are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOperation {
+    /// Members present in every set
+    Intersection,
+    /// Members present in any set
+    Union,
+}
+
+impl KVStore {
+    /// Add members to a set, creating the set if needed
+    ///
+    /// # Returns
+    ///
+    /// The number of members that were newly added
+    pub async fn set_add(&self, token: &str, key: &str, members: &[String]) -> Result<usize> {
+        if members.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "At least one member is required".to_string(),
+            ));
+        }
+        self.policy.check_key(key)?;
+        self.policy
+            .check_values(members.iter().map(String::as_str))?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("SADD {} ({} members)", namespaced_key, members.len());
+
+        let added: usize = self
+            .write_collection(token, key, CollectionWrite::Counted, Some("SADD"), members)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to add members to {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(added)
+    }
+
+    /// Remove members from a set
+    ///
+    /// # Returns
+    ///
+    /// The number of members that were removed
+    pub async fn set_remove(&self, token: &str, key: &str, members: &[String]) -> Result<usize> {
+        if members.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "At least one member is required".to_string(),
+            ));
+        }
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("SREM {} {:?}", namespaced_key, members);
+
+        let removed: usize = self
+            .write_collection(token, key, CollectionWrite::Counted, Some("SREM"), members)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to remove members from {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(removed)
+    }
+
+    /// Check whether `member` belongs to a set
+    pub async fn set_is_member(&self, token: &str, key: &str, member: &str) -> Result<bool> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("SISMEMBER {} {}", namespaced_key, member);
+
+        let mut conn = self.conn.clone();
+        let is_member: bool = conn.sismember(&namespaced_key, member).await.map_err(|e| {
+            tracing::error!("Failed to check membership in {}: {}", namespaced_key, e);
+            redis_error(key, e)
+        })?;
+
+        Ok(is_member)
+    }
+
+    /// Get the number of members in a set
+    ///
+    /// # Returns
+    ///
+    /// The cardinality of the set, or zero if it doesn't exist
+    pub async fn set_cardinality(&self, token: &str, key: &str) -> Result<usize> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("SCARD {}", namespaced_key);
+
+        let mut conn = self.conn.clone();
+        let cardinality: usize = conn.scard(&namespaced_key).await.map_err(|e| {
+            tracing::error!("Failed to get cardinality of {}: {}", namespaced_key, e);
+            redis_error(key, e)
+        })?;
+
+        Ok(cardinality)
+    }
+
+    /// Read one page of set members using `SSCAN`
+    ///
+    /// # Arguments
+    ///
+    /// * `cursor` - `0` to start, then the cursor returned by the previous page
+    /// * `count` - Hint for how many members to return per page
+    ///
+    /// # Returns
+    ///
+    /// The next cursor (`0` once iteration is complete) and the members found.
+    /// Members added or removed during iteration may or may not be returned.
+    pub async fn set_scan(
+        &self,
+        token: &str,
+        key: &str,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<String>)> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("SSCAN {} {} COUNT {}", namespaced_key, cursor, count);
+
+        let mut conn = self.conn.clone();
+        let page: (u64, Vec<String>) = redis::cmd("SSCAN")
+            .arg(&namespaced_key)
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(count.max(1))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to SSCAN {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(page)
+    }
+
+    /// Stream every member of a set
+    ///
+    /// Members are read incrementally with `SSCAN`, so large sets never have to
+    /// be loaded in one command.
+    ///
+    /// # Returns
+    ///
+    /// A stream of members
+    pub async fn set_members(&self, token: &str, key: &str) -> Result<impl Stream<Item = String>> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("SMEMBERS (SSCAN) {}", namespaced_key);
+
+        let conn = self.conn.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+
+        tokio::spawn(async move {
+            let mut conn = conn;
+            let mut iter = match conn.sscan::<_, String>(&namespaced_key).await {
+                Ok(iter) => iter,
+                Err(e) => {
+                    tracing::error!("Failed to SSCAN {}: {}", namespaced_key, e);
+                    return;
+                }
+            };
+
+            while let Some(member) = iter.next_item().await {
+                if tx.send(member).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Combine several sets of the same namespace
+    ///
+    /// Every key is resolved inside `token`'s namespace, so sets belonging to
+    /// other tokens can never take part in the operation.
+    ///
+    /// # Returns
+    ///
+    /// The members of the intersection or union
+    pub async fn set_combine(
+        &self,
+        token: &str,
+        keys: &[String],
+        operation: SetOperation,
+    ) -> Result<Vec<String>> {
+        if keys.is_empty() || keys.len() > MAX_SET_OPERANDS {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Between 1 and {} keys are required",
+                MAX_SET_OPERANDS
+            )));
+        }
+
+        let namespaced_keys: Vec<String> = keys.iter().map(|k| namespaced_key(token, k)).collect();
+        tracing::debug!("{:?} {:?}", operation, namespaced_keys);
+
+        let command = match operation {
+            SetOperation::Intersection => "SINTER",
+            SetOperation::Union => "SUNION",
+        };
+        let mut conn = self.conn.clone();
+        let members: Vec<String> = redis::cmd(command)
+            .arg(&namespaced_keys)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to {} {:?}: {}", command, namespaced_keys, e);
+                redis_error(&keys.join(", "), e)
+            })?;
+
+        Ok(members)
+    }
+}
@@ -0,0 +1,376 @@
+//! Sorted-set-valued keys
+//!
+//! Stores members ranked by a numeric score, e.g. leaderboards or priority
+//! schedules.
+
+use super::watch::CollectionWrite;
+use super::{namespaced_key, redis_error};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Largest page a single range query may return
+pub const MAX_RANGE_LIMIT: usize = 1000;
+
+/// A sorted set member together with its score
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredMember {
+    pub member: String,
+    /// Infinite scores are written as the strings `"inf"` and `"-inf"`, which
+    /// JSON numbers can't hold
+    #[serde(
+        serialize_with = "serialize_score",
+        deserialize_with = "deserialize_score"
+    )]
+    pub score: f64,
+}
+
+fn serialize_score<S: Serializer>(
+    score: &f64,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    if score.is_finite() {
+        serializer.serialize_f64(*score)
+    } else if *score > 0.0 {
+        serializer.serialize_str("inf")
+    } else {
+        serializer.serialize_str("-inf")
+    }
+}
+
+fn deserialize_score<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<f64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Score {
+        Number(f64),
+        Text(String),
+    }
+
+    match Score::deserialize(deserializer)? {
+        Score::Number(score) => Ok(score),
+        Score::Text(text) => match text.as_str() {
+            "inf" | "+inf" => Ok(f64::INFINITY),
+            "-inf" => Ok(f64::NEG_INFINITY),
+            _ => Err(serde::de::Error::custom(format!("Invalid score {}", text))),
+        },
+    }
+}
+
+/// Format a score bound the way Redis expects, mapping infinities to `-inf`/`+inf`
+pub(super) fn score_bound(score: f64) -> String {
+    if score == f64::INFINITY {
+        "+inf".to_string()
+    } else if score == f64::NEG_INFINITY {
+        "-inf".to_string()
+    } else {
+        score.to_string()
+    }
+}
+
+fn check_limit(limit: usize) -> Result<()> {
+    if limit == 0 || limit > MAX_RANGE_LIMIT {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Limit must be between 1 and {}",
+            MAX_RANGE_LIMIT
+        )));
+    }
+    Ok(())
+}
+
+impl KVStore {
+    /// Add members to a sorted set, or update the scores of existing members
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Authentication token (used as namespace prefix)
+    /// * `key` - The sorted set key
+    /// * `members` - Members and their scores
+    ///
+    /// # Returns
+    ///
+    /// The number of members that were newly added
+    pub async fn sorted_set_add(
+        &self,
+        token: &str,
+        key: &str,
+        members: &[ScoredMember],
+    ) -> Result<usize> {
+        if members.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "At least one member is required".to_string(),
+            ));
+        }
+        if members.iter().any(|m| m.score.is_nan()) {
+            return Err(KVStoreError::InvalidRequest(
+                "Scores must be numbers".to_string(),
+            ));
+        }
+        self.policy.check_key(key)?;
+        self.policy
+            .check_values(members.iter().map(|m| m.member.as_str()))?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("ZADD {} ({} members)", namespaced_key, members.len());
+
+        let args: Vec<(f64, &str)> = members
+            .iter()
+            .map(|m| (m.score, m.member.as_str()))
+            .collect();
+        let added: usize = self
+            .write_collection(token, key, CollectionWrite::Always, Some("ZADD"), args)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to add members to {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(added)
+    }
+
+    /// Atomically increment the score of a member, adding it if needed
+    ///
+    /// # Returns
+    ///
+    /// The new score of the member
+    pub async fn sorted_set_increment(
+        &self,
+        token: &str,
+        key: &str,
+        member: &str,
+        delta: f64,
+    ) -> Result<f64> {
+        if delta.is_nan() {
+            return Err(KVStoreError::InvalidRequest(
+                "Increment must be a number".to_string(),
+            ));
+        }
+        self.policy.check_key(key)?;
+        self.policy.check_value(member)?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("ZINCRBY {} {} {}", namespaced_key, member, delta);
+
+        let score: f64 = self
+            .write_collection(
+                token,
+                key,
+                CollectionWrite::Always,
+                Some("ZINCRBY"),
+                (delta, member),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!(
+                    "Failed to increment {} in {}: {}",
+                    member,
+                    namespaced_key,
+                    e
+                );
+                redis_error(key, e)
+            })?;
+
+        Ok(score)
+    }
+
+    /// Get a page of members ordered by rank
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Rank of the first member to return (zero-based)
+    /// * `limit` - Maximum number of members to return (at most [`MAX_RANGE_LIMIT`])
+    /// * `reverse` - Order from highest to lowest score instead
+    pub async fn sorted_set_range_by_rank(
+        &self,
+        token: &str,
+        key: &str,
+        offset: usize,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<Vec<ScoredMember>> {
+        check_limit(limit)?;
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!(
+            "ZRANGE {} offset {} limit {} (rev: {})",
+            namespaced_key,
+            offset,
+            limit,
+            reverse
+        );
+
+        let mut cmd = redis::cmd("ZRANGE");
+        cmd.arg(&namespaced_key)
+            .arg(offset)
+            .arg(offset.saturating_add(limit - 1));
+        if reverse {
+            cmd.arg("REV");
+        }
+        cmd.arg("WITHSCORES");
+
+        self.query_scored_members(key, &namespaced_key, cmd).await
+    }
+
+    /// Get a page of members whose score lies within `[min, max]`
+    ///
+    /// Infinite bounds select everything below or above the other bound.
+    ///
+    /// # Arguments
+    ///
+    /// * `min` / `max` - Inclusive score bounds
+    /// * `offset` - Number of matching members to skip
+    /// * `limit` - Maximum number of members to return (at most [`MAX_RANGE_LIMIT`])
+    /// * `reverse` - Order from highest to lowest score instead
+    #[allow(clippy::too_many_arguments)]
+    pub async fn sorted_set_range_by_score(
+        &self,
+        token: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<Vec<ScoredMember>> {
+        check_limit(limit)?;
+        if min.is_nan() || max.is_nan() {
+            return Err(KVStoreError::InvalidRequest(
+                "Score bounds must be numbers".to_string(),
+            ));
+        }
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!(
+            "ZRANGE {} BYSCORE [{}, {}] offset {} limit {} (rev: {})",
+            namespaced_key,
+            min,
+            max,
+            offset,
+            limit,
+            reverse
+        );
+
+        // With REV the bounds are given from high to low
+        let (first, second) = if reverse { (max, min) } else { (min, max) };
+        let mut cmd = redis::cmd("ZRANGE");
+        cmd.arg(&namespaced_key)
+            .arg(score_bound(first))
+            .arg(score_bound(second))
+            .arg("BYSCORE");
+        if reverse {
+            cmd.arg("REV");
+        }
+        cmd.arg("LIMIT").arg(offset).arg(limit).arg("WITHSCORES");
+
+        self.query_scored_members(key, &namespaced_key, cmd).await
+    }
+
+    /// Get the rank and score of a member
+    ///
+    /// # Returns
+    ///
+    /// The zero-based rank (highest score first when `reverse` is set) and the
+    /// score, or `None` if the member doesn't exist
+    pub async fn sorted_set_rank(
+        &self,
+        token: &str,
+        key: &str,
+        member: &str,
+        reverse: bool,
+    ) -> Result<Option<(usize, f64)>> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("ZRANK {} {} (rev: {})", namespaced_key, member, reverse);
+
+        let (rank, score): (Option<usize>, Option<f64>) = redis::pipe()
+            .atomic()
+            .cmd(if reverse { "ZREVRANK" } else { "ZRANK" })
+            .arg(&namespaced_key)
+            .arg(member)
+            .cmd("ZSCORE")
+            .arg(&namespaced_key)
+            .arg(member)
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(|e| {
+                tracing::error!(
+                    "Failed to get rank of {} in {}: {}",
+                    member,
+                    namespaced_key,
+                    e
+                );
+                redis_error(key, e)
+            })?;
+
+        Ok(rank.zip(score))
+    }
+
+    /// Remove members from a sorted set
+    ///
+    /// # Returns
+    ///
+    /// The number of members that were removed
+    pub async fn sorted_set_remove(
+        &self,
+        token: &str,
+        key: &str,
+        members: &[String],
+    ) -> Result<usize> {
+        if members.is_empty() {
+            return Err(KVStoreError::InvalidRequest(
+                "At least one member is required".to_string(),
+            ));
+        }
+
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("ZREM {} {:?}", namespaced_key, members);
+
+        let removed: usize = self
+            .write_collection(token, key, CollectionWrite::Counted, Some("ZREM"), members)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to remove members from {}: {}", namespaced_key, e);
+                redis_error(key, e)
+            })?;
+
+        Ok(removed)
+    }
+
+    async fn query_scored_members(
+        &self,
+        key: &str,
+        namespaced_key: &str,
+        cmd: redis::Cmd,
+    ) -> Result<Vec<ScoredMember>> {
+        let mut conn = self.conn.clone();
+        let members: Vec<(String, f64)> = cmd.query_async(&mut conn).await.map_err(|e| {
+            tracing::error!("Failed to read range of {}: {}", namespaced_key, e);
+            redis_error(key, e)
+        })?;
+
+        Ok(members
+            .into_iter()
+            .map(|(member, score)| ScoredMember { member, score })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_bound() {
+        assert_eq!(score_bound(f64::INFINITY), "+inf");
+        assert_eq!(score_bound(f64::NEG_INFINITY), "-inf");
+        assert_eq!(score_bound(1.5), "1.5");
+    }
+
+    #[test]
+    fn test_check_limit() {
+        assert!(check_limit(0).is_err());
+        assert!(check_limit(1).is_ok());
+        assert!(check_limit(MAX_RANGE_LIMIT).is_ok());
+        assert!(check_limit(MAX_RANGE_LIMIT + 1).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Usage statistics of a namespace
+
+use super::{prefix, KVStore};
+use crate::error::Result;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap};
+
+/// Number of keys reported in [`NamespaceStats::largest_keys`]
+pub const STATS_LARGEST_KEYS: usize = 10;
+
+/// Memory used by a single key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeySize {
+    pub key: String,
+    /// Approximate memory used by the key and its value, in bytes
+    pub bytes: u64,
+}
+
+/// Usage statistics of a namespace
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct NamespaceStats {
+    /// Number of keys
+    pub key_count: u64,
+    /// Approximate memory used by all keys, in bytes
+    pub total_bytes: u64,
+    /// Number of keys that expire
+    pub keys_with_ttl: u64,
+    /// The largest keys, largest first
+    pub largest_keys: Vec<KeySize>,
+}
+
+/// Keeps the `limit` largest keys offered to it
+struct LargestKeys {
+    limit: usize,
+    heap: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+impl LargestKeys {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            heap: BinaryHeap::with_capacity(limit + 1),
+        }
+    }
+
+    fn offer(&mut self, key: &str, bytes: u64) {
+        if self.heap.len() == self.limit {
+            match self.heap.peek() {
+                Some(Reverse((smallest, _))) if *smallest < bytes => {
+                    self.heap.pop();
+                }
+                _ => return,
+            }
+        }
+        self.heap.push(Reverse((bytes, key.to_string())));
+    }
+
+    fn into_sorted_vec(self) -> Vec<KeySize> {
+        // Ascending order of `Reverse` is descending order of size
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((bytes, key))| KeySize { key, bytes })
+            .collect()
+    }
+}
+
+impl KVStore {
+    /// Gather usage statistics of the namespace owned by `token`
+    ///
+    /// The namespace is scanned incrementally and each key is measured with
+    /// `MEMORY USAGE`, so the cost grows with the number of keys. Figures are
+    /// approximate when keys change during the scan, and don't include the
+    /// metadata kvstore keeps for each key.
+    pub async fn stats(&self, token: &str) -> Result<NamespaceStats> {
+        let pattern = prefix::prefix_pattern(token, "");
+        tracing::debug!("STATS {}", pattern);
+
+        let prefix_len = token.len() + 1; // +1 for the colon
+        let mut conn = self.conn.clone();
+        let mut cursor = 0u64;
+        let mut stats = NamespaceStats::default();
+        let mut largest = LargestKeys::new(STATS_LARGEST_KEYS);
+        // SCAN may return a key more than once, so only its first sighting counts
+        let mut seen = BTreeSet::new();
+
+        loop {
+            let (next, batch) = self.scan_batch(&pattern, cursor).await?;
+            let batch: Vec<String> = batch
+                .into_iter()
+                .filter(|key| seen.insert(key.clone()))
+                .collect();
+
+            if !batch.is_empty() {
+                let mut pipe = redis::pipe();
+                for redis_key in &batch {
+                    pipe.cmd("MEMORY")
+                        .arg("USAGE")
+                        .arg(redis_key)
+                        .cmd("PTTL")
+                        .arg(redis_key);
+                }
+                let replies: Vec<Option<i64>> = pipe.query_async(&mut conn).await.map_err(|e| {
+                    tracing::error!("Failed to measure keys matching {}: {}", pattern, e);
+                    e
+                })?;
+
+                for (redis_key, reply) in batch.iter().zip(replies.chunks(2)) {
+                    // Keys that expired since the SCAN have no size
+                    let (Some(bytes), Some(ttl)) = (reply[0], reply[1]) else {
+                        continue;
+                    };
+                    let bytes = bytes.max(0) as u64;
+
+                    stats.key_count += 1;
+                    stats.total_bytes += bytes;
+                    if ttl >= 0 {
+                        stats.keys_with_ttl += 1;
+                    }
+                    largest.offer(&redis_key[prefix_len..], bytes);
+                }
+            }
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        stats.largest_keys = largest.into_sorted_vec();
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_largest_keys_keeps_the_largest() {
+        let mut largest = LargestKeys::new(2);
+        largest.offer("a", 10);
+        largest.offer("b", 30);
+        largest.offer("c", 5);
+        largest.offer("d", 20);
+
+        assert_eq!(
+            largest.into_sorted_vec(),
+            vec![
+                KeySize {
+                    key: "b".to_string(),
+                    bytes: 30
+                },
+                KeySize {
+                    key: "d".to_string(),
+                    bytes: 20
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_largest_keys_with_zero_limit() {
+        let mut largest = LargestKeys::new(0);
+        largest.offer("a", 10);
+        assert!(largest.into_sorted_vec().is_empty());
+    }
+}
@@ -0,0 +1,539 @@
+//! Multi-key atomic transactions
+//!
+//! A transaction checks a list of preconditions and, only if all of them hold,
+//! applies a list of operations. Everything runs in a single Lua script, so no
+//! other client can observe or interleave with a partially applied
+//! transaction. Operations are checked against the keys' current state before
+//! the first write, so a transaction either applies completely or not at all.
+
+use super::history::{history_key, history_policy_key};
+use super::index::indexes_key;
+use super::metadata::{add_namespace, ttl_millis, value_script};
+use super::trash::{trash_key, trash_meta_key};
+use super::{meta_key, namespaced_key, TEXT_CONTENT_TYPE};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Largest number of operations a single transaction may contain
+pub const MAX_TRANSACTION_OPERATIONS: usize = 128;
+
+/// Check preconditions, then apply operations
+///
+/// KEYS[1] - history policy key, KEYS[2] - index definitions key, then for
+/// every key of the transaction its value, metadata, history, trashed value
+/// and trashed metadata keys
+/// ARGV[1] - JSON plan: `{"preconditions": [...], "operations": [...]}` whose
+/// entries refer to keys by their 1-based position
+///
+/// Returns JSON: `{"failed": n}` for the first failed precondition,
+/// `{"error": n, "reason": ...}` for an operation that cannot be applied, or
+/// `{"results": [...]}` once every operation has been applied.
+const TRANSACTION_SCRIPT: &str = r#"
+local plan = cjson.decode(ARGV[1])
+local policy_key = KEYS[1]
+local indexes_key = KEYS[2]
+
+local function keys_of(i)
+    local base = 3 + (i - 1) * 5
+    return KEYS[base], KEYS[base + 1], KEYS[base + 2], KEYS[base + 3], KEYS[base + 4]
+end
+
+local function is_integer(value)
+    return string.len(value) <= 20
+        and (value == '0' or string.match(value, '^-?[1-9]%d*$') ~= nil)
+end
+
+for n, check in ipairs(plan.preconditions) do
+    local value_key, meta_key = keys_of(check.key)
+    local exists = redis.call('EXISTS', value_key) == 1
+    local ok = false
+    if check.check == 'exists' then
+        ok = exists
+    elseif check.check == 'missing' then
+        ok = not exists
+    elseif check.check == 'version' then
+        local version = 0
+        if exists then
+            version = tonumber(redis.call('HGET', meta_key, 'version')) or 0
+        end
+        ok = version == tonumber(check.version)
+    elseif check.check == 'value' then
+        ok = redis.call('TYPE', value_key).ok == 'string'
+            and redis.call('GET', value_key) == check.value
+    end
+    if not ok then
+        return cjson.encode({failed = n - 1})
+    end
+end
+
+-- Walk the operations against the keys' simulated state first, so that
+-- nothing can fail once the first write has been made
+local state = {}
+for n, op in ipairs(plan.operations) do
+    local current = state[op.key]
+    if current == nil then
+        local value_key = keys_of(op.key)
+        current = {kind = redis.call('TYPE', value_key).ok}
+        if current.kind == 'string' then
+            current.value = redis.call('GET', value_key)
+        end
+    end
+
+    if op.op == 'set' then
+        state[op.key] = {kind = 'string', value = op.value}
+    elseif op.op == 'delete' then
+        state[op.key] = {kind = 'none'}
+    elseif op.op == 'incr' then
+        if current.kind == 'none' then
+            current = {kind = 'string', value = '0'}
+        end
+        if current.kind ~= 'string' then
+            return cjson.encode({error = n - 1, reason = 'wrong_type'})
+        end
+        if not is_integer(current.value) then
+            return cjson.encode({error = n - 1, reason = 'not_integer'})
+        end
+        local result = tonumber(current.value) + tonumber(op.by)
+        if math.abs(result) >= 9223372036854775807 then
+            return cjson.encode({error = n - 1, reason = 'overflow'})
+        end
+        state[op.key] = {kind = 'string', value = string.format('%d', result)}
+    end
+end
+
+local results = {}
+for n, op in ipairs(plan.operations) do
+    local value_key, meta_key, history_key, trash_key, trash_meta = keys_of(op.key)
+    if op.op == 'set' then
+        local version = write_value(value_key, meta_key, history_key, policy_key, indexes_key,
+            op.value, op.ttl, op.content_type, {})
+        results[n] = {op = 'set', version = version}
+    elseif op.op == 'delete' then
+        local deleted = delete_value(value_key, meta_key, history_key, policy_key, indexes_key,
+            trash_key, trash_meta)
+        results[n] = {op = 'delete', deleted = deleted == 1}
+    elseif op.op == 'incr' then
+        redis.call('INCRBY', value_key, op.by)
+        local version = touch_value(value_key, meta_key, history_key, policy_key, indexes_key, false)
+        results[n] = {op = 'incr', value = redis.call('GET', value_key), version = version}
+    end
+end
+
+return cjson.encode({results = results})
+"#;
+
+/// A condition that must hold for a transaction to be applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "check", rename_all = "snake_case")]
+pub enum Precondition {
+    /// The key exists
+    Exists { key: String },
+    /// The key does not exist
+    Missing { key: String },
+    /// The key's version (see [`Metadata::version`](super::Metadata::version))
+    /// equals `version`; missing keys have version 0
+    Version { key: String, version: u64 },
+    /// The key holds exactly `value`
+    Value { key: String, value: String },
+}
+
+impl Precondition {
+    fn key(&self) -> &str {
+        match self {
+            Precondition::Exists { key }
+            | Precondition::Missing { key }
+            | Precondition::Version { key, .. }
+            | Precondition::Value { key, .. } => key,
+        }
+    }
+}
+
+/// A change applied by a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Set a value, like [`KVStore::set_with_options`] without tags
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_seconds: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+    },
+    /// Delete a key of any type
+    Delete { key: String },
+    /// Add `by` to an integer value, treating missing keys as 0
+    Incr {
+        key: String,
+        #[serde(default = "default_increment")]
+        by: i64,
+    },
+}
+
+fn default_increment() -> i64 {
+    1
+}
+
+impl Operation {
+    fn key(&self) -> &str {
+        match self {
+            Operation::Set { key, .. }
+            | Operation::Delete { key }
+            | Operation::Incr { key, .. } => key,
+        }
+    }
+}
+
+/// The result of an applied operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum OperationResult {
+    /// The key's new version
+    Set { version: u64 },
+    /// Whether the key existed
+    Delete { deleted: bool },
+    /// The new value and version
+    Incr { value: i64, version: u64 },
+}
+
+/// The outcome of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    /// Every operation was applied; results are in operation order
+    Committed(Vec<OperationResult>),
+    /// Nothing was applied because a precondition did not hold
+    PreconditionFailed {
+        /// Position of the precondition in the request
+        index: usize,
+        precondition: Precondition,
+    },
+}
+
+/// Distinct keys of a transaction in order of first use, with their positions
+#[derive(Debug, Default)]
+struct KeyTable {
+    keys: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl KeyTable {
+    /// The 1-based position of `key`, adding it if needed
+    fn position(&mut self, key: &str) -> usize {
+        if let Some(&position) = self.positions.get(key) {
+            return position;
+        }
+        self.keys.push(key.to_string());
+        let position = self.keys.len();
+        self.positions.insert(key.to_string(), position);
+        position
+    }
+}
+
+/// Encode the plan passed to the transaction script
+fn encode_plan(
+    preconditions: &[Precondition],
+    operations: &[Operation],
+) -> Result<(KeyTable, String)> {
+    let mut keys = KeyTable::default();
+
+    let preconditions: Vec<Value> = preconditions
+        .iter()
+        .map(|precondition| {
+            let mut check = serde_json::to_value(precondition).unwrap_or_default();
+            check["key"] = json!(keys.position(precondition.key()));
+            if let Precondition::Version { version, .. } = precondition {
+                // Lua numbers are doubles, so versions travel as strings
+                check["version"] = json!(version.to_string());
+            }
+            check
+        })
+        .collect();
+
+    let operations = operations
+        .iter()
+        .map(|operation| {
+            let key = keys.position(operation.key());
+            Ok(match operation {
+                Operation::Set {
+                    value,
+                    ttl_seconds,
+                    content_type,
+                    ..
+                } => json!({
+                    "op": "set",
+                    "key": key,
+                    "value": value,
+                    "ttl": ttl_millis(*ttl_seconds)?,
+                    "content_type": content_type.as_deref().unwrap_or(TEXT_CONTENT_TYPE),
+                }),
+                Operation::Delete { .. } => json!({"op": "delete", "key": key}),
+                Operation::Incr { by, .. } => json!({
+                    "op": "incr",
+                    "key": key,
+                    "by": by.to_string(),
+                }),
+            })
+        })
+        .collect::<Result<Vec<Value>>>()?;
+
+    let plan = json!({
+        "preconditions": preconditions,
+        "operations": operations,
+    });
+    Ok((keys, plan.to_string()))
+}
+
+/// Decode the reply of the transaction script
+fn decode_reply(
+    reply: &str,
+    preconditions: &[Precondition],
+    operations: &[Operation],
+) -> Result<TransactionOutcome> {
+    let reply: Value = serde_json::from_str(reply)
+        .map_err(|e| KVStoreError::Internal(format!("Invalid transaction reply: {}", e)))?;
+    let position = |field: &str| reply[field].as_u64().map(|n| n as usize);
+
+    if let Some(index) = position("failed") {
+        let precondition = preconditions.get(index).cloned().ok_or_else(|| {
+            KVStoreError::Internal(format!("Unknown precondition {} failed", index))
+        })?;
+        return Ok(TransactionOutcome::PreconditionFailed {
+            index,
+            precondition,
+        });
+    }
+
+    if let Some(index) = position("error") {
+        let key = operations
+            .get(index)
+            .map(Operation::key)
+            .unwrap_or_default();
+        return Err(match reply["reason"].as_str() {
+            Some("wrong_type") => KVStoreError::WrongType(key.to_string()),
+            Some("overflow") => KVStoreError::InvalidRequest(format!(
+                "Operation {} would overflow the value at {}",
+                index, key
+            )),
+            _ => KVStoreError::InvalidRequest(format!(
+                "Operation {} failed: value at {} is not an integer",
+                index, key
+            )),
+        });
+    }
+
+    let results = reply["results"]
+        .as_array()
+        .map(|results| results.iter().map(decode_result).collect())
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+
+    Ok(TransactionOutcome::Committed(results))
+}
+
+fn decode_result(result: &Value) -> Result<OperationResult> {
+    let version = result["version"].as_u64().unwrap_or_default();
+    match result["op"].as_str() {
+        Some("set") => Ok(OperationResult::Set { version }),
+        Some("delete") => Ok(OperationResult::Delete {
+            deleted: result["deleted"].as_bool().unwrap_or_default(),
+        }),
+        Some("incr") => {
+            let value = result["value"]
+                .as_str()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| KVStoreError::Internal("Invalid increment result".to_string()))?;
+            Ok(OperationResult::Incr { value, version })
+        }
+        _ => Err(KVStoreError::Internal(format!(
+            "Unknown transaction result: {}",
+            result
+        ))),
+    }
+}
+
+impl KVStore {
+    /// Atomically apply several operations if all preconditions hold
+    ///
+    /// Every key is resolved inside `token`'s namespace. Values written by
+    /// `set` operations are validated against the JSON Schemas of their
+    /// prefixes before anything is applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Authentication token (used as namespace prefix)
+    /// * `preconditions` - Conditions checked before any operation is applied
+    /// * `operations` - Operations applied in order (at most
+    ///   [`MAX_TRANSACTION_OPERATIONS`])
+    ///
+    /// # Returns
+    ///
+    /// The per-operation results, or the first precondition that failed
+    pub async fn transaction(
+        &self,
+        token: &str,
+        preconditions: &[Precondition],
+        operations: &[Operation],
+    ) -> Result<TransactionOutcome> {
+        if operations.is_empty() || operations.len() > MAX_TRANSACTION_OPERATIONS {
+            return Err(KVStoreError::InvalidRequest(format!(
+                "Between 1 and {} operations are required",
+                MAX_TRANSACTION_OPERATIONS
+            )));
+        }
+
+        for operation in operations {
+            match operation {
+                Operation::Set {
+                    key,
+                    value,
+                    content_type,
+                    ..
+                } => {
+                    let content_type = content_type.as_deref().unwrap_or(TEXT_CONTENT_TYPE);
+                    self.validate_write(token, key, value, content_type).await?;
+                }
+                Operation::Incr { key, .. } => self.policy.check_key(key)?,
+                Operation::Delete { .. } => {}
+            }
+        }
+
+        let (keys, plan) = encode_plan(preconditions, operations)?;
+        tracing::debug!(
+            "TRANSACTION {} ({} preconditions, {} operations, {} keys)",
+            token,
+            preconditions.len(),
+            operations.len(),
+            keys.keys.len()
+        );
+
+        let script = value_script(TRANSACTION_SCRIPT);
+        let mut invocation = script.key(history_policy_key(token));
+        invocation.key(indexes_key(token));
+        for key in &keys.keys {
+            invocation
+                .key(namespaced_key(token, key))
+                .key(meta_key(token, key))
+                .key(history_key(token, key))
+                .key(trash_key(token, key))
+                .key(trash_meta_key(token, key));
+        }
+        invocation.arg(plan);
+        add_namespace(&mut invocation, token);
+
+        let mut conn = self.conn.clone();
+        let reply: String = invocation.invoke_async(&mut conn).await.map_err(|e| {
+            tracing::error!("Failed to run transaction: {}", e);
+            e
+        })?;
+
+        decode_reply(&reply, preconditions, operations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_plan_numbers_keys_in_order_of_use() {
+        let preconditions = vec![Precondition::Version {
+            key: "b".to_string(),
+            version: 3,
+        }];
+        let operations = vec![
+            Operation::Set {
+                key: "a".to_string(),
+                value: "1".to_string(),
+                ttl_seconds: Some(2),
+                content_type: None,
+            },
+            Operation::Incr {
+                key: "b".to_string(),
+                by: 5,
+            },
+        ];
+
+        let (keys, plan) = encode_plan(&preconditions, &operations).unwrap();
+        assert_eq!(keys.keys, vec!["b".to_string(), "a".to_string()]);
+
+        let plan: Value = serde_json::from_str(&plan).unwrap();
+        assert_eq!(plan["preconditions"][0]["key"], 1);
+        assert_eq!(plan["preconditions"][0]["version"], "3");
+        assert_eq!(plan["operations"][0]["key"], 2);
+        assert_eq!(plan["operations"][0]["ttl"], 2000);
+        assert_eq!(plan["operations"][0]["content_type"], TEXT_CONTENT_TYPE);
+        assert_eq!(plan["operations"][1]["by"], "5");
+    }
+
+    #[test]
+    fn test_decode_reply() {
+        let preconditions = vec![Precondition::Exists {
+            key: "a".to_string(),
+        }];
+        let operations = vec![
+            Operation::Delete {
+                key: "a".to_string(),
+            },
+            Operation::Incr {
+                key: "n".to_string(),
+                by: 1,
+            },
+        ];
+
+        assert_eq!(
+            decode_reply(r#"{"failed":0}"#, &preconditions, &operations).unwrap(),
+            TransactionOutcome::PreconditionFailed {
+                index: 0,
+                precondition: preconditions[0].clone(),
+            }
+        );
+
+        let reply =
+            r#"{"results":[{"op":"delete","deleted":true},{"op":"incr","value":"7","version":2}]}"#;
+        assert_eq!(
+            decode_reply(reply, &preconditions, &operations).unwrap(),
+            TransactionOutcome::Committed(vec![
+                OperationResult::Delete { deleted: true },
+                OperationResult::Incr {
+                    value: 7,
+                    version: 2
+                },
+            ])
+        );
+
+        assert!(matches!(
+            decode_reply(
+                r#"{"error":1,"reason":"wrong_type"}"#,
+                &preconditions,
+                &operations
+            ),
+            Err(KVStoreError::WrongType(key)) if key == "n"
+        ));
+    }
+
+    #[test]
+    fn test_operation_json_shape() {
+        let operation: Operation =
+            serde_json::from_str(r#"{"op": "incr", "key": "counter"}"#).unwrap();
+        assert_eq!(
+            operation,
+            Operation::Incr {
+                key: "counter".to_string(),
+                by: 1
+            }
+        );
+
+        let precondition: Precondition =
+            serde_json::from_str(r#"{"check": "version", "key": "a", "version": 2}"#).unwrap();
+        assert_eq!(
+            precondition,
+            Precondition::Version {
+                key: "a".to_string(),
+                version: 2
+            }
+        );
+    }
+}
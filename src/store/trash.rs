@@ -0,0 +1,294 @@
+//! Soft delete: a per-namespace trash for deleted keys
+//!
+//! While a namespace has a trash retention set, deleting a key moves it and
+//! its metadata to the trash instead of removing them, whatever deleted it: a
+//! plain delete, a prefix deletion or a transaction. Trashed keys are
+//! invisible to reads, listings and searches, and can be restored with
+//! [`KVStore::undelete`] until the retention runs out and Redis expires them.
+//! Renames don't go through the trash.
+
+use super::metadata::{add_namespace, value_script, Metadata};
+use super::{history, index, meta_key, namespaced_key, INTERNAL_PREFIX};
+use crate::error::{KVStoreError, Result};
+use crate::KVStore;
+use redis::AsyncCommands;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Longest time deleted keys may be kept in the trash
+pub const MAX_TRASH_RETENTION: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Lua function moving a value and its metadata to the namespace's trash
+///
+/// Included in every script built with `value_script` and called by
+/// `delete_value`, with the trash policy and index of the namespace passed by
+/// `add_namespace`. Returns false, leaving the value alone, if the namespace
+/// has no trash retention or the value doesn't exist. The value's remaining
+/// TTL is kept in the trashed metadata so that it can be restored.
+pub(super) const TRASH_LUA: &str = r#"
+local function trash_value(value_key, meta_key, trash_key, trash_meta)
+    local retention = tonumber(redis.call('HGET', namespace.trash_policy, 'retention_seconds')) or 0
+    if retention <= 0 or redis.call('EXISTS', value_key) == 0 then
+        return false
+    end
+    local key = string.sub(value_key, string.len(namespace.token) + 2)
+    local time = redis.call('TIME')
+    local now = time[1] * 1000 + math.floor(time[2] / 1000)
+    local purge_at = now + retention * 1000
+
+    local ttl = redis.call('PTTL', value_key)
+    redis.call('RENAME', value_key, trash_key)
+    redis.call('UNLINK', trash_meta)
+    if redis.call('EXISTS', meta_key) == 1 then
+        redis.call('RENAME', meta_key, trash_meta)
+    end
+    redis.call('HSET', trash_meta, 'deleted_at', now, 'ttl', ttl)
+    redis.call('PEXPIREAT', trash_key, purge_at)
+    redis.call('PEXPIREAT', trash_meta, purge_at)
+    redis.call('ZADD', namespace.trash, purge_at, key)
+    return true
+end
+"#;
+
+/// Restore a trashed value and its metadata, as a new version of the key
+///
+/// KEYS[1] - value key, KEYS[2] - metadata key, KEYS[3] - history key,
+/// KEYS[4] - history policy key, KEYS[5] - index definitions key,
+/// KEYS[6] - trashed value, KEYS[7] - trashed metadata, KEYS[8] - trash index
+/// ARGV[1] - key
+/// Returns 'ok', or 'missing' or 'exists' without changing anything
+const UNDELETE_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[6]) == 0 then
+    return 'missing'
+end
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 'exists'
+end
+local ttl = tonumber(redis.call('HGET', KEYS[7], 'ttl')) or -1
+local version = (tonumber(redis.call('HGET', KEYS[7], 'version')) or 0) + 1
+
+redis.call('RENAME', KEYS[6], KEYS[1])
+redis.call('UNLINK', KEYS[2])
+if redis.call('EXISTS', KEYS[7]) == 1 then
+    redis.call('RENAME', KEYS[7], KEYS[2])
+end
+redis.call('HDEL', KEYS[2], 'deleted_at', 'ttl')
+redis.call('HSET', KEYS[2], 'version', version, 'updated_at', now_millis())
+if ttl > 0 then
+    redis.call('PEXPIRE', KEYS[1], ttl)
+    redis.call('PEXPIRE', KEYS[2], ttl)
+else
+    redis.call('PERSIST', KEYS[1])
+    redis.call('PERSIST', KEYS[2])
+end
+redis.call('ZREM', KEYS[8], ARGV[1])
+
+if redis.call('TYPE', KEYS[1]).ok == 'string' then
+    local value = redis.call('GET', KEYS[1])
+    local content_type = redis.call('HGET', KEYS[2], 'content_type') or 'text/plain'
+    record_revision(KEYS[3], KEYS[4], 'set', value, content_type)
+    update_indexes(KEYS[5], KEYS[1], value)
+    publish_change('put', KEYS[1], KEYS[2], value, version, content_type)
+else
+    update_indexes(KEYS[5], KEYS[1], false)
+    publish_change('put', KEYS[1], KEYS[2], nil, version)
+end
+return 'ok'
+"#;
+
+/// Drop the trash index entries of keys Redis already expired, then list the
+/// rest
+///
+/// KEYS[1] - trash index
+/// Returns key and purge time pairs, oldest deletion first
+const LIST_TRASH_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now)
+return redis.call('ZRANGE', KEYS[1], 0, -1, 'WITHSCORES')
+"#;
+
+/// A deleted key kept in the trash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrashedKey {
+    pub key: String,
+    /// When the key was deleted, in milliseconds since the Unix epoch
+    pub deleted_at: u64,
+    /// When the key will be purged, in milliseconds since the Unix epoch
+    pub purge_at: u64,
+    /// The key's metadata when it was deleted
+    pub metadata: Metadata,
+}
+
+/// Build the Redis key of the trash policy of `token`'s namespace
+pub(super) fn trash_policy_key(token: &str) -> String {
+    format!("{}:{}:trash_policy", INTERNAL_PREFIX, token)
+}
+
+/// Build the Redis key of the sorted set of trashed keys, scored by when they
+/// are purged
+pub(super) fn trash_index_key(token: &str) -> String {
+    format!("{}:{}:trash", INTERNAL_PREFIX, token)
+}
+
+pub(super) fn trash_key(token: &str, key: &str) -> String {
+    super::internal_key(token, "trash", key)
+}
+
+pub(super) fn trash_meta_key(token: &str, key: &str) -> String {
+    super::internal_key(token, "trash_meta", key)
+}
+
+impl KVStore {
+    /// Turn soft delete on or off for a namespace
+    ///
+    /// With a retention set, deleted keys are kept in the trash for that long.
+    /// `None` turns soft delete off; keys already in the trash stay there
+    /// until their retention runs out or they are purged.
+    pub async fn set_trash_retention(
+        &self,
+        token: &str,
+        retention: Option<Duration>,
+    ) -> Result<()> {
+        let policy_key = trash_policy_key(token);
+        tracing::debug!("SET TRASH RETENTION {} {:?}", policy_key, retention);
+
+        let mut conn = self.conn.clone();
+        match retention {
+            Some(retention) => {
+                if retention.as_secs() == 0 || retention > MAX_TRASH_RETENTION {
+                    return Err(KVStoreError::InvalidRequest(format!(
+                        "Trash retention must be between 1 and {} seconds",
+                        MAX_TRASH_RETENTION.as_secs()
+                    )));
+                }
+                conn.hset::<_, _, _, ()>(&policy_key, "retention_seconds", retention.as_secs())
+                    .await?;
+            }
+            None => conn.del::<_, ()>(&policy_key).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Get how long deleted keys of a namespace are kept in the trash, or
+    /// `None` if soft delete is off
+    pub async fn trash_retention(&self, token: &str) -> Result<Option<Duration>> {
+        let mut conn = self.conn.clone();
+        let seconds: Option<u64> = conn
+            .hget(trash_policy_key(token), "retention_seconds")
+            .await?;
+
+        Ok(seconds.map(Duration::from_secs))
+    }
+
+    /// List the keys in a namespace's trash that start with `prefix`, oldest
+    /// deletion first
+    pub async fn list_trash(&self, token: &str, prefix: &str) -> Result<Vec<TrashedKey>> {
+        let mut conn = self.conn.clone();
+        let trashed: Vec<(String, u64)> = redis::Script::new(LIST_TRASH_SCRIPT)
+            .key(trash_index_key(token))
+            .invoke_async(&mut conn)
+            .await?;
+        let trashed: Vec<(String, u64)> = trashed
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .collect();
+
+        let mut pipe = redis::pipe();
+        for (key, _) in &trashed {
+            pipe.hgetall(trash_meta_key(token, key));
+        }
+        let metadata: Vec<HashMap<String, String>> = pipe.query_async(&mut conn).await?;
+
+        let mut keys: Vec<TrashedKey> = trashed
+            .into_iter()
+            .zip(metadata)
+            .map(|((key, purge_at), fields)| TrashedKey {
+                key,
+                deleted_at: fields
+                    .get("deleted_at")
+                    .and_then(|at| at.parse().ok())
+                    .unwrap_or_default(),
+                purge_at,
+                metadata: Metadata::from_fields(fields),
+            })
+            .collect();
+        keys.sort_by_key(|trashed| trashed.deleted_at);
+
+        Ok(keys)
+    }
+
+    /// Restore a deleted key from the trash, with its TTL and metadata
+    ///
+    /// The restored key gets a new version and is reported as a put to
+    /// watchers and the change log.
+    ///
+    /// # Returns
+    ///
+    /// [`KVStoreError::KeyNotFound`] if the key isn't in the trash, or
+    /// [`KVStoreError::Conflict`] if a key of the same name was written since
+    pub async fn undelete(&self, token: &str, key: &str) -> Result<()> {
+        let namespaced_key = namespaced_key(token, key);
+        tracing::debug!("UNDELETE {}", namespaced_key);
+
+        let script = value_script(UNDELETE_SCRIPT);
+        let mut invocation = script.key(&namespaced_key);
+        invocation
+            .key(meta_key(token, key))
+            .key(history::history_key(token, key))
+            .key(history::history_policy_key(token))
+            .key(index::indexes_key(token))
+            .key(trash_key(token, key))
+            .key(trash_meta_key(token, key))
+            .key(trash_index_key(token))
+            .arg(key);
+        add_namespace(&mut invocation, token);
+
+        let mut conn = self.conn.clone();
+        let outcome: String = invocation.invoke_async(&mut conn).await?;
+
+        match outcome.as_str() {
+            "ok" => Ok(()),
+            "missing" => Err(KVStoreError::KeyNotFound(key.to_string())),
+            _ => Err(KVStoreError::Conflict(format!(
+                "Key {} was written since it was deleted",
+                key
+            ))),
+        }
+    }
+
+    /// Permanently remove keys from the trash: `key`, or everything if `None`
+    ///
+    /// # Returns
+    ///
+    /// The number of keys purged
+    pub async fn purge_trash(&self, token: &str, key: Option<&str>) -> Result<u64> {
+        let index_key = trash_index_key(token);
+        tracing::debug!("PURGE TRASH {} {:?}", index_key, key);
+
+        let mut conn = self.conn.clone();
+        let keys: Vec<String> = match key {
+            Some(key) => vec![key.to_string()],
+            None => conn.zrange(&index_key, 0, -1).await?,
+        };
+
+        let mut purged = 0;
+        for batch in keys.chunks(500) {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for key in batch {
+                pipe.unlink(trash_key(token, key))
+                    .unlink(trash_meta_key(token, key))
+                    .ignore()
+                    .zrem(&index_key, key)
+                    .ignore();
+            }
+            let unlinked: Vec<u64> = pipe.query_async(&mut conn).await?;
+            purged += unlinked.iter().sum::<u64>();
+        }
+
+        Ok(purged)
+    }
+}
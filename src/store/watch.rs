@@ -0,0 +1,427 @@
+//! Change notifications
+//!
+//! The Lua value functions record every write and delete of a value in the
+//! change log of its namespace (see the `changes` module) and publish it on a
+//! Pub/Sub channel, so notifications carry the value and version that were
+//! written and watchers can pick up where they left off. Writes to hashes,
+//! lists, sets and sorted sets go through [`COLLECTION_WRITE_SCRIPT`] and are
+//! reported the same way, without a value or version. Expirations are picked
+//! up from Redis keyspace notifications, which must be enabled on the server
+//! with `notify-keyspace-events` including `Kx`; they are not logged.
+
+use super::changes::CHANGES_BATCH_SIZE;
+use super::metadata::{add_namespace, value_script};
+use super::{namespaced_key, prefix, KVStore, INTERNAL_PREFIX};
+use crate::error::{KVStoreError, Result};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+
+/// Lua function recording and publishing a change of a value, and queueing
+/// its webhook deliveries
+///
+/// Included in every script built with `value_script`, after `WEBHOOK_LUA`.
+/// The change log and channel are the namespace's, passed by `add_namespace`.
+pub(super) const CHANGES_LUA: &str = r#"
+-- Log and publish a change of value_key; meta_key, value, version and content_type
+-- may be false or nil. A put is timestamped with the value's updated_at, if it has
+-- one, a delete with the current time. Puts of expiring keys carry when they expire.
+local function publish_change(kind, value_key, meta_key, value, version, content_type)
+    local time = redis.call('TIME')
+    local now = time[1] * 1000 + math.floor(time[2] / 1000)
+    local timestamp = false
+    local expires_at = nil
+    if kind == 'put' then
+        if meta_key then
+            timestamp = tonumber(redis.call('HGET', meta_key, 'updated_at'))
+        end
+        local ttl = redis.call('PTTL', value_key)
+        if ttl > 0 then
+            expires_at = now + ttl
+        end
+    end
+    local change = {
+        type = kind, key = value_key, value = value, version = version,
+        content_type = content_type, timestamp = timestamp or now, expires_at = expires_at
+    }
+    change.id = redis.call('XADD', namespace.change_log, 'MAXLEN', '~', 10000, '*',
+        'change', cjson.encode(change))
+    redis.call('PUBLISH', namespace.channel, cjson.encode(change))
+    enqueue_webhooks(change)
+end
+"#;
+
+/// Apply a write command to a hash, list, set or sorted set and publish the
+/// change, as a delete if the command removed the key's last element
+///
+/// KEYS[1] - value key
+/// ARGV[1] - 'always' if the command always changes the key, or 'counted' if
+/// only a non-zero count or a non-empty reply means it did, ARGV[2] - the
+/// command (empty to only publish a change made already), ARGV[3..] - its
+/// arguments after the key, sent in chunks of 1000
+/// Returns the command's reply, with the counts of several chunks added up
+pub(super) const COLLECTION_WRITE_SCRIPT: &str = r#"
+local existed = redis.call('EXISTS', KEYS[1]) == 1
+local reply = false
+if ARGV[2] ~= '' then
+    for i = 3, math.max(ARG_COUNT, 3), 1000 do
+        local part = redis.call(ARGV[2], KEYS[1], unpack(ARGV, i, math.min(i + 999, ARG_COUNT)))
+        if type(reply) == 'number' then
+            reply = reply + part
+        else
+            reply = part
+        end
+    end
+end
+if ARGV[1] == 'counted' and (not reply or reply == 0 or (type(reply) == 'table' and #reply == 0)) then
+    return reply
+end
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    publish_change('put', KEYS[1], false)
+elseif existed or ARGV[2] == '' then
+    publish_change('delete', KEYS[1], false)
+end
+return reply
+"#;
+
+/// Whether a write to a hash, list, set or sorted set changes the key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CollectionWrite {
+    /// The command always changes the key, e.g. a push or an increment
+    Always,
+    /// The command changed the key only if it reports a non-zero count or a
+    /// non-empty list, e.g. a removal or a pop
+    Counted,
+}
+
+/// Number of changes buffered for a watcher that isn't keeping up
+pub(super) const WATCH_BUFFER: usize = 128;
+
+/// What happened to a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    /// The value was written
+    Put,
+    /// The key was deleted
+    Delete,
+    /// The key's TTL ran out
+    Expire,
+}
+
+/// A change of a key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// Position of the change in the namespace's change log; `None` for
+    /// expirations, which are not logged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: ChangeKind,
+    /// The changed key, without the namespace
+    pub key: String,
+    /// The new value of a string or JSON value that was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// The key's version after a write, or the version of the deleted value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// Content type of the written value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// When the value was written or deleted, in milliseconds since the Unix
+    /// epoch; `None` for expirations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// When a written key expires, in milliseconds since the Unix epoch;
+    /// `None` if it doesn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// Build the Pub/Sub channel the changes of `token`'s values are published on
+pub(super) fn changes_channel(token: &str) -> String {
+    format!("{}:{}:changes", INTERNAL_PREFIX, token)
+}
+
+/// Build the Redis key of the change log of `token`'s values
+pub(super) fn change_log_key(token: &str) -> String {
+    format!("{}:{}:change_log", INTERNAL_PREFIX, token)
+}
+
+/// Parse a change log entry ID into its comparable parts
+pub(super) fn parse_id(id: &str) -> Option<(u64, u64)> {
+    let (millis, seq) = id.split_once('-')?;
+    Some((millis.parse().ok()?, seq.parse().ok()?))
+}
+
+/// The keys a watcher is interested in
+#[derive(Debug, Clone)]
+pub(super) struct WatchTarget {
+    /// Namespaced key, or key prefix
+    key: String,
+    prefix: bool,
+    /// Length of the namespace prefix of every key
+    namespace_len: usize,
+}
+
+impl WatchTarget {
+    pub(super) fn new(token: &str, key: &str, prefix: bool) -> Self {
+        WatchTarget {
+            key: namespaced_key(token, key),
+            prefix,
+            namespace_len: token.len() + 1, // +1 for the colon
+        }
+    }
+
+    /// Strip the namespace from `key` if the watcher is interested in it
+    fn matches(&self, key: &str) -> Option<String> {
+        let matched = if self.prefix {
+            key.starts_with(&self.key)
+        } else {
+            key == self.key
+        };
+        matched.then(|| key[self.namespace_len..].to_string())
+    }
+
+    /// Keyspace notification channel pattern for the watched keys in `db`
+    fn keyspace_pattern(&self, db: i64) -> String {
+        let key = prefix::escape_glob(&self.key);
+        let wildcard = if self.prefix { "*" } else { "" };
+        format!("__keyspace@{}__:{}{}", db, key, wildcard)
+    }
+
+    /// Parse a logged or published change, keeping it if it is of interest
+    fn change(&self, text: &str) -> Option<ChangeEvent> {
+        let mut event: ChangeEvent = match serde_json::from_str(text) {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!("Ignoring malformed change notification: {}", e);
+                return None;
+            }
+        };
+        event.key = self.matches(&event.key)?;
+        Some(event)
+    }
+
+    /// Turn a change log entry into a change of a watched key
+    pub(super) fn logged(&self, id: String, text: &str) -> Option<ChangeEvent> {
+        let mut event = self.change(text)?;
+        event.id = Some(id);
+        Some(event)
+    }
+
+    /// Turn a Pub/Sub message into a change of a watched key
+    fn event(&self, msg: &redis::Msg) -> Option<ChangeEvent> {
+        let payload: String = msg.get_payload().ok()?;
+        if !msg.from_pattern() {
+            return self.change(&payload);
+        }
+
+        // Keyspace notifications name the key in the channel and the command
+        // in the payload
+        let (_, key) = msg.get_channel_name().split_once("__:")?;
+        if payload != "expired" {
+            return None;
+        }
+        Some(ChangeEvent {
+            id: None,
+            kind: ChangeKind::Expire,
+            key: self.matches(key)?,
+            value: None,
+            version: None,
+            content_type: None,
+            timestamp: None,
+            expires_at: None,
+        })
+    }
+}
+
+impl KVStore {
+    /// Run a write `command` against the hash, list, set or sorted set `key`
+    /// and report the change to watchers, the change log and webhooks
+    ///
+    /// `args` follow the key; a `command` of `None` only reports a change
+    /// already made to `key`.
+    pub(super) async fn write_collection<T: redis::FromRedisValue>(
+        &self,
+        token: &str,
+        key: &str,
+        write: CollectionWrite,
+        command: Option<&str>,
+        args: impl redis::ToRedisArgs,
+    ) -> redis::RedisResult<T> {
+        let script = value_script(COLLECTION_WRITE_SCRIPT);
+        let mut invocation = script.key(namespaced_key(token, key));
+        invocation
+            .arg(match write {
+                CollectionWrite::Always => "always",
+                CollectionWrite::Counted => "counted",
+            })
+            .arg(command.unwrap_or_default())
+            .arg(args);
+        add_namespace(&mut invocation, token);
+
+        let mut conn = self.conn.clone();
+        invocation.invoke_async(&mut conn).await
+    }
+
+    /// Watch a key, or every key starting with `key` if `prefix` is set, for
+    /// changes
+    ///
+    /// Writes and deletes of string and JSON values are reported with the new
+    /// value and version, those of hashes, lists, sets and sorted sets without
+    /// them; expirations are reported when Redis keyspace notifications are
+    /// enabled. The stream ends when the Pub/Sub connection is lost.
+    ///
+    /// With `after` set to the [`ChangeEvent::id`] of the last change seen,
+    /// the changes logged since then are replayed first. If `after` is older
+    /// than the log, the replay starts at the oldest change kept; use
+    /// [`KVStore::changes`] to be told about such gaps instead.
+    pub async fn watch(
+        &self,
+        token: &str,
+        key: &str,
+        prefix: bool,
+        after: Option<&str>,
+    ) -> Result<impl Stream<Item = ChangeEvent>> {
+        let client = self.client.as_ref().ok_or_else(|| {
+            KVStoreError::Internal(
+                "Watching keys requires a KVStore created with KVStore::new".to_string(),
+            )
+        })?;
+        let mut last_id = match after {
+            Some(id) => Some(parse_id(id).ok_or_else(|| {
+                KVStoreError::InvalidRequest(format!("Invalid change ID: {:?}", id))
+            })?),
+            None => None,
+        };
+
+        let target = WatchTarget::new(token, key, prefix);
+        let channel = changes_channel(token);
+        let pattern = target.keyspace_pattern(client.get_connection_info().redis.db);
+        tracing::debug!(
+            "WATCH {} (prefix: {}, after: {:?}) on {}",
+            target.key,
+            prefix,
+            after,
+            channel
+        );
+
+        // Subscribe before reading the log so no change falls in between
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(&channel).await?;
+        pubsub.psubscribe(&pattern).await?;
+
+        let store = self.clone();
+        let log_key = change_log_key(token);
+        let mut after = after.map(String::from);
+        let (tx, rx) = tokio::sync::mpsc::channel(WATCH_BUFFER);
+
+        tokio::spawn(async move {
+            while let Some(id) = after.take() {
+                let batch = match store.read_changes(&log_key, &id, false).await {
+                    Ok(batch) => batch.unwrap_or_default(),
+                    Err(e) => {
+                        tracing::warn!("Failed to replay changes of {}: {}", target.key, e);
+                        return;
+                    }
+                };
+                let full = batch.len() == CHANGES_BATCH_SIZE;
+                for (id, text) in batch {
+                    last_id = parse_id(&id);
+                    if full {
+                        after = Some(id.clone());
+                    }
+                    if let Some(event) = target.logged(id, &text) {
+                        if tx.send(event).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+
+            let mut messages = pubsub.into_on_message();
+            loop {
+                let msg = tokio::select! {
+                    msg = messages.next() => msg,
+                    // Stop listening as soon as the watcher goes away
+                    _ = tx.closed() => break,
+                };
+                let Some(msg) = msg else {
+                    tracing::warn!("Lost the Pub/Sub connection watching {}", target.key);
+                    break;
+                };
+                let Some(event) = target.event(&msg) else {
+                    continue;
+                };
+                // Skip changes that were already replayed from the log
+                let id = event.id.as_deref().and_then(parse_id);
+                if id.is_some() && id <= last_id {
+                    continue;
+                }
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::changes::CHANGE_LOG_LENGTH;
+    use super::*;
+
+    fn target(key: &str, prefix: bool) -> WatchTarget {
+        WatchTarget::new("t", key, prefix)
+    }
+
+    #[test]
+    fn test_change_log_length_matches_lua() {
+        assert!(CHANGES_LUA.contains(&format!("'~', {},", CHANGE_LOG_LENGTH)));
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("1700000000000-3"), Some((1700000000000, 3)));
+        assert!(parse_id("1700000000000-3") > parse_id("1700000000000-2"));
+        assert!(parse_id("1700000000001-0") > parse_id("1700000000000-9"));
+        assert_eq!(parse_id("1700000000000"), None);
+        assert_eq!(parse_id("a-b"), None);
+    }
+
+    #[test]
+    fn test_target_matches() {
+        assert_eq!(target("a", false).matches("t:a"), Some("a".to_string()));
+        assert_eq!(target("a", false).matches("t:ab"), None);
+        assert_eq!(target("a", true).matches("t:ab"), Some("ab".to_string()));
+        assert_eq!(target("", true).matches("t:x"), Some("x".to_string()));
+        assert_eq!(target("a", true).matches("t:b"), None);
+    }
+
+    #[test]
+    fn test_keyspace_pattern() {
+        assert_eq!(
+            target("a*", false).keyspace_pattern(0),
+            "__keyspace@0__:t:a\\*"
+        );
+        assert_eq!(target("a", true).keyspace_pattern(2), "__keyspace@2__:t:a*");
+    }
+
+    #[test]
+    fn test_change_strips_namespace() {
+        let event = target("a", true)
+            .change(r#"{"id":"1-0","type":"put","key":"t:ab","value":"v","version":2}"#)
+            .unwrap();
+        assert_eq!(event.id.as_deref(), Some("1-0"));
+        assert_eq!(event.kind, ChangeKind::Put);
+        assert_eq!(event.key, "ab");
+        assert_eq!(event.version, Some(2));
+        assert!(target("b", true)
+            .change(r#"{"type":"delete","key":"t:ab"}"#)
+            .is_none());
+    }
+}
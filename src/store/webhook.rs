@@ -0,0 +1,929 @@
+//! Outbound webhooks on key changes
+//!
+//! A namespace registers webhooks, each with a URL, a key prefix, the types
+//! of change it wants and a shared secret. Deliveries are queued in a Redis
+//! sorted set scored by when they are due: writes and deletes by the Lua
+//! script that makes the change, expirations by every running
+//! [`WebhookWorker`] from Redis keyspace notifications. Workers claim due
+//! deliveries for a while so that several kvstore instances can share the
+//! queue, POST them signed with HMAC-SHA256, retry failures with exponential
+//! backoff and move deliveries that keep failing to a dead-letter list.
+//!
+//! Deliveries are at least once: a worker that dies mid-delivery leaves the
+//! delivery to be claimed again once its claim runs out.
+
+use super::index::{check_name, indexes_key, INDEX_LUA};
+use super::metadata::{add_namespace, NAMESPACE_LUA};
+use super::watch::{ChangeEvent, ChangeKind};
+use super::{internal_key, redis_error, KVStore, INTERNAL_PREFIX};
+use crate::error::{KVStoreError, Result};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header carrying the HMAC-SHA256 signature of a delivery's body
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-KVStore-Signature";
+
+/// Header carrying the type of change delivered
+pub const WEBHOOK_EVENT_HEADER: &str = "X-KVStore-Event";
+
+/// Number of attempts made at a delivery before it is dead-lettered, unless
+/// configured otherwise with [`WebhookWorker::with_retries`]
+pub const DEFAULT_WEBHOOK_MAX_ATTEMPTS: u32 = 8;
+
+/// Delay before the first retry; each further retry waits twice as long
+pub const DEFAULT_WEBHOOK_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between two attempts at a delivery
+const MAX_WEBHOOK_BACKOFF: Duration = Duration::from_secs(600);
+
+/// How long a receiver has to answer a delivery
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a claimed delivery is hidden from other workers
+const WEBHOOK_CLAIM: Duration = Duration::from_secs(60);
+
+/// Number of deliveries a worker claims at a time
+const WEBHOOK_BATCH_SIZE: usize = 32;
+
+/// How long a worker waits before looking for due deliveries again
+const WEBHOOK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of failed deliveries kept per webhook
+pub const MAX_DEAD_LETTERS: usize = 1000;
+
+/// Lua function queueing deliveries of a change
+///
+/// Included in every script built with `value_script` and called by
+/// `publish_change`, with the webhooks and queue of the namespace passed by
+/// `add_namespace`. `change.key` is the namespaced key.
+pub(super) const WEBHOOK_LUA: &str = r#"
+local function enqueue_webhooks(change)
+    local hooks = redis.call('HGETALL', namespace.webhooks)
+    if #hooks == 0 then
+        return
+    end
+    local token = namespace.token
+    local key = string.sub(change.key, string.len(token) + 2)
+    local time = redis.call('TIME')
+    local now = time[1] * 1000 + math.floor(time[2] / 1000)
+    for i = 1, #hooks, 2 do
+        local hook = cjson.decode(hooks[i + 1])
+        if string.sub(key, 1, string.len(hook.prefix)) == hook.prefix then
+            for _, kind in ipairs(hook.events) do
+                if kind == change.type then
+                    redis.call('ZADD', namespace.webhook_queue, 'NX', now, cjson.encode({
+                        token = token, webhook = hooks[i], attempts = 0,
+                        event = {
+                            id = change.id, type = change.type, key = key, value = change.value,
+                            version = change.version, content_type = change.content_type,
+                            timestamp = change.timestamp, expires_at = change.expires_at
+                        }
+                    }))
+                    break
+                end
+            end
+        end
+    end
+end
+"#;
+
+/// Drop an expired key from the namespace's indexes and queue deliveries of
+/// its expiration
+///
+/// Every worker sees every expiration; removing index entries twice is
+/// harmless and, as the queued deliveries are identical, the queue holds each
+/// of them once.
+///
+/// KEYS[1] - index definitions key
+/// ARGV[1] - namespaced key, followed by the namespace passed by
+/// `add_namespace`
+const EXPIRED_SCRIPT: &str = r#"
+update_indexes(KEYS[1], ARGV[1], false)
+enqueue_webhooks({type = 'expire', key = ARGV[1]})
+"#;
+
+/// Claim the deliveries that are due
+///
+/// KEYS[1] - delivery queue
+/// ARGV[1] - claim duration in milliseconds, ARGV[2] - maximum number to claim
+const CLAIM_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+local jobs = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', now, 'LIMIT', 0, tonumber(ARGV[2]))
+for _, job in ipairs(jobs) do
+    redis.call('ZADD', KEYS[1], now + tonumber(ARGV[1]), job)
+end
+return jobs
+"#;
+
+/// Record the outcome of an attempt at a claimed delivery
+///
+/// KEYS[1] - delivery queue, KEYS[2] - webhook status, KEYS[3] - dead letters
+/// ARGV[1] - the claimed delivery, ARGV[2] - 'delivered', 'retry', 'dead' or
+/// 'drop', ARGV[3] - HTTP status ('' for none), ARGV[4] - error ('' for none),
+/// ARGV[5] - delivery to retry or dead letter, ARGV[6] - retry delay in
+/// milliseconds, ARGV[7] - number of dead letters kept
+/// Returns 0 if the delivery was no longer claimed, e.g. after the claim ran out
+const FINISH_SCRIPT: &str = r#"
+if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then
+    return 0
+end
+if ARGV[2] == 'drop' then
+    return 1
+end
+local time = redis.call('TIME')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+redis.call('HSET', KEYS[2], 'last_attempt_at', now, 'last_status', ARGV[3], 'last_error', ARGV[4])
+if ARGV[2] == 'delivered' then
+    redis.call('HINCRBY', KEYS[2], 'delivered', 1)
+elseif ARGV[2] == 'retry' then
+    redis.call('ZADD', KEYS[1], now + tonumber(ARGV[6]), ARGV[5])
+else
+    redis.call('HINCRBY', KEYS[2], 'failed', 1)
+    redis.call('LPUSH', KEYS[3], ARGV[5])
+    redis.call('LTRIM', KEYS[3], 0, tonumber(ARGV[7]) - 1)
+end
+return 1
+"#;
+
+fn all_events() -> Vec<ChangeKind> {
+    vec![ChangeKind::Put, ChangeKind::Delete, ChangeKind::Expire]
+}
+
+/// A webhook subscription
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Webhook {
+    /// `http` or `https` URL deliveries are POSTed to
+    pub url: String,
+    /// Only changes of keys starting with this prefix are delivered
+    #[serde(default)]
+    pub prefix: String,
+    /// Types of change delivered, all by default
+    #[serde(default = "all_events")]
+    pub events: Vec<ChangeKind>,
+    /// Key of the HMAC-SHA256 signature of each delivery
+    pub secret: String,
+}
+
+/// Delivery counters and the outcome of the latest attempt of a webhook
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct WebhookStatus {
+    /// Number of deliveries accepted by the receiver
+    pub delivered: u64,
+    /// Number of deliveries given up on and dead-lettered
+    pub failed: u64,
+    /// When a delivery was last attempted, in milliseconds since the Unix epoch
+    pub last_attempt_at: Option<u64>,
+    /// HTTP status of the latest attempt, if the receiver answered
+    pub last_status: Option<u16>,
+    /// Why the latest attempt failed
+    pub last_error: Option<String>,
+}
+
+impl WebhookStatus {
+    fn from_fields(fields: HashMap<String, String>) -> Self {
+        let field = |name: &str| fields.get(name).filter(|value| !value.is_empty());
+        WebhookStatus {
+            delivered: field("delivered").and_then(|v| v.parse().ok()).unwrap_or(0),
+            failed: field("failed").and_then(|v| v.parse().ok()).unwrap_or(0),
+            last_attempt_at: field("last_attempt_at").and_then(|v| v.parse().ok()),
+            last_status: field("last_status").and_then(|v| v.parse().ok()),
+            last_error: field("last_error").cloned(),
+        }
+    }
+}
+
+/// A registered webhook as reported by the API, without its secret
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WebhookInfo {
+    pub name: String,
+    pub url: String,
+    pub prefix: String,
+    pub events: Vec<ChangeKind>,
+    pub status: WebhookStatus,
+}
+
+/// A delivery that failed every attempt
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub event: ChangeEvent,
+    pub attempts: u32,
+    /// Why the last attempt failed
+    pub error: String,
+    /// When the delivery was given up on, in milliseconds since the Unix epoch
+    pub failed_at: u64,
+}
+
+/// A queued delivery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WebhookJob {
+    token: String,
+    webhook: String,
+    /// Attempts made so far
+    attempts: u32,
+    event: ChangeEvent,
+}
+
+/// Body POSTed to a webhook
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    webhook: &'a str,
+    /// Attempt number, starting at 1
+    attempt: u32,
+    event: &'a ChangeEvent,
+}
+
+/// What became of an attempt at a delivery
+enum Outcome {
+    Delivered,
+    Retry(WebhookJob, Duration),
+    Dead(DeadLetter),
+    /// The delivery can't be made, e.g. because the webhook was removed
+    Drop,
+}
+
+/// Redis key of the delivery queue shared by all namespaces
+pub(super) fn webhook_queue_key() -> String {
+    format!("{}:webhook_queue", INTERNAL_PREFIX)
+}
+
+/// Build the Redis key of the hash of webhooks of `token`
+pub(super) fn webhooks_key(token: &str) -> String {
+    format!("{}:{}:webhooks", INTERNAL_PREFIX, token)
+}
+
+fn status_key(token: &str, name: &str) -> String {
+    internal_key(token, "webhook_status", name)
+}
+
+fn dead_letters_key(token: &str, name: &str) -> String {
+    internal_key(token, "webhook_dead_letters", name)
+}
+
+/// Sign a delivery body with a webhook's secret
+///
+/// Receivers verify deliveries by comparing the [`WEBHOOK_SIGNATURE_HEADER`]
+/// with the signature of the raw body they received.
+pub fn webhook_signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delay before the next attempt after `attempts` failed ones
+fn backoff(base: Duration, attempts: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_WEBHOOK_BACKOFF)
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn check_webhook(webhook: &Webhook) -> Result<()> {
+    let url = reqwest::Url::parse(&webhook.url)
+        .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid webhook URL: {}", e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(KVStoreError::InvalidRequest(
+            "Webhook URLs must use http or https".to_string(),
+        ));
+    }
+    if webhook.events.is_empty() {
+        return Err(KVStoreError::InvalidRequest(
+            "Webhooks need at least one event type".to_string(),
+        ));
+    }
+    if webhook.secret.is_empty() {
+        return Err(KVStoreError::InvalidRequest(
+            "Webhooks need a secret to sign deliveries with".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether webhooks are kept from delivering to `ip`: loopback, private,
+/// link-local, shared, unspecified, broadcast and multicast addresses
+fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // Shared address space (100.64.0.0/10) and 0.0.0.0/8
+                || (a == 100 && b & 0xc0 == 64)
+                || a == 0
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_address(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local (fc00::/7) and link-local (fe80::/10)
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Resolve a webhook host, refusing hosts with any non-public address
+async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| {
+            KVStoreError::InvalidRequest(format!("Failed to resolve webhook host {}: {}", host, e))
+        })?
+        .collect();
+    if let Some(addr) = addrs.iter().find(|addr| is_private_address(addr.ip())) {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Webhook host {} resolves to the non-public address {}",
+            host,
+            addr.ip()
+        )));
+    }
+    Ok(addrs)
+}
+
+/// Refuse webhook URLs whose host is, or resolves to, a non-public address
+async fn check_webhook_target(url: &str) -> Result<()> {
+    let url = reqwest::Url::parse(url)
+        .map_err(|e| KVStoreError::InvalidRequest(format!("Invalid webhook URL: {}", e)))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| KVStoreError::InvalidRequest("Webhook URLs need a host".to_string()))?;
+    // IPv6 addresses are bracketed in URLs
+    let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() else {
+        resolve_public(host, url.port_or_known_default().unwrap_or(0)).await?;
+        return Ok(());
+    };
+    if is_private_address(ip) {
+        return Err(KVStoreError::InvalidRequest(format!(
+            "Webhooks can't target the non-public address {}",
+            ip
+        )));
+    }
+    Ok(())
+}
+
+/// DNS resolver of the webhook HTTP client, resolving hosts as they are
+/// connected to so that a host can't switch to a non-public address after
+/// being checked
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = resolve_public(&host, 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+impl KVStore {
+    /// Register a webhook, replacing any webhook of the same name
+    ///
+    /// Changes made from now on are delivered by the running
+    /// [`WebhookWorker`]s; deliveries already queued keep going to the
+    /// webhook's new URL. Unless the store allows private webhooks, the URL's
+    /// host must resolve to public addresses only.
+    pub async fn set_webhook(&self, token: &str, name: &str, webhook: &Webhook) -> Result<()> {
+        check_name("Webhook", name)?;
+        check_webhook(webhook)?;
+        if !self.private_webhooks {
+            check_webhook_target(&webhook.url).await?;
+        }
+        let definition = serde_json::to_string(webhook)
+            .map_err(|e| KVStoreError::Internal(format!("Failed to encode webhook: {}", e)))?;
+
+        let key = webhooks_key(token);
+        tracing::debug!("WEBHOOK SET {} {} -> {}", key, name, webhook.url);
+
+        let mut conn = self.conn.clone();
+        conn.hset::<_, _, _, ()>(&key, name, definition)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to register webhook {}: {}", name, e);
+                e
+            })?;
+
+        Ok(())
+    }
+
+    /// List the webhooks of a namespace with their delivery status, ordered
+    /// by name
+    pub async fn list_webhooks(&self, token: &str) -> Result<Vec<WebhookInfo>> {
+        let mut conn = self.conn.clone();
+        let definitions: HashMap<String, String> = conn.hgetall(webhooks_key(token)).await?;
+        let mut names: Vec<String> = definitions.keys().cloned().collect();
+        names.sort();
+
+        let mut pipe = redis::pipe();
+        for name in &names {
+            pipe.hgetall(status_key(token, name));
+        }
+        let statuses: Vec<HashMap<String, String>> = pipe.query_async(&mut conn).await?;
+
+        names
+            .into_iter()
+            .zip(statuses)
+            .map(|(name, status)| {
+                let webhook = parse_webhook(&definitions[&name])?;
+                Ok(webhook_info(name, webhook, status))
+            })
+            .collect()
+    }
+
+    /// Get a webhook with its delivery status
+    ///
+    /// # Returns
+    ///
+    /// [`KVStoreError::KeyNotFound`] if there is no webhook called `name`
+    pub async fn get_webhook(&self, token: &str, name: &str) -> Result<WebhookInfo> {
+        let webhook = self
+            .webhook(token, name)
+            .await?
+            .ok_or_else(|| KVStoreError::KeyNotFound(format!("webhook {}", name)))?;
+
+        let mut conn = self.conn.clone();
+        let status: HashMap<String, String> = conn.hgetall(status_key(token, name)).await?;
+
+        Ok(webhook_info(name.to_string(), webhook, status))
+    }
+
+    /// Remove a webhook with its status and dead letters
+    ///
+    /// Queued deliveries of the webhook are dropped.
+    ///
+    /// # Returns
+    ///
+    /// [`KVStoreError::KeyNotFound`] if there is no webhook called `name`
+    pub async fn delete_webhook(&self, token: &str, name: &str) -> Result<()> {
+        tracing::debug!("WEBHOOK DELETE {} {}", webhooks_key(token), name);
+
+        let mut conn = self.conn.clone();
+        let (removed, _, _): (u64, u64, u64) = redis::pipe()
+            .atomic()
+            .hdel(webhooks_key(token), name)
+            .del(status_key(token, name))
+            .del(dead_letters_key(token, name))
+            .query_async(&mut conn)
+            .await?;
+
+        if removed == 0 {
+            return Err(KVStoreError::KeyNotFound(format!("webhook {}", name)));
+        }
+        Ok(())
+    }
+
+    /// List the deliveries of a webhook that failed every attempt, newest
+    /// first
+    ///
+    /// At most [`MAX_DEAD_LETTERS`] are kept.
+    pub async fn webhook_dead_letters(&self, token: &str, name: &str) -> Result<Vec<DeadLetter>> {
+        let mut conn = self.conn.clone();
+        let letters: Vec<String> = conn.lrange(dead_letters_key(token, name), 0, -1).await?;
+
+        letters
+            .iter()
+            .map(|letter| {
+                serde_json::from_str(letter).map_err(|e| {
+                    KVStoreError::Internal(format!("Corrupt dead letter of {}: {}", name, e))
+                })
+            })
+            .collect()
+    }
+
+    /// Read a webhook's definition, including its secret
+    async fn webhook(&self, token: &str, name: &str) -> Result<Option<Webhook>> {
+        let mut conn = self.conn.clone();
+        let definition: Option<String> = conn.hget(webhooks_key(token), name).await?;
+
+        definition.as_deref().map(parse_webhook).transpose()
+    }
+
+    /// Claim up to [`WEBHOOK_BATCH_SIZE`] due deliveries
+    async fn claim_webhook_jobs(&self) -> Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        let jobs: Vec<String> = redis::Script::new(CLAIM_SCRIPT)
+            .key(webhook_queue_key())
+            .arg(WEBHOOK_CLAIM.as_millis() as u64)
+            .arg(WEBHOOK_BATCH_SIZE)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(jobs)
+    }
+
+    /// Record the outcome of an attempt at the claimed delivery `claimed`
+    async fn finish_webhook_job(
+        &self,
+        claimed: &str,
+        job: Option<&WebhookJob>,
+        outcome: Outcome,
+        status: Option<u16>,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let (action, next, delay) = match outcome {
+            Outcome::Delivered => ("delivered", String::new(), Duration::ZERO),
+            Outcome::Retry(job, delay) => ("retry", encode(&job)?, delay),
+            Outcome::Dead(letter) => ("dead", encode(&letter)?, Duration::ZERO),
+            Outcome::Drop => ("drop", String::new(), Duration::ZERO),
+        };
+        let (status_key, dead_letters_key) = match job {
+            Some(job) => (
+                status_key(&job.token, &job.webhook),
+                dead_letters_key(&job.token, &job.webhook),
+            ),
+            // Nothing is recorded for deliveries that can't be parsed
+            None => (String::new(), String::new()),
+        };
+
+        let mut conn = self.conn.clone();
+        redis::Script::new(FINISH_SCRIPT)
+            .key(webhook_queue_key())
+            .key(status_key)
+            .key(dead_letters_key)
+            .arg(claimed)
+            .arg(action)
+            .arg(status.map(|status| status.to_string()).unwrap_or_default())
+            .arg(error.unwrap_or_default())
+            .arg(next)
+            .arg(delay.as_millis() as u64)
+            .arg(MAX_DEAD_LETTERS)
+            .invoke_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn parse_webhook(definition: &str) -> Result<Webhook> {
+    serde_json::from_str(definition)
+        .map_err(|e| KVStoreError::Internal(format!("Corrupt webhook definition: {}", e)))
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<String> {
+    serde_json::to_string(value)
+        .map_err(|e| KVStoreError::Internal(format!("Failed to encode delivery: {}", e)))
+}
+
+fn webhook_info(name: String, webhook: Webhook, status: HashMap<String, String>) -> WebhookInfo {
+    WebhookInfo {
+        name,
+        url: webhook.url,
+        prefix: webhook.prefix,
+        events: webhook.events,
+        status: WebhookStatus::from_fields(status),
+    }
+}
+
+/// Background worker delivering queued webhook deliveries
+///
+/// Run one per kvstore instance; workers share the queue. Workers also listen
+/// for expirations, which they queue deliveries of and drop from the
+/// namespace's indexes. Stores created with
+/// [`KVStore::from_connection_manager`] can deliver writes and deletes but
+/// can't listen for expirations.
+#[derive(Clone)]
+pub struct WebhookWorker {
+    store: KVStore,
+    client: reqwest::Client,
+    max_attempts: u32,
+    backoff: Duration,
+}
+
+impl WebhookWorker {
+    pub fn new(store: KVStore) -> Result<Self> {
+        // Redirects could lead deliveries to any address
+        let mut builder = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none());
+        if !store.private_webhooks {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+        let client = builder
+            .build()
+            .map_err(|e| KVStoreError::Internal(format!("Failed to build HTTP client: {}", e)))?;
+
+        Ok(WebhookWorker {
+            store,
+            client,
+            max_attempts: DEFAULT_WEBHOOK_MAX_ATTEMPTS,
+            backoff: DEFAULT_WEBHOOK_BACKOFF,
+        })
+    }
+
+    /// Set how many attempts are made at a delivery and the delay before the
+    /// first retry
+    pub fn with_retries(mut self, max_attempts: u32, backoff: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    /// Deliver queued deliveries until the task is dropped
+    pub async fn run(self) {
+        if self.store.client.is_some() {
+            tokio::spawn(self.clone().queue_expirations());
+        }
+
+        loop {
+            let jobs = match self.store.claim_webhook_jobs().await {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    tracing::error!("Failed to claim webhook deliveries: {}", e);
+                    Vec::new()
+                }
+            };
+            if jobs.is_empty() {
+                tokio::time::sleep(WEBHOOK_POLL_INTERVAL).await;
+                continue;
+            }
+            futures::future::join_all(jobs.iter().map(|job| self.deliver(job))).await;
+        }
+    }
+
+    /// Make one attempt at a claimed delivery and record its outcome
+    async fn deliver(&self, claimed: &str) {
+        let job: WebhookJob = match serde_json::from_str(claimed) {
+            Ok(job) => job,
+            Err(e) => {
+                tracing::warn!("Dropping malformed webhook delivery: {}", e);
+                let _ = self
+                    .store
+                    .finish_webhook_job(claimed, None, Outcome::Drop, None, None)
+                    .await;
+                return;
+            }
+        };
+        let webhook = match self.store.webhook(&job.token, &job.webhook).await {
+            Ok(Some(webhook)) => webhook,
+            Ok(None) => {
+                let _ = self
+                    .store
+                    .finish_webhook_job(claimed, Some(&job), Outcome::Drop, None, None)
+                    .await;
+                return;
+            }
+            // The delivery is claimed again once the claim runs out
+            Err(e) => {
+                tracing::error!("Failed to read webhook {}: {}", job.webhook, e);
+                return;
+            }
+        };
+
+        let attempt = job.attempts + 1;
+        let (status, error) = self.post(&webhook, &job, attempt).await;
+        let outcome = match &error {
+            None => Outcome::Delivered,
+            Some(error) if attempt >= self.max_attempts => {
+                tracing::warn!(
+                    "Giving up on delivering {} to webhook {} after {} attempts: {}",
+                    job.event.key,
+                    job.webhook,
+                    attempt,
+                    error
+                );
+                Outcome::Dead(DeadLetter {
+                    event: job.event.clone(),
+                    attempts: attempt,
+                    error: error.clone(),
+                    failed_at: now_millis(),
+                })
+            }
+            Some(_) => {
+                let delay = backoff(self.backoff, attempt);
+                let retry = WebhookJob {
+                    attempts: attempt,
+                    ..job.clone()
+                };
+                Outcome::Retry(retry, delay)
+            }
+        };
+
+        if let Err(e) = self
+            .store
+            .finish_webhook_job(claimed, Some(&job), outcome, status, error.as_deref())
+            .await
+        {
+            tracing::error!("Failed to record webhook delivery: {}", e);
+        }
+    }
+
+    /// POST a delivery, returning the receiver's status and why it failed
+    async fn post(
+        &self,
+        webhook: &Webhook,
+        job: &WebhookJob,
+        attempt: u32,
+    ) -> (Option<u16>, Option<String>) {
+        let body = match serde_json::to_vec(&WebhookPayload {
+            webhook: &job.webhook,
+            attempt,
+            event: &job.event,
+        }) {
+            Ok(body) => body,
+            Err(e) => return (None, Some(format!("Failed to encode delivery: {}", e))),
+        };
+        // Addresses in URLs aren't resolved, so the client can't refuse them
+        if !self.store.private_webhooks {
+            if let Err(e) = check_webhook_target(&webhook.url).await {
+                return (None, Some(e.to_string()));
+            }
+        }
+        let event = match job.event.kind {
+            ChangeKind::Put => "put",
+            ChangeKind::Delete => "delete",
+            ChangeKind::Expire => "expire",
+        };
+
+        let response = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(WEBHOOK_EVENT_HEADER, event)
+            .header(
+                WEBHOOK_SIGNATURE_HEADER,
+                webhook_signature(&webhook.secret, &body),
+            )
+            .body(body)
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16()), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                Some(format!("Receiver answered {}", response.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        }
+    }
+
+    /// Handle expirations, resubscribing when the connection is lost
+    async fn queue_expirations(self) {
+        loop {
+            if let Err(e) = self.follow_expirations().await {
+                tracing::warn!("Stopped listening for expirations: {}", e);
+            }
+            tokio::time::sleep(WEBHOOK_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn follow_expirations(&self) -> Result<()> {
+        let Some(client) = self.store.client.as_ref() else {
+            return Ok(());
+        };
+        let channel = format!("__keyspace@{}__:", client.get_connection_info().redis.db);
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.psubscribe(format!("{}*", channel)).await?;
+
+        let script =
+            redis::Script::new(&[NAMESPACE_LUA, INDEX_LUA, WEBHOOK_LUA, EXPIRED_SCRIPT].concat());
+        let mut messages = pubsub.into_on_message();
+        while let Some(msg) = messages.next().await {
+            if msg.get_payload::<String>().ok().as_deref() != Some("expired") {
+                continue;
+            }
+            let Some(key) = msg.get_channel_name().strip_prefix(&channel) else {
+                continue;
+            };
+            let Some((token, _)) = key.split_once(':') else {
+                continue;
+            };
+            if token == INTERNAL_PREFIX {
+                continue;
+            }
+
+            let mut conn = self.store.conn.clone();
+            let mut invocation = script.key(indexes_key(token));
+            invocation.arg(key);
+            add_namespace(&mut invocation, token);
+            if let Err(e) = invocation.invoke_async::<()>(&mut conn).await {
+                tracing::error!(
+                    "Failed to handle expiry of {}: {}",
+                    key,
+                    redis_error(key, e)
+                );
+            }
+        }
+
+        Err(KVStoreError::Internal(
+            "Lost the Pub/Sub connection".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            webhook_signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let base = Duration::from_secs(1);
+        assert_eq!(backoff(base, 1), Duration::from_secs(1));
+        assert_eq!(backoff(base, 2), Duration::from_secs(2));
+        assert_eq!(backoff(base, 4), Duration::from_secs(8));
+        assert_eq!(backoff(base, 40), MAX_WEBHOOK_BACKOFF);
+    }
+
+    #[test]
+    fn test_check_webhook() {
+        let webhook = Webhook {
+            url: "https://example.com/hook".to_string(),
+            prefix: String::new(),
+            events: all_events(),
+            secret: "s".to_string(),
+        };
+        assert!(check_webhook(&webhook).is_ok());
+        assert!(check_webhook(&Webhook {
+            url: "ftp://example.com".to_string(),
+            ..webhook.clone()
+        })
+        .is_err());
+        assert!(check_webhook(&Webhook {
+            events: Vec::new(),
+            ..webhook.clone()
+        })
+        .is_err());
+        assert!(check_webhook(&Webhook {
+            secret: String::new(),
+            ..webhook
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_private_address(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:4700::1111"] {
+            assert!(!is_private_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_webhook_target() {
+        assert!(check_webhook_target("http://127.0.0.1:8080/hook")
+            .await
+            .is_err());
+        assert!(check_webhook_target("http://[::1]/hook").await.is_err());
+        assert!(check_webhook_target("http://localhost/hook").await.is_err());
+        assert!(check_webhook_target("https://93.184.216.34/hook")
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_job_from_lua() {
+        // As encoded by enqueue_webhooks
+        let job: WebhookJob = serde_json::from_str(
+            r#"{"token":"t","webhook":"w","attempts":0,"event":{"id":"1-0","type":"put","key":"a\/b","value":"v","version":1}}"#,
+        )
+        .unwrap();
+        assert_eq!(job.event.key, "a/b");
+        assert_eq!(job.event.kind, ChangeKind::Put);
+    }
+}
@@ -145,252 +145,2783 @@ mod http_tests {
         let result = store.get("test-token", "test-key-del-http").await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_http_hash_fields() {
+        let store = setup_store().await;
+        let app = create_http_server(store.clone());
+
+        // Set two fields
+        let set_body = json!({"fields": {"name": "Alice", "visits": "1"}}).to_string();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/test-hash-http/fields")
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(set_body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Increment one field
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/test-hash-http/fields/visits/incr")
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json!({"by": 2}).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Read a single field
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("GET")
+                    .uri("/test-hash-http/fields/visits")
+                    .header("Authorization", "Bearer test-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let values = store
+            .hash_get("test-token", "test-hash-http", &["visits".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(values, vec![Some("3".to_string())]);
+
+        // Clean up
+        store.delete("test-token", "test-hash-http").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_http_json_document() {
+        let store = setup_store().await;
+        let app = create_http_server(store.clone());
+
+        // Store a document
+        let set_body = json!({"value": {"user": {"name": "Alice", "age": 30}}}).to_string();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/test-json-http")
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(set_body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Merge-patch a sub-path
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PATCH")
+                    .uri("/test-json-http")
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/merge-patch+json")
+                    .body(Body::from(json!({"user": {"name": "Bob"}}).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Read the patched field by path
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("GET")
+                    .uri("/test-json-http?path=$.user.name")
+                    .header("Authorization", "Bearer test-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["value"], "Bob");
+
+        // Invalid paths are rejected
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("GET")
+                    .uri("/test-json-http?path=user")
+                    .header("Authorization", "Bearer test-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Clean up
+        store.delete("test-token", "test-json-http").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_http_conditional_get() {
+        let store = setup_store().await;
+        let app = create_http_server(store.clone());
+
+        let set_body = json!({
+            "value": "a,b",
+            "content_type": "text/csv",
+            "tags": {"owner": "billing"}
+        })
+        .to_string();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/test-meta-http")
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(set_body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Raw reads carry the stored content type and Last-Modified
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("GET")
+                    .uri("/test-meta-http?raw=true")
+                    .header("Authorization", "Bearer test-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/csv");
+        let last_modified = response.headers()["last-modified"].clone();
+
+        // The same date in If-Modified-Since yields 304 Not Modified
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("GET")
+                    .uri("/test-meta-http")
+                    .header("Authorization", "Bearer test-token")
+                    .header("If-Modified-Since", last_modified)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Clean up
+        store.delete("test-token", "test-meta-http").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_http_delete_prefix() {
+        let store = setup_store().await;
+        let app = create_http_server(store.clone());
+
+        for key in ["purge-http:a", "purge-http:b"] {
+            store.set("test-token", key, "value", None).await.unwrap();
+        }
+
+        // The prefix parameter is required
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri("/")
+                    .header("Authorization", "Bearer test-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri("/?prefix=purge-http:&dry_run=true")
+                    .header("Authorization", "Bearer test-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["count"], 2);
+        assert_eq!(json["keys"], json!(["purge-http:a", "purge-http:b"]));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri("/?prefix=purge-http:")
+                    .header("Authorization", "Bearer test-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(store.get("test-token", "purge-http:a").await.is_err());
+    }
+
+    /// Read a Server-Sent Events body until an event of type `name` arrives,
+    /// returning its ID and data
+    async fn next_sse_event(
+        body: &mut (impl tokio_stream::Stream<Item = Result<axum::body::Bytes, axum::Error>> + Unpin),
+        name: &str,
+    ) -> (String, serde_json::Value) {
+        let mut text = String::new();
+        loop {
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), body.next())
+                .await
+                .expect("timed out waiting for an event")
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+            while let Some(end) = text.find("\n\n") {
+                let event: String = text.drain(..end + 2).collect();
+                let field = |prefix: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(prefix))
+                        .map(String::from)
+                };
+                if field("event: ").as_deref() == Some(name) {
+                    let data = serde_json::from_str(&field("data: ").unwrap()).unwrap();
+                    return (field("id: ").unwrap(), data);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_http_watch_events() {
+        let store = setup_store().await;
+        let app = create_http_server(store.clone());
+
+        let watch = |last_event_id: Option<String>| {
+            let mut request = Request::builder()
+                .uri("/_watch?prefix=feed-http:")
+                .header("Authorization", "Bearer test-token");
+            if let Some(id) = last_event_id {
+                request = request.header("Last-Event-ID", id);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = watch(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body().into_data_stream();
+
+        store
+            .set("test-token", "feed-http:a", "1", None)
+            .await
+            .unwrap();
+        let (id, data) = next_sse_event(&mut body, "put").await;
+        assert_eq!(data["key"], "feed-http:a");
+        assert_eq!(data["value"], "1");
+        drop(body);
+
+        // Changes made while disconnected are replayed after Last-Event-ID
+        store
+            .set("test-token", "feed-http:b", "2", None)
+            .await
+            .unwrap();
+        let response = watch(Some(id)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body().into_data_stream();
+        let (_, data) = next_sse_event(&mut body, "put").await;
+        assert_eq!(data["key"], "feed-http:b");
+        assert_eq!(data["version"], 1);
+
+        store
+            .delete_prefix("test-token", "feed-http:")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_http_idempotency_key() {
+        let store = setup_store().await;
+        let app = create_http_server(store.clone());
+        let _ = store.delete("test-token", "idem-http:a").await;
+
+        // Keys are kept for a day, so use a fresh one on every run
+        let key = format!(
+            "set-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        );
+        let set = |value: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/idem-http:a")
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/json")
+                    .header("Idempotency-Key", &key)
+                    .body(Body::from(json!({ "value": value }).to_string()))
+                    .unwrap(),
+            )
+        };
+
+        let response = set("1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("idempotent-replayed").is_none());
+        let first = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let version = store
+            .get_entry("test-token", "idem-http:a")
+            .await
+            .unwrap()
+            .metadata
+            .version;
+
+        // A retry gets the same response without writing again
+        let response = set("1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["idempotent-replayed"], "true");
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let replayed = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(replayed, first);
+        let entry = store.get_entry("test-token", "idem-http:a").await.unwrap();
+        assert_eq!(entry.metadata.version, version);
+
+        // Reusing the key with a different payload is rejected
+        let response = set("2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(store.get("test-token", "idem-http:a").await.unwrap(), "1");
+
+        store.delete("test-token", "idem-http:a").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_http_webhooks() {
+        use axum::http::HeaderMap;
+        use kvstore::store::{webhook_signature, WebhookWorker, WEBHOOK_SIGNATURE_HEADER};
+        use std::time::Duration;
+
+        // Webhooks can't target local addresses unless the store allows it
+        let store = setup_store().await;
+        let response = create_http_server(store.clone())
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/_webhooks/hook-local")
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(
+                        json!({"url": "http://127.0.0.1/hook", "secret": "s"}).to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let store = store.with_private_webhooks(true);
+        let app = create_http_server(store.clone());
+
+        // A local receiver recording every delivery
+        let (tx, mut deliveries) = tokio::sync::mpsc::unbounded_channel();
+        let receiver = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |headers: HeaderMap, body: axum::body::Bytes| {
+                let tx = tx.clone();
+                async move {
+                    tx.send((headers, body)).unwrap();
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+
+        let register = |name: &str, body: serde_json::Value| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(format!("/_webhooks/{}", name))
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+        };
+        let response = register(
+            "hook-ok",
+            json!({"url": format!("http://{}/hook", addr), "prefix": "hook-http:", "secret": "s3cret"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Nothing listens on port 1, so every attempt fails
+        let response = register(
+            "hook-dead",
+            json!({"url": "http://127.0.0.1:1/hook", "prefix": "hook-http:", "events": ["delete"], "secret": "s"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = register(
+            "hook-bad",
+            json!({"url": "ftp://example.com", "secret": "s"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let worker = WebhookWorker::new(store.clone())
+            .unwrap()
+            .with_retries(2, Duration::from_millis(50));
+        let worker = tokio::spawn(worker.run());
+
+        store
+            .set("test-token", "hook-http:a", "1", None)
+            .await
+            .unwrap();
+        store.delete("test-token", "hook-http:a").await.unwrap();
+
+        // Deliveries due at the same time are made concurrently
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let (headers, body) = tokio::time::timeout(Duration::from_secs(10), deliveries.recv())
+                .await
+                .expect("timed out waiting for a delivery")
+                .unwrap();
+            assert_eq!(
+                headers[WEBHOOK_SIGNATURE_HEADER],
+                webhook_signature("s3cret", &body).as_str()
+            );
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(payload["webhook"], "hook-ok");
+            assert_eq!(payload["event"]["key"], "hook-http:a");
+            assert_eq!(
+                headers["x-kvstore-event"],
+                payload["event"]["type"].as_str().unwrap()
+            );
+            events.push(payload["event"]["type"].as_str().unwrap().to_string());
+        }
+        events.sort();
+        assert_eq!(events, ["delete", "put"]);
+
+        // The delete to the unreachable receiver ends up dead-lettered
+        let mut dead_letters = serde_json::Value::Null;
+        for _ in 0..100 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/_webhooks/hook-dead/dead_letters")
+                        .header("Authorization", "Bearer test-token")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            dead_letters = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            if !dead_letters["dead_letters"].as_array().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(dead_letters["dead_letters"][0]["event"]["type"], "delete");
+        assert_eq!(dead_letters["dead_letters"][0]["attempts"], 2);
+
+        let webhooks = store.list_webhooks("test-token").await.unwrap();
+        let ok = webhooks.iter().find(|w| w.name == "hook-ok").unwrap();
+        assert_eq!(ok.status.delivered, 2);
+        assert_eq!(ok.status.last_status, Some(204));
+        let dead = webhooks.iter().find(|w| w.name == "hook-dead").unwrap();
+        assert_eq!(dead.status.failed, 1);
+        assert!(dead.status.last_error.is_some());
+
+        worker.abort();
+        store.delete_webhook("test-token", "hook-ok").await.unwrap();
+        store
+            .delete_webhook("test-token", "hook-dead")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_http_export_import() {
+        let store = setup_store().await;
+        let app = create_http_server(store.clone());
+        store.delete_prefix("test-token", "ndjson:").await.unwrap();
+        store
+            .set("test-token", "ndjson:a", "1", None)
+            .await
+            .unwrap();
+        store
+            .set("test-token", "ndjson:b", "2", None)
+            .await
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/_export?prefix=ndjson:")
+                    .header("Authorization", "Bearer test-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let export = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(export.lines().count(), 2);
+
+        let import = |conflict: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(format!("/_import?conflict={}", conflict))
+                    .header("Authorization", "Bearer test-token")
+                    .header("Content-Type", "application/x-ndjson")
+                    .body(Body::from(export.clone()))
+                    .unwrap(),
+            )
+        };
+
+        // Importing into the same namespace finds every key already there
+        let response = import("skip").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(summary, json!({"imported": 0, "skipped": 2}));
+
+        let response = import("fail").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = import("merge").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        store.delete_prefix("test-token", "ndjson:").await.unwrap();
+    }
+}
+
+mod grpc_tests {
+    use super::*;
+    use kvstore::grpc::kv_store::{
+        kv_store_client::KvStoreClient, DeleteRequest, GetRequest, HashGetRequest, HashSetRequest,
+        HealthCheckRequest, SetRequest,
+    };
+    use tonic::transport::Channel;
+
+    async fn setup_grpc_test() -> (KVStore, tokio::task::JoinHandle<()>, u16) {
+        let store = KVStore::new("redis://127.0.0.1:6379")
+            .await
+            .expect("Failed to connect to Redis");
+
+        // Add a test token
+        let mut conn = store.connection_manager();
+        redis::cmd("SADD")
+            .arg("tokens")
+            .arg("grpc-test-token")
+            .query_async::<()>(&mut conn)
+            .await
+            .expect("Failed to add test token");
+
+        // Start gRPC server on a random available port
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind to port");
+        let addr = listener.local_addr().expect("Failed to get local address");
+        let port = addr.port();
+
+        let store_clone = store.clone();
+        let service = create_grpc_server(store_clone);
+
+        let handle = tokio::spawn(async move {
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .unwrap();
+        });
+
+        // Give the server time to start
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        (store, handle, port)
+    }
+
+    async fn create_client(port: u16) -> KvStoreClient<Channel> {
+        KvStoreClient::connect(format!("http://127.0.0.1:{}", port))
+            .await
+            .expect("Failed to connect to gRPC server")
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_grpc_health_check() {
+        let (_store, _handle, port) = setup_grpc_test().await;
+        let mut client = create_client(port).await;
+
+        let response = client.health_check(HealthCheckRequest {}).await.unwrap();
+
+        assert!(response.get_ref().healthy);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_grpc_set_and_get() {
+        let (store, _handle, port) = setup_grpc_test().await;
+        let mut client = create_client(port).await;
+
+        // Set a value
+        let set_response = client
+            .set(SetRequest {
+                key: "grpc-test-key".to_string(),
+                value: "grpc-test-value".to_string(),
+                token: "grpc-test-token".to_string(),
+                ttl_seconds: None,
+                content_type: None,
+                tags: Default::default(),
+            })
+            .await
+            .unwrap();
+
+        assert!(set_response.get_ref().success);
+
+        // Get the value
+        let get_response = client
+            .get(GetRequest {
+                key: "grpc-test-key".to_string(),
+                token: "grpc-test-token".to_string(),
+            })
+            .await
+            .unwrap();
+
+        assert!(get_response.get_ref().found);
+        assert_eq!(get_response.get_ref().value, "grpc-test-value");
+
+        // Clean up
+        store
+            .delete("grpc-test-token", "grpc-test-key")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_grpc_delete() {
+        let (store, _handle, port) = setup_grpc_test().await;
+
+        // Set a value first
+        store
+            .set("grpc-test-token", "grpc-test-key-del", "test-value", None)
+            .await
+            .unwrap();
+
+        let mut client = create_client(port).await;
+
+        // Delete the value
+        let delete_response = client
+            .delete(DeleteRequest {
+                key: "grpc-test-key-del".to_string(),
+                token: "grpc-test-token".to_string(),
+            })
+            .await
+            .unwrap();
+
+        assert!(delete_response.get_ref().success);
+
+        // Verify it's deleted
+        let result = store.get("grpc-test-token", "grpc-test-key-del").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_grpc_hash_fields() {
+        let (store, _handle, port) = setup_grpc_test().await;
+        let mut client = create_client(port).await;
+
+        let response = client
+            .hash_set(HashSetRequest {
+                key: "grpc-test-hash".to_string(),
+                token: "grpc-test-token".to_string(),
+                fields: [("color".to_string(), "blue".to_string())].into(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.get_ref().added, 1);
+
+        let response = client
+            .hash_get(HashGetRequest {
+                key: "grpc-test-hash".to_string(),
+                token: "grpc-test-token".to_string(),
+                fields: vec!["color".to_string(), "size".to_string()],
+            })
+            .await
+            .unwrap();
+        let values = &response.get_ref().values;
+        assert!(values[0].found);
+        assert_eq!(values[0].value, "blue");
+        assert!(!values[1].found);
+
+        // Clean up
+        store
+            .delete("grpc-test-token", "grpc-test-hash")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_grpc_unauthorized() {
+        let (_store, _handle, port) = setup_grpc_test().await;
+        let mut client = create_client(port).await;
+
+        // Try to get with invalid token
+        let result = client
+            .get(GetRequest {
+                key: "test-key".to_string(),
+                token: "invalid-token".to_string(),
+            })
+            .await;
+
+        assert!(result.is_err());
+        let status = result.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_grpc_idempotency_key() {
+        use kvstore::grpc::kv_store::HashIncrementRequest;
+
+        let (store, _handle, port) = setup_grpc_test().await;
+        let mut client = create_client(port).await;
+        let _ = store.delete("grpc-test-token", "grpc-test-counter").await;
+
+        // Keys are kept for a day, so use a fresh one on every run
+        let key = format!(
+            "incr-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        );
+        let increment = |delta: i64| {
+            let mut request = tonic::Request::new(HashIncrementRequest {
+                key: "grpc-test-counter".to_string(),
+                token: "grpc-test-token".to_string(),
+                field: "hits".to_string(),
+                delta,
+            });
+            request
+                .metadata_mut()
+                .insert("idempotency-key", key.parse().unwrap());
+            request
+        };
+
+        let first = client.hash_increment(increment(5)).await.unwrap();
+        assert_eq!(first.get_ref().value, 5);
+        assert!(first.metadata().get("idempotent-replayed").is_none());
+
+        // A retry is not applied again
+        let retry = client.hash_increment(increment(5)).await.unwrap();
+        assert_eq!(retry.get_ref().value, 5);
+        assert_eq!(retry.metadata().get("idempotent-replayed").unwrap(), "true");
+        let values = store
+            .hash_get(
+                "grpc-test-token",
+                "grpc-test-counter",
+                &["hits".to_string()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(values, [Some("5".to_string())]);
+
+        // Reusing the key for a different call is rejected
+        let status = client.hash_increment(increment(6)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        store
+            .delete("grpc-test-token", "grpc-test-counter")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_grpc_export_import() {
+        use kvstore::grpc::kv_store::{ExportRequest, ImportConflict, ImportRequest};
+
+        let (store, _handle, port) = setup_grpc_test().await;
+        let mut client = create_client(port).await;
+        store
+            .delete_prefix("grpc-test-token", "export:")
+            .await
+            .unwrap();
+        store
+            .set("grpc-test-token", "export:a", "1", None)
+            .await
+            .unwrap();
+
+        let keys: Vec<_> = client
+            .export(ExportRequest {
+                token: "grpc-test-token".to_string(),
+                prefix: "export:".to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|key| key.unwrap())
+            .collect()
+            .await;
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].key, "export:a");
+        assert_eq!(keys[0].value, "1");
+
+        // Import the key back under a new name
+        store.delete("grpc-test-token", "export:a").await.unwrap();
+        let mut renamed = keys[0].clone();
+        renamed.key = "export:b".to_string();
+        let requests = vec![
+            ImportRequest {
+                token: "grpc-test-token".to_string(),
+                conflict: ImportConflict::Fail.into(),
+                key: Some(renamed),
+            },
+            ImportRequest {
+                key: Some(keys[0].clone()),
+                ..Default::default()
+            },
+        ];
+        let response = client
+            .import(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((response.imported, response.skipped), (2, 0));
+        assert_eq!(store.get("grpc-test-token", "export:b").await.unwrap(), "1");
+
+        let status = client
+            .import(tokio_stream::iter(vec![ImportRequest {
+                token: "wrong-token".to_string(),
+                ..Default::default()
+            }]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        store
+            .delete_prefix("grpc-test-token", "export:")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_grpc_replication() {
+        use kvstore::replication::{ReplicatedNamespace, Replicator};
+        use std::time::Duration;
+
+        // The source serves gRPC from database 0, the replica uses database 1
+        let (source, _handle, port) = setup_grpc_test().await;
+        let replica = KVStore::new("redis://127.0.0.1:6379/1")
+            .await
+            .expect("Failed to connect to Redis");
+        let token = "grpc-test-token";
+        let mut conn = replica.connection_manager();
+        redis::pipe()
+            .sadd("tokens", token)
+            .del(format!("_kvstore:{}:replication", token))
+            .query_async::<()>(&mut conn)
+            .await
+            .unwrap();
+        for store in [&source, &replica] {
+            store.delete_prefix(token, "repl:").await.unwrap();
+            store.delete_prefix(token, "other:").await.unwrap();
+        }
+
+        // Keys written before the replica starts arrive with the full copy
+        source.set(token, "repl:a", "1", None).await.unwrap();
+        source.set(token, "other:a", "1", None).await.unwrap();
+        // Keys without metadata, as written by older versions, are copied too
+        let mut source_conn = source.connection_manager();
+        redis::cmd("SET")
+            .arg(format!("{}:repl:legacy", token))
+            .arg("old")
+            .query_async::<()>(&mut source_conn)
+            .await
+            .unwrap();
+
+        let replicator = Replicator::new(
+            replica.clone(),
+            format!("http://127.0.0.1:{}", port),
+            token.parse::<ReplicatedNamespace>().unwrap(),
+        )
+        .with_prefixes(vec!["repl:".to_string()])
+        .with_retry_delay(Duration::from_millis(100));
+        let follower = tokio::spawn(replicator.run());
+
+        let eventually = |key: &'static str, expected: Option<&'static str>| {
+            let replica = replica.clone();
+            async move {
+                for _ in 0..50 {
+                    let value = replica.get(token, key).await.ok();
+                    if value.as_deref() == expected {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                panic!("{} never became {:?} on the replica", key, expected);
+            }
+        };
+        eventually("repl:a", Some("1")).await;
+        eventually("repl:legacy", Some("old")).await;
+
+        // Later changes follow the change feed, keeping the source's version
+        source.set(token, "repl:b", "1", None).await.unwrap();
+        source.set(token, "repl:b", "2", None).await.unwrap();
+        source.set(token, "other:b", "1", None).await.unwrap();
+        eventually("repl:b", Some("2")).await;
+        let entry = replica.get_entry(token, "repl:b").await.unwrap();
+        assert_eq!(entry.metadata.version, 2);
+        assert!(replica.get(token, "other:a").await.is_err());
+        assert!(replica.get(token, "other:b").await.is_err());
+
+        // A newer local version wins over an older one from the source
+        for value in ["x", "y", "z"] {
+            replica.set(token, "repl:c", value, None).await.unwrap();
+        }
+        source.set(token, "repl:c", "old", None).await.unwrap();
+        source.delete(token, "repl:a").await.unwrap();
+        eventually("repl:a", None).await;
+        assert_eq!(replica.get(token, "repl:c").await.unwrap(), "z");
+
+        let status = replica.replication_status(token).await.unwrap().unwrap();
+        assert!(status.connected);
+        assert!(status.offset.is_some());
+        assert!(status.applied >= 4);
+        assert!(status.stale >= 1);
+        assert!(status.lag_ms.is_some());
+
+        follower.abort();
+        for store in [&source, &replica] {
+            store.delete_prefix(token, "repl:").await.unwrap();
+            store.delete_prefix(token, "other:").await.unwrap();
+        }
+    }
 }
 
-mod grpc_tests {
-    use super::*;
-    use kvstore::grpc::kv_store::{
-        kv_store_client::KvStoreClient, DeleteRequest, GetRequest, HealthCheckRequest, SetRequest,
-    };
-    use tonic::transport::Channel;
+mod store_tests {
+    use super::*;
+
+    async fn setup() -> KVStore {
+        let store = KVStore::new("redis://127.0.0.1:6379")
+            .await
+            .expect("Failed to connect to Redis");
+
+        // Add a test token
+        let mut conn = store.connection_manager();
+        redis::cmd("SADD")
+            .arg("tokens")
+            .arg("store-test-token")
+            .query_async::<()>(&mut conn)
+            .await
+            .expect("Failed to add test token");
+
+        store
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_set_get_delete() {
+        let store = setup().await;
+
+        // Set
+        store
+            .set("store-test-token", "key1", "value1", None)
+            .await
+            .unwrap();
+
+        // Get
+        let value = store.get("store-test-token", "key1").await.unwrap();
+        assert_eq!(value, "value1");
+
+        // Delete
+        store.delete("store-test-token", "key1").await.unwrap();
+
+        // Verify deletion
+        let result = store.get("store-test-token", "key1").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_set_with_ttl() {
+        let store = setup().await;
+
+        // Set with TTL of 2 seconds
+        store
+            .set("store-test-token", "ttl-key", "ttl-value", Some(2))
+            .await
+            .unwrap();
+
+        // Get immediately
+        let value = store.get("store-test-token", "ttl-key").await.unwrap();
+        assert_eq!(value, "ttl-value");
+
+        // Wait for expiration
+        tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+
+        // Verify expiration
+        let result = store.get("store-test-token", "ttl-key").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_list_keys() {
+        let store = setup().await;
+
+        // Set multiple keys
+        store
+            .set("store-test-token", "list:key1", "value1", None)
+            .await
+            .unwrap();
+        store
+            .set("store-test-token", "list:key2", "value2", None)
+            .await
+            .unwrap();
+        store
+            .set("store-test-token", "other:key", "value3", None)
+            .await
+            .unwrap();
+
+        // List with prefix
+        let keys: Vec<String> = store.list("store-test-token", "list:").await.unwrap().collect().await;
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&"list:key1".to_string()));
+        assert!(keys.contains(&"list:key2".to_string()));
+
+        // Clean up
+        store.delete("store-test-token", "list:key1").await.unwrap();
+        store.delete("store-test-token", "list:key2").await.unwrap();
+        store.delete("store-test-token", "other:key").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_hash_operations() {
+        let store = setup().await;
+        let token = "store-test-token";
+
+        let added = store
+            .hash_set(
+                token,
+                "hash-key",
+                &[
+                    ("name".to_string(), "Alice".to_string()),
+                    ("age".to_string(), "30".to_string()),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(added, 2);
+
+        let values = store
+            .hash_get(
+                token,
+                "hash-key",
+                &["name".to_string(), "missing".to_string()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(values, vec![Some("Alice".to_string()), None]);
+
+        let age = store
+            .hash_increment(token, "hash-key", "age", 1)
+            .await
+            .unwrap();
+        assert_eq!(age, 31);
+
+        let removed = store
+            .hash_delete(token, "hash-key", &["name".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(removed, 1);
+
+        let fields = store.hash_get_all(token, "hash-key").await.unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields.get("age"), Some(&"31".to_string()));
+
+        // Field-level commands against a plain string are a type error
+        store.set(token, "string-key", "value", None).await.unwrap();
+        let result = store.hash_get_all(token, "string-key").await;
+        assert!(matches!(result, Err(kvstore::KVStoreError::WrongType(_))));
+
+        // Clean up
+        store.delete(token, "hash-key").await.unwrap();
+        store.delete(token, "string-key").await.unwrap();
+        let result = store.hash_get_all(token, "hash-key").await;
+        assert!(matches!(result, Err(kvstore::KVStoreError::KeyNotFound(_))));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_list_queue_operations() {
+        use kvstore::store::ListEnd;
+
+        let store = setup().await;
+        let token = "store-test-token";
+
+        let length = store
+            .list_push(
+                token,
+                "queue-key",
+                &["a".to_string(), "b".to_string(), "c".to_string()],
+                ListEnd::Right,
+            )
+            .await
+            .unwrap();
+        assert_eq!(length, 3);
+
+        let values = store.list_range(token, "queue-key", 0, -1).await.unwrap();
+        assert_eq!(values, vec!["a", "b", "c"]);
+
+        let popped = store
+            .list_pop(token, "queue-key", ListEnd::Left, 2)
+            .await
+            .unwrap();
+        assert_eq!(popped, vec!["a", "b"]);
+        assert_eq!(store.list_length(token, "queue-key").await.unwrap(), 1);
+
+        // Blocking pop returns immediately while values remain...
+        let value = store
+            .list_blocking_pop(
+                token,
+                "queue-key",
+                ListEnd::Left,
+                std::time::Duration::from_secs(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(value, Some("c".to_string()));
+
+        // ...and times out on an empty list
+        let value = store
+            .list_blocking_pop(
+                token,
+                "queue-key",
+                ListEnd::Left,
+                std::time::Duration::from_millis(200),
+            )
+            .await
+            .unwrap();
+        assert_eq!(value, None);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_blocking_pop_waits_for_push() {
+        use kvstore::store::ListEnd;
+
+        let store = setup().await;
+        let token = "store-test-token";
+
+        let pusher = store.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+            pusher
+                .list_push(token, "wait-queue", &["job".to_string()], ListEnd::Right)
+                .await
+                .unwrap();
+        });
+
+        let value = store
+            .list_blocking_pop(
+                token,
+                "wait-queue",
+                ListEnd::Left,
+                std::time::Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert_eq!(value, Some("job".to_string()));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_sorted_set_leaderboard() {
+        use kvstore::store::ScoredMember;
+
+        let store = setup().await;
+        let token = "store-test-token";
+
+        let members: Vec<ScoredMember> = [("alice", 30.0), ("bob", 10.0), ("carol", 20.0)]
+            .into_iter()
+            .map(|(member, score)| ScoredMember {
+                member: member.to_string(),
+                score,
+            })
+            .collect();
+        let added = store
+            .sorted_set_add(token, "leaderboard", &members)
+            .await
+            .unwrap();
+        assert_eq!(added, 3);
+
+        let score = store
+            .sorted_set_increment(token, "leaderboard", "bob", 25.0)
+            .await
+            .unwrap();
+        assert_eq!(score, 35.0);
 
-    async fn setup_grpc_test() -> (KVStore, tokio::task::JoinHandle<()>, u16) {
-        let store = KVStore::new("redis://127.0.0.1:6379")
+        // Highest score first, paged two at a time
+        let page = store
+            .sorted_set_range_by_rank(token, "leaderboard", 0, 2, true)
+            .await
+            .unwrap();
+        let names: Vec<&str> = page.iter().map(|m| m.member.as_str()).collect();
+        assert_eq!(names, vec!["bob", "alice"]);
+
+        let page = store
+            .sorted_set_range_by_score(token, "leaderboard", 15.0, 31.0, 0, 10, false)
+            .await
+            .unwrap();
+        let names: Vec<&str> = page.iter().map(|m| m.member.as_str()).collect();
+        assert_eq!(names, vec!["carol", "alice"]);
+
+        let rank = store
+            .sorted_set_rank(token, "leaderboard", "carol", true)
+            .await
+            .unwrap();
+        assert_eq!(rank, Some((2, 20.0)));
+
+        let removed = store
+            .sorted_set_remove(token, "leaderboard", &["carol".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(removed, 1);
+        let rank = store
+            .sorted_set_rank(token, "leaderboard", "carol", false)
+            .await
+            .unwrap();
+        assert_eq!(rank, None);
+
+        // Clean up
+        store.delete(token, "leaderboard").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_set_operations() {
+        use kvstore::store::SetOperation;
+
+        let store = setup().await;
+        let token = "store-test-token";
+
+        let tags =
+            |members: &[&str]| -> Vec<String> { members.iter().map(|m| m.to_string()).collect() };
+        store
+            .set_add(token, "tags:a", &tags(&["red", "green", "blue"]))
+            .await
+            .unwrap();
+        store
+            .set_add(token, "tags:b", &tags(&["green", "blue", "black"]))
+            .await
+            .unwrap();
+
+        assert!(store.set_is_member(token, "tags:a", "red").await.unwrap());
+        assert!(!store.set_is_member(token, "tags:b", "red").await.unwrap());
+        assert_eq!(store.set_cardinality(token, "tags:a").await.unwrap(), 3);
+
+        let mut members: Vec<String> = store
+            .set_members(token, "tags:a")
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        members.sort();
+        assert_eq!(members, tags(&["blue", "green", "red"]));
+
+        let mut common = store
+            .set_combine(
+                token,
+                &tags(&["tags:a", "tags:b"]),
+                SetOperation::Intersection,
+            )
+            .await
+            .unwrap();
+        common.sort();
+        assert_eq!(common, tags(&["blue", "green"]));
+
+        let all = store
+            .set_combine(token, &tags(&["tags:a", "tags:b"]), SetOperation::Union)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 4);
+
+        // Sets of another namespace are invisible to set operations
+        store
+            .set_add("other-token", "tags:b", &tags(&["red"]))
+            .await
+            .unwrap();
+        let common = store
+            .set_combine(
+                token,
+                &tags(&["tags:a", "tags:b"]),
+                SetOperation::Intersection,
+            )
+            .await
+            .unwrap();
+        assert!(!common.contains(&"red".to_string()));
+
+        // Clean up
+        store.delete(token, "tags:a").await.unwrap();
+        store.delete(token, "tags:b").await.unwrap();
+        store.delete("other-token", "tags:b").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_json_documents() {
+        use kvstore::store::PatchFormat;
+        use serde_json::json;
+
+        let store = setup().await;
+        let token = "store-test-token";
+
+        store
+            .set_json(
+                token,
+                "doc",
+                &json!({"user": {"name": "Alice", "tags": ["a"]}}),
+                Some(60),
+            )
+            .await
+            .unwrap();
+
+        let entry = store.get_entry(token, "doc").await.unwrap();
+        assert!(entry.is_json());
+        assert_eq!(
+            store
+                .get_json(token, "doc", Some("$.user.name"))
+                .await
+                .unwrap(),
+            json!("Alice")
+        );
+
+        let patched = store
+            .patch_json(
+                token,
+                "doc",
+                &json!([{"op": "add", "path": "/user/tags/-", "value": "b"}]),
+                PatchFormat::JsonPatch,
+            )
+            .await
+            .unwrap();
+        assert_eq!(patched["user"]["tags"], json!(["a", "b"]));
+
+        // Overwriting with plain text drops the JSON content type
+        store.set(token, "doc", "plain", None).await.unwrap();
+        assert!(!store.get_entry(token, "doc").await.unwrap().is_json());
+        assert!(store.get_json(token, "doc", None).await.is_err());
+
+        // Clean up
+        store.delete(token, "doc").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_schema_validation() {
+        use kvstore::KVStoreError;
+        use serde_json::json;
+
+        let store = setup().await;
+        let token = "store-test-token";
+
+        store
+            .register_schema(
+                token,
+                "user:",
+                &json!({
+                    "type": "object",
+                    "properties": {"name": {"type": "string"}},
+                    "required": ["name"]
+                }),
+            )
+            .await
+            .unwrap();
+
+        // Matching documents and text are accepted
+        store
+            .set_json(token, "user:1", &json!({"name": "Alice"}), None)
+            .await
+            .unwrap();
+        store
+            .set(token, "user:2", r#"{"name": "Bob"}"#, None)
+            .await
+            .unwrap();
+
+        // Violations are rejected, including through patches
+        let result = store
+            .set_json(token, "user:3", &json!({"age": 1}), None)
+            .await;
+        assert!(matches!(result, Err(KVStoreError::InvalidRequest(_))));
+        let result = store.set(token, "user:3", "not json", None).await;
+        assert!(matches!(result, Err(KVStoreError::InvalidRequest(_))));
+        let result = store
+            .patch_json(
+                token,
+                "user:1",
+                &json!([{"op": "remove", "path": "/name"}]),
+                kvstore::store::PatchFormat::JsonPatch,
+            )
+            .await;
+        assert!(matches!(result, Err(KVStoreError::InvalidRequest(_))));
+
+        // Keys outside the prefix are not validated
+        store
+            .set(token, "session:1", "anything", None)
+            .await
+            .unwrap();
+
+        let schemas = store.list_schemas(token).await.unwrap();
+        assert_eq!(schemas.len(), 1);
+        assert_eq!(schemas[0].0, "user:");
+
+        // Clean up
+        store.delete_schema(token, "user:").await.unwrap();
+        store.delete(token, "user:1").await.unwrap();
+        store.delete(token, "user:2").await.unwrap();
+        store.delete(token, "session:1").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_key_metadata() {
+        use kvstore::store::SetOptions;
+        use std::collections::HashMap;
+
+        let store = setup().await;
+        let token = "store-test-token";
+
+        let options = SetOptions {
+            content_type: Some("text/csv".to_string()),
+            tags: HashMap::from([("owner".to_string(), "billing".to_string())]),
+            ..Default::default()
+        };
+        store
+            .set_with_options(token, "report", "a,b\n1,2", &options)
+            .await
+            .unwrap();
+
+        let entry = store.get_entry(token, "report").await.unwrap();
+        assert_eq!(entry.metadata.content_type, "text/csv");
+        assert_eq!(entry.metadata.size, Some(8));
+        assert_eq!(
+            entry.metadata.tags.get("owner").map(String::as_str),
+            Some("billing")
+        );
+        let created_at = entry.metadata.created_at.unwrap();
+        assert!(entry.metadata.updated_at.unwrap() >= created_at);
+
+        // Overwriting keeps the creation time and replaces content type and tags
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        store.set(token, "report", "plain", None).await.unwrap();
+        let metadata = store.get_metadata(token, "report").await.unwrap();
+        assert_eq!(metadata.created_at, Some(created_at));
+        assert!(metadata.updated_at.unwrap() > created_at);
+        assert_eq!(metadata.content_type, "text/plain");
+        assert!(metadata.tags.is_empty());
+
+        // Clean up
+        store.delete(token, "report").await.unwrap();
+        assert!(store.get_metadata(token, "report").await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_key_history() {
+        use kvstore::store::HistoryPolicy;
+
+        let store = setup().await;
+        // A dedicated namespace, so enabling history doesn't affect other tests
+        let token = "history-test-token";
+
+        store
+            .set_history_policy(
+                token,
+                &HistoryPolicy {
+                    max_revisions: Some(2),
+                    max_age_seconds: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        store.set(token, "config", "v1", None).await.unwrap();
+        store.set(token, "config", "v2", None).await.unwrap();
+        store.set(token, "config", "v3", None).await.unwrap();
+
+        // Only the newest two revisions are kept, newest first
+        let history = store.history(token, "config").await.unwrap();
+        let values: Vec<_> = history.iter().map(|r| r.value.as_deref()).collect();
+        assert_eq!(values, vec![Some("v3"), Some("v2")]);
+
+        let v2 = &history[1].revision;
+        let revision = store.get_at_revision(token, "config", v2).await.unwrap();
+        assert_eq!(revision.value.as_deref(), Some("v2"));
+
+        // Restoring writes the old value back as a new revision
+        store.restore(token, "config", v2).await.unwrap();
+        assert_eq!(store.get(token, "config").await.unwrap(), "v2");
+
+        // Deletions are recorded, and reads as of a later time see no value
+        store.delete(token, "config").await.unwrap();
+        let history = store.history(token, "config").await.unwrap();
+        assert!(history[0].deleted);
+        let now = history[0].timestamp.to_string();
+        assert!(store.get_at_revision(token, "config", &now).await.is_err());
+
+        assert!(matches!(
+            store.get_at_revision(token, "config", "latest").await,
+            Err(kvstore::KVStoreError::InvalidRequest(_))
+        ));
+
+        // Clean up
+        store
+            .set_history_policy(token, &HistoryPolicy::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_transactions() {
+        use kvstore::store::{Operation, OperationResult, Precondition, TransactionOutcome};
+
+        let store = setup().await;
+        let token = "store-test-token";
+
+        store.set(token, "txn:from", "payload", None).await.unwrap();
+        let version = store.get_metadata(token, "txn:from").await.unwrap().version;
+
+        // Move a value between keys and bump a counter, all or nothing
+        let outcome = store
+            .transaction(
+                token,
+                &[
+                    Precondition::Version {
+                        key: "txn:from".to_string(),
+                        version,
+                    },
+                    Precondition::Missing {
+                        key: "txn:to".to_string(),
+                    },
+                ],
+                &[
+                    Operation::Set {
+                        key: "txn:to".to_string(),
+                        value: "payload".to_string(),
+                        ttl_seconds: None,
+                        content_type: None,
+                    },
+                    Operation::Delete {
+                        key: "txn:from".to_string(),
+                    },
+                    Operation::Incr {
+                        key: "txn:moves".to_string(),
+                        by: 1,
+                    },
+                ],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            outcome,
+            TransactionOutcome::Committed(vec![
+                OperationResult::Set { version: 1 },
+                OperationResult::Delete { deleted: true },
+                OperationResult::Incr {
+                    value: 1,
+                    version: 1
+                },
+            ])
+        );
+        assert_eq!(store.get(token, "txn:to").await.unwrap(), "payload");
+        assert!(store.get(token, "txn:from").await.is_err());
+
+        // A failed precondition applies nothing
+        let outcome = store
+            .transaction(
+                token,
+                &[Precondition::Exists {
+                    key: "txn:from".to_string(),
+                }],
+                &[Operation::Delete {
+                    key: "txn:to".to_string(),
+                }],
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            TransactionOutcome::PreconditionFailed { index: 0, .. }
+        ));
+        assert!(store.get(token, "txn:to").await.is_ok());
+
+        // Later operations see the effects of earlier ones
+        let result = store
+            .transaction(
+                token,
+                &[],
+                &[
+                    Operation::Delete {
+                        key: "txn:to".to_string(),
+                    },
+                    Operation::Incr {
+                        key: "txn:to".to_string(),
+                        by: 1,
+                    },
+                    Operation::Incr {
+                        key: "txn:moves".to_string(),
+                        by: 1,
+                    },
+                ],
+            )
+            .await;
+        assert!(result.is_ok());
+
+        // An operation that cannot be applied aborts the whole transaction
+        store.set(token, "txn:text", "abc", None).await.unwrap();
+        let result = store
+            .transaction(
+                token,
+                &[],
+                &[
+                    Operation::Delete {
+                        key: "txn:moves".to_string(),
+                    },
+                    Operation::Incr {
+                        key: "txn:text".to_string(),
+                        by: 1,
+                    },
+                ],
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(store.get(token, "txn:moves").await.unwrap(), "2");
+
+        // Clean up
+        for key in ["txn:to", "txn:moves", "txn:text"] {
+            store.delete(token, key).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_delete_prefix() {
+        let store = setup().await;
+        let token = "prefix-test-token";
+
+        for i in 0..1200 {
+            store
+                .set(token, &format!("purge:{}", i), "value", None)
+                .await
+                .unwrap();
+        }
+        store.set(token, "keep:1", "value", None).await.unwrap();
+
+        // A dry run reports the keys without deleting them
+        let matched = store.delete_prefix_dry_run(token, "purge:").await.unwrap();
+        assert_eq!(matched.count, 1200);
+        assert_eq!(matched.keys.len(), kvstore::store::MAX_DRY_RUN_KEYS);
+        assert!(matched.truncated);
+        assert!(store.get(token, "purge:0").await.is_ok());
+
+        // More keys than `list` returns are removed, with their metadata
+        let deleted = store.delete_prefix(token, "purge:").await.unwrap();
+        assert_eq!(deleted, 1200);
+        assert!(store.get(token, "purge:0").await.is_err());
+        assert!(store.get_metadata(token, "purge:0").await.is_err());
+        assert_eq!(store.get(token, "keep:1").await.unwrap(), "value");
+
+        // An empty prefix purges the namespace
+        assert_eq!(store.delete_prefix(token, "").await.unwrap(), 1);
+        assert!(store.get(token, "keep:1").await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_rename_and_copy() {
+        use kvstore::store::{PrefixRename, SetOptions};
+        use kvstore::KVStoreError;
+
+        let store = setup().await;
+        let token = "rename-test-token";
+        let options = SetOptions {
+            ttl_seconds: Some(300),
+            content_type: Some("text/csv".to_string()),
+            tags: [("owner".to_string(), "billing".to_string())].into(),
+        };
+
+        store
+            .set_with_options(token, "old:a", "1,2", &options)
+            .await
+            .unwrap();
+
+        // Copies keep the TTL and metadata of the source
+        store.copy(token, "old:a", "copy:a", false).await.unwrap();
+        let entry = store.get_entry(token, "copy:a").await.unwrap();
+        assert_eq!(entry.value, "1,2");
+        assert_eq!(entry.metadata.content_type, "text/csv");
+        assert_eq!(entry.metadata.tags["owner"], "billing");
+        assert_eq!(entry.metadata.version, 1);
+        let mut conn = store.connection_manager();
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(format!("{}:copy:a", token))
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(ttl > 0 && ttl <= 300);
+
+        // Existing destinations are only replaced on request
+        assert!(matches!(
+            store.copy(token, "old:a", "copy:a", false).await,
+            Err(KVStoreError::Conflict(_))
+        ));
+        store.copy(token, "old:a", "copy:a", true).await.unwrap();
+        assert_eq!(
+            store.get_metadata(token, "copy:a").await.unwrap().version,
+            2
+        );
+
+        // Renames remove the source
+        store
+            .rename(token, "copy:a", "moved:a", false)
+            .await
+            .unwrap();
+        assert!(store.get(token, "copy:a").await.is_err());
+        assert_eq!(store.get(token, "moved:a").await.unwrap(), "1,2");
+        assert!(matches!(
+            store.rename(token, "copy:a", "moved:b", false).await,
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+
+        // Prefix renames skip taken names unless overwriting
+        store.set(token, "old:b", "2", None).await.unwrap();
+        store.set(token, "new:b", "taken", None).await.unwrap();
+        let outcome = store
+            .rename_prefix(token, "old:", "new:", false)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            PrefixRename {
+                renamed: 1,
+                skipped: 1
+            }
+        );
+        assert_eq!(store.get(token, "new:a").await.unwrap(), "1,2");
+        assert_eq!(store.get(token, "new:b").await.unwrap(), "taken");
+        assert!(store
+            .rename_prefix(token, "old:", "old:v2:", false)
+            .await
+            .is_err());
+        store.set(token, "x:y:y:a", "1", None).await.unwrap();
+        assert!(matches!(
+            store.rename_prefix(token, "x:y:", "x:", false).await,
+            Err(KVStoreError::InvalidRequest(_))
+        ));
+        assert_eq!(store.get(token, "x:y:y:a").await.unwrap(), "1");
+
+        // Clean up
+        store.delete_prefix(token, "").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_namespace_stats() {
+        let store = setup().await;
+        let token = "stats-test-token";
+
+        store.set(token, "small", "x", None).await.unwrap();
+        store
+            .set(token, "large", &"x".repeat(10_000), Some(300))
+            .await
+            .unwrap();
+
+        let stats = store.stats(token).await.unwrap();
+        assert_eq!(stats.key_count, 2);
+        assert_eq!(stats.keys_with_ttl, 1);
+        assert!(stats.total_bytes >= 10_000);
+        assert_eq!(stats.largest_keys[0].key, "large");
+        assert_eq!(stats.largest_keys.len(), 2);
+
+        // Clean up
+        store.delete_prefix(token, "").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_key_policy() {
+        use kvstore::store::KeyPolicy;
+        use kvstore::KVStoreError;
+
+        let store = setup().await.with_key_policy(KeyPolicy {
+            max_key_length: 16,
+            max_value_size: 8,
+            ..Default::default()
+        });
+        let token = "policy-test-token";
+
+        for (key, value) in [
+            ("", "v"),
+            ("k".repeat(17).as_str(), "v"),
+            ("_kvstore:reserved", "v"),
+            ("_stats", "v"),
+            ("ok", "too long a value"),
+        ] {
+            let result = store.set(token, key, value, None).await;
+            assert!(matches!(result, Err(KVStoreError::InvalidRequest(_))));
+        }
+        let result = store
+            .list_push(
+                token,
+                "queue",
+                &["too long a value".to_string()],
+                kvstore::store::ListEnd::Right,
+            )
+            .await;
+        assert!(matches!(result, Err(KVStoreError::InvalidRequest(_))));
+
+        // Prefix renames check every new name before renaming anything
+        store.set(token, "p:a", "v", None).await.unwrap();
+        store.set(token, "p:a-long-key", "v", None).await.unwrap();
+        let result = store.rename_prefix(token, "p:", "prefix:", false).await;
+        assert!(matches!(result, Err(KVStoreError::InvalidRequest(_))));
+        assert_eq!(store.get(token, "p:a").await.unwrap(), "v");
+        assert!(store.get(token, "prefix:a").await.is_err());
+
+        // Glob metacharacters in keys and prefixes are matched literally
+        store.set(token, "a*", "star", None).await.unwrap();
+        store.set(token, "ab", "plain", None).await.unwrap();
+        let keys: Vec<String> = store.list(token, "a*").await.unwrap().collect().await;
+        assert_eq!(keys, vec!["a*".to_string()]);
+
+        // Clean up
+        store.delete_prefix(token, "").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_search() {
+        use kvstore::store::{SearchMode, SearchQuery};
+
+        let store = setup().await;
+        let token = "search-test-token";
+
+        for i in 0..25 {
+            store
+                .set(token, &format!("session:{}:cart", i), "v", None)
+                .await
+                .unwrap();
+            store
+                .set(token, &format!("session:{}:user", i), "v", None)
+                .await
+                .unwrap();
+        }
+
+        // Page through a glob search
+        let mut query = SearchQuery {
+            mode: SearchMode::Glob,
+            pattern: "session:*:cart".to_string(),
+            limit: Some(10),
+            ..Default::default()
+        };
+        let mut found = Vec::new();
+        loop {
+            let page = store.search(token, &query).await.unwrap();
+            assert!(page.keys.len() <= 10);
+            found.extend(page.keys);
+            match page.cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        found.sort();
+        found.dedup();
+        assert_eq!(found.len(), 25);
+        assert!(found.iter().all(|key| key.ends_with(":cart")));
+
+        // Regex searches are matched by the server
+        let page = store
+            .search(
+                token,
+                &SearchQuery {
+                    mode: SearchMode::Regex,
+                    pattern: "^session:1[0-9]:user$".to_string(),
+                    limit: Some(1000),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.keys.len(), 10);
+        assert_eq!(page.cursor, None);
+
+        // Clean up
+        store.delete_prefix(token, "").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_secondary_indexes() {
+        use kvstore::store::{IndexDefinition, IndexQuery};
+        use serde_json::json;
+
+        let store = setup().await;
+        let token = "index-test-token";
+
+        store
+            .set_json(
+                token,
+                "order:1",
+                &json!({"status": "pending", "total": 5}),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // Existing documents are indexed when the index is created
+        let indexed = store
+            .create_index(
+                token,
+                "status",
+                &IndexDefinition {
+                    prefix: "order:".to_string(),
+                    field: "status".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(indexed, 1);
+        store
+            .create_index(
+                token,
+                "total",
+                &IndexDefinition {
+                    prefix: "order:".to_string(),
+                    field: "total".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+
+        store
+            .set_json(
+                token,
+                "order:2",
+                &json!({"status": "pending", "total": 15}),
+                None,
+            )
+            .await
+            .unwrap();
+        store
+            .set_json(
+                token,
+                "order:3",
+                &json!({"status": "shipped", "total": 25}),
+                None,
+            )
+            .await
+            .unwrap();
+        store
+            .set_json(token, "other:1", &json!({"status": "pending"}), None)
             .await
-            .expect("Failed to connect to Redis");
+            .unwrap();
 
-        // Add a test token
-        let mut conn = store.connection_manager();
-        redis::cmd("SADD")
-            .arg("tokens")
-            .arg("grpc-test-token")
-            .query_async::<()>(&mut conn)
+        let pending = IndexQuery::Equals(json!("pending"));
+        let page = store
+            .query_index(token, "status", &pending, None, None)
             .await
-            .expect("Failed to add test token");
-
-        // Start gRPC server on a random available port
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap();
+        assert_eq!(page.keys, vec!["order:1", "order:2"]);
+        assert_eq!(page.cursor, None);
+
+        // Numeric ranges are inclusive and paged
+        let range = IndexQuery::Range {
+            min: Some(5.0),
+            max: Some(25.0),
+        };
+        let page = store
+            .query_index(token, "total", &range, None, Some(2))
             .await
-            .expect("Failed to bind to port");
-        let addr = listener.local_addr().expect("Failed to get local address");
-        let port = addr.port();
-
-        let store_clone = store.clone();
-        let service = create_grpc_server(store_clone);
-
-        let handle = tokio::spawn(async move {
-            Server::builder()
-                .add_service(service)
-                .serve_with_incoming(TcpListenerStream::new(listener))
-                .await
-                .unwrap();
-        });
+            .unwrap();
+        assert_eq!(page.keys, vec!["order:1", "order:2"]);
+        let page = store
+            .query_index(token, "total", &range, page.cursor.as_deref(), Some(2))
+            .await
+            .unwrap();
+        assert_eq!(page.keys, vec!["order:3"]);
+        assert_eq!(page.cursor, None);
 
-        // Give the server time to start
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        // Writes and deletes keep the index up to date
+        store
+            .set_json(
+                token,
+                "order:2",
+                &json!({"status": "shipped", "total": 15}),
+                None,
+            )
+            .await
+            .unwrap();
+        store.delete(token, "order:1").await.unwrap();
+        let page = store
+            .query_index(token, "status", &pending, None, None)
+            .await
+            .unwrap();
+        assert!(page.keys.is_empty());
 
-        (store, handle, port)
-    }
+        let indexes = store.list_indexes(token).await.unwrap();
+        assert_eq!(indexes.len(), 2);
 
-    async fn create_client(port: u16) -> KvStoreClient<Channel> {
-        KvStoreClient::connect(format!("http://127.0.0.1:{}", port))
-            .await
-            .expect("Failed to connect to gRPC server")
+        // Clean up
+        store.drop_index(token, "status").await.unwrap();
+        store.drop_index(token, "total").await.unwrap();
+        assert!(store.drop_index(token, "total").await.is_err());
+        store.delete_prefix(token, "").await.unwrap();
     }
 
     #[tokio::test]
     #[ignore] // Requires Redis
-    async fn test_grpc_health_check() {
-        let (_store, _handle, port) = setup_grpc_test().await;
-        let mut client = create_client(port).await;
+    async fn test_watch() {
+        use kvstore::store::ChangeKind;
+        use std::time::Duration;
 
-        let response = client.health_check(HealthCheckRequest {}).await.unwrap();
+        let store = setup().await;
+        let token = "watch-test-token";
 
-        assert!(response.get_ref().healthy);
+        let events = store.watch(token, "config:", true, None).await.unwrap();
+
+        store.set(token, "config:a", "1", None).await.unwrap();
+        store.set(token, "other", "ignored", None).await.unwrap();
+        store.set(token, "config:a", "2", None).await.unwrap();
+        store.delete(token, "config:a").await.unwrap();
+
+        let events: Vec<_> = tokio::time::timeout(Duration::from_secs(5), events.take(3).collect())
+            .await
+            .expect("timed out waiting for changes");
+        assert_eq!(events[0].kind, ChangeKind::Put);
+        assert_eq!(events[0].key, "config:a");
+        assert_eq!(events[0].value.as_deref(), Some("1"));
+        assert_eq!(events[0].version, Some(1));
+        assert_eq!(events[1].value.as_deref(), Some("2"));
+        assert_eq!(events[1].version, Some(2));
+        assert_eq!(events[2].kind, ChangeKind::Delete);
+        assert_eq!(events[2].key, "config:a");
+
+        // Clean up
+        store.delete_prefix(token, "").await.unwrap();
     }
 
     #[tokio::test]
     #[ignore] // Requires Redis
-    async fn test_grpc_set_and_get() {
-        let (store, _handle, port) = setup_grpc_test().await;
-        let mut client = create_client(port).await;
+    async fn test_watch_collections() {
+        use kvstore::store::{ChangeKind, ListEnd};
+        use std::time::Duration;
 
-        // Set a value
-        let set_response = client
-            .set(SetRequest {
-                key: "grpc-test-key".to_string(),
-                value: "grpc-test-value".to_string(),
-                token: "grpc-test-token".to_string(),
-                ttl_seconds: None,
-            })
-            .await
-            .unwrap();
+        let store = setup().await;
+        let token = "watch-collections-test-token";
 
-        assert!(set_response.get_ref().success);
+        let events = store.watch(token, "", true, None).await.unwrap();
 
-        // Get the value
-        let get_response = client
-            .get(GetRequest {
-                key: "grpc-test-key".to_string(),
-                token: "grpc-test-token".to_string(),
-            })
+        let fields = vec![("name".to_string(), "alice".to_string())];
+        store.hash_set(token, "user", &fields).await.unwrap();
+        store
+            .hash_delete(token, "user", &["name".to_string()])
+            .await
+            .unwrap();
+        store
+            .list_push(token, "queue", &["job".to_string()], ListEnd::Right)
+            .await
+            .unwrap();
+        // Removing a missing member changes nothing and is not reported
+        store
+            .set_remove(token, "tags", &["missing".to_string()])
+            .await
+            .unwrap();
+        store
+            .set_add(token, "tags", &["red".to_string()])
             .await
             .unwrap();
 
-        assert!(get_response.get_ref().found);
-        assert_eq!(get_response.get_ref().value, "grpc-test-value");
+        let events: Vec<_> = tokio::time::timeout(Duration::from_secs(5), events.take(4).collect())
+            .await
+            .expect("timed out waiting for changes");
+        assert_eq!(events[0].kind, ChangeKind::Put);
+        assert_eq!(events[0].key, "user");
+        assert_eq!(events[0].value, None);
+        assert_eq!(events[0].version, None);
+        assert_eq!(events[1].kind, ChangeKind::Delete);
+        assert_eq!(events[1].key, "user");
+        assert_eq!(events[2].kind, ChangeKind::Put);
+        assert_eq!(events[2].key, "queue");
+        assert_eq!(events[3].kind, ChangeKind::Put);
+        assert_eq!(events[3].key, "tags");
 
         // Clean up
-        store
-            .delete("grpc-test-token", "grpc-test-key")
+        store.delete_prefix(token, "").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_change_heartbeats() {
+        use kvstore::store::{ChangeFeedEntry, ChangeOffset};
+        use std::time::Duration;
+
+        let store = setup().await;
+        let token = "heartbeats-test-token";
+        store.set(token, "other", "before", None).await.unwrap();
+
+        // Heartbeats report the position of the feed, including changes
+        // filtered out by prefix
+        let entries = store
+            .changes_with_heartbeats(token, &ChangeOffset::Latest, "doc:")
             .await
             .unwrap();
+        store.set(token, "other", "after", None).await.unwrap();
+        let offsets = entries.filter_map(|entry| match entry.unwrap() {
+            ChangeFeedEntry::Heartbeat { offset, .. } => Some(offset),
+            ChangeFeedEntry::Change(change) => panic!("unexpected change of {}", change.key),
+        });
+        let mut offsets = Box::pin(offsets);
+        let start = offsets.next().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while let Some(offset) = offsets.next().await {
+                if offset != start {
+                    return;
+                }
+            }
+            panic!("the feed ended");
+        })
+        .await
+        .expect("timed out waiting for a heartbeat");
+
+        // Clean up
+        store.delete_prefix(token, "").await.unwrap();
     }
 
     #[tokio::test]
     #[ignore] // Requires Redis
-    async fn test_grpc_delete() {
-        let (store, _handle, port) = setup_grpc_test().await;
+    async fn test_change_log() {
+        use kvstore::store::{ChangeKind, ChangeOffset};
+        use std::time::Duration;
 
-        // Set a value first
-        store
-            .set("grpc-test-token", "grpc-test-key-del", "test-value", None)
+        let store = setup().await;
+        let token = "changes-test-token";
+
+        // The log outlives the keys, so only follow changes from now on
+        let changes = store
+            .changes(token, &ChangeOffset::Latest, "doc:")
             .await
             .unwrap();
 
-        let mut client = create_client(port).await;
+        store.set(token, "doc:1", "a", None).await.unwrap();
+        store.set(token, "other", "ignored", None).await.unwrap();
+        store.set(token, "doc:1", "b", None).await.unwrap();
+        store.delete(token, "doc:1").await.unwrap();
 
-        // Delete the value
-        let delete_response = client
-            .delete(DeleteRequest {
-                key: "grpc-test-key-del".to_string(),
-                token: "grpc-test-token".to_string(),
-            })
+        let changes: Vec<_> =
+            tokio::time::timeout(Duration::from_secs(5), changes.take(3).collect())
+                .await
+                .expect("timed out waiting for changes");
+        let changes: Vec<_> = changes.into_iter().map(Result::unwrap).collect();
+        assert_eq!(changes[0].kind, ChangeKind::Put);
+        assert_eq!(changes[0].value.as_deref(), Some("a"));
+        assert_eq!(changes[1].version, Some(2));
+        assert_eq!(changes[2].kind, ChangeKind::Delete);
+
+        // Consumers resume from their committed offset
+        let offset = ChangeOffset::After(changes[1].id.clone().unwrap());
+        store
+            .commit_change_offset(token, "indexer", &offset)
             .await
             .unwrap();
+        let committed = store.change_offset(token, "indexer").await.unwrap();
+        assert_eq!(committed, Some(offset.clone()));
+        assert_eq!(store.change_offset(token, "unknown").await.unwrap(), None);
 
-        assert!(delete_response.get_ref().success);
+        // Resuming replays the changes after the offset
+        let mut resumed = store.changes(token, &offset, "doc:").await.unwrap();
+        let change = tokio::time::timeout(Duration::from_secs(5), resumed.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.id, changes[2].id);
 
-        // Verify it's deleted
-        let result = store.get("grpc-test-token", "grpc-test-key-del").await;
-        assert!(result.is_err());
+        // New changes are picked up while following
+        store.set(token, "doc:2", "c", None).await.unwrap();
+        let change = tokio::time::timeout(Duration::from_secs(10), resumed.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.key, "doc:2");
+
+        // Clean up
+        store.delete_prefix(token, "").await.unwrap();
     }
 
     #[tokio::test]
     #[ignore] // Requires Redis
-    async fn test_grpc_unauthorized() {
-        let (_store, _handle, port) = setup_grpc_test().await;
-        let mut client = create_client(port).await;
-
-        // Try to get with invalid token
-        let result = client
-            .get(GetRequest {
-                key: "test-key".to_string(),
-                token: "invalid-token".to_string(),
-            })
-            .await;
+    async fn test_soft_delete() {
+        use std::time::Duration;
 
-        assert!(result.is_err());
-        let status = result.unwrap_err();
-        assert_eq!(status.code(), tonic::Code::Unauthenticated);
-    }
-}
+        let store = setup().await;
+        let token = "test_token_trash";
+        store.set_trash_retention(token, None).await.unwrap();
+        store.delete_prefix(token, "").await.unwrap();
+        store.purge_trash(token, None).await.unwrap();
 
-mod store_tests {
-    use super::*;
+        // Without a retention deletes are permanent
+        store.set(token, "gone", "1", None).await.unwrap();
+        store.delete(token, "gone").await.unwrap();
+        assert!(store.list_trash(token, "").await.unwrap().is_empty());
 
-    async fn setup() -> KVStore {
-        let store = KVStore::new("redis://127.0.0.1:6379")
+        store
+            .set_trash_retention(token, Some(Duration::from_secs(3600)))
             .await
-            .expect("Failed to connect to Redis");
-
-        // Add a test token
+            .unwrap();
+        assert_eq!(
+            store.trash_retention(token).await.unwrap(),
+            Some(Duration::from_secs(3600))
+        );
+
+        store.set(token, "config:a", "1", Some(600)).await.unwrap();
+        let version = store
+            .get_entry(token, "config:a")
+            .await
+            .unwrap()
+            .metadata
+            .version;
+        store.delete(token, "config:a").await.unwrap();
+        store.set(token, "config:b", "2", None).await.unwrap();
+        store.delete_prefix(token, "config:").await.unwrap();
+
+        // Trashed keys are invisible but listed in the trash
+        assert!(store.get(token, "config:a").await.is_err());
+        let keys: Vec<String> = store.list(token, "").await.unwrap().collect().await;
+        assert!(keys.is_empty());
+        let trash = store.list_trash(token, "config:").await.unwrap();
+        let names: Vec<&str> = trash.iter().map(|t| t.key.as_str()).collect();
+        assert_eq!(names, ["config:a", "config:b"]);
+        assert!(trash[0].purge_at > trash[0].deleted_at);
+
+        // Undeleting restores the value and TTL as a new version
+        store.undelete(token, "config:a").await.unwrap();
+        let entry = store.get_entry(token, "config:a").await.unwrap();
+        assert_eq!(entry.value, "1");
+        assert_eq!(entry.metadata.version, version + 1);
         let mut conn = store.connection_manager();
-        redis::cmd("SADD")
-            .arg("tokens")
-            .arg("store-test-token")
-            .query_async::<()>(&mut conn)
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(format!("{}:config:a", token))
+            .query_async(&mut conn)
             .await
-            .expect("Failed to add test token");
+            .unwrap();
+        assert!(ttl > 0 && ttl <= 600);
+        assert!(matches!(
+            store.undelete(token, "config:a").await,
+            Err(kvstore::KVStoreError::KeyNotFound(_))
+        ));
+
+        // A key written again since it was deleted is not overwritten
+        store.set(token, "config:b", "3", None).await.unwrap();
+        assert!(matches!(
+            store.undelete(token, "config:b").await,
+            Err(kvstore::KVStoreError::Conflict(_))
+        ));
+
+        assert_eq!(store.purge_trash(token, Some("config:b")).await.unwrap(), 1);
+        assert!(store.list_trash(token, "").await.unwrap().is_empty());
 
-        store
+        // Clean up
+        store.set_trash_retention(token, None).await.unwrap();
+        store.delete_prefix(token, "").await.unwrap();
+        assert!(store.list_trash(token, "").await.unwrap().is_empty());
     }
 
     #[tokio::test]
     #[ignore] // Requires Redis
-    async fn test_set_get_delete() {
+    async fn test_locks() {
+        use kvstore::KVStoreError;
+        use std::time::Duration;
+
         let store = setup().await;
+        let token = "test_token_locks";
+        let ttl = Duration::from_secs(10);
+        let _ = store.release_lock(token, "job", "a").await;
+        let _ = store.release_lock(token, "job", "b").await;
 
-        // Set
-        store
-            .set("store-test-token", "key1", "value1", None)
+        let lease = store
+            .acquire_lock(token, "job", "a", ttl, Duration::ZERO)
             .await
             .unwrap();
+        assert_eq!(lease.owner, "a");
 
-        // Get
-        let value = store.get("store-test-token", "key1").await.unwrap();
-        assert_eq!(value, "value1");
-
-        // Delete
-        store.delete("store-test-token", "key1").await.unwrap();
+        // Another owner fails fast, or gives up once the wait elapses
+        let err = store
+            .acquire_lock(token, "job", "b", ttl, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, KVStoreError::Conflict(_)));
+        let err = store
+            .acquire_lock(token, "job", "b", ttl, Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, KVStoreError::Conflict(_)));
+
+        // Only the owner can renew or release
+        assert!(store.renew_lock(token, "job", "b", ttl).await.is_err());
+        assert!(store.release_lock(token, "job", "b").await.is_err());
+        let renewed = store.renew_lock(token, "job", "a", ttl).await.unwrap();
+        assert_eq!(renewed.fencing_token, lease.fencing_token);
+        let holder = store.lock_holder(token, "job").await.unwrap().unwrap();
+        assert_eq!(holder.owner, "a");
+        assert!(holder.ttl <= ttl);
+
+        // A waiting owner gets the lock once it is released, with a greater
+        // fencing token
+        let waiter = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                store
+                    .acquire_lock(token, "job", "b", ttl, Duration::from_secs(5))
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        store.release_lock(token, "job", "a").await.unwrap();
+        let lease_b = waiter.await.unwrap().unwrap();
+        assert_eq!(lease_b.owner, "b");
+        assert!(lease_b.fencing_token > lease.fencing_token);
+
+        // Expired leases free the lock
+        store.release_lock(token, "job", "b").await.unwrap();
+        store
+            .acquire_lock(
+                token,
+                "job",
+                "a",
+                Duration::from_millis(100),
+                Duration::ZERO,
+            )
+            .await
+            .unwrap();
+        let lease_b = store
+            .acquire_lock(token, "job", "b", ttl, Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(lease_b.owner, "b");
+        assert!(store.renew_lock(token, "job", "a", ttl).await.is_err());
 
-        // Verify deletion
-        let result = store.get("store-test-token", "key1").await;
-        assert!(result.is_err());
+        store.release_lock(token, "job", "b").await.unwrap();
+        assert!(store.lock_holder(token, "job").await.unwrap().is_none());
     }
 
     #[tokio::test]
     #[ignore] // Requires Redis
-    async fn test_set_with_ttl() {
+    async fn test_export_import() {
+        use kvstore::store::{ExportRecord, ExportedValue, ImportConflict, ScoredMember};
+        use kvstore::KVStoreError;
+        use serde_json::json;
+
         let store = setup().await;
+        let (source, target) = ("test_token_export_a", "test_token_export_b");
+        for token in [source, target] {
+            store.delete_prefix(token, "").await.unwrap();
+        }
 
-        // Set with TTL of 2 seconds
         store
-            .set("store-test-token", "ttl-key", "ttl-value", Some(2))
+            .set(source, "greeting", "hello", Some(600))
+            .await
+            .unwrap();
+        store
+            .hash_set(source, "user", &[("name".to_string(), "ada".to_string())])
+            .await
+            .unwrap();
+        store
+            .sorted_set_add(
+                source,
+                "scores",
+                &[ScoredMember {
+                    member: "ada".to_string(),
+                    score: 2.5,
+                }],
+            )
             .await
             .unwrap();
 
-        // Get immediately
-        let value = store.get("store-test-token", "ttl-key").await.unwrap();
-        assert_eq!(value, "ttl-value");
+        let mut records: Vec<_> = store
+            .export_namespace(source, "")
+            .await
+            .unwrap()
+            .map(|record| record.unwrap())
+            .collect()
+            .await;
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            records.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(),
+            ["greeting", "scores", "user"]
+        );
+        assert_eq!(records[0].value, ExportedValue::String("hello".to_string()));
+        assert!(records[0].ttl_ms.unwrap() <= 600_000);
+        assert_eq!(records[0].metadata["version"], "1");
+        assert_eq!(records[1].ttl_ms, None);
+
+        // Import into an empty namespace keeps values, TTLs and metadata
+        let stream = tokio_stream::iter(records.clone().into_iter().map(Ok));
+        let summary = store
+            .import_namespace(target, stream, ImportConflict::Fail)
+            .await
+            .unwrap();
+        assert_eq!((summary.imported, summary.skipped), (3, 0));
+        let entry = store.get_entry(target, "greeting").await.unwrap();
+        assert_eq!(entry.value, "hello");
+        assert_eq!(entry.metadata.version, 1);
+        let fields = store
+            .hash_get(target, "user", &["name".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(fields, [Some("ada".to_string())]);
 
-        // Wait for expiration
-        tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+        // Existing keys are skipped, overwritten or stop the import
+        store
+            .set(target, "greeting", "changed", None)
+            .await
+            .unwrap();
+        let import = |conflict| {
+            let stream = tokio_stream::iter(records.clone().into_iter().map(Ok));
+            store.import_namespace(target, stream, conflict)
+        };
+        let summary = import(ImportConflict::Skip).await.unwrap();
+        assert_eq!((summary.imported, summary.skipped), (0, 3));
+        assert_eq!(store.get(target, "greeting").await.unwrap(), "changed");
+        assert!(matches!(
+            import(ImportConflict::Fail).await,
+            Err(KVStoreError::Conflict(_))
+        ));
+        let summary = import(ImportConflict::Overwrite).await.unwrap();
+        assert_eq!((summary.imported, summary.skipped), (3, 0));
+        assert_eq!(store.get(target, "greeting").await.unwrap(), "hello");
+
+        // Keys are checked against the key policy
+        let mut reserved = records[0].clone();
+        reserved.key = "_kvstore:reserved".to_string();
+        let stream = tokio_stream::iter([Ok(reserved)]);
+        assert!(matches!(
+            store
+                .import_namespace(target, stream, ImportConflict::Overwrite)
+                .await,
+            Err(KVStoreError::InvalidRequest(_))
+        ));
+
+        // String values are validated like any other write
+        store
+            .register_schema(target, "doc:", &json!({"type": "object"}))
+            .await
+            .unwrap();
+        let document = |key: &str, value: &str, content_type: &str| ExportRecord {
+            key: key.to_string(),
+            value: ExportedValue::String(value.to_string()),
+            ttl_ms: None,
+            metadata: [("content_type".to_string(), content_type.to_string())].into(),
+        };
+        for record in [
+            document("doc:a", "[1]", "application/json"),
+            document("plain", "not json", "application/json"),
+            ExportRecord {
+                value: ExportedValue::List(vec!["x".to_string()]),
+                ..document("list", "", "application/json")
+            },
+        ] {
+            let stream = tokio_stream::iter([Ok(record)]);
+            assert!(matches!(
+                store
+                    .import_namespace(target, stream, ImportConflict::Overwrite)
+                    .await,
+                Err(KVStoreError::InvalidRequest(_))
+            ));
+        }
+        let stream = tokio_stream::iter([Ok(document("doc:a", "{}", "application/json"))]);
+        store
+            .import_namespace(target, stream, ImportConflict::Overwrite)
+            .await
+            .unwrap();
+        store.delete_schema(target, "doc:").await.unwrap();
 
-        // Verify expiration
-        let result = store.get("store-test-token", "ttl-key").await;
-        assert!(result.is_err());
+        for token in [source, target] {
+            store.delete_prefix(token, "").await.unwrap();
+        }
     }
 
     #[tokio::test]
     #[ignore] // Requires Redis
-    async fn test_list_keys() {
-        let store = setup().await;
+    async fn test_backup_restore() {
+        use chrono::Utc;
+        use kvstore::backup::{
+            backup_namespace, list_snapshots, restore_snapshot, BackupTarget, FileObjectStore,
+            ObjectStore,
+        };
+        use kvstore::store::ImportConflict;
 
-        // Set multiple keys
-        store
-            .set("store-test-token", "list:key1", "value1", None)
+        let store = setup().await;
+        let token = "test_token_backup";
+        store.delete_prefix(token, "").await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let objects = FileObjectStore::new(dir.path());
+        let target = BackupTarget::new("backup-test", token).unwrap();
+
+        store.set(token, "a", "1", None).await.unwrap();
+        store.set(token, "b", "2", Some(600)).await.unwrap();
+        let first = backup_namespace(&store, &objects, &target, Utc::now())
             .await
             .unwrap();
-        store
-            .set("store-test-token", "list:key2", "value2", None)
+
+        store.set(token, "a", "changed", None).await.unwrap();
+        store.set(token, "c", "3", None).await.unwrap();
+        let later = Utc::now() + chrono::Duration::seconds(1);
+        let second = backup_namespace(&store, &objects, &target, later)
             .await
             .unwrap();
-        store
-            .set("store-test-token", "other:key", "value3", None)
+        assert_eq!(
+            list_snapshots(&objects, "backup-test").await.unwrap(),
+            [first.clone(), second]
+        );
+
+        // Restoring the first snapshot into a clean namespace rolls it back
+        let summary = restore_snapshot(
+            &store,
+            &objects,
+            "backup-test",
+            Some(&first),
+            token,
+            ImportConflict::Fail,
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.imported, 2);
+        assert_eq!(store.get(token, "a").await.unwrap(), "1");
+        assert!(store.get(token, "c").await.is_err());
+
+        // Without a snapshot id the newest one is restored
+        let summary = restore_snapshot(
+            &store,
+            &objects,
+            "backup-test",
+            None,
+            token,
+            ImportConflict::Overwrite,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.imported, 3);
+        assert_eq!(store.get(token, "c").await.unwrap(), "3");
+
+        // A corrupt snapshot is rejected before the namespace is cleaned
+        let corrupt = "{\"key\":\"z\",\"type\":\"string\",\"value\":\"9\"}\nnot json\n";
+        objects
+            .put(
+                "backup-corrupt/20200101T000000Z.ndjson",
+                Box::pin(tokio_stream::iter([Ok(corrupt.as_bytes().to_vec())])),
+            )
             .await
             .unwrap();
+        assert!(restore_snapshot(
+            &store,
+            &objects,
+            "backup-corrupt",
+            None,
+            token,
+            ImportConflict::Fail,
+            true,
+        )
+        .await
+        .is_err());
+        assert_eq!(store.get(token, "c").await.unwrap(), "3");
+        assert!(store.get(token, "z").await.is_err());
+
+        store.delete_prefix(token, "").await.unwrap();
+    }
 
-        // List with prefix
-        let keys: Vec<String> = store.list("store-test-token", "list:").await.unwrap().collect().await;
-        assert_eq!(keys.len(), 2);
-        assert!(keys.contains(&"list:key1".to_string()));
-        assert!(keys.contains(&"list:key2".to_string()));
+    #[tokio::test]
+    #[ignore] // Requires Redis
+    async fn test_apply_replicated_change() {
+        use kvstore::store::{ChangeEvent, ChangeKind};
 
-        // Clean up
-        store.delete("store-test-token", "list:key1").await.unwrap();
-        store.delete("store-test-token", "list:key2").await.unwrap();
-        store.delete("store-test-token", "other:key").await.unwrap();
+        let store = setup().await;
+        let token = "test_token_replicated";
+        store.delete_prefix(token, "").await.unwrap();
+
+        let change = |kind, version, timestamp, value: Option<&str>| ChangeEvent {
+            id: None,
+            kind,
+            key: "k".to_string(),
+            value: value.map(String::from),
+            version: Some(version),
+            content_type: None,
+            timestamp: Some(timestamp),
+            expires_at: None,
+        };
+
+        // Puts keep the source's version and timestamp
+        assert!(store
+            .apply_replicated_change(token, &change(ChangeKind::Put, 3, 1000, Some("a")))
+            .await
+            .unwrap());
+        let entry = store.get_entry(token, "k").await.unwrap();
+        assert_eq!((entry.value.as_str(), entry.metadata.version), ("a", 3));
+
+        // The same change again, or an older one, is stale
+        for (version, timestamp) in [(3, 1000), (3, 999), (2, 5000)] {
+            let put = change(ChangeKind::Put, version, timestamp, Some("b"));
+            assert!(!store.apply_replicated_change(token, &put).await.unwrap());
+        }
+        // The same version written later wins
+        assert!(store
+            .apply_replicated_change(token, &change(ChangeKind::Put, 3, 1001, Some("c")))
+            .await
+            .unwrap());
+        assert_eq!(store.get(token, "k").await.unwrap(), "c");
+
+        // Deletes of an older version are stale
+        assert!(!store
+            .apply_replicated_change(token, &change(ChangeKind::Delete, 2, 9000, None))
+            .await
+            .unwrap());
+        assert!(store
+            .apply_replicated_change(token, &change(ChangeKind::Delete, 3, 9000, None))
+            .await
+            .unwrap());
+        assert!(store.get(token, "k").await.is_err());
+
+        // Puts need a value
+        assert!(store
+            .apply_replicated_change(token, &change(ChangeKind::Put, 4, 9001, None))
+            .await
+            .is_err());
+
+        // Puts expire when the source's value does
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let expiring = ChangeEvent {
+            expires_at: Some(now + 60_000),
+            ..change(ChangeKind::Put, 5, 9002, Some("d"))
+        };
+        assert!(store
+            .apply_replicated_change(token, &expiring)
+            .await
+            .unwrap());
+        let records: Vec<_> = store
+            .export_namespace(token, "k")
+            .await
+            .unwrap()
+            .map(|record| record.unwrap())
+            .collect()
+            .await;
+        assert!(records[0].ttl_ms.unwrap() <= 60_000);
+        let expired = ChangeEvent {
+            expires_at: Some(now - 1),
+            ..change(ChangeKind::Put, 6, 9003, Some("e"))
+        };
+        assert!(store
+            .apply_replicated_change(token, &expired)
+            .await
+            .unwrap());
+        assert!(store.get(token, "k").await.is_err());
     }
 
     #[tokio::test]